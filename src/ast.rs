@@ -0,0 +1,207 @@
+//! The commands that parsing produces and execution runs.
+
+use std::borrow::Cow;
+use std::os::unix::io::RawFd;
+use std::rc::Rc;
+
+/// A parsed shell command. Its words are borrowed from the source text
+/// where they can be; `to_static` makes a copy that owns them all, for
+/// keeping after the source is gone.
+#[derive(Debug)]
+pub(crate) enum Command<'a> {
+    /// A simple command: its words, assignments first, and its
+    /// redirections, all still unexpanded.
+    Simple { words: Vec<Cow<'a, str>>, redirects: Vec<Redirect<'a>> },
+    /// Commands joined by `|`, or a single one, started as `launch` says,
+    /// with the source text that traps, `jobs` and the audit log show.
+    /// Every simple command is in one; a compound command only when it is
+    /// piped or run in the background.
+    Pipeline { stages: Vec<Command<'a>>, launch: Launch, text: Cow<'a, str> },
+    /// `if`/`elif` branches as (condition, body) pairs, plus an optional
+    /// `else` body, with redirections written after `fi`.
+    If {
+        branches: Vec<(Vec<Command<'a>>, Vec<Command<'a>>)>,
+        otherwise: Option<Vec<Command<'a>>>,
+        redirects: Vec<Redirect<'a>>,
+    },
+    /// `while` (or `until` when `until` is set) loop, with redirections
+    /// written after `done` applied to the whole loop.
+    Loop {
+        until: bool,
+        condition: Vec<Command<'a>>,
+        body: Vec<Command<'a>>,
+        redirects: Vec<Redirect<'a>>,
+    },
+    /// `case word in patterns) body;; ... esac`, holding each item's
+    /// alternative patterns and its body.
+    Case {
+        word: Cow<'a, str>,
+        items: Vec<(Vec<String>, Vec<Command<'a>>)>,
+        redirects: Vec<Redirect<'a>>,
+    },
+    /// `for variable in words; do body; done`, with the words expanded
+    /// each time the loop starts. Without `in`, `words` is `None` and the
+    /// loop runs over the positional parameters.
+    For {
+        variable: Cow<'a, str>,
+        words: Option<Vec<Cow<'a, str>>>,
+        body: Vec<Command<'a>>,
+        redirects: Vec<Redirect<'a>>,
+    },
+    /// `[[ expression ]]`, holding the unexpanded words between the brackets.
+    Cond(Vec<Cow<'a, str>>),
+    /// `{ list; }`
+    Group {
+        body: Vec<Command<'a>>,
+        redirects: Vec<Redirect<'a>>,
+    },
+    /// `name() compound-command`, which defines a function when executed.
+    Function {
+        name: Cow<'a, str>,
+        body: Rc<Command<'a>>,
+    },
+    /// `first && second`: runs `second` only if `first` succeeds.
+    And(Box<Command<'a>>, Box<Command<'a>>),
+    /// `first || second`: runs `second` only if `first` fails.
+    Or(Box<Command<'a>>, Box<Command<'a>>),
+    /// `! command`: runs `command` and inverts its exit status.
+    Not(Box<Command<'a>>),
+    /// `time [-p] [command]`: runs `command` and reports how long it took,
+    /// in the POSIX format with `-p`.
+    Time { posix: bool, command: Option<Box<Command<'a>>> },
+    /// `coproc [name] command`: runs `command` in the background with pipes
+    /// to and from it, as set up by `Shell::start_coproc`.
+    Coproc { name: Cow<'a, str>, command: Box<Command<'a>> },
+}
+
+impl Command<'_> {
+    /// A copy of the command that owns all its words.
+    pub(crate) fn to_static(&self) -> Command<'static> {
+        let list = |commands: &[Command]| commands.iter().map(Command::to_static).collect::<Vec<_>>();
+        let words = |words: &[Cow<str>]| words.iter().map(|word| Cow::Owned(word.to_string())).collect::<Vec<_>>();
+        let redirects = |redirects: &[Redirect]| redirects.iter().map(Redirect::to_static).collect::<Vec<_>>();
+        let owned = |text: &Cow<str>| Cow::Owned(text.to_string());
+        match self {
+            Command::Simple { words: w, redirects: r } => Command::Simple { words: words(w), redirects: redirects(r) },
+            Command::Pipeline { stages, launch, text } => Command::Pipeline { stages: list(stages), launch: *launch, text: owned(text) },
+            Command::If { branches, otherwise, redirects: r } => Command::If {
+                branches: branches.iter().map(|(condition, body)| (list(condition), list(body))).collect(),
+                otherwise: otherwise.as_deref().map(list),
+                redirects: redirects(r),
+            },
+            Command::Loop { until, condition, body, redirects: r } => {
+                Command::Loop { until: *until, condition: list(condition), body: list(body), redirects: redirects(r) }
+            }
+            Command::Case { word, items, redirects: r } => Command::Case {
+                word: owned(word),
+                items: items.iter().map(|(patterns, body)| (patterns.clone(), list(body))).collect(),
+                redirects: redirects(r),
+            },
+            Command::For { variable, words: w, body, redirects: r } => {
+                Command::For { variable: owned(variable), words: w.as_deref().map(words), body: list(body), redirects: redirects(r) }
+            }
+            Command::Cond(w) => Command::Cond(words(w)),
+            Command::Group { body, redirects: r } => Command::Group { body: list(body), redirects: redirects(r) },
+            Command::Function { name, body } => Command::Function { name: owned(name), body: Rc::new(body.to_static()) },
+            Command::And(first, second) => Command::And(Box::new(first.to_static()), Box::new(second.to_static())),
+            Command::Or(first, second) => Command::Or(Box::new(first.to_static()), Box::new(second.to_static())),
+            Command::Not(command) => Command::Not(Box::new(command.to_static())),
+            Command::Time { posix, command } => Command::Time { posix: *posix, command: command.as_ref().map(|command| Box::new(command.to_static())) },
+            Command::Coproc { name, command } => Command::Coproc { name: owned(name), command: Box::new(command.to_static()) },
+        }
+    }
+}
+
+/// A redirection of a simple or compound command, of the descriptor
+/// given before the operator or else 0 for `<` and 1 for `>`.
+#[derive(Clone, Debug)]
+pub(crate) enum Redirect<'a> {
+    /// `[n]< file`
+    Input(RawFd, Cow<'a, str>),
+    /// `[n]> file`
+    Output(RawFd, Cow<'a, str>),
+    /// `[n]>| file`: like `>`, but overwrites the file even with `noclobber`.
+    Clobber(RawFd, Cow<'a, str>),
+    /// `[n]<> file`: open for reading and writing, creating the file if
+    /// need be.
+    ReadWrite(RawFd, Cow<'a, str>),
+    /// `[n]<&m` or `[n]>&m`: make `n` a copy of descriptor `m`, or close
+    /// it if `m` is `-`.
+    Duplicate(RawFd, Cow<'a, str>),
+}
+
+impl<'a> Redirect<'a> {
+    /// The redirection made by the operator token `operator` (as split
+    /// off by `tokenize`) and the word after it, if it is one.
+    pub(crate) fn parse(operator: &str, target: Cow<'a, str>) -> Option<Redirect<'a>> {
+        let digits = operator.len() - operator.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let fd = match &operator[..digits] {
+            "" => None,
+            number => Some(number.parse().ok()?),
+        };
+        Some(match &operator[digits..] {
+            "<" => Redirect::Input(fd.unwrap_or(0), target),
+            ">" => Redirect::Output(fd.unwrap_or(1), target),
+            ">|" => Redirect::Clobber(fd.unwrap_or(1), target),
+            "<>" => Redirect::ReadWrite(fd.unwrap_or(0), target),
+            "<&" => Redirect::Duplicate(fd.unwrap_or(0), target),
+            ">&" => Redirect::Duplicate(fd.unwrap_or(1), target),
+            _ => return None,
+        })
+    }
+
+    /// The descriptor redirected.
+    pub(crate) fn fd(&self) -> RawFd {
+        match self {
+            Redirect::Input(fd, _)
+            | Redirect::Output(fd, _)
+            | Redirect::Clobber(fd, _)
+            | Redirect::ReadWrite(fd, _)
+            | Redirect::Duplicate(fd, _) => *fd,
+        }
+    }
+
+    /// The (unexpanded) file name, or descriptor for `Duplicate`.
+    pub(crate) fn target(&self) -> &str {
+        match self {
+            Redirect::Input(_, target)
+            | Redirect::Output(_, target)
+            | Redirect::Clobber(_, target)
+            | Redirect::ReadWrite(_, target)
+            | Redirect::Duplicate(_, target) => target,
+        }
+    }
+
+    /// The same redirection of another target.
+    pub(crate) fn with_target<'b>(&self, target: impl Into<Cow<'b, str>>) -> Redirect<'b> {
+        let target = target.into();
+        match self {
+            Redirect::Input(fd, _) => Redirect::Input(*fd, target),
+            Redirect::Output(fd, _) => Redirect::Output(*fd, target),
+            Redirect::Clobber(fd, _) => Redirect::Clobber(*fd, target),
+            Redirect::ReadWrite(fd, _) => Redirect::ReadWrite(*fd, target),
+            Redirect::Duplicate(fd, _) => Redirect::Duplicate(*fd, target),
+        }
+    }
+
+    /// A copy of the redirection that owns its target.
+    pub(crate) fn to_static(&self) -> Redirect<'static> {
+        self.with_target(self.target().to_string())
+    }
+
+    /// Whether it opens a file for writing.
+    pub(crate) fn writes_file(&self) -> bool {
+        matches!(self, Redirect::Output(..) | Redirect::Clobber(..) | Redirect::ReadWrite(..))
+    }
+}
+
+/// How a pipeline is started, going by how it ends.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Launch {
+    Foreground,
+    /// `command &`
+    Background,
+    /// `command &!`: in the background, left out of the job table and
+    /// immune to hangups, with terminal output going to `nohup.out`.
+    Detached,
+}
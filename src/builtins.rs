@@ -0,0 +1,3623 @@
+//! The builtin commands.
+
+use std::borrow::Cow;
+use std::ffi::CString;
+use std::io::{self, Write};
+use nix::errno::Errno;
+use nix::unistd::{fork, ForkResult, Pid};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::stat::Mode;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use anyhow::Result;
+use crate::{Shell, Status};
+use crate::ast::{Command, Launch, Redirect};
+use crate::exec::{exec_command, search_path};
+use crate::expand::{fnmatch, join_path};
+use crate::direnv;
+use crate::frecency::{self, Visit};
+use crate::plugin::Grants;
+use crate::snapshot::Snapshot;
+use crate::theme::Theme;
+use crate::jobs::{TIMEOUT_KILL_AFTER, TIMEOUT_SENT, TIMEOUT_SIGNAL, TIMEOUT_TARGET, enter_job_process, list_signals, on_sigchld, on_sigint, on_timeout, on_trapped_signal, parse_signal};
+use crate::lexer::{RESERVED_WORDS, assignment, is_name, quote_for_display, tokenize};
+use crate::parser::parse;
+
+impl Shell {
+    /// Start a command in a child process of its own, as a foreground job
+    /// when job control is on, running `setup` in the child just before the
+    /// command. The caller waits for it.
+    pub(crate) fn spawn_foreground(&mut self, argv: &[String], setup: impl FnOnce()) -> nix::Result<Pid> {
+        let program = match &argv[0] {
+            name if !self.is_builtin(name) && !self.functions.contains_key(name) => self.resolve_command(name),
+            _ => None,
+        };
+        let _ = io::stdout().flush();
+        match unsafe { fork()? } {
+            ForkResult::Child => {
+                if self.job_control {
+                    enter_job_process(None, true);
+                }
+                setup();
+                self.exec_in_child(program.as_deref(), argv);
+            }
+            ForkResult::Parent { child } => {
+                if self.job_control {
+                    let _ = nix::unistd::setpgid(child, child);
+                }
+                Ok(child)
+            }
+        }
+    }
+
+    /// `nice [-n adjustment | -adjustment] [command [arg...]]`: run a command
+    /// with its niceness raised by `adjustment` (10 by default; negative
+    /// values need privilege), or print the shell's niceness.
+    fn builtin_nice(&mut self, args: &[String]) -> i32 {
+        let mut adjustment = 10;
+        let mut args = args;
+        if let Some(arg) = args.first() {
+            let value = match arg.as_str() {
+                "-n" => match args.get(1) {
+                    Some(value) => Some((value.as_str(), 2)),
+                    None => {
+                        eprintln!("nice: -n: option requires an argument");
+                        return 125;
+                    }
+                },
+                _ if arg.len() > 1 && arg.starts_with('-') => Some((&arg[1..], 1)),
+                _ => None,
+            };
+            if let Some((value, used)) = value {
+                match value.parse::<i32>() {
+                    Ok(value) => adjustment = value,
+                    Err(_) => {
+                        eprintln!("nice: {}: invalid adjustment", value);
+                        return 125;
+                    }
+                }
+                args = &args[used..];
+            }
+        }
+        let niceness = niceness();
+        if args.is_empty() {
+            println!("{}", niceness);
+            return 0;
+        }
+        let spawned = self.spawn_foreground(args, || {
+            let target = (niceness + adjustment).clamp(-20, 19);
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, target) } != 0 {
+                eprintln!("nice: cannot set niceness: {}", Errno::last().desc());
+            }
+        });
+        match spawned {
+            Ok(child) => self.wait_foreground(args.join(" "), vec![child]),
+            Err(e) => {
+                eprintln!("nice: {}", e.desc());
+                125
+            }
+        }
+    }
+
+    /// `timeout [-s signal] [-k duration] duration command [arg...]`: run a
+    /// command in the foreground, and if it has not finished after
+    /// `duration` send it SIGTERM (or `signal`), then SIGKILL `-k duration`
+    /// later (10s by default). Durations are seconds, or numbers ending in
+    /// s, m, h or d; 0 means no limit. Returns 124 if the command timed out.
+    fn builtin_timeout(&mut self, args: &[String]) -> i32 {
+        let mut signal = Signal::SIGTERM;
+        let mut kill_after = std::time::Duration::from_secs(10);
+        let mut args = args;
+        while let Some(flag) = args.first().filter(|arg| matches!(arg.as_str(), "-s" | "-k")) {
+            let Some(value) = args.get(1) else {
+                eprintln!("timeout: {}: option requires an argument", flag);
+                return 125;
+            };
+            if flag == "-s" {
+                match parse_signal(value).flatten() {
+                    Some(parsed) => signal = parsed,
+                    None => {
+                        eprintln!("timeout: {}: invalid signal", value);
+                        return 125;
+                    }
+                }
+            } else {
+                match parse_duration(value) {
+                    Some(parsed) => kill_after = parsed,
+                    None => {
+                        eprintln!("timeout: {}: invalid time interval", value);
+                        return 125;
+                    }
+                }
+            }
+            args = &args[2..];
+        }
+        let (Some(limit), Some(argv)) = (args.first(), args.get(1..).filter(|argv| !argv.is_empty())) else {
+            eprintln!("timeout: usage: timeout [-s signal] [-k duration] duration command [arg...]");
+            return 125;
+        };
+        let Some(limit) = parse_duration(limit) else {
+            eprintln!("timeout: {}: invalid time interval", limit);
+            return 125;
+        };
+        let child = match self.spawn_foreground(argv, || {}) {
+            Ok(child) => child,
+            Err(e) => {
+                eprintln!("timeout: {}", e.desc());
+                return 125;
+            }
+        };
+        let target = if self.job_control { -child.as_raw() } else { child.as_raw() };
+        TIMEOUT_TARGET.store(target, Ordering::SeqCst);
+        TIMEOUT_SIGNAL.store(signal as i32, Ordering::SeqCst);
+        TIMEOUT_KILL_AFTER.store(kill_after.as_secs_f64().ceil() as i32, Ordering::SeqCst);
+        TIMEOUT_SENT.store(0, Ordering::SeqCst);
+        let action = SigAction::new(SigHandler::Handler(on_timeout), SaFlags::SA_RESTART, SigSet::empty());
+        let _ = unsafe { sigaction(Signal::SIGALRM, &action) };
+        let timer = start_timer(limit);
+        if let Err(e) = &timer {
+            eprintln!("timeout: cannot start timer: {}", e.desc());
+        }
+        let status = self.wait_foreground(argv.join(" "), vec![child]);
+        drop(timer);
+        unsafe { libc::alarm(0) };
+        TIMEOUT_TARGET.store(0, Ordering::SeqCst);
+        let default = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+        let _ = unsafe { sigaction(Signal::SIGALRM, &default) };
+        if TIMEOUT_SENT.load(Ordering::SeqCst) > 0 { 124 } else { status }
+    }
+}
+
+/// Names of the commands the shell runs itself instead of executing.
+pub(crate) const BUILTINS: &[&str] = &["cd", "local", "return", "source", ".", "test", "[", "read", "echo", "printf", "getopts", "eval", "exec", "exit", "true", "false", ":", "type", "hash", "pwd", "pushd", "popd", "dirs", "setopt", "unsetopt", "shopt", "jobs", "fg", "bg", "kill", "disown", "wait", "set", "trap", "umask", "ulimit", "timeout", "nice", "parse", "autoload", "history", "j", "envctl", "envsave", "envrestore", "plugin", "theme", "alias", "unalias"];
+
+/// An option that changes how the shell behaves.
+struct ShellOption {
+    /// The name `set -o`, `setopt` and `shopt` know it by.
+    name: &'static str,
+    /// The single-letter flag of `set` (and of the shell itself), if any.
+    flag: Option<char>,
+}
+
+/// Every option the shell has. `set -o`, `setopt` and `shopt` all work on
+/// this one list.
+const SHELL_OPTIONS: &[ShellOption] = &[
+    ShellOption { name: "autocd", flag: None },
+    ShellOption { name: "cdspell", flag: None },
+    ShellOption { name: "errexit", flag: Some('e') },
+    ShellOption { name: "globcollate", flag: None },
+    ShellOption { name: "globstar", flag: None },
+    ShellOption { name: "histignorealldups", flag: None },
+    ShellOption { name: "histignoredups", flag: None },
+    ShellOption { name: "histignorespace", flag: None },
+    ShellOption { name: "huponexit", flag: None },
+    ShellOption { name: "noclobber", flag: Some('C') },
+    ShellOption { name: "noexec", flag: Some('n') },
+    ShellOption { name: "notify", flag: None },
+    ShellOption { name: "nounset", flag: Some('u') },
+    ShellOption { name: "numericglobsort", flag: None },
+    ShellOption { name: "pipefail", flag: None },
+    ShellOption { name: "printexitvalue", flag: None },
+    ShellOption { name: "restricted", flag: Some('r') },
+    ShellOption { name: "verbose", flag: Some('v') },
+    ShellOption { name: "xtrace", flag: Some('x') },
+];
+
+/// Variables a restricted shell does not let commands change.
+pub(crate) const RESTRICTED_VARIABLES: &[&str] = &["PATH", "SHELL", "ENV", "HISTFILE", "VSSH_JUMP_FILE", "VSSH_TRUST_FILE"];
+
+/// The registered name of an option, if `name` is one.
+fn option_named(name: &str) -> Option<&'static str> {
+    SHELL_OPTIONS.iter().find(|option| option.name == name).map(|option| option.name)
+}
+
+/// The option a single-letter flag of `set` (or of the shell itself) stands for.
+pub(crate) fn option_for_flag(flag: char) -> Option<&'static str> {
+    SHELL_OPTIONS.iter().find(|option| option.flag == Some(flag)).map(|option| option.name)
+}
+
+/// Whether the file at `path` is a WebAssembly module.
+fn is_wasm(path: &str) -> bool {
+    let mut magic = [0; 4];
+    std::fs::File::open(path).and_then(|mut file| io::Read::read_exact(&mut file, &mut magic)).is_ok() && magic == *b"\0asm"
+}
+
+/// Whether `name` can be an alias: a word with nothing in it that quotes,
+/// expands or ends it.
+fn is_alias_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(|c: char| c.is_whitespace() || "/$`='\"\\;|&<>()".contains(c))
+}
+
+impl Shell {
+    /// Whether `name` is a builtin, the shell's own or a plugin's.
+    pub(crate) fn is_builtin(&self, name: &str) -> bool {
+        BUILTINS.contains(&name) || self.plugins.has_builtin(name)
+    }
+
+    /// Runs the builtin named by `argv[0]`, updating `$?`.
+    pub(crate) fn run_builtin(&mut self, argv: &[String]) -> Status {
+        let args = &argv[1..];
+        self.last_status = match argv[0].as_str() {
+            "cd" | "pushd" | "popd" | "j" | "exec" | "envsave" if self.option("restricted") => {
+                eprintln!("{}: restricted", argv[0]);
+                1
+            }
+            "source" | "." if self.option("restricted") && args.first().is_some_and(|path| path.contains('/')) => {
+                eprintln!("{}: {}: restricted: cannot specify `/' in command names", argv[0], args[0]);
+                1
+            }
+            "exit" => return self.builtin_exit(args),
+            "return" => return self.builtin_return(args),
+            "source" | "." => return self.builtin_source(args),
+            "eval" => return self.builtin_eval(args),
+            "autoload" => return self.builtin_autoload(args),
+            "true" | ":" => 0,
+            "false" => 1,
+            "cd" => self.changing_dir(|shell| shell.builtin_cd(args)),
+            "pwd" => self.builtin_pwd(args),
+            "pushd" => self.changing_dir(|shell| shell.builtin_pushd(args)),
+            "popd" => self.changing_dir(|shell| shell.builtin_popd(args)),
+            "dirs" => self.builtin_dirs(args),
+            "setopt" => self.set_options(args, true),
+            "unsetopt" => self.set_options(args, false),
+            "shopt" => self.builtin_shopt(args),
+            "set" => self.builtin_set(args),
+            "jobs" => self.builtin_jobs(args),
+            "fg" => self.builtin_fg(args),
+            "bg" => self.builtin_bg(args),
+            "kill" => self.builtin_kill(args),
+            "disown" => self.builtin_disown(args),
+            "wait" => self.builtin_wait(args),
+            "trap" => self.builtin_trap(args),
+            "umask" => builtin_umask(args),
+            "ulimit" => builtin_ulimit(args),
+            "parse" => builtin_parse(args),
+            "timeout" => self.builtin_timeout(args),
+            "nice" => self.builtin_nice(args),
+            "local" => self.builtin_local(args),
+            "read" => self.builtin_read(args),
+            "getopts" => self.builtin_getopts(args),
+            "type" => self.builtin_type(args),
+            "echo" => builtin_echo(args),
+            "exec" => self.builtin_exec(args),
+            "hash" => self.builtin_hash(args),
+            "alias" => self.builtin_alias(args),
+            "unalias" => self.builtin_unalias(args),
+            "history" => self.builtin_history(args),
+            "j" => self.builtin_j(args),
+            "envctl" => self.builtin_envctl(args),
+            "envsave" | "envrestore" => self.builtin_envsave(&argv[0], args),
+            "plugin" => self.builtin_plugin(args),
+            "theme" => self.builtin_theme(args),
+            "printf" => builtin_printf(args),
+            "test" => builtin_test(args),
+            "[" => match args.split_last() {
+                Some((last, rest)) if last == "]" => builtin_test(rest),
+                _ => {
+                    eprintln!("[: missing `]'");
+                    2
+                }
+            },
+            _ => self.plugins.run_builtin(argv),
+        };
+        Status::Continue
+    }
+
+    /// Run `change`, a command that may change directory, then the `chpwd`
+    /// hook if it did. An interactive shell also counts the visit for `j`
+    /// and loads the environment file of the new directory.
+    pub(crate) fn changing_dir(&mut self, change: impl FnOnce(&mut Shell) -> i32) -> i32 {
+        let old = self.vars.get("PWD").cloned();
+        let status = change(self);
+        if self.vars.get("PWD") != old.as_ref() {
+            self.last_status = status;
+            if self.interactive {
+                self.record_visit();
+                self.update_dir_env();
+            }
+            self.run_hook("chpwd", &[]);
+        }
+        status
+    }
+
+    /// The file trusted environment files are recorded in:
+    /// `$VSSH_TRUST_FILE`, or `~/.vssh_trusted` if that is unset.
+    fn trust_file(&self) -> Option<String> {
+        match self.vars.get("VSSH_TRUST_FILE") {
+            Some(path) => Some(path.clone()).filter(|path| !path.is_empty()),
+            None => self.vars.get("HOME").map(|home| format!("{}/.vssh_trusted", home)),
+        }
+    }
+
+    /// Load the environment file that applies in the current directory,
+    /// if it is trusted, after unloading the one loaded before if it no
+    /// longer applies. An untrusted file is only reported.
+    pub(crate) fn update_dir_env(&mut self) {
+        let wanted = self.vars.get("PWD").and_then(|dir| direnv::find(dir));
+        if self.dir_env.as_ref().map(|loaded| &loaded.path) == wanted.as_ref() {
+            return;
+        }
+        self.unload_dir_env();
+        let Some(path) = wanted else { return };
+        let trusted = match self.trust_file() {
+            Some(trust_file) => direnv::is_trusted(&trust_file, &path),
+            None => Ok(false),
+        };
+        match trusted {
+            Ok(true) => {}
+            Ok(false) => {
+                eprintln!("vssh: {} is not trusted; run `envctl allow` to load it", path);
+                return;
+            }
+            Err(e) => {
+                eprintln!("vssh: {}: {}", path, e);
+                return;
+            }
+        }
+        let assignments = match std::fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|text| direnv::parse(&text)) {
+            Ok(assignments) => assignments,
+            Err(e) => {
+                eprintln!("vssh: {}: {}", path, e);
+                return;
+            }
+        };
+        let mut loaded = direnv::Loaded { path, saved: Vec::new() };
+        for (name, value) in assignments {
+            if !self.may_assign(&name) {
+                continue;
+            }
+            if !loaded.saved.iter().any(|(saved, _, _)| *saved == name) {
+                loaded.saved.push((name.clone(), self.vars.get(&name).cloned(), std::env::var_os(&name)));
+            }
+            unsafe { std::env::set_var(&name, &value) };
+            self.vars.insert(name, value);
+        }
+        let names: Vec<&str> = loaded.saved.iter().map(|(name, _, _)| name.as_str()).collect();
+        eprintln!("vssh: loaded {}: {}", loaded.path, names.join(" "));
+        self.dir_env = Some(loaded);
+    }
+
+    /// Put back the variables the loaded environment file set, if any.
+    fn unload_dir_env(&mut self) {
+        let Some(loaded) = self.dir_env.take() else { return };
+        for (name, value, exported) in loaded.saved.into_iter().rev() {
+            match exported {
+                Some(exported) => unsafe { std::env::set_var(&name, exported) },
+                None => unsafe { std::env::remove_var(&name) },
+            }
+            match value {
+                Some(value) => self.vars.insert(name, value),
+                None => self.vars.remove(&name),
+            };
+        }
+        eprintln!("vssh: unloaded {}", loaded.path);
+    }
+
+    /// `envctl allow|deny [dir]`, `envctl status`: trust the environment
+    /// file that applies in `dir` (the current directory by default) as it
+    /// is now, and load it; or stop trusting it, and unload it; or tell
+    /// which file applies here and whether it is loaded. A restricted
+    /// shell can only ask for the status.
+    fn builtin_envctl(&mut self, args: &[String]) -> i32 {
+        let dir = self.logical_path(args.get(1).map_or(".", String::as_str));
+        let trust = match args.first().map(String::as_str) {
+            Some("allow") => true,
+            Some("deny") => false,
+            Some("status") => {
+                match direnv::find(&dir) {
+                    Some(path) if self.dir_env.as_ref().is_some_and(|loaded| loaded.path == path) => println!("{}: loaded", path),
+                    Some(path) => println!("{}: not loaded", path),
+                    None => println!("no environment file"),
+                }
+                return 0;
+            }
+            _ => {
+                eprintln!("envctl: usage: envctl allow|deny [dir] or envctl status");
+                return 2;
+            }
+        };
+        if self.option("restricted") {
+            eprintln!("envctl: {}: restricted", args[0]);
+            return 1;
+        }
+        let Some(path) = direnv::find(&dir) else {
+            eprintln!("envctl: {}: no environment file", dir);
+            return 1;
+        };
+        let Some(trust_file) = self.trust_file() else {
+            eprintln!("envctl: no trust file: set VSSH_TRUST_FILE");
+            return 1;
+        };
+        if let Err(e) = direnv::set_trusted(&trust_file, &path, trust) {
+            eprintln!("envctl: {}: {}", trust_file, e);
+            return 1;
+        }
+        if trust {
+            // Load it afresh, in case it has changed since it was loaded.
+            self.unload_dir_env();
+            self.update_dir_env();
+        } else if self.dir_env.as_ref().is_some_and(|loaded| loaded.path == path) {
+            self.unload_dir_env();
+        }
+        0
+    }
+
+    /// `envsave name`, `envrestore name`: save the shell's variables,
+    /// arrays, environment, aliases and options as snapshot `name`, or put
+    /// them back as they were in it, unsetting what has been set since.
+    /// `$PWD` and `$OLDPWD` are left alone, as the directory does not
+    /// change, and so are the variables `may_assign` refuses.
+    /// Snapshots are kept in `$VSSH_ENV_DIR`, or `~/.vssh_envs`; as that
+    /// means writing files, a restricted shell cannot save one.
+    fn builtin_envsave(&mut self, command: &str, args: &[String]) -> i32 {
+        let [name] = args else {
+            eprintln!("{}: usage: {} name", command, command);
+            return 2;
+        };
+        if name.is_empty() || name.contains('/') || name.starts_with('.') {
+            eprintln!("{}: {}: invalid snapshot name", command, name);
+            return 2;
+        }
+        let dir = match self.vars.get("VSSH_ENV_DIR").filter(|dir| !dir.is_empty()) {
+            Some(dir) => dir.clone(),
+            None => match self.vars.get("HOME") {
+                Some(home) => format!("{}/.vssh_envs", home),
+                None => {
+                    eprintln!("{}: HOME not set", command);
+                    return 1;
+                }
+            },
+        };
+        let path = join_path(&dir, name);
+        let kept = |name: &String| name != "PWD" && name != "OLDPWD" && self.protection(name).is_none();
+        if command == "envsave" {
+            let snapshot = Snapshot {
+                vars: self.vars.iter().filter(|(name, _)| kept(name)).map(|(name, value)| (name.clone(), value.clone())).collect(),
+                arrays: self.arrays.clone(),
+                exported: std::env::vars().filter(|(name, _)| kept(name)).collect(),
+                aliases: self.aliases.clone(),
+                options: self.options.iter().map(|name| name.to_string()).collect(),
+            };
+            if let Err(e) = std::fs::create_dir_all(&dir).and_then(|_| snapshot.save(&path)) {
+                eprintln!("envsave: {}: {}", path, e);
+                return 1;
+            }
+            return 0;
+        }
+        let snapshot = match Snapshot::load(&path) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                eprintln!("envrestore: {}: {}", path, e);
+                return 1;
+            }
+        };
+        let stale: Vec<String> = self.vars.keys().filter(|name| kept(name) && !snapshot.vars.contains_key(*name)).cloned().collect();
+        let vars: Vec<(String, String)> = snapshot.vars.into_iter().filter(|(name, _)| kept(name)).collect();
+        let stale_exported: Vec<String> = std::env::vars().map(|(name, _)| name).filter(|name| kept(name) && !snapshot.exported.contains_key(name)).collect();
+        let exported: Vec<(String, String)> = snapshot.exported.into_iter().filter(|(name, _)| kept(name)).collect();
+        let stale_arrays: Vec<String> = self.arrays.keys().filter(|name| kept(name)).cloned().collect();
+        let arrays: Vec<(String, Vec<String>)> = snapshot.arrays.into_iter().filter(|(name, _)| kept(name)).collect();
+        for name in stale {
+            self.vars.remove(&name);
+        }
+        self.vars.extend(vars);
+        for name in stale_arrays {
+            self.arrays.remove(&name);
+        }
+        self.arrays.extend(arrays);
+        self.aliases = snapshot.aliases.into_iter().filter(|(name, _)| is_alias_name(name)).collect();
+        for name in stale_exported {
+            unsafe { std::env::remove_var(name) };
+        }
+        for (name, value) in exported {
+            unsafe { std::env::set_var(name, value) };
+        }
+        for option in SHELL_OPTIONS {
+            let on = snapshot.options.iter().any(|name| name == option.name);
+            if on != self.option(option.name) {
+                self.set_option(option.name, on);
+            }
+        }
+        0
+    }
+
+    /// `plugin load [-e] [-r dir]... path...`, `plugin list`: load plugins,
+    /// or list those loaded and what they provide. A plugin is either a
+    /// native shared library or a WebAssembly module, which is sandboxed:
+    /// `-e` lets it read the environment, and `-r` files in `dir`. A
+    /// restricted shell may only load sandboxed plugins, with no grants.
+    fn builtin_plugin(&mut self, args: &[String]) -> i32 {
+        match args.split_first() {
+            Some((command, rest)) if command == "load" => {
+                let mut grants = Grants::default();
+                let mut rest = rest;
+                loop {
+                    match rest {
+                        [flag, more @ ..] if flag == "-e" => {
+                            grants.env = true;
+                            rest = more;
+                        }
+                        [flag, dir, more @ ..] if flag == "-r" => {
+                            match std::fs::canonicalize(self.logical_path(dir)) {
+                                Ok(dir) => grants.read.push(dir),
+                                Err(e) => {
+                                    eprintln!("plugin: {}: {}", dir, e);
+                                    return 1;
+                                }
+                            }
+                            rest = more;
+                        }
+                        [flag, ..] if flag == "--" => {
+                            rest = &rest[1..];
+                            break;
+                        }
+                        _ => break,
+                    }
+                }
+                if rest.is_empty() || rest[0].starts_with('-') {
+                    eprintln!("plugin: usage: plugin load [-e] [-r dir]... path...");
+                    return 2;
+                }
+                let restricted = self.option("restricted");
+                if restricted && (grants.env || !grants.read.is_empty()) {
+                    eprintln!("plugin: restricted: cannot grant access");
+                    return 1;
+                }
+                let mut status = 0;
+                for path in rest {
+                    let result = if is_wasm(path) {
+                        let path = self.logical_path(path);
+                        self.plugins.load_sandboxed(&path, grants.clone())
+                    } else if restricted {
+                        Err("restricted: only WebAssembly plugins can be loaded".to_string())
+                    } else if path.contains('/') {
+                        self.plugins.load(&self.logical_path(path))
+                    } else {
+                        // Looked up by `dlopen`, in the library path, rather
+                        // than in the current directory.
+                        self.plugins.load(path)
+                    };
+                    if let Err(e) = result {
+                        eprintln!("plugin: {}: {}", path, e);
+                        status = 1;
+                    }
+                }
+                status
+            }
+            Some((command, [])) if command == "list" => {
+                for line in self.plugins.describe() {
+                    println!("{}", line);
+                }
+                0
+            }
+            _ => {
+                eprintln!("plugin: usage: plugin load [-e] [-r dir]... path... or plugin list");
+                2
+            }
+        }
+    }
+
+    /// The directory prompt themes are kept in: `vssh/themes` in
+    /// `$XDG_CONFIG_HOME`, or in `~/.config` if that is unset.
+    fn themes_dir(&self) -> Option<String> {
+        match self.vars.get("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+            Some(config) => Some(format!("{}/vssh/themes", config)),
+            None => self.vars.get("HOME").map(|home| format!("{}/.config/vssh/themes", home)),
+        }
+    }
+
+    /// `theme use name`, `theme off`, `theme list`: lay the prompt out as
+    /// theme `name.toml` in the themes directory says, or go back to the
+    /// plain prompt, or list the themes there, marking the one in use.
+    fn builtin_theme(&mut self, args: &[String]) -> i32 {
+        let Some(dir) = self.themes_dir() else {
+            eprintln!("theme: HOME not set");
+            return 1;
+        };
+        match args {
+            [command, name] if command == "use" => {
+                if name.is_empty() || name.contains('/') {
+                    eprintln!("theme: {}: invalid theme name", name);
+                    return 2;
+                }
+                let path = format!("{}/{}.toml", dir, name);
+                let theme = std::fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|text| Theme::parse(&text));
+                match theme {
+                    Ok(theme) => {
+                        self.theme = Some((name.clone(), theme));
+                        0
+                    }
+                    Err(e) => {
+                        eprintln!("theme: {}: {}", path, e);
+                        1
+                    }
+                }
+            }
+            [command] if command == "off" => {
+                self.theme = None;
+                0
+            }
+            [command] if command == "list" => {
+                let mut names: Vec<String> = std::fs::read_dir(&dir)
+                    .into_iter()
+                    .flatten()
+                    .flatten()
+                    .filter_map(|entry| entry.file_name().to_str()?.strip_suffix(".toml").map(str::to_string))
+                    .collect();
+                names.sort();
+                let current = self.theme.as_ref().map(|(name, _)| name);
+                for name in names {
+                    println!("{} {}", if current == Some(&name) { '*' } else { ' ' }, name);
+                }
+                0
+            }
+            _ => {
+                eprintln!("theme: usage: theme use name, theme off or theme list");
+                2
+            }
+        }
+    }
+
+    /// The file `j` keeps visited directories in: `$VSSH_JUMP_FILE`, or
+    /// `~/.vssh_jumps` if that is unset. Set but empty, there is none, and
+    /// visits are not counted.
+    fn jump_file(&self) -> Option<String> {
+        match self.vars.get("VSSH_JUMP_FILE") {
+            Some(path) => Some(path.clone()).filter(|path| !path.is_empty()),
+            None => self.vars.get("HOME").map(|home| format!("{}/.vssh_jumps", home)),
+        }
+    }
+
+    /// Count a visit to the current directory, unless it is `$HOME`, which
+    /// is never worth jumping to.
+    fn record_visit(&mut self) {
+        let (Some(path), Some(dir)) = (self.jump_file(), self.vars.get("PWD")) else { return };
+        if self.vars.get("HOME") == Some(dir) {
+            return;
+        }
+        if let Err(e) = frecency::record(&path, dir) {
+            eprintln!("vssh: {}: {}", path, e);
+        }
+    }
+
+    /// `j [-l] term...`: go to the directory, of those `cd` has been to,
+    /// whose path contains each `term` in turn and that has been visited
+    /// most, counting recent visits for more. Case is ignored if no path
+    /// matches with it. With `-l`, list the candidates and their scores
+    /// instead, best last. Like `cd`, it is refused in a restricted shell.
+    fn builtin_j(&mut self, args: &[String]) -> i32 {
+        let (list, terms) = match args.first().map(String::as_str) {
+            Some("-l") => (true, &args[1..]),
+            _ => (false, args),
+        };
+        if terms.is_empty() && !list {
+            eprintln!("j: usage: j [-l] term...");
+            return 2;
+        }
+        let Some(path) = self.jump_file() else {
+            eprintln!("j: no directories recorded");
+            return 1;
+        };
+        let visits = match frecency::load(&path) {
+            Ok(visits) => visits,
+            Err(e) => {
+                eprintln!("j: {}: {}", path, e);
+                return 1;
+            }
+        };
+        let candidates = |fold: bool| -> Vec<&Visit> {
+            visits.iter().filter(|visit| frecency::matches(&visit.path, terms, fold) && std::path::Path::new(&visit.path).is_dir()).collect()
+        };
+        let mut candidates = match candidates(false) {
+            found if found.is_empty() => candidates(true),
+            found => found,
+        };
+        let now = frecency::now();
+        candidates.sort_by(|a, b| a.score(now).total_cmp(&b.score(now)));
+        if list {
+            for visit in candidates {
+                println!("{:<10.1} {}", visit.score(now), visit.path);
+            }
+            return 0;
+        }
+        match candidates.last() {
+            Some(visit) => {
+                let dir = [visit.path.clone()];
+                self.changing_dir(|shell| shell.builtin_cd(&dir))
+            }
+            None => {
+                eprintln!("j: {}: no matching directory", terms.join(" "));
+                1
+            }
+        }
+    }
+
+    /// `cd [-L|-P] [dir]`: change directory, to `$HOME` by default. `$PWD` follows the path as
+    /// written (`-L`, the default) or with symlinks resolved (`-P`), and
+    /// `$OLDPWD` keeps the previous one for `cd -`.
+    pub(crate) fn builtin_cd(&mut self, args: &[String]) -> i32 {
+        let (physical, args) = match args.first().map(String::as_str) {
+            Some("-P") => (true, &args[1..]),
+            Some("-L") => (false, &args[1..]),
+            _ => (false, args),
+        };
+        let mut dir = match args.first() {
+            Some(dir) => dir.clone(),
+            None => match self.vars.get("HOME") {
+                Some(home) => home.clone(),
+                None => {
+                    eprintln!("cd: HOME not set");
+                    return 1;
+                }
+            },
+        };
+        // Print the new directory when it is not the one that was typed.
+        let mut print = dir == "-";
+        if print {
+            match self.vars.get("OLDPWD") {
+                Some(oldpwd) => dir = oldpwd.clone(),
+                None => {
+                    eprintln!("cd: OLDPWD not set");
+                    return 1;
+                }
+            }
+        } else if let Some(found) = self.search_cdpath(&dir) {
+            dir = found;
+            print = true;
+        }
+        let mut target = if physical { dir.clone() } else { self.logical_path(&dir) };
+        let mut suggestion = None;
+        if !std::path::Path::new(&target).exists()
+            && let Some(fixed) = correct_dir_spelling(&dir)
+        {
+            if self.option("cdspell") || confirm_correction(&dir, &fixed) {
+                target = if physical { fixed.clone() } else { self.logical_path(&fixed) };
+                dir = fixed;
+                print = true;
+            } else {
+                suggestion = Some(fixed);
+            }
+        }
+        if let Err(e) = std::env::set_current_dir(&target) {
+            eprintln!("cd: {}: {}", dir, e);
+            if let Some(fixed) = suggestion {
+                eprintln!("cd: did you mean `{}`?", fixed);
+            }
+            return 1;
+        }
+        let pwd = if physical {
+            std::env::current_dir().map(|dir| dir.display().to_string()).unwrap_or(target)
+        } else {
+            target
+        };
+        if print {
+            println!("{}", pwd);
+        }
+        if let Some(oldpwd) = self.vars.insert("PWD".to_string(), pwd) {
+            self.vars.insert("OLDPWD".to_string(), oldpwd);
+        }
+        0
+    }
+
+    /// `pushd [dir]`: save the current directory on the stack and change to
+    /// `dir`, or without one swap the current directory with the top entry.
+    fn builtin_pushd(&mut self, args: &[String]) -> i32 {
+        let old = self.vars.get("PWD").cloned().unwrap_or_default();
+        let status = match args.first() {
+            Some(dir) => {
+                let status = self.builtin_cd(std::slice::from_ref(dir));
+                if status == 0 {
+                    self.dir_stack.insert(0, old);
+                }
+                status
+            }
+            None => {
+                let Some(top) = self.dir_stack.first().cloned() else {
+                    eprintln!("pushd: no other directory");
+                    return 1;
+                };
+                let status = self.builtin_cd(&[top]);
+                if status == 0 {
+                    self.dir_stack[0] = old;
+                }
+                status
+            }
+        };
+        if status == 0 {
+            self.builtin_dirs(&[]);
+        }
+        status
+    }
+
+    /// `popd`: change to the top directory of the stack and remove it.
+    fn builtin_popd(&mut self, _args: &[String]) -> i32 {
+        let Some(top) = self.dir_stack.first().cloned() else {
+            eprintln!("popd: directory stack empty");
+            return 1;
+        };
+        let status = self.builtin_cd(&[top]);
+        if status == 0 {
+            self.dir_stack.remove(0);
+            self.builtin_dirs(&[]);
+        }
+        status
+    }
+
+    /// `dirs [-c] [-v]`: print the directory stack, current directory
+    /// first, one numbered entry per line with `-v`. `-c` empties it.
+    fn builtin_dirs(&mut self, args: &[String]) -> i32 {
+        let mut verbose = false;
+        for arg in args {
+            match arg.as_str() {
+                "-c" => self.dir_stack.clear(),
+                "-v" => verbose = true,
+                _ => {
+                    eprintln!("dirs: {}: invalid option", arg);
+                    return 2;
+                }
+            }
+        }
+        let home = self.vars.get("HOME").filter(|home| home.len() > 1);
+        let pwd = self.vars.get("PWD").cloned().unwrap_or_default();
+        let entries: Vec<String> = std::iter::once(&pwd)
+            .chain(&self.dir_stack)
+            .map(|dir| match home.and_then(|home| dir.strip_prefix(home.as_str())) {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("~{}", rest),
+                _ => dir.clone(),
+            })
+            .collect();
+        if verbose {
+            for (n, dir) in entries.iter().enumerate() {
+                println!("{:2}  {}", n, dir);
+            }
+        } else {
+            println!("{}", entries.join(" "));
+        }
+        0
+    }
+
+    /// Whether the option `name`, which must be in `SHELL_OPTIONS`, is on.
+    pub(crate) fn option(&self, name: &str) -> bool {
+        debug_assert!(option_named(name).is_some(), "unknown option {}", name);
+        self.options.contains(name)
+    }
+
+    /// Turn an option from `SHELL_OPTIONS` on or off. A restricted shell
+    /// stays restricted.
+    pub(crate) fn set_option(&mut self, name: &'static str, on: bool) {
+        if name == "restricted" && !on && self.option(name) {
+            eprintln!("vssh: restricted: cannot be turned off");
+            return;
+        }
+        if on {
+            self.options.insert(name);
+        } else {
+            self.options.remove(name);
+        }
+    }
+
+    /// `setopt name...` / `unsetopt name...`: turn options on or off.
+    /// `setopt` alone lists the options that are on.
+    fn set_options(&mut self, names: &[String], on: bool) -> i32 {
+        if names.is_empty() && on {
+            for option in SHELL_OPTIONS.iter().filter(|option| self.option(option.name)) {
+                println!("{}", option.name);
+            }
+            return 0;
+        }
+        let mut status = 0;
+        for name in names {
+            match option_named(name) {
+                Some(name) => self.set_option(name, on),
+                None => {
+                    eprintln!("{}: no such option: {}", if on { "setopt" } else { "unsetopt" }, name);
+                    status = 1;
+                }
+            }
+        }
+        status
+    }
+
+    /// `$-`: the flags of the options that are on, and `i` in an
+    /// interactive shell.
+    pub(crate) fn option_flags(&self) -> String {
+        let mut flags: String = SHELL_OPTIONS
+            .iter()
+            .filter(|option| self.option(option.name))
+            .filter_map(|option| option.flag)
+            .collect();
+        if self.interactive {
+            flags.push('i');
+        }
+        flags
+    }
+
+    /// `set [-e|+e] [-o|+o name] [--] [arg...]`: turn options on (`-`) or
+    /// off (`+`), and replace the positional parameters with any `arg`s.
+    /// Without arguments, lists the shell variables; `-o` without a name
+    /// lists the options, and `+o` prints the `set` commands that would
+    /// restore them, for saving in `~/.vsshrc`.
+    fn builtin_set(&mut self, args: &[String]) -> i32 {
+        if args.is_empty() {
+            let mut names: Vec<&String> = self.vars.keys().collect();
+            names.sort();
+            for name in names {
+                println!("{}={}", name, self.vars[name]);
+            }
+            return 0;
+        }
+        let mut i = 0;
+        while let Some(arg) = args.get(i) {
+            let on = arg.starts_with('-');
+            if arg == "--" {
+                i += 1;
+                self.positional = args[i..].to_vec();
+                return 0;
+            } else if arg == "-o" || arg == "+o" {
+                match args.get(i + 1) {
+                    Some(name) => {
+                        if self.set_options(std::slice::from_ref(name), on) != 0 {
+                            return 1;
+                        }
+                    }
+                    None if on => {
+                        for option in SHELL_OPTIONS {
+                            println!("{:15}\t{}", option.name, if self.option(option.name) { "on" } else { "off" });
+                        }
+                    }
+                    None => {
+                        for option in SHELL_OPTIONS {
+                            println!("set {}o {}", if self.option(option.name) { '-' } else { '+' }, option.name);
+                        }
+                    }
+                }
+                i += 2;
+            } else if arg.len() > 1 && (on || arg.starts_with('+')) {
+                for flag in arg[1..].chars() {
+                    match option_for_flag(flag) {
+                        Some(name) => self.set_option(name, on),
+                        None => {
+                            eprintln!("set: {}{}: invalid option", &arg[..1], flag);
+                            return 2;
+                        }
+                    };
+                }
+                i += 1;
+            } else {
+                break;
+            }
+        }
+        if i < args.len() {
+            self.positional = args[i..].to_vec();
+        }
+        0
+    }
+
+    /// `shopt [-s|-u|-p] [name...]`: turn options on (`-s`) or off (`-u`),
+    /// or report whether they are on, as a table or (`-p`) as `shopt`
+    /// commands that would restore them.
+    fn builtin_shopt(&mut self, args: &[String]) -> i32 {
+        match args.first().map(String::as_str) {
+            Some("-s") => self.set_options(&args[1..], true),
+            Some("-u") => self.set_options(&args[1..], false),
+            first => {
+                let reusable = first == Some("-p");
+                let args = if reusable { &args[1..] } else { args };
+                let names: Vec<&str> = if args.is_empty() {
+                    SHELL_OPTIONS.iter().map(|option| option.name).collect()
+                } else {
+                    args.iter().map(String::as_str).collect()
+                };
+                let mut status = 0;
+                for name in names {
+                    let Some(name) = option_named(name) else {
+                        eprintln!("shopt: {}: invalid shell option name", name);
+                        status = 1;
+                        continue;
+                    };
+                    let on = self.option(name);
+                    if reusable {
+                        println!("shopt {} {}", if on { "-s" } else { "-u" }, name);
+                    } else {
+                        println!("{:15}\t{}", name, if on { "on" } else { "off" });
+                    }
+                    if !on {
+                        status = 1;
+                    }
+                }
+                status
+            }
+        }
+    }
+
+    /// Look up a relative `dir` that is not in the current directory in
+    /// each `$CDPATH` directory, returning the first existing match.
+    /// Paths starting with `.` or `..` are never searched for.
+    fn search_cdpath(&self, dir: &str) -> Option<String> {
+        let cdpath = self.vars.get("CDPATH")?;
+        if dir.starts_with('/') || matches!(dir.split('/').next(), Some("." | "..")) || std::path::Path::new(dir).is_dir() {
+            return None;
+        }
+        cdpath
+            .split(':')
+            .filter(|prefix| !prefix.is_empty())
+            .map(|prefix| format!("{}/{}", prefix.trim_end_matches('/'), dir))
+            .find(|candidate| std::path::Path::new(candidate).is_dir())
+    }
+
+    /// `dir` made absolute against `$PWD`, with `.` and `..` components
+    /// removed textually rather than by following symlinks.
+    fn logical_path(&self, dir: &str) -> String {
+        let base = if dir.starts_with('/') { "" } else { self.vars.get("PWD").map(String::as_str).unwrap_or_default() };
+        let mut components: Vec<&str> = Vec::new();
+        for component in base.split('/').chain(dir.split('/')) {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    components.pop();
+                }
+                _ => components.push(component),
+            }
+        }
+        format!("/{}", components.join("/"))
+    }
+
+    /// `pwd [-L|-P]`: print `$PWD`, or with `-P` the directory with all
+    /// symlinks resolved.
+    fn builtin_pwd(&mut self, args: &[String]) -> i32 {
+        let physical = args.iter().rev().find(|arg| *arg == "-P" || *arg == "-L").is_some_and(|arg| arg == "-P");
+        match self.working_dir(physical) {
+            Ok(dir) => println!("{}", dir),
+            Err(e) => {
+                eprintln!("pwd: {}", e);
+                return 1;
+            }
+        }
+        0
+    }
+
+    /// The current directory as `pwd` shows it: `$PWD` while it still
+    /// names it, unless `physical` asks for the one with symlinks resolved.
+    fn working_dir(&self, physical: bool) -> std::io::Result<String> {
+        match self.vars.get("PWD").filter(|pwd| names_current_dir(pwd)) {
+            Some(pwd) if !physical => Ok(pwd.clone()),
+            _ => std::env::current_dir().map(|dir| dir.display().to_string()),
+        }
+    }
+
+    /// `exit [n]`: terminate the shell with status `n`, or the last
+    /// command's status.
+    fn builtin_exit(&mut self, args: &[String]) -> Status {
+        if self.interactive && nix::unistd::getpid() == self.pid && self.warn_about_jobs() {
+            self.last_status = 1;
+            return Status::Continue;
+        }
+        if let Some(arg) = args.first() {
+            match arg.parse::<i32>() {
+                Ok(n) => self.last_status = n & 0xff,
+                Err(_) => {
+                    eprintln!("exit: {}: numeric argument required", arg);
+                    self.last_status = 2;
+                }
+            }
+        }
+        Status::Exit
+    }
+
+    /// `return [n]`: leave the current function or sourced file with
+    /// status `n`, or the last command's status.
+    fn builtin_return(&mut self, args: &[String]) -> Status {
+        if self.local_frames.is_empty() && self.source_depth == 0 {
+            eprintln!("return: can only `return' from a function or sourced script");
+            self.last_status = 1;
+            return Status::Continue;
+        }
+        if let Some(arg) = args.first() {
+            match arg.parse::<i32>() {
+                Ok(n) => self.last_status = n & 0xff,
+                Err(_) => {
+                    eprintln!("return: {}: numeric argument required", arg);
+                    self.last_status = 2;
+                }
+            }
+        }
+        Status::Return
+    }
+
+    /// `source file [args...]` / `. file`: run a file in the current shell.
+    fn builtin_source(&mut self, args: &[String]) -> Status {
+        let Some(path) = args.first() else {
+            eprintln!("source: filename argument required");
+            self.last_status = 2;
+            return Status::Continue;
+        };
+        let saved = (args.len() > 1).then(|| std::mem::replace(&mut self.positional, args[1..].to_vec()));
+        self.source_depth += 1;
+        let status = self.source_file(path);
+        self.source_depth -= 1;
+        if let Some(saved) = saved {
+            self.positional = saved;
+        }
+        match status {
+            Ok(Status::Exit) => Status::Exit,
+            Ok(_) => Status::Continue,
+            Err(e) => {
+                eprintln!("source: {}: {}", path, e);
+                self.last_status = 1;
+                Status::Continue
+            }
+        }
+    }
+
+    /// `read [-rs] [-p prompt] [-n count] [name...]`: read a line from
+    /// standard input and split it on `$IFS` into the named variables, the
+    /// last one taking the rest of the line. Without names, sets `REPLY`.
+    fn builtin_read(&mut self, args: &[String]) -> i32 {
+        let mut raw = false;
+        let mut silent = false;
+        let mut prompt = None;
+        let mut count = None;
+        let mut args = args.iter();
+        let mut names = Vec::new();
+        while let Some(arg) = args.next() {
+            if names.is_empty() && arg.len() > 1 && arg.starts_with('-') {
+                for (i, flag) in arg.char_indices().skip(1) {
+                    match flag {
+                        'r' => raw = true,
+                        's' => silent = true,
+                        'p' | 'n' => {
+                            let value = match &arg[i + 1..] {
+                                "" => args.next().cloned(),
+                                rest => Some(rest.to_string()),
+                            };
+                            let Some(value) = value else {
+                                eprintln!("read: -{}: option requires an argument", flag);
+                                return 2;
+                            };
+                            if flag == 'p' {
+                                prompt = Some(value);
+                            } else {
+                                match value.parse::<usize>() {
+                                    Ok(n) => count = Some(n),
+                                    Err(_) => {
+                                        eprintln!("read: {}: invalid number", value);
+                                        return 2;
+                                    }
+                                }
+                            }
+                            break;
+                        }
+                        _ => {
+                            eprintln!("read: -{}: invalid option", flag);
+                            return 2;
+                        }
+                    }
+                }
+            } else {
+                names.push(arg.clone());
+            }
+        }
+        if let Some(name) = names.iter().find(|name| !is_name(name)) {
+            eprintln!("read: `{}': not a valid identifier", name);
+            return 1;
+        }
+        if let Some(prompt) = prompt {
+            eprint!("{}", prompt);
+        }
+
+        let saved_termios = configure_tty_for_read(silent, count.is_some());
+        let (line, complete) = read_input_line(raw, count);
+        if let Some(termios) = saved_termios {
+            use nix::sys::termios::{tcsetattr, SetArg};
+            let _ = tcsetattr(0, SetArg::TCSANOW, &termios);
+            if silent {
+                eprintln!();
+            }
+        }
+
+        if names.is_empty() {
+            self.vars.insert("REPLY".to_string(), line.clone());
+        } else {
+            let ifs = self.vars.get("IFS").cloned().unwrap_or_else(|| " \t\n".to_string());
+            let mut rest = line.trim_matches(|c| ifs.contains(c));
+            for (i, name) in names.iter().enumerate() {
+                let value = if i + 1 == names.len() {
+                    std::mem::take(&mut rest)
+                } else {
+                    let end = rest.find(|c| ifs.contains(c)).unwrap_or(rest.len());
+                    let (value, remainder) = rest.split_at(end);
+                    rest = remainder.trim_start_matches(|c| ifs.contains(c));
+                    value
+                };
+                if self.may_assign(name) {
+                    self.vars.insert(name.clone(), value.to_string());
+                }
+            }
+        }
+        if complete || !line.is_empty() && count.is_some() { 0 } else { 1 }
+    }
+
+    /// `getopts optstring name [arg...]`: parse the next option from the
+    /// arguments (default: the positional parameters) into `name`, with
+    /// its argument in `OPTARG` and the next index in `OPTIND`. A leading
+    /// `:` in `optstring` selects silent error reporting.
+    fn builtin_getopts(&mut self, args: &[String]) -> i32 {
+        let [optstring, name, rest @ ..] = args else {
+            eprintln!("getopts: usage: getopts optstring name [arg ...]");
+            return 2;
+        };
+        if !["OPTIND", "OPTARG", name].iter().all(|name| self.may_assign(name)) {
+            return 1;
+        }
+        let words = if rest.is_empty() { self.positional.clone() } else { rest.to_vec() };
+        let (silent, optstring) = match optstring.strip_prefix(':') {
+            Some(optstring) => (true, optstring),
+            None => (false, optstring.as_str()),
+        };
+        let mut optind = self.vars.get("OPTIND").and_then(|v| v.parse().ok()).unwrap_or(1).max(1);
+        let mut offset = if self.getopts_position.0 == optind { self.getopts_position.1 } else { 1 };
+        self.vars.remove("OPTARG");
+
+        let result = 'parse: {
+            let Some(word) = words.get(optind - 1) else { break 'parse None };
+            if offset == 1 {
+                if word == "--" {
+                    optind += 1;
+                    break 'parse None;
+                }
+                if !word.starts_with('-') || word == "-" {
+                    break 'parse None;
+                }
+            }
+            let Some(option) = word[offset..].chars().next() else { break 'parse None };
+            offset += option.len_utf8();
+            let at_end = offset >= word.len();
+            if at_end {
+                optind += 1;
+                offset = 1;
+            }
+            let spec = optstring.find(option).filter(|_| option != ':');
+            let Some(spec) = spec else {
+                if silent {
+                    self.vars.insert("OPTARG".to_string(), option.to_string());
+                } else {
+                    eprintln!("{}: illegal option -- {}", self.name, option);
+                }
+                break 'parse Some("?".to_string());
+            };
+            if optstring[spec + option.len_utf8()..].starts_with(':') {
+                let value = if !at_end {
+                    let value = word[offset..].to_string();
+                    optind += 1;
+                    offset = 1;
+                    Some(value)
+                } else if let Some(value) = words.get(optind - 1) {
+                    optind += 1;
+                    Some(value.clone())
+                } else {
+                    None
+                };
+                match value {
+                    Some(value) => {
+                        self.vars.insert("OPTARG".to_string(), value);
+                    }
+                    None if silent => {
+                        self.vars.insert("OPTARG".to_string(), option.to_string());
+                        break 'parse Some(":".to_string());
+                    }
+                    None => {
+                        eprintln!("{}: option requires an argument -- {}", self.name, option);
+                        break 'parse Some("?".to_string());
+                    }
+                }
+            }
+            Some(option.to_string())
+        };
+
+        self.getopts_position = (optind, offset);
+        self.vars.insert("OPTIND".to_string(), optind.to_string());
+        match result {
+            Some(option) => {
+                self.vars.insert(name.clone(), option);
+                0
+            }
+            None => {
+                self.vars.insert(name.clone(), "?".to_string());
+                1
+            }
+        }
+    }
+
+    /// `eval [arg...]`: join the arguments with spaces and run the result
+    /// as shell input in the current shell.
+    fn builtin_eval(&mut self, args: &[String]) -> Status {
+        self.last_status = 0;
+        let source = args.join(" ");
+        let mut lines = source.lines();
+        self.run(|_, _| lines.next().map(str::to_string))
+    }
+
+    /// `autoload name...`: define each `name` as a function that is loaded
+    /// on its first call, from the file of that name in the first directory
+    /// of `$FPATH` that has one. The file must define the function; until
+    /// it is needed, a large function library costs nothing at startup.
+    /// `autoload -X name`, the body given to such a function, loads it and
+    /// then runs it with the same arguments.
+    fn builtin_autoload(&mut self, args: &[String]) -> Status {
+        if let [flag, name] = args
+            && flag == "-X"
+        {
+            return self.load_function(name);
+        }
+        self.last_status = 0;
+        for name in args {
+            if !is_name(name) {
+                eprintln!("autoload: {}: not a valid function name", name);
+                self.last_status = 1;
+                continue;
+            }
+            if !self.functions.contains_key(name) {
+                let body = parse(&format!("autoload -X {}", name)).expect("a simple command");
+                self.functions.insert(name.clone(), Rc::new(Command::Group { body, redirects: Vec::new() }));
+            }
+        }
+        Status::Continue
+    }
+
+    /// Worker for `autoload -X`, run in place of the function `name`.
+    fn load_function(&mut self, name: &str) -> Status {
+        let stub = self.functions.get(name).cloned();
+        let fpath = self.vars.get("FPATH").cloned().unwrap_or_default();
+        let found = fpath
+            .split(':')
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| join_path(dir, name))
+            .find(|file| std::path::Path::new(file).is_file());
+        let Some(file) = found else {
+            eprintln!("autoload: {}: function definition file not found", name);
+            self.last_status = 1;
+            return Status::Continue;
+        };
+        self.source_depth += 1;
+        let status = self.source_file(&file);
+        self.source_depth -= 1;
+        match status {
+            Ok(Status::Exit) => return Status::Exit,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("autoload: {}: {}", file, e);
+                self.last_status = 1;
+                return Status::Continue;
+            }
+        }
+        let defined = self.functions.get(name).is_some_and(|body| !stub.as_ref().is_some_and(|stub| Rc::ptr_eq(stub, body)));
+        if !defined {
+            eprintln!("autoload: {}: not defined by {}", name, file);
+            self.last_status = 1;
+            return Status::Continue;
+        }
+        self.builtin_eval(&[format!("{} \"$@\"", name)])
+    }
+
+    /// `type [-a] name...`: describe how each name would be run. `-a`
+    /// lists every match in precedence order instead of only the first.
+    fn builtin_type(&mut self, args: &[String]) -> i32 {
+        let (all, names) = match args.first().map(String::as_str) {
+            Some("-a") => (true, &args[1..]),
+            _ => (false, args),
+        };
+        let mut status = 0;
+        for name in names {
+            let matches = self.describe(name, all);
+            if matches.is_empty() {
+                eprintln!("type: {}: not found", name);
+                status = 1;
+            }
+            let shown = if all { matches.len() } else { 1 };
+            for line in matches.iter().take(shown) {
+                println!("{}", line);
+            }
+        }
+        status
+    }
+
+    /// What `type` says of `name`: how it would be run, first, then with
+    /// `all` the other ways it could be.
+    fn describe(&self, name: &str, all: bool) -> Vec<String> {
+        let mut matches = Vec::new();
+        if let Some(value) = self.aliases.get(name) {
+            matches.push(format!("{} is aliased to `{}'", name, value));
+        }
+        if RESERVED_WORDS.contains(&name) {
+            matches.push(format!("{} is a shell keyword", name));
+        }
+        if self.functions.contains_key(name) {
+            matches.push(format!("{} is a function", name));
+        }
+        if self.is_builtin(name) {
+            matches.push(format!("{} is a shell builtin", name));
+        }
+        let path = self.vars.get("PATH").map(String::as_str).unwrap_or_default();
+        for file in search_path(name, path, all) {
+            matches.push(format!("{} is {}", name, file));
+        }
+        matches
+    }
+
+    /// `exec [command [arg...]]`: replace the shell with `command`. Without
+    /// one, only the redirections take effect, and they stay in place.
+    fn builtin_exec(&mut self, args: &[String]) -> i32 {
+        if args.is_empty() {
+            return 0;
+        }
+        let _ = io::stdout().flush();
+        let program = self.resolve_command(&args[0]);
+        if !self.policy_permits(&args[0], program.as_deref()) {
+            return 126;
+        }
+        exec_command(program.as_deref(), args);
+    }
+
+    /// `alias [name[=value]...]`: define each `name` to stand for `value`
+    /// as the first word of a command, or show the named aliases, or all
+    /// of them, in a form that can be read back in.
+    fn builtin_alias(&mut self, args: &[String]) -> i32 {
+        if args.is_empty() {
+            let mut names: Vec<&String> = self.aliases.keys().collect();
+            names.sort();
+            for name in names {
+                println!("alias {}={}", name, quote_for_display(&self.aliases[name]));
+            }
+            return 0;
+        }
+        let mut status = 0;
+        for arg in args {
+            match arg.split_once('=') {
+                Some((name, value)) if is_alias_name(name) => {
+                    self.aliases.insert(name.to_string(), value.to_string());
+                }
+                Some((name, _)) => {
+                    eprintln!("alias: {}: invalid alias name", name);
+                    status = 1;
+                }
+                None => match self.aliases.get(arg) {
+                    Some(value) => println!("alias {}={}", arg, quote_for_display(value)),
+                    None => {
+                        eprintln!("alias: {}: not found", arg);
+                        status = 1;
+                    }
+                },
+            }
+        }
+        status
+    }
+
+    /// `unalias -a | name...`: remove the named aliases, or all of them.
+    fn builtin_unalias(&mut self, args: &[String]) -> i32 {
+        if args.first().is_some_and(|arg| arg == "-a") {
+            self.aliases.clear();
+            return 0;
+        }
+        if args.is_empty() {
+            eprintln!("unalias: usage: unalias [-a] name...");
+            return 2;
+        }
+        let mut status = 0;
+        for name in args {
+            if self.aliases.remove(name).is_none() {
+                eprintln!("unalias: {}: not found", name);
+                status = 1;
+            }
+        }
+        status
+    }
+
+    /// `history [-c] [count]`: list the command lines typed at the prompt,
+    /// numbered, or the last `count` of them, or forget them all with `-c`.
+    fn builtin_history(&mut self, args: &[String]) -> i32 {
+        let entries = self.history.entries();
+        let shown = match args.first().map(String::as_str) {
+            None => entries.len(),
+            Some("-c") => {
+                self.history.clear();
+                return 0;
+            }
+            Some(count) => match count.parse::<usize>() {
+                Ok(count) => count.min(entries.len()),
+                Err(_) => {
+                    eprintln!("history: {}: numeric argument required", count);
+                    return 2;
+                }
+            },
+        };
+        for (number, line) in (1..).zip(entries).skip(entries.len() - shown) {
+            println!("{:5}  {}", number, line);
+        }
+        0
+    }
+
+    /// `hash [-r] [name...]`: list the remembered command locations, forget
+    /// them all with `-r` (rescanning `$PATH`), or look up and remember
+    /// each `name`.
+    fn builtin_hash(&mut self, args: &[String]) -> i32 {
+        let mut names = args;
+        let mut reset = false;
+        while let Some(option) = names.first().filter(|arg| arg.starts_with('-') && *arg != "-") {
+            names = &names[1..];
+            match option.as_str() {
+                "-r" => reset = true,
+                "--" => break,
+                _ => {
+                    eprintln!("hash: {}: invalid option", option);
+                    return 2;
+                }
+            }
+        }
+        if reset {
+            self.hashed.clear();
+            self.path_index.clear();
+        }
+        if names.is_empty() {
+            if reset {
+                return 0;
+            }
+            if self.hashed.is_empty() {
+                println!("hash: hash table empty");
+                return 0;
+            }
+            let mut entries: Vec<_> = self.hashed.values().collect();
+            entries.sort();
+            println!("hits\tcommand");
+            for (file, hits) in entries {
+                println!("{:4}\t{}", hits, file);
+            }
+            return 0;
+        }
+        let mut status = 0;
+        for name in names {
+            if self.is_builtin(name) || name.contains('/') {
+                continue;
+            }
+            self.hashed.remove(name);
+            match self.resolve_command(name) {
+                Some(_) => {
+                    if let Some((_, hits)) = self.hashed.get_mut(name.as_str()) {
+                        *hits = 0;
+                    }
+                }
+                None => {
+                    eprintln!("hash: {}: not found", name);
+                    status = 1;
+                }
+            }
+        }
+        status
+    }
+
+    /// `local name[=value]...`: declare variables scoped to the current
+    /// function call.
+    fn builtin_local(&mut self, args: &[String]) -> i32 {
+        if self.local_frames.is_empty() {
+            eprintln!("local: can only be used in a function");
+            return 1;
+        }
+        let mut status = 0;
+        for arg in args {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+            if !is_name(name) {
+                eprintln!("local: `{}': not a valid identifier", arg);
+                status = 1;
+                continue;
+            }
+            if !self.may_assign(name) {
+                status = 1;
+                continue;
+            }
+            let frame = self.local_frames.last_mut().expect("checked above");
+            if !frame.contains_key(name) {
+                frame.insert(name.to_string(), self.vars.get(name).cloned());
+            }
+            self.vars.insert(name.to_string(), value.unwrap_or_default().to_string());
+        }
+        status
+    }
+
+    /// `trap [-lp] [[action] condition...]`: run `action` when one of the
+    /// signals arrives, for `EXIT` (or 0) when the shell exits, for `DEBUG`
+    /// before each simple command, or for `ERR` when one fails. An empty
+    /// action ignores the signal, and `-` (or no action) puts back its
+    /// default. Without arguments, or with `-p`, prints the traps as the
+    /// commands that would set them again.
+    fn builtin_trap(&mut self, args: &[String]) -> i32 {
+        match args.first().map(String::as_str) {
+            Some("-l") => return list_signals(&args[1..]),
+            None | Some("-p") => return self.print_traps(args.get(1..).unwrap_or_default()),
+            Some("--") => return self.builtin_trap(&args[1..]),
+            _ => {}
+        }
+        // A lone condition, or a list starting with a signal number, is
+        // reset like `trap - condition...`.
+        let (action, conditions) = if args.len() == 1 || args[0].parse::<u32>().is_ok() {
+            (None, args)
+        } else if args[0] == "-" {
+            (None, &args[1..])
+        } else {
+            (Some(args[0].clone()), &args[1..])
+        };
+        let mut status = 0;
+        for condition in conditions {
+            let Some((name, signal)) = trap_condition(condition) else {
+                eprintln!("trap: {}: invalid signal specification", condition);
+                status = 1;
+                continue;
+            };
+            if let Err(e) = self.set_trap(name, signal, action.clone()) {
+                eprintln!("trap: {}: {}", condition, e.desc());
+                status = 1;
+            }
+        }
+        status
+    }
+
+    /// `trap -p [condition...]`: print the given traps, or all of them.
+    fn print_traps(&self, conditions: &[String]) -> i32 {
+        let mut names = Vec::new();
+        let mut status = 0;
+        for condition in conditions {
+            match trap_condition(condition) {
+                Some((name, _)) => names.push(name),
+                None => {
+                    eprintln!("trap: {}: invalid signal specification", condition);
+                    status = 1;
+                }
+            }
+        }
+        if conditions.is_empty() {
+            names = self.traps.keys().cloned().collect();
+            names.sort();
+        }
+        for name in names {
+            if let Some(action) = self.traps.get(&name) {
+                println!("trap -- {} {}", quote_for_display(action), name);
+            }
+        }
+        status
+    }
+
+    /// Set (`Some`) or remove (`None`) the trap for a condition, changing
+    /// how the shell handles its signal, if it has one, to match.
+    fn set_trap(&mut self, name: String, signal: Option<Signal>, action: Option<String>) -> nix::Result<()> {
+        if let Some(signal) = signal {
+            let handler = match &action {
+                None => self.default_handler(signal),
+                Some(action) if action.is_empty() => SigHandler::SigIgn,
+                Some(_) => SigHandler::Handler(on_trapped_signal),
+            };
+            let flags = if self.interactive { SaFlags::empty() } else { SaFlags::SA_RESTART };
+            unsafe { sigaction(signal, &SigAction::new(handler, flags, SigSet::empty())) }?;
+        }
+        match action {
+            Some(action) => self.traps.insert(name, action),
+            None => self.traps.remove(&name),
+        };
+        Ok(())
+    }
+
+    /// How the shell handles a signal when no trap is set for it.
+    fn default_handler(&self, signal: Signal) -> SigHandler {
+        match signal {
+            Signal::SIGCHLD => SigHandler::Handler(on_sigchld),
+            Signal::SIGINT if self.interactive => SigHandler::Handler(on_sigint),
+            Signal::SIGTSTP | Signal::SIGTTIN | Signal::SIGTTOU if self.job_control => SigHandler::SigIgn,
+            _ => SigHandler::SigDfl,
+        }
+    }
+}
+
+/// The name a `trap` condition is stored under, and its signal: `EXIT` (or
+/// 0), `DEBUG` and `ERR` have none.
+fn trap_condition(text: &str) -> Option<(String, Option<Signal>)> {
+    if let Some(name) = ["EXIT", "DEBUG", "ERR"].into_iter().find(|name| text.eq_ignore_ascii_case(name)) {
+        return Some((name.to_string(), None));
+    }
+    match parse_signal(text)? {
+        None => Some(("EXIT".to_string(), None)),
+        Some(signal) => Some((signal.as_str()[3..].to_string(), Some(signal))),
+    }
+}
+
+/// `echo [-neE] [arg...]`: print the arguments separated by spaces. `-n`
+/// drops the trailing newline and `-e` interprets backslash escapes.
+fn builtin_echo(args: &[String]) -> i32 {
+    let mut newline = true;
+    let mut escapes = false;
+    let mut args = args;
+    while let Some(flags) = args.first().and_then(|arg| arg.strip_prefix('-')) {
+        if flags.is_empty() || !flags.chars().all(|c| matches!(c, 'n' | 'e' | 'E')) {
+            break;
+        }
+        for flag in flags.chars() {
+            match flag {
+                'n' => newline = false,
+                'e' => escapes = true,
+                _ => escapes = false,
+            }
+        }
+        args = &args[1..];
+    }
+    let mut output = args.join(" ");
+    if escapes {
+        let (text, stop) = interpret_escapes(&output);
+        output = text;
+        newline &= !stop;
+    }
+    if newline {
+        output.push('\n');
+    }
+    let mut stdout = io::stdout().lock();
+    match stdout.write_all(output.as_bytes()).and_then(|_| stdout.flush()) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// Expand the backslash escapes understood by `echo -e`. Returns the text
+/// and whether a `\c` asked for all further output to be suppressed.
+pub(crate) fn interpret_escapes(text: &str) -> (String, bool) {
+    let mut output = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        let Some(escape) = chars.next() else {
+            output.push('\\');
+            break;
+        };
+        match escape {
+            'n' => output.push('\n'),
+            't' => output.push('\t'),
+            'r' => output.push('\r'),
+            'a' => output.push('\x07'),
+            'b' => output.push('\x08'),
+            'e' | 'E' => output.push('\x1b'),
+            'f' => output.push('\x0c'),
+            'v' => output.push('\x0b'),
+            '\\' => output.push('\\'),
+            'c' => return (output, true),
+            '0' | 'x' => {
+                let (radix, max_digits) = if escape == '0' { (8, 3) } else { (16, 2) };
+                let mut value = 0;
+                let mut digits = 0;
+                while digits < max_digits {
+                    let Some(digit) = chars.peek().and_then(|c| c.to_digit(radix)) else { break };
+                    value = value * radix + digit;
+                    digits += 1;
+                    chars.next();
+                }
+                if escape == 'x' && digits == 0 {
+                    output.push_str("\\x");
+                } else {
+                    output.extend(char::from_u32(value));
+                }
+            }
+            other => {
+                output.push('\\');
+                output.push(other);
+            }
+        }
+    }
+    (output, false)
+}
+
+/// `umask [-p] [-S] [mode]`: set the file creation mask to `mode`, octal
+/// or symbolic as in `u=rwx,g=rx,o=`, or print it: in octal, `-S`
+/// symbolically, or `-p` as a `umask` command.
+fn builtin_umask(args: &[String]) -> i32 {
+    let mut symbolic = false;
+    let mut reusable = false;
+    let mut args = args;
+    while let Some(flags) = args.first().and_then(|arg| arg.strip_prefix('-')).filter(|flags| !flags.is_empty()) {
+        for flag in flags.chars() {
+            match flag {
+                'S' => symbolic = true,
+                'p' => reusable = true,
+                _ => {
+                    eprintln!("umask: -{}: invalid option", flag);
+                    return 2;
+                }
+            }
+        }
+        args = &args[1..];
+    }
+    let mask = nix::sys::stat::umask(Mode::empty());
+    let Some(mode) = args.first() else {
+        nix::sys::stat::umask(mask);
+        let shown = if symbolic { symbolic_mode(mask) } else { format!("{:04o}", mask.bits()) };
+        if reusable {
+            println!("umask{} {}", if symbolic { " -S" } else { "" }, shown);
+        } else {
+            println!("{}", shown);
+        }
+        return 0;
+    };
+    let new_mask = if mode.chars().all(|c| c.is_digit(8)) {
+        libc::mode_t::from_str_radix(mode, 8).ok().filter(|bits| *bits <= 0o777)
+    } else {
+        apply_symbolic_mode(mask.bits(), mode)
+    };
+    match new_mask {
+        Some(bits) => {
+            nix::sys::stat::umask(Mode::from_bits_truncate(bits));
+            0
+        }
+        None => {
+            nix::sys::stat::umask(mask);
+            eprintln!("umask: {}: invalid mode", mode);
+            1
+        }
+    }
+}
+
+/// The permissions a mask leaves, as `umask -S` shows them.
+fn symbolic_mode(mask: Mode) -> String {
+    let allowed = !mask.bits();
+    let classes = [('u', 6), ('g', 3), ('o', 0)];
+    let parts: Vec<String> = classes
+        .iter()
+        .map(|&(class, shift)| {
+            let perms: String = [('r', 4), ('w', 2), ('x', 1)]
+                .iter()
+                .filter(|&&(_, bit)| allowed >> shift & bit != 0)
+                .map(|&(perm, _)| perm)
+                .collect();
+            format!("{}={}", class, perms)
+        })
+        .collect();
+    parts.join(",")
+}
+
+/// Apply a symbolic mode such as `u=rwx,go-w` to the permissions `mask`
+/// leaves, returning the new mask, or `None` if the mode is malformed.
+fn apply_symbolic_mode(mask: libc::mode_t, mode: &str) -> Option<libc::mode_t> {
+    let mut allowed = !mask & 0o777;
+    for clause in mode.split(',') {
+        let at = clause.find(['=', '+', '-'])?;
+        let (who, rest) = clause.split_at(at);
+        let mut shifts = Vec::new();
+        for class in who.chars() {
+            match class {
+                'u' => shifts.push(6),
+                'g' => shifts.push(3),
+                'o' => shifts.push(0),
+                'a' => shifts.extend([6, 3, 0]),
+                _ => return None,
+            }
+        }
+        if shifts.is_empty() {
+            shifts.extend([6, 3, 0]);
+        }
+        let (op, perms) = rest.split_at(1);
+        let mut bits = 0;
+        for perm in perms.chars() {
+            bits |= match perm {
+                'r' => 4,
+                'w' => 2,
+                'x' => 1,
+                _ => return None,
+            };
+        }
+        for shift in shifts {
+            match op {
+                "=" => allowed = allowed & !(7 << shift) | bits << shift,
+                "+" => allowed |= bits << shift,
+                _ => allowed &= !(bits << shift),
+            }
+        }
+    }
+    Some(!allowed & 0o777)
+}
+
+/// A resource limit `ulimit` knows: its flag, what `-a` calls it, and the
+/// unit its value is shown in, in bytes (1 for plain counts).
+struct ResourceLimit {
+    flag: char,
+    resource: nix::sys::resource::Resource,
+    description: &'static str,
+    unit: libc::rlim_t,
+}
+
+const RESOURCE_LIMITS: &[ResourceLimit] = {
+    use nix::sys::resource::Resource;
+    &[
+        ResourceLimit { flag: 'c', resource: Resource::RLIMIT_CORE, description: "core file size          (blocks, -c)", unit: 1024 },
+        ResourceLimit { flag: 'd', resource: Resource::RLIMIT_DATA, description: "data seg size           (kbytes, -d)", unit: 1024 },
+        ResourceLimit { flag: 'f', resource: Resource::RLIMIT_FSIZE, description: "file size               (blocks, -f)", unit: 1024 },
+        ResourceLimit { flag: 'n', resource: Resource::RLIMIT_NOFILE, description: "open files                      (-n)", unit: 1 },
+        ResourceLimit { flag: 's', resource: Resource::RLIMIT_STACK, description: "stack size              (kbytes, -s)", unit: 1024 },
+        ResourceLimit { flag: 't', resource: Resource::RLIMIT_CPU, description: "cpu time               (seconds, -t)", unit: 1 },
+        ResourceLimit { flag: 'u', resource: Resource::RLIMIT_NPROC, description: "max user processes              (-u)", unit: 1 },
+        ResourceLimit { flag: 'v', resource: Resource::RLIMIT_AS, description: "virtual memory          (kbytes, -v)", unit: 1024 },
+    ]
+};
+
+/// `ulimit [-SHa] [-cdfnstuv] [limit]`: print or set the resource limits
+/// that the shell and the commands it starts run under. `-S` and `-H`
+/// pick the soft or hard limit; setting changes both unless one is given,
+/// and printing shows the soft one. The default limit is `-f`. A `limit`
+/// is a number, `unlimited`, or the current `soft` or `hard` limit.
+fn builtin_ulimit(args: &[String]) -> i32 {
+    use nix::sys::resource::{getrlimit, setrlimit};
+    let mut soft = false;
+    let mut hard = false;
+    let mut all = false;
+    let mut selected = Vec::new();
+    let mut args = args;
+    while let Some(flags) = args.first().and_then(|arg| arg.strip_prefix('-')).filter(|flags| !flags.is_empty()) {
+        for flag in flags.chars() {
+            match flag {
+                'S' => soft = true,
+                'H' => hard = true,
+                'a' => all = true,
+                _ => match RESOURCE_LIMITS.iter().find(|limit| limit.flag == flag) {
+                    Some(limit) => selected.push(limit),
+                    None => {
+                        eprintln!("ulimit: -{}: invalid option", flag);
+                        return 2;
+                    }
+                },
+            }
+        }
+        args = &args[1..];
+    }
+    if all {
+        selected = RESOURCE_LIMITS.iter().collect();
+    } else if selected.is_empty() {
+        selected.extend(RESOURCE_LIMITS.iter().find(|limit| limit.flag == 'f'));
+    }
+    let show = |value: libc::rlim_t, unit: libc::rlim_t| {
+        if value == libc::RLIM_INFINITY { "unlimited".to_string() } else { (value / unit).to_string() }
+    };
+    let Some(value) = args.first().filter(|_| !all) else {
+        let mut status = 0;
+        for limit in &selected {
+            match getrlimit(limit.resource) {
+                Ok((current, maximum)) => {
+                    let value = show(if hard && !soft { maximum } else { current }, limit.unit);
+                    if selected.len() > 1 {
+                        println!("{} {}", limit.description, value);
+                    } else {
+                        println!("{}", value);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("ulimit: -{}: {}", limit.flag, e.desc());
+                    status = 1;
+                }
+            }
+        }
+        return status;
+    };
+    if selected.len() > 1 {
+        eprintln!("ulimit: only one limit can be set at a time");
+        return 2;
+    }
+    let limit = selected[0];
+    let Ok((current, maximum)) = getrlimit(limit.resource) else {
+        eprintln!("ulimit: -{}: cannot get limit", limit.flag);
+        return 1;
+    };
+    let new = match value.as_str() {
+        "unlimited" => libc::RLIM_INFINITY,
+        "soft" => current,
+        "hard" => maximum,
+        _ => match value.parse::<libc::rlim_t>().ok().and_then(|n| n.checked_mul(limit.unit)) {
+            Some(new) => new,
+            None => {
+                eprintln!("ulimit: {}: invalid number", value);
+                return 1;
+            }
+        },
+    };
+    let (soft, hard) = if soft || hard { (soft, hard) } else { (true, true) };
+    let result = setrlimit(
+        limit.resource,
+        if soft { new } else { current },
+        if hard { new } else { maximum },
+    );
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("ulimit: -{}: cannot modify limit: {}", limit.flag, e.desc());
+            1
+        }
+    }
+}
+
+/// `parse [-t] command-line`: show how the shell reads a command line
+/// without running it, as a tree of its commands, pipelines, words and
+/// redirections, each word split into its quoted and unquoted parts. With
+/// `-t`, show the tokens instead.
+fn builtin_parse(args: &[String]) -> i32 {
+    let tokens = args.first().is_some_and(|arg| arg == "-t");
+    let source = args[tokens as usize..].join(" ");
+    if tokens {
+        for token in tokenize(&source) {
+            println!("{:>4}..{:<4} {}", token.start, token.end, quote_for_display(token.text));
+        }
+        return 0;
+    }
+    match parse(&source) {
+        Ok(commands) => {
+            let mut out = String::new();
+            for command in &commands {
+                describe_command(command, 0, &mut out);
+            }
+            print!("{}", out);
+            0
+        }
+        Err(e) => {
+            eprintln!("vssh: parse: syntax error: {}", e);
+            2
+        }
+    }
+}
+
+/// Describe `command` for `parse`, one line per part, indented by `depth`
+/// levels.
+fn describe_command(command: &Command, depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth);
+    let describe_list = |out: &mut String, label: &str, list: &[Command]| {
+        out.push_str(&format!("{}  {}\n", indent, label));
+        for command in list {
+            describe_command(command, depth + 2, out);
+        }
+    };
+    let describe_redirects = |out: &mut String, redirects: &[Redirect]| {
+        for redirect in redirects {
+            out.push_str(&format!("{}  redirect {}\n", indent, describe_redirect(redirect)));
+        }
+    };
+    match command {
+        Command::Simple { words, redirects } => {
+            out.push_str(&format!("{}command\n", indent));
+            let mut leading = true;
+            for word in words {
+                leading &= assignment(word).is_some();
+                let kind = if leading { "assignment" } else { "word" };
+                out.push_str(&format!("{}  {} {}{}\n", indent, kind, word, describe_word(word)));
+            }
+            describe_redirects(out, redirects);
+        }
+        Command::Pipeline { stages, launch, .. } => {
+            let launch = match launch {
+                Launch::Foreground => "",
+                Launch::Background => " (background)",
+                Launch::Detached => " (background, detached)",
+            };
+            match &stages[..] {
+                [stage] if launch.is_empty() => describe_command(stage, depth, out),
+                _ => {
+                    out.push_str(&format!("{}pipeline{}\n", indent, launch));
+                    for (i, stage) in stages.iter().enumerate() {
+                        if i > 0 {
+                            out.push_str(&format!("{}  |\n", indent));
+                        }
+                        describe_command(stage, depth + 1, out);
+                    }
+                }
+            }
+        }
+        Command::If { branches, otherwise, redirects } => {
+            out.push_str(&format!("{}if\n", indent));
+            for (i, (condition, body)) in branches.iter().enumerate() {
+                describe_list(out, if i == 0 { "condition" } else { "elif" }, condition);
+                describe_list(out, "then", body);
+            }
+            if let Some(body) = otherwise {
+                describe_list(out, "else", body);
+            }
+            describe_redirects(out, redirects);
+        }
+        Command::Loop { until, condition, body, redirects } => {
+            out.push_str(&format!("{}{}\n", indent, if *until { "until" } else { "while" }));
+            describe_list(out, "condition", condition);
+            describe_list(out, "do", body);
+            describe_redirects(out, redirects);
+        }
+        Command::Case { word, items, redirects } => {
+            out.push_str(&format!("{}case {}{}\n", indent, word, describe_word(word)));
+            for (patterns, body) in items {
+                describe_list(out, &format!("pattern {}", patterns.join(" | ")), body);
+            }
+            describe_redirects(out, redirects);
+        }
+        Command::For { variable, words, body, redirects } => {
+            out.push_str(&format!("{}for {}\n", indent, variable));
+            match words {
+                Some(words) => {
+                    for word in words {
+                        out.push_str(&format!("{}  in {}{}\n", indent, word, describe_word(word)));
+                    }
+                }
+                None => out.push_str(&format!("{}  in \"$@\"\n", indent)),
+            }
+            describe_list(out, "do", body);
+            describe_redirects(out, redirects);
+        }
+        Command::Cond(words) => {
+            out.push_str(&format!("{}[[\n", indent));
+            for word in words {
+                out.push_str(&format!("{}  word {}{}\n", indent, word, describe_word(word)));
+            }
+        }
+        Command::Group { body, redirects } => {
+            out.push_str(&format!("{}group\n", indent));
+            for command in body {
+                describe_command(command, depth + 1, out);
+            }
+            describe_redirects(out, redirects);
+        }
+        Command::Function { name, body } => {
+            out.push_str(&format!("{}function {}\n", indent, name));
+            describe_command(body, depth + 1, out);
+        }
+        Command::And(first, second) | Command::Or(first, second) => {
+            out.push_str(&format!("{}{}\n", indent, if matches!(command, Command::And(..)) { "&&" } else { "||" }));
+            describe_command(first, depth + 1, out);
+            describe_command(second, depth + 1, out);
+        }
+        Command::Not(command) => {
+            out.push_str(&format!("{}!\n", indent));
+            describe_command(command, depth + 1, out);
+        }
+        Command::Time { posix, command } => {
+            out.push_str(&format!("{}time{}\n", indent, if *posix { " -p" } else { "" }));
+            if let Some(command) = command {
+                describe_command(command, depth + 1, out);
+            }
+        }
+        Command::Coproc { name, command } => {
+            out.push_str(&format!("{}coproc {}\n", indent, name));
+            describe_command(command, depth + 1, out);
+        }
+    }
+}
+
+fn describe_redirect(redirect: &Redirect) -> String {
+    let operator = match redirect {
+        Redirect::Input(..) => "<",
+        Redirect::Output(..) => ">",
+        Redirect::Clobber(..) => ">|",
+        Redirect::ReadWrite(..) => "<>",
+        Redirect::Duplicate(0, _) => "<&",
+        Redirect::Duplicate(..) => ">&",
+    };
+    let default = if operator.starts_with('<') { 0 } else { 1 };
+    let fd = if redirect.fd() == default { String::new() } else { redirect.fd().to_string() };
+    format!("{}{} {}{}", fd, operator, redirect.target(), describe_word(redirect.target()))
+}
+
+/// For `parse`: the quoted, escaped and unquoted parts of `word`, after a
+/// separator, if it has any quoting at all.
+fn describe_word(word: &str) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    let mut plain = String::new();
+    let mut chars = word.chars();
+    while let Some(c) = chars.next() {
+        let part = match c {
+            '\'' => format!("single-quoted {}", quote_for_display(&chars.by_ref().take_while(|&c| c != '\'').collect::<String>())),
+            '"' => {
+                let mut text = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => text.extend(Some(c).into_iter().chain(chars.next())),
+                        c => text.push(c),
+                    }
+                }
+                format!("double-quoted {}", quote_for_display(&text))
+            }
+            '\\' => format!("escaped {}", quote_for_display(&chars.next().map(String::from).unwrap_or_default())),
+            c => {
+                plain.push(c);
+                continue;
+            }
+        };
+        if !plain.is_empty() {
+            parts.push(format!("unquoted {}", quote_for_display(&std::mem::take(&mut plain))));
+        }
+        parts.push(part);
+    }
+    if parts.is_empty() {
+        return String::new();
+    }
+    if !plain.is_empty() {
+        parts.push(format!("unquoted {}", quote_for_display(&plain)));
+    }
+    format!("    ({})", parts.join(", "))
+}
+
+/// `printf format [arg...]`: formatted output. The format is reused until
+/// every argument has been consumed; missing arguments act as empty
+/// strings or zero.
+fn builtin_printf(args: &[String]) -> i32 {
+    let Some((format, mut args)) = args.split_first() else {
+        eprintln!("printf: usage: printf format [arguments]");
+        return 2;
+    };
+    let mut output = String::new();
+    let mut status = 0;
+    loop {
+        let before = args.len();
+        if printf_once(format, &mut args, &mut output, &mut status) || args.is_empty() || args.len() == before {
+            break;
+        }
+    }
+    let mut stdout = io::stdout().lock();
+    if stdout.write_all(output.as_bytes()).and_then(|_| stdout.flush()).is_err() {
+        return 1;
+    }
+    status
+}
+
+/// Apply `format` once, consuming arguments from the front of `args`.
+/// Returns true if a `\c` escape asked for output to stop.
+fn printf_once(format: &str, args: &mut &[String], output: &mut String, status: &mut i32) -> bool {
+    let mut next_arg = || -> Option<&String> {
+        let (first, rest) = args.split_first()?;
+        *args = rest;
+        Some(first)
+    };
+    let chars: Vec<char> = format.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                let end = (i + 2).min(chars.len());
+                // Octal and hex escapes may run longer than one character.
+                let end = if matches!(chars.get(i + 1), Some('0'..='7')) {
+                    let digits = chars[i + 1..].iter().take(3).take_while(|c| c.is_digit(8)).count();
+                    i + 1 + digits
+                } else if chars.get(i + 1) == Some(&'x') {
+                    i + 2 + chars[i + 2..].iter().take(2).take_while(|c| c.is_ascii_hexdigit()).count()
+                } else {
+                    end
+                };
+                let mut escape: String = chars[i..end].iter().collect();
+                if matches!(chars.get(i + 1), Some('1'..='7')) {
+                    escape.insert(1, '0');
+                }
+                let (text, stop) = interpret_escapes(&escape);
+                output.push_str(&text);
+                if stop {
+                    return true;
+                }
+                i = end;
+            }
+            '%' if chars.get(i + 1) == Some(&'%') => {
+                output.push('%');
+                i += 2;
+            }
+            '%' => {
+                let start = i;
+                i += 1;
+                let mut flags = String::new();
+                while let Some(&c) = chars.get(i).filter(|c| "-+ 0#".contains(**c)) {
+                    flags.push(c);
+                    i += 1;
+                }
+                let mut number = |i: &mut usize| -> Option<usize> {
+                    if chars.get(*i) == Some(&'*') {
+                        *i += 1;
+                        return Some(next_arg().and_then(|arg| arg.parse().ok()).unwrap_or(0));
+                    }
+                    let digits: String = chars[*i..].iter().take_while(|c| c.is_ascii_digit()).collect();
+                    *i += digits.len();
+                    digits.parse().ok()
+                };
+                let width = number(&mut i).unwrap_or(0);
+                let precision = if chars.get(i) == Some(&'.') {
+                    i += 1;
+                    Some(number(&mut i).unwrap_or(0))
+                } else {
+                    None
+                };
+                let Some(&conversion) = chars.get(i) else {
+                    output.extend(&chars[start..]);
+                    break;
+                };
+                i += 1;
+                let arg = next_arg().cloned().unwrap_or_default();
+                let spec = FormatSpec { flags: &flags, width, precision };
+                match conversion {
+                    's' => spec.pad(output, &truncate(&arg, precision), false),
+                    'b' => {
+                        let (text, stop) = interpret_escapes(&arg);
+                        spec.pad(output, &truncate(&text, precision), false);
+                        if stop {
+                            return true;
+                        }
+                    }
+                    'c' => spec.pad(output, &arg.chars().take(1).collect::<String>(), false),
+                    'd' | 'i' | 'u' | 'x' | 'X' | 'o' => {
+                        let value = printf_integer(&arg, status);
+                        spec.pad_integer(output, value, conversion);
+                    }
+                    'f' | 'F' | 'e' | 'E' | 'g' | 'G' => {
+                        let value = if arg.is_empty() {
+                            0.0
+                        } else {
+                            arg.trim().parse::<f64>().unwrap_or_else(|_| {
+                                eprintln!("printf: {}: invalid number", arg);
+                                *status = 1;
+                                0.0
+                            })
+                        };
+                        spec.pad_float(output, value, conversion);
+                    }
+                    other => {
+                        eprintln!("printf: %{}: invalid format character", other);
+                        *status = 1;
+                        return true;
+                    }
+                }
+            }
+            c => {
+                output.push(c);
+                i += 1;
+            }
+        }
+    }
+    false
+}
+
+/// Parse a `printf` integer argument: decimal, `0x` hex, leading-zero
+/// octal, or `'c` for a character's code point.
+fn printf_integer(arg: &str, status: &mut i32) -> i64 {
+    let text = arg.trim();
+    if let Some(c) = text.strip_prefix('\'').or_else(|| text.strip_prefix('"')) {
+        return c.chars().next().map_or(0, |c| c as i64);
+    }
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let parsed = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        i64::from_str_radix(&digits[1..], 8)
+    } else if digits.is_empty() {
+        Ok(0)
+    } else {
+        digits.parse()
+    };
+    match parsed {
+        Ok(value) if negative => -value,
+        Ok(value) => value,
+        Err(_) => {
+            eprintln!("printf: {}: invalid number", arg);
+            *status = 1;
+            0
+        }
+    }
+}
+
+fn truncate(text: &str, precision: Option<usize>) -> String {
+    match precision {
+        Some(precision) => text.chars().take(precision).collect(),
+        None => text.to_string(),
+    }
+}
+
+/// Flags, width, and precision of one `printf` conversion.
+struct FormatSpec<'a> {
+    flags: &'a str,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl FormatSpec<'_> {
+    /// Append `text` padded to the field width. Numbers may be zero-padded,
+    /// which goes after any sign or `0x` prefix.
+    fn pad(&self, output: &mut String, text: &str, numeric: bool) {
+        let len = text.chars().count();
+        if len >= self.width {
+            output.push_str(text);
+        } else if self.flags.contains('-') {
+            output.push_str(text);
+            output.extend(std::iter::repeat_n(' ', self.width - len));
+        } else if numeric && self.flags.contains('0') {
+            let prefix_len = if text.starts_with(['-', '+', ' ']) { 1 } else { 0 };
+            let prefix_len = prefix_len + if text[prefix_len..].starts_with("0x") || text[prefix_len..].starts_with("0X") { 2 } else { 0 };
+            output.push_str(&text[..prefix_len]);
+            output.extend(std::iter::repeat_n('0', self.width - len));
+            output.push_str(&text[prefix_len..]);
+        } else {
+            output.extend(std::iter::repeat_n(' ', self.width - len));
+            output.push_str(text);
+        }
+    }
+
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.flags.contains('+') {
+            "+"
+        } else if self.flags.contains(' ') {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    fn pad_integer(&self, output: &mut String, value: i64, conversion: char) {
+        let magnitude = value.unsigned_abs();
+        let mut digits = match conversion {
+            'x' => format!("{:x}", magnitude),
+            'X' => format!("{:X}", magnitude),
+            'o' => format!("{:o}", magnitude),
+            _ => magnitude.to_string(),
+        };
+        if let Some(precision) = self.precision {
+            while digits.len() < precision {
+                digits.insert(0, '0');
+            }
+        }
+        let prefix = match conversion {
+            'x' if self.flags.contains('#') && magnitude != 0 => "0x",
+            'X' if self.flags.contains('#') && magnitude != 0 => "0X",
+            'o' if self.flags.contains('#') && !digits.starts_with('0') => "0",
+            _ => "",
+        };
+        let signed = matches!(conversion, 'd' | 'i');
+        let sign = if signed { self.sign(value < 0) } else { "" };
+        let text = format!("{}{}{}", sign, prefix, digits);
+        self.pad(output, &text, self.precision.is_none());
+    }
+
+    fn pad_float(&self, output: &mut String, value: f64, conversion: char) {
+        let precision = self.precision.unwrap_or(6);
+        let magnitude = value.abs();
+        let mut text = match conversion {
+            'f' | 'F' => format!("{:.*}", precision, magnitude),
+            'e' | 'E' => c_exponent(magnitude, precision),
+            _ => {
+                let precision = precision.max(1);
+                let exponent = if magnitude == 0.0 { 0 } else { magnitude.log10().floor() as i32 };
+                let mut text = if exponent < -4 || exponent >= precision as i32 {
+                    c_exponent(magnitude, precision - 1)
+                } else {
+                    format!("{:.*}", (precision as i32 - 1 - exponent).max(0) as usize, magnitude)
+                };
+                if !self.flags.contains('#') && text.contains('.') {
+                    let (mantissa, exponent) = match text.find('e') {
+                        Some(e) => text.split_at(e),
+                        None => (text.as_str(), ""),
+                    };
+                    text = format!("{}{}", mantissa.trim_end_matches('0').trim_end_matches('.'), exponent);
+                }
+                text
+            }
+        };
+        if conversion.is_ascii_uppercase() {
+            text = text.to_uppercase();
+        }
+        let text = format!("{}{}", self.sign(value.is_sign_negative() && value != 0.0), text);
+        self.pad(output, &text, true);
+    }
+}
+
+/// Format like C's `%e`: one digit before the point and a signed exponent
+/// of at least two digits.
+fn c_exponent(value: f64, precision: usize) -> String {
+    let text = format!("{:.*e}", precision, value);
+    let (mantissa, exponent) = text.split_once('e').unwrap_or((&text, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    format!("{}e{}{:02}", mantissa, if exponent < 0 { '-' } else { '+' }, exponent.abs())
+}
+
+/// Switch the terminal on standard input to no-echo and/or character-at-a-
+/// time mode for `read -s` / `read -n`, returning the settings to restore.
+fn configure_tty_for_read(silent: bool, by_char: bool) -> Option<nix::sys::termios::Termios> {
+    use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg, SpecialCharacterIndices};
+    if !(silent || by_char) || !nix::unistd::isatty(0).unwrap_or(false) {
+        return None;
+    }
+    let saved = tcgetattr(0).ok()?;
+    let mut termios = saved.clone();
+    if silent {
+        termios.local_flags.remove(LocalFlags::ECHO);
+    }
+    if by_char {
+        termios.local_flags.remove(LocalFlags::ICANON);
+        termios.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        termios.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+    }
+    tcsetattr(0, SetArg::TCSANOW, &termios).ok()?;
+    Some(saved)
+}
+
+/// Read one line from fd 0 a byte at a time, so no input past the line is
+/// consumed. Unless `raw`, a backslash escapes the next character and a
+/// backslash-newline continues the line. Stops early after `count`
+/// characters if given. Returns the text and whether a newline (or the
+/// character count) ended it rather than end of input.
+fn read_input_line(raw: bool, count: Option<usize>) -> (String, bool) {
+    let mut bytes = Vec::new();
+    let mut chars = 0;
+    let mut escaped = false;
+    loop {
+        if count.is_some_and(|count| chars >= count) {
+            return (String::from_utf8_lossy(&bytes).into_owned(), true);
+        }
+        let mut byte = [0u8];
+        match nix::unistd::read(0, &mut byte) {
+            Ok(1) => {}
+            Err(Errno::EINTR) => continue,
+            _ => return (String::from_utf8_lossy(&bytes).into_owned(), false),
+        }
+        let byte = byte[0];
+        // Count characters rather than UTF-8 continuation bytes.
+        if byte & 0xc0 != 0x80 {
+            chars += 1;
+        }
+        if escaped {
+            escaped = false;
+            if byte != b'\n' {
+                bytes.push(byte);
+            }
+            continue;
+        }
+        match byte {
+            b'\n' => return (String::from_utf8_lossy(&bytes).into_owned(), true),
+            b'\\' if !raw => escaped = true,
+            _ => bytes.push(byte),
+        }
+    }
+}
+
+/// `test expression`: exit 0 if the expression is true, 1 if false, and
+/// 2 if it is malformed.
+fn builtin_test(args: &[String]) -> i32 {
+    let mut test = TestExpr { args, pos: 0 };
+    let result = if args.is_empty() { Ok(false) } else { test.or() };
+    match result {
+        Ok(_) if test.pos < args.len() => {
+            eprintln!("test: unexpected argument `{}'", args[test.pos]);
+            2
+        }
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("test: {}", e);
+            2
+        }
+    }
+}
+
+/// Recursive-descent evaluator for `test` arguments, where `-o` binds
+/// looser than `-a`, which binds looser than `!`.
+struct TestExpr<'a> {
+    args: &'a [String],
+    pos: usize,
+}
+
+impl TestExpr<'_> {
+    fn peek(&self, offset: usize) -> Option<&str> {
+        self.args.get(self.pos + offset).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Result<&str, String> {
+        let arg = self.args.get(self.pos).ok_or("argument expected")?;
+        self.pos += 1;
+        Ok(arg)
+    }
+
+    fn or(&mut self) -> Result<bool, String> {
+        let mut value = self.and()?;
+        while self.peek(0) == Some("-o") {
+            self.pos += 1;
+            value |= self.and()?;
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<bool, String> {
+        let mut value = self.not()?;
+        while self.peek(0) == Some("-a") {
+            self.pos += 1;
+            value &= self.not()?;
+        }
+        Ok(value)
+    }
+
+    fn not(&mut self) -> Result<bool, String> {
+        if self.peek(0) == Some("!") && self.peek(1).is_some() {
+            self.pos += 1;
+            return Ok(!self.not()?);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<bool, String> {
+        // A binary operator wins over a unary one, so `[ -f = -f ]` compares.
+        if let Some(op) = self.peek(1).filter(|op| is_binary_test(op))
+            && self.peek(2).is_some()
+        {
+            let op = op.to_string();
+            let left = self.next()?.to_string();
+            self.pos += 1;
+            let right = self.next()?.to_string();
+            return binary_test(&op, &left, &right);
+        }
+        if self.peek(0) == Some("(") && self.peek(1).is_some() {
+            self.pos += 1;
+            let value = self.or()?;
+            if self.next()? != ")" {
+                return Err("missing `)'".to_string());
+            }
+            return Ok(value);
+        }
+        let arg = self.next()?.to_string();
+        if is_unary_test(&arg)
+            && let Some(operand) = self.peek(0).map(str::to_string)
+        {
+            self.pos += 1;
+            return Ok(unary_test(&arg, &operand));
+        }
+        Ok(!arg.is_empty())
+    }
+}
+
+fn is_unary_test(op: &str) -> bool {
+    matches!(op, "-e" | "-f" | "-d" | "-r" | "-w" | "-x" | "-s" | "-L" | "-h" | "-z" | "-n")
+}
+
+fn is_binary_test(op: &str) -> bool {
+    matches!(op, "=" | "==" | "!=" | "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge")
+}
+
+fn unary_test(op: &str, operand: &str) -> bool {
+    use nix::unistd::{access, AccessFlags};
+    let metadata = || std::fs::metadata(operand);
+    match op {
+        "-e" => metadata().is_ok(),
+        "-f" => metadata().is_ok_and(|m| m.is_file()),
+        "-d" => metadata().is_ok_and(|m| m.is_dir()),
+        "-s" => metadata().is_ok_and(|m| m.len() > 0),
+        "-L" | "-h" => std::fs::symlink_metadata(operand).is_ok_and(|m| m.file_type().is_symlink()),
+        "-r" => access(operand, AccessFlags::R_OK).is_ok(),
+        "-w" => access(operand, AccessFlags::W_OK).is_ok(),
+        "-x" => access(operand, AccessFlags::X_OK).is_ok(),
+        "-z" => operand.is_empty(),
+        "-n" => !operand.is_empty(),
+        _ => unreachable!("not a unary test: {}", op),
+    }
+}
+
+fn binary_test(op: &str, left: &str, right: &str) -> Result<bool, String> {
+    match op {
+        "=" | "==" => return Ok(left == right),
+        "!=" => return Ok(left != right),
+        _ => {}
+    }
+    let number = |arg: &str| {
+        arg.trim()
+            .parse::<i64>()
+            .map_err(|_| format!("{}: integer expression expected", arg))
+    };
+    let (left, right) = (number(left)?, number(right)?);
+    Ok(match op {
+        "-eq" => left == right,
+        "-ne" => left != right,
+        "-lt" => left < right,
+        "-le" => left <= right,
+        "-gt" => left > right,
+        "-ge" => left >= right,
+        _ => unreachable!("not a binary test: {}", op),
+    })
+}
+
+/// Evaluator for `[[ ... ]]`. Operands are expanded here, without field
+/// splitting or globbing; the right side of `==` and `!=` is a pattern and
+/// the right side of `=~` an extended regular expression.
+pub(crate) struct CondExpr<'a> {
+    pub(crate) shell: &'a mut Shell,
+    pub(crate) words: &'a [Cow<'a, str>],
+    pub(crate) pos: usize,
+}
+
+impl CondExpr<'_> {
+    fn peek(&self, offset: usize) -> Option<&str> {
+        self.words.get(self.pos + offset).map(|word| word.as_ref())
+    }
+
+    fn operand(&mut self) -> Result<String, String> {
+        let word = self.words.get(self.pos).ok_or("argument expected")?;
+        self.pos += 1;
+        self.shell.expand_single(word).map_err(|e| e.to_string())
+    }
+
+    pub(crate) fn or(&mut self) -> Result<bool, String> {
+        let mut value = self.and()?;
+        while self.peek(0) == Some("||") {
+            self.pos += 1;
+            value |= self.and()?;
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<bool, String> {
+        let mut value = self.not()?;
+        while self.peek(0) == Some("&&") {
+            self.pos += 1;
+            value &= self.not()?;
+        }
+        Ok(value)
+    }
+
+    fn not(&mut self) -> Result<bool, String> {
+        if self.peek(0) == Some("!") {
+            self.pos += 1;
+            return Ok(!self.not()?);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<bool, String> {
+        if self.peek(0) == Some("(") {
+            self.pos += 1;
+            let value = self.or()?;
+            if self.peek(0) != Some(")") {
+                return Err("missing `)'".to_string());
+            }
+            self.pos += 1;
+            return Ok(value);
+        }
+        if let Some(op) = self.peek(0).filter(|op| is_unary_test(op)).map(str::to_string)
+            && self.peek(1).is_some()
+        {
+            self.pos += 1;
+            let operand = self.operand()?;
+            return Ok(unary_test(&op, &operand));
+        }
+        let left = self.operand()?;
+        let Some(op) = self.peek(0).filter(|op| is_binary_test(op) || matches!(*op, "=~" | "<" | ">")) else {
+            return Ok(!left.is_empty());
+        };
+        let op = op.to_string();
+        self.pos += 1;
+        let word = self.words.get(self.pos).ok_or("argument expected")?;
+        self.pos += 1;
+        match op.as_str() {
+            "==" | "=" | "!=" => {
+                let pattern = self.shell.expand_pattern(word).map_err(|e| e.to_string())?;
+                Ok(fnmatch(&pattern, &left) == (op != "!="))
+            }
+            "=~" => {
+                let regex = self.shell.expand_pattern(word).map_err(|e| e.to_string())?;
+                let groups = regex_match(&regex, &left)?;
+                let matched = groups.is_some();
+                self.shell.arrays.insert("BASH_REMATCH".to_string(), groups.unwrap_or_default());
+                Ok(matched)
+            }
+            "<" | ">" => {
+                let right = self.shell.expand_single(word).map_err(|e| e.to_string())?;
+                Ok(if op == "<" { left < right } else { left > right })
+            }
+            _ => {
+                let right = self.shell.expand_single(word).map_err(|e| e.to_string())?;
+                binary_test(&op, &left, &right)
+            }
+        }
+    }
+}
+
+/// Match `text` against a POSIX extended regular expression, returning the
+/// whole match followed by each parenthesized group, or `None` if it does
+/// not match.
+fn regex_match(pattern: &str, text: &str) -> Result<Option<Vec<String>>, String> {
+    let invalid = || format!("invalid regular expression `{}'", pattern);
+    let c_pattern = CString::new(pattern).map_err(|_| invalid())?;
+    let c_text = CString::new(text).map_err(|_| invalid())?;
+    let mut matches = vec![libc::regmatch_t { rm_so: -1, rm_eo: -1 }; count_groups(pattern) + 1];
+    // SAFETY: `regex` is initialized by a successful `regcomp` before use and
+    // freed exactly once; `matches` has room for the number of entries passed.
+    let matched = unsafe {
+        let mut regex: libc::regex_t = std::mem::zeroed();
+        if libc::regcomp(&mut regex, c_pattern.as_ptr(), libc::REG_EXTENDED) != 0 {
+            return Err(invalid());
+        }
+        let rc = libc::regexec(&regex, c_text.as_ptr(), matches.len(), matches.as_mut_ptr(), 0);
+        libc::regfree(&mut regex);
+        rc == 0
+    };
+    if !matched {
+        return Ok(None);
+    }
+    Ok(Some(
+        matches
+            .iter()
+            .map(|m| match (usize::try_from(m.rm_so), usize::try_from(m.rm_eo)) {
+                (Ok(start), Ok(end)) => text[start..end].to_string(),
+                _ => String::new(),
+            })
+            .collect(),
+    ))
+}
+
+/// Count the capture groups in a regular expression: the `(`s that are
+/// neither escaped nor inside a bracket expression.
+fn count_groups(pattern: &str) -> usize {
+    let mut count = 0;
+    let mut in_bracket = false;
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '[' if !in_bracket => {
+                in_bracket = true;
+                // A `]` right after `[` or `[^` is a literal member.
+                chars.next_if_eq(&'^');
+                chars.next_if_eq(&']');
+            }
+            ']' if in_bracket => in_bracket = false,
+            '(' if !in_bracket => count += 1,
+            _ => {}
+        }
+    }
+    count
+}
+
+/// `dir` with each missing component replaced by the most similar
+/// existing directory name, if every one has a close enough match.
+fn correct_dir_spelling(dir: &str) -> Option<String> {
+    let mut fixed = if dir.starts_with('/') { "/".to_string() } else { String::new() };
+    for component in dir.split('/').filter(|component| !component.is_empty()) {
+        let parent = if fixed.is_empty() { "." } else { fixed.as_str() };
+        let exists = std::path::Path::new(parent).join(component).is_dir();
+        let component = if exists || component == "." || component == ".." {
+            component.to_string()
+        } else {
+            let limit = if component.chars().count() <= 4 { 1 } else { 2 };
+            std::fs::read_dir(parent)
+                .ok()?
+                .flatten()
+                .filter(|entry| entry.path().is_dir())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .map(|name| (edit_distance(component, &name), name))
+                .filter(|(distance, _)| *distance <= limit)
+                .min()?
+                .1
+        };
+        if !fixed.is_empty() && !fixed.ends_with('/') {
+            fixed.push('/');
+        }
+        fixed.push_str(&component);
+    }
+    (fixed != dir.trim_end_matches('/')).then_some(fixed)
+}
+
+/// Ask on the terminal whether to use `fixed` in place of `dir`. Without
+/// a terminal to ask on, the answer is no.
+fn confirm_correction(dir: &str, fixed: &str) -> bool {
+    if !nix::unistd::isatty(0).unwrap_or(false) {
+        return false;
+    }
+    eprint!("vssh: correct `{}' to `{}' [y/n]? ", dir, fixed);
+    let (answer, _) = read_input_line(true, None);
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// The number of single-character insertions, deletions, substitutions and
+/// adjacent transpositions needed to turn `a` into `b`.
+pub(crate) fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    rows[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            rows[i][j] = (rows[i - 1][j] + 1).min(rows[i][j - 1] + 1).min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                rows[i][j] = rows[i][j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+/// Whether `path` is an absolute path to the current directory
+pub(crate) fn names_current_dir(path: &str) -> bool {
+    path.starts_with('/') && std::fs::canonicalize(path).ok() == std::env::current_dir().ok()
+}
+
+/// The niceness of the shell process.
+fn niceness() -> i32 {
+    // -1 is a valid niceness, so only errno tells a failure apart.
+    Errno::clear();
+    let niceness = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+    if niceness == -1 && Errno::last() != Errno::UnknownErrno { 0 } else { niceness }
+}
+
+/// Parse a `timeout` duration: a number of seconds, possibly fractional,
+/// optionally followed by `s`, `m`, `h` or `d`.
+fn parse_duration(text: &str) -> Option<std::time::Duration> {
+    let (number, scale) = match text.char_indices().last()? {
+        (i, 's') => (&text[..i], 1.0),
+        (i, 'm') => (&text[..i], 60.0),
+        (i, 'h') => (&text[..i], 3600.0),
+        (i, 'd') => (&text[..i], 86400.0),
+        _ => (text, 1.0),
+    };
+    let seconds = number.parse::<f64>().ok().filter(|seconds| seconds.is_finite() && *seconds >= 0.0)?;
+    std::time::Duration::try_from_secs_f64(seconds * scale).ok()
+}
+
+/// Start a timer that raises SIGALRM once after `delay`. Dropping it
+/// disarms it.
+fn start_timer(delay: std::time::Duration) -> nix::Result<nix::sys::timer::Timer> {
+    use nix::sys::signal::{SigEvent, SigevNotify};
+    use nix::sys::timer::{Expiration, Timer, TimerSetTimeFlags};
+    let event = SigEvent::new(SigevNotify::SigevSignal { signal: Signal::SIGALRM, si_value: 0 });
+    let mut timer = Timer::new(nix::time::ClockId::CLOCK_MONOTONIC, event)?;
+    timer.set(Expiration::OneShot(nix::sys::time::TimeSpec::from_duration(delay)), TimerSetTimeFlags::empty())?;
+    Ok(timer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::Policy;
+    use crate::tests::{run, shell};
+
+    #[test]
+    fn getopts_and_local_leave_protected_variables_alone() {
+        let (_lock, mut shell) = shell();
+        shell.set_option("restricted", true);
+        shell.set_var("PATH", "/bin");
+        assert_eq!(run(&mut shell, "getopts a PATH -a"), (1, String::new()));
+        assert_eq!(run(&mut shell, "f() { local PATH=/tmp; echo \"$PATH\"; }; f"), (0, "/bin\n".to_string()));
+        assert_eq!(shell.var("PATH"), Some("/bin"));
+        assert_eq!(run(&mut shell, "getopts a opt -a"), (0, String::new()));
+        assert_eq!(shell.var("opt"), Some("a"));
+
+        let mut shell = Shell::new();
+        shell.policy = Some(Policy::default());
+        shell.set_var("VSSH_POLICY", "/etc/policy");
+        assert_eq!(run(&mut shell, "getopts a VSSH_POLICY -a"), (1, String::new()));
+        assert_eq!(run(&mut shell, "f() { local VSSH_POLICY=; }; f"), (1, String::new()));
+        assert_eq!(shell.var("VSSH_POLICY"), Some("/etc/policy"));
+    }
+
+    #[test]
+    fn envrestore_leaves_protected_variables_alone() {
+        let (_lock, mut shell) = shell();
+        let dir = crate::tests::temp_path("envs");
+        shell.set_var("VSSH_ENV_DIR", dir.to_str().unwrap());
+        shell.set_var("PATH", "/usr/bin");
+        assert_eq!(run(&mut shell, "envsave plain"), (0, String::new()));
+
+        shell.policy = Some(Policy::default());
+        shell.set_var("VSSH_POLICY", "/etc/policy");
+        unsafe { std::env::set_var("VSSH_POLICY", "/etc/policy") };
+        assert_eq!(run(&mut shell, "x=1; envrestore plain"), (0, String::new()));
+        assert_eq!(shell.var("x"), None);
+        assert_eq!(shell.var("VSSH_POLICY"), Some("/etc/policy"));
+        assert_eq!(std::env::var("VSSH_POLICY").as_deref(), Ok("/etc/policy"));
+        unsafe { std::env::remove_var("VSSH_POLICY") };
+
+        shell.set_option("restricted", true);
+        shell.set_var("PATH", "/bin");
+        assert_eq!(run(&mut shell, "envrestore plain"), (0, String::new()));
+        assert_eq!(shell.var("PATH"), Some("/bin"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn j_is_refused_in_a_restricted_shell() {
+        let (_lock, mut shell) = shell();
+        let jumps = crate::tests::temp_path("jumps");
+        std::fs::write(&jumps, format!("/|10|{}\n", frecency::now())).unwrap();
+        shell.set_var("VSSH_JUMP_FILE", jumps.to_str().unwrap());
+        let pwd = shell.var("PWD").map(str::to_string);
+        shell.set_option("restricted", true);
+        assert_eq!(run(&mut shell, "j /"), (1, String::new()));
+        assert_eq!(shell.var("PWD"), pwd.as_deref());
+        std::fs::remove_file(&jumps).unwrap();
+    }
+
+    #[test]
+    fn hash_remembers_and_forgets_commands() {
+        let (_lock, mut shell) = shell();
+        let dir = crate::tests::temp_path("hash");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy("/bin/true", dir.join("cmd")).unwrap();
+        shell.set_var("PATH", dir.to_str().unwrap());
+        assert_eq!(run(&mut shell, "hash cmd"), (0, String::new()));
+        assert_eq!(shell.hashed.get("cmd"), Some(&(dir.join("cmd").to_str().unwrap().to_string(), 0)));
+        assert_eq!(run(&mut shell, "hash missing"), (1, String::new()));
+        assert_eq!(run(&mut shell, "hash -r cmd"), (0, String::new()));
+        assert_eq!(shell.hashed.keys().collect::<Vec<_>>(), ["cmd"]);
+        assert_eq!(run(&mut shell, "hash -r"), (0, String::new()));
+        assert!(shell.hashed.is_empty());
+        assert_eq!(run(&mut shell, "hash -x"), (2, String::new()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn local_variables_are_restored_on_return() {
+        let (_lock, mut shell) = shell();
+        shell.set_var("x", "global");
+        let source = "f() { local x=inner y; y=set; g; echo \"$x $y\"; }; g() { x=changed; }; f; echo \"$x\"";
+        assert_eq!(run(&mut shell, source), (0, "changed set\nglobal\n".to_string()));
+        assert_eq!(shell.var("y"), None);
+        assert_eq!(run(&mut shell, "local z=1"), (1, String::new()));
+        assert_eq!(shell.var("z"), None);
+    }
+
+    #[test]
+    fn return_leaves_the_function_or_sourced_file() {
+        let (_lock, mut shell) = shell();
+        let file = crate::tests::temp_path("sourced");
+        std::fs::write(&file, "echo in\nreturn 4\necho after\n").unwrap();
+        assert_eq!(run(&mut shell, "f() { echo a; return 3; echo b; }; f; echo $?"), (0, "a\n3\n".to_string()));
+        assert_eq!(run(&mut shell, &format!(". {}; echo $?", file.to_str().unwrap())), (0, "in\n4\n".to_string()));
+        assert_eq!(run(&mut shell, "f() { false; return; }; f"), (1, String::new()));
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_evaluates_expressions() {
+        let test = |expression: &str| builtin_test(&expression.split(' ').map(str::to_string).collect::<Vec<_>>());
+        for (expression, status) in [
+            ("-d /", 0),
+            ("-f /", 1),
+            ("-e /no/such/file", 1),
+            ("-x /bin/sh", 0),
+            ("-z ", 0),
+            ("-n a", 0),
+            ("a = a", 0),
+            ("a != a", 1),
+            ("2 -lt 10", 0),
+            ("10 -le 2", 1),
+            ("3 -eq 03", 0),
+            ("! a = b", 0),
+            ("a = b -o 1 -gt 0", 0),
+            ("a = a -a 1 -gt 1", 1),
+            ("x -eq 1", 2),
+            ("a =", 2),
+        ] {
+            assert_eq!(test(expression), status, "{}", expression);
+        }
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "[ -d / ] && [ a = a ]"), (0, String::new()));
+        assert_eq!(run(&mut shell, "[ a = a"), (2, String::new()));
+    }
+
+    #[test]
+    fn double_brackets_match_patterns_and_regexes() {
+        let (_lock, mut shell) = shell();
+        shell.set_var("x", "a b*");
+        assert_eq!(run(&mut shell, "[[ $x == 'a b'* && -n $x ]]"), (0, String::new()));
+        assert_eq!(run(&mut shell, "[[ $x == a ]] || [[ ! $x = \"a b*\" ]]"), (1, String::new()));
+        assert_eq!(run(&mut shell, "[[ abc123 =~ ^([a-z]+)([0-9]+)$ ]]"), (0, String::new()));
+        assert_eq!(shell.arrays["BASH_REMATCH"], ["abc123", "abc", "123"]);
+        assert_eq!(run(&mut shell, "[[ abc =~ [0-9] ]]"), (1, String::new()));
+        assert!(shell.arrays["BASH_REMATCH"].is_empty());
+    }
+
+    #[test]
+    fn read_splits_lines_into_variables() {
+        let (_lock, mut shell) = shell();
+        let input = crate::tests::temp_path("input");
+        std::fs::write(&input, "one two  three\nx\\ y\\z\nx\\ y\nabcdef\nlast").unwrap();
+        shell.set_stdin(std::fs::File::open(&input).unwrap());
+        let source = "read a b; read -r c; read d; read -n 3 e; read; echo \"$a|$b|$c|$d|$e|$REPLY\"; read f; echo $? $f";
+        assert_eq!(run(&mut shell, source), (0, "one|two  three|x\\ y\\z|x y|abc|def\n1 last\n".to_string()));
+        std::fs::remove_file(&input).unwrap();
+    }
+
+    #[test]
+    fn echo_handles_its_options() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "echo -n a; echo -e 'b\\tc\\x41'; echo 'd\\n' -n; echo -en 'e\\c'; echo f"), (0, "ab\tcA\nd\\n -n\nef\n".to_string()));
+    }
+
+    #[test]
+    fn printf_formats_and_cycles_arguments() {
+        let (_lock, mut shell) = shell();
+        let source = "printf '%s=%d\\n' a 1 b 2; printf '%5.2f|%-3s|%x|%%|%03d\\n' 3.14159 x 255 7; printf '%s\\n'";
+        assert_eq!(run(&mut shell, source), (0, "a=1\nb=2\n 3.14|x  |ff|%|007\n\n".to_string()));
+    }
+
+    #[test]
+    fn getopts_parses_clustered_options_and_arguments() {
+        let (_lock, mut shell) = shell();
+        shell.positional = ["-ab", "-c", "value", "-dvalue2", "rest"].map(str::to_string).to_vec();
+        let source = "while getopts abc:d: opt; do echo \"$opt ${OPTARG}\"; done; echo $OPTIND";
+        assert_eq!(run(&mut shell, source), (0, "a \nb \nc value\nd value2\n5\n".to_string()));
+        let source = "OPTIND=1; getopts :a:x opt -y; echo \"$opt $OPTARG\"; OPTIND=1; getopts :a:x opt -a; echo \"$opt $OPTARG\"";
+        assert_eq!(run(&mut shell, source), (0, "? y\n: a\n".to_string()));
+    }
+
+    #[test]
+    fn eval_runs_its_arguments_in_the_current_shell() {
+        let (_lock, mut shell) = shell();
+        shell.set_var("cmd", "echo \"a  b\" | cat; x=2");
+        assert_eq!(run(&mut shell, "eval $cmd; eval 'echo $x;' false"), (1, "a b\n2\n".to_string()));
+        assert_eq!(shell.var("x"), Some("2"));
+        assert_eq!(run(&mut shell, "eval"), (0, String::new()));
+    }
+
+    #[test]
+    fn exec_without_a_command_keeps_its_redirections() {
+        let (_lock, mut shell) = shell();
+        let file = crate::tests::temp_path("exec");
+        // Well above the descriptors the tests' own files get.
+        let source = format!("exec 50>{}; echo a >&50; f() {{ echo b >&50; }}; f; exec 50>&-; echo c >&50", file.to_str().unwrap());
+        assert_eq!(run(&mut shell, &source), (1, String::new()));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "a\nb\n");
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn exit_stops_with_the_status_given() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "echo a; exit 258; echo b"), (2, "a\n".to_string()));
+        assert_eq!(run(&mut shell, "false; exit"), (1, String::new()));
+        assert_eq!(run(&mut shell, "exit x"), (2, String::new()));
+        assert_eq!(run(&mut shell, "f() { exit 4; }; f; echo b"), (4, String::new()));
+    }
+
+    #[test]
+    fn true_false_and_colon_run_in_the_shell() {
+        let (_lock, mut shell) = shell();
+        shell.set_var("PATH", "");
+        assert_eq!(run(&mut shell, "true && : ignored 'args' && false"), (1, String::new()));
+        assert_eq!(run(&mut shell, "false || true"), (0, String::new()));
+        assert_eq!(run(&mut shell, "n=; while [ \"$n\" != xxx ] && true; do n=x$n; done; : > /dev/null; echo $n"), (0, "xxx\n".to_string()));
+    }
+
+    #[test]
+    fn type_describes_each_way_a_name_could_run() {
+        let (_lock, mut shell) = shell();
+        let dir = crate::tests::temp_path("type");
+        std::fs::create_dir_all(dir.join("a")).unwrap();
+        std::fs::create_dir_all(dir.join("b")).unwrap();
+        for bin in ["a/echo", "b/echo", "b/other"] {
+            std::fs::copy("/bin/true", dir.join(bin)).unwrap();
+        }
+        let dir_name = dir.to_str().unwrap();
+        shell.set_var("PATH", &format!("{0}/a:{0}/b", dir_name));
+        assert_eq!(run(&mut shell, "alias echo='echo -n'; echo() { :; }"), (0, String::new()));
+        let echo = [
+            "echo is aliased to `echo -n'".to_string(),
+            "echo is a function".to_string(),
+            "echo is a shell builtin".to_string(),
+            format!("echo is {}/a/echo", dir_name),
+            format!("echo is {}/b/echo", dir_name),
+        ];
+        assert_eq!(shell.describe("echo", true), echo);
+        assert_eq!(shell.describe("echo", false)[0], echo[0]);
+        assert_eq!(shell.describe("if", false), ["if is a shell keyword"]);
+        assert_eq!(shell.describe("other", false), [format!("other is {}/b/other", dir_name)]);
+        assert_eq!(run(&mut shell, "type other"), (0, String::new()));
+        assert_eq!(run(&mut shell, "type other missing"), (1, String::new()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pwd_shows_the_logical_or_physical_directory() {
+        let (_lock, mut shell) = shell();
+        let cwd = std::env::current_dir().unwrap();
+        let dir = crate::tests::temp_path("pwd");
+        std::fs::create_dir_all(dir.join("real")).unwrap();
+        let dir = std::fs::canonicalize(dir).unwrap();
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("link")).unwrap();
+        let dir_name = dir.to_str().unwrap();
+        assert_eq!(run(&mut shell, &format!("cd {}/link", dir_name)), (0, String::new()));
+        assert_eq!(shell.working_dir(false).unwrap(), format!("{}/link", dir_name));
+        assert_eq!(shell.working_dir(true).unwrap(), format!("{}/real", dir_name));
+        shell.set_var("PWD", "/");
+        assert_eq!(shell.working_dir(false).unwrap(), format!("{}/real", dir_name));
+        assert_eq!(run(&mut shell, "pwd -P -L"), (0, String::new()));
+        std::env::set_current_dir(cwd).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cd_dash_returns_to_the_previous_directory() {
+        let (_lock, mut shell) = shell();
+        let cwd = std::env::current_dir().unwrap();
+        let dir = crate::tests::temp_path("oldpwd");
+        std::fs::create_dir_all(dir.join("a")).unwrap();
+        let dir = std::fs::canonicalize(dir).unwrap();
+        let dir_name = dir.to_str().unwrap();
+        shell.vars.remove("OLDPWD");
+        assert_eq!(run(&mut shell, "cd -"), (1, String::new()));
+        assert_eq!(run(&mut shell, &format!("cd {}; cd a", dir_name)), (0, String::new()));
+        assert_eq!(shell.var("OLDPWD"), Some(dir_name));
+        assert_eq!(run(&mut shell, "cd -"), (0, String::new()));
+        assert_eq!(std::env::current_dir().unwrap(), dir);
+        assert_eq!(shell.var("PWD"), Some(dir_name));
+        assert_eq!(shell.var("OLDPWD"), Some(format!("{}/a", dir_name).as_str()));
+        std::env::set_current_dir(cwd).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cd_goes_home_by_default_and_expands_tildes() {
+        let (_lock, mut shell) = shell();
+        let cwd = std::env::current_dir().unwrap();
+        let dir = crate::tests::temp_path("home");
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        let dir = std::fs::canonicalize(dir).unwrap();
+        let dir_name = dir.to_str().unwrap();
+        shell.set_var("HOME", dir_name);
+        assert_eq!(run(&mut shell, "cd"), (0, String::new()));
+        assert_eq!(std::env::current_dir().unwrap(), dir);
+        assert_eq!(shell.var("PWD"), Some(dir_name));
+        assert_eq!(run(&mut shell, "cd /; cd ~/src"), (0, String::new()));
+        assert_eq!(std::env::current_dir().unwrap(), dir.join("src"));
+        shell.vars.remove("HOME");
+        assert_eq!(run(&mut shell, "cd"), (1, String::new()));
+        std::env::set_current_dir(cwd).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cd_searches_cdpath_for_relative_directories() {
+        let (_lock, mut shell) = shell();
+        let cwd = std::env::current_dir().unwrap();
+        let dir = crate::tests::temp_path("cdpath");
+        std::fs::create_dir_all(dir.join("one")).unwrap();
+        std::fs::create_dir_all(dir.join("two/project")).unwrap();
+        std::fs::create_dir_all(dir.join("work/project")).unwrap();
+        let dir = std::fs::canonicalize(dir).unwrap();
+        let dir_name = dir.to_str().unwrap();
+        shell.set_var("CDPATH", &format!(":{0}/one:{0}/two/", dir_name));
+        assert_eq!(shell.search_cdpath("project"), Some(format!("{}/two/project", dir_name)));
+        assert_eq!(shell.search_cdpath("./project"), None);
+        assert_eq!(shell.search_cdpath("/project"), None);
+        assert_eq!(run(&mut shell, "cd /; cd project"), (0, String::new()));
+        assert_eq!(std::env::current_dir().unwrap(), dir.join("two/project"));
+        assert_eq!(run(&mut shell, &format!("cd {}/work; cd project", dir_name)), (0, String::new()));
+        assert_eq!(std::env::current_dir().unwrap(), dir.join("work/project"));
+        std::env::set_current_dir(cwd).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pushd_and_popd_keep_a_stack_of_directories() {
+        let (_lock, mut shell) = shell();
+        let cwd = std::env::current_dir().unwrap();
+        let dir = crate::tests::temp_path("pushd");
+        std::fs::create_dir_all(dir.join("a")).unwrap();
+        std::fs::create_dir_all(dir.join("b")).unwrap();
+        let dir = std::fs::canonicalize(dir).unwrap();
+        let dir_name = dir.to_str().unwrap();
+        let (a, b) = (format!("{}/a", dir_name), format!("{}/b", dir_name));
+        assert_eq!(run(&mut shell, &format!("cd {}; pushd a; pushd ../b", dir_name)), (0, String::new()));
+        assert_eq!(shell.dir_stack, [a.clone(), dir_name.to_string()]);
+        assert_eq!(run(&mut shell, "echo ~0 ~1 ~-0"), (0, format!("{} {} {}\n", b, a, dir_name)));
+        assert_eq!(run(&mut shell, "pushd"), (0, String::new()));
+        assert_eq!(shell.var("PWD"), Some(a.as_str()));
+        assert_eq!(shell.dir_stack, [b.clone(), dir_name.to_string()]);
+        assert_eq!(run(&mut shell, "popd; popd"), (0, String::new()));
+        assert_eq!(std::env::current_dir().unwrap(), dir);
+        assert!(shell.dir_stack.is_empty());
+        assert_eq!(run(&mut shell, "popd"), (1, String::new()));
+        assert_eq!(run(&mut shell, "pushd a; dirs -c"), (0, String::new()));
+        assert!(shell.dir_stack.is_empty());
+        std::env::set_current_dir(cwd).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cd_corrects_misspelled_directories() {
+        let (_lock, mut shell) = shell();
+        let cwd = std::env::current_dir().unwrap();
+        let dir = crate::tests::temp_path("cdspell");
+        std::fs::create_dir_all(dir.join("projects/vssh")).unwrap();
+        let dir = std::fs::canonicalize(dir).unwrap();
+        let dir_name = dir.to_str().unwrap();
+        assert_eq!(correct_dir_spelling(&format!("{}/projcts/vsh", dir_name)), Some(format!("{}/projects/vssh", dir_name)));
+        assert_eq!(correct_dir_spelling(&format!("{}/projects/vssh", dir_name)), None);
+        assert_eq!(correct_dir_spelling(&format!("{}/elsewhere", dir_name)), None);
+        assert_eq!(run(&mut shell, &format!("cd {}; shopt -s cdspell; cd projcts", dir_name)), (0, String::new()));
+        assert_eq!(std::env::current_dir().unwrap(), dir.join("projects"));
+        std::env::set_current_dir(cwd).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn options_are_set_by_name_or_flag_from_any_builtin() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "set -o noclobber; shopt -s globstar; setopt autocd; set -eu"), (0, String::new()));
+        assert!(["noclobber", "globstar", "autocd", "errexit", "nounset"].iter().all(|name| shell.option(name)));
+        assert_eq!(run(&mut shell, "shopt globstar noclobber"), (0, String::new()));
+        assert_eq!(run(&mut shell, "set +o noclobber +eu; unsetopt autocd; shopt -u globstar"), (0, String::new()));
+        assert!(["noclobber", "globstar", "autocd", "errexit", "nounset"].iter().all(|name| !shell.option(name)));
+        assert_eq!(run(&mut shell, "shopt globstar"), (1, String::new()));
+        assert_eq!(run(&mut shell, "set -o nosuch"), (1, String::new()));
+        assert_eq!(run(&mut shell, "shopt nosuch"), (1, String::new()));
+        assert_eq!(run(&mut shell, "set -Q"), (2, String::new()));
+        assert_eq!(run(&mut shell, "set -x -- a b; set +x; echo $# $2"), (0, "2 b\n".to_string()));
+    }
+
+    #[test]
+    fn trap_runs_actions_for_signals_and_on_exit() {
+        let (_lock, mut shell) = shell();
+        // Another of the test threads may take the signal, so give it time
+        // to arrive before the next command looks for it.
+        assert_eq!(run(&mut shell, "trap 'echo got $?' USR1; false; kill -USR1 $$; sleep 0.1; echo after"), (0, "got 0\nafter\n".to_string()));
+        assert_eq!(shell.traps.get("USR1").map(String::as_str), Some("echo got $?"));
+        assert_eq!(run(&mut shell, "trap - SIGUSR1; trap '' usr2; trap 'x=ran' 0"), (0, String::new()));
+        assert!(!shell.traps.contains_key("USR1"));
+        assert_eq!(shell.traps.get("USR2").map(String::as_str), Some(""));
+        assert_eq!(run(&mut shell, "kill -USR2 $$; trap USR2; trap -p EXIT"), (0, String::new()));
+        assert_eq!(shell.run_exit_trap(3), 3);
+        assert_eq!(shell.var("x"), Some("ran"));
+        assert_eq!(run(&mut shell, "trap 'exit 4' EXIT"), (0, String::new()));
+        assert_eq!(shell.run_exit_trap(3), 4);
+        assert_eq!(run(&mut shell, "trap 'echo x' NOPE"), (1, String::new()));
+        assert!(shell.traps.is_empty());
+    }
+
+    #[test]
+    fn debug_and_err_traps_run_around_commands() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "trap 'echo \"> $BASH_COMMAND\"' DEBUG; echo a; trap - DEBUG; echo b"), (0, "> echo a\na\n> trap - DEBUG\nb\n".to_string()));
+        assert_eq!(run(&mut shell, "trap 'echo failed $?' ERR; false; true; if false; then :; fi; sh -c 'exit 3'"), (3, "failed 1\nfailed 3\n".to_string()));
+        assert_eq!(run(&mut shell, "trap - ERR; false"), (1, String::new()));
+    }
+
+    #[test]
+    fn umask_sets_the_mask_in_octal_or_symbols() {
+        use std::os::unix::fs::PermissionsExt;
+        let (_lock, mut shell) = shell();
+        let saved = nix::sys::stat::umask(Mode::from_bits_truncate(0o022));
+        let file = crate::tests::temp_path("umask");
+        assert_eq!(run(&mut shell, &format!("umask 027; : > {}", file.to_str().unwrap())), (0, String::new()));
+        assert_eq!(std::fs::metadata(&file).unwrap().permissions().mode() & 0o777, 0o640);
+        assert_eq!(symbolic_mode(Mode::from_bits_truncate(0o027)), "u=rwx,g=rx,o=");
+        assert_eq!(apply_symbolic_mode(0o027, "g=,o+r"), Some(0o073));
+        assert_eq!(apply_symbolic_mode(0o022, "a-w"), Some(0o222));
+        assert_eq!(run(&mut shell, "umask u=rwx,g=rx,o=; umask -S"), (0, String::new()));
+        assert_eq!(nix::sys::stat::umask(Mode::empty()).bits(), 0o027);
+        assert_eq!(run(&mut shell, "umask 999"), (1, String::new()));
+        assert_eq!(run(&mut shell, "umask -x"), (2, String::new()));
+        nix::sys::stat::umask(saved);
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn ulimit_sets_limits_that_commands_inherit() {
+        use nix::sys::resource::{getrlimit, setrlimit, Resource};
+        let (_lock, mut shell) = shell();
+        let saved = getrlimit(Resource::RLIMIT_CORE).unwrap();
+        assert_eq!(run(&mut shell, "ulimit -S -c 0; sh -c 'test \"$(ulimit -c)\" = 0'"), (0, String::new()));
+        assert_eq!(getrlimit(Resource::RLIMIT_CORE).unwrap(), (0, saved.1));
+        let unit = RESOURCE_LIMITS.iter().find(|limit| limit.flag == 'c').unwrap().unit;
+        assert_eq!(run(&mut shell, "ulimit -S -c 3"), (0, String::new()));
+        assert_eq!(getrlimit(Resource::RLIMIT_CORE).unwrap().0, 3 * unit);
+        assert_eq!(run(&mut shell, "ulimit -S -c hard; ulimit -c"), (0, String::new()));
+        assert_eq!(getrlimit(Resource::RLIMIT_CORE).unwrap(), (saved.1, saved.1));
+        assert_eq!(run(&mut shell, "ulimit -c lots"), (1, String::new()));
+        assert_eq!(run(&mut shell, "ulimit -c -n 5"), (2, String::new()));
+        assert_eq!(run(&mut shell, "ulimit -Q"), (2, String::new()));
+        setrlimit(Resource::RLIMIT_CORE, saved.0, saved.1).unwrap();
+    }
+
+    #[test]
+    fn timeout_stops_commands_that_run_too_long() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(parse_duration("1.5"), Some(std::time::Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2m"), Some(std::time::Duration::from_secs(120)));
+        assert_eq!(parse_duration("1d"), Some(std::time::Duration::from_secs(86400)));
+        assert_eq!(parse_duration("-1"), None);
+        assert_eq!(parse_duration("soon"), None);
+        let started = std::time::Instant::now();
+        assert_eq!(run(&mut shell, "timeout 0.1 sleep 5"), (124, String::new()));
+        assert_eq!(run(&mut shell, "timeout -s KILL -k 1 0.1s sleep 5"), (124, String::new()));
+        assert!(started.elapsed() < std::time::Duration::from_secs(4));
+        assert_eq!(run(&mut shell, "timeout 5 sh -c 'exit 3'"), (3, String::new()));
+        assert_eq!(run(&mut shell, "timeout -s NOPE 1 true"), (125, String::new()));
+        assert_eq!(run(&mut shell, "timeout 1"), (125, String::new()));
+    }
+
+    #[test]
+    fn nice_runs_commands_at_a_lower_priority() {
+        let (_lock, mut shell) = shell();
+        let base = niceness();
+        let expected = |adjustment: i32| format!("{}\n", (base + adjustment).clamp(-20, 19));
+        assert_eq!(run(&mut shell, "nice -n 5 /usr/bin/nice"), (0, expected(5)));
+        assert_eq!(run(&mut shell, "nice -3 /usr/bin/nice"), (0, expected(3)));
+        assert_eq!(run(&mut shell, "nice /usr/bin/nice"), (0, expected(10)));
+        assert_eq!(run(&mut shell, "nice sh -c 'exit 4'"), (4, String::new()));
+        assert_eq!(niceness(), base);
+        assert_eq!(run(&mut shell, "nice -n x true"), (125, String::new()));
+    }
+
+    #[test]
+    fn parse_describes_the_tree_of_a_command_line() {
+        let describe = |source: &str| {
+            let mut out = String::new();
+            for command in &parse(source).unwrap() {
+                describe_command(command, 0, &mut out);
+            }
+            out
+        };
+        assert_eq!(describe("x=1 echo \"a $b\"'c' 2>/dev/null | wc -l &"), concat!(
+            "pipeline (background)\n",
+            "  command\n",
+            "    assignment x=1\n",
+            "    word echo\n",
+            "    word \"a $b\"'c'    (double-quoted 'a $b', single-quoted c)\n",
+            "    redirect 2> /dev/null\n",
+            "  |\n",
+            "  command\n",
+            "    word wc\n",
+            "    word -l\n",
+        ));
+        assert_eq!(describe("if a; then b; fi > out"), concat!(
+            "if\n",
+            "  condition\n",
+            "    command\n",
+            "      word a\n",
+            "  then\n",
+            "    command\n",
+            "      word b\n",
+            "  redirect > out\n",
+        ));
+        assert_eq!(describe_word("a\\ b"), "    (unquoted a, escaped ' ', unquoted b)");
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "parse 'a | | b'"), (2, String::new()));
+        assert_eq!(run(&mut shell, "parse -t 'a | b'"), (0, String::new()));
+    }
+
+    #[test]
+    fn chpwd_runs_after_each_change_of_directory() {
+        let (_lock, mut shell) = shell();
+        let cwd = std::env::current_dir().unwrap();
+        let dir = std::fs::canonicalize(std::env::temp_dir()).unwrap();
+        let dir_name = dir.to_str().unwrap();
+        let hook = "chpwd() { changes=\"$changes $PWD\"; }";
+        assert_eq!(run(&mut shell, &format!("{}; cd {}; cd .; pushd /; popd; shopt -s autocd; /", hook, dir_name)), (0, String::new()));
+        assert_eq!(run(&mut shell, "cd /nonexistent"), (1, String::new()));
+        assert_eq!(shell.var("changes"), Some(format!(" {0} / {0} /", dir_name).as_str()));
+        // A change made by the hook itself does not run it again.
+        assert_eq!(run(&mut shell, &format!("chpwd() {{ n=x$n; cd /; }}; cd {}", dir_name)), (0, String::new()));
+        assert_eq!((shell.var("n"), shell.var("PWD")), (Some("x"), Some("/")));
+        std::env::set_current_dir(cwd).unwrap();
+    }
+
+    #[test]
+    fn builtins_run_in_the_shell_unless_piped() {
+        let (_lock, mut shell) = shell();
+        let file = crate::tests::temp_path("builtin-redirect");
+        let name = file.to_str().unwrap();
+        assert_eq!(run(&mut shell, &format!("alias a=b; echo a > {}; echo b", name)), (0, "b\n".to_string()));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "a\n");
+        assert_eq!(shell.alias("a"), Some("b"));
+        assert_eq!(run(&mut shell, "export VSSH_PIPED=1 | true; alias c=d | cat"), (0, String::new()));
+        assert!(std::env::var("VSSH_PIPED").is_err() && shell.alias("c").is_none());
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn autoload_defines_functions_on_their_first_call() {
+        let (_lock, mut shell) = shell();
+        let dir = crate::tests::temp_path("fpath");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("greet"), "echo loading\ngreet() { echo \"hi $1\"; }\n").unwrap();
+        std::fs::write(dir.join("empty"), "x=1\n").unwrap();
+        shell.set_var("FPATH", &format!("/nonexistent:{}", dir.display()));
+        assert_eq!(run(&mut shell, "autoload greet empty missing"), (0, String::new()));
+        assert_eq!(run(&mut shell, "greet bob; greet sam"), (0, "loading\nhi bob\nhi sam\n".to_string()));
+        assert_eq!(run(&mut shell, "empty"), (1, String::new()));
+        assert_eq!(run(&mut shell, "missing"), (1, String::new()));
+        assert_eq!(run(&mut shell, "autoload 1x"), (1, String::new()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn trusted_environment_files_load_on_entering_and_unload_on_leaving() {
+        let (_lock, mut shell) = shell();
+        let cwd = std::env::current_dir().unwrap();
+        let dir = crate::tests::temp_path("dir-env");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let dir = std::fs::canonicalize(dir).unwrap();
+        std::fs::write(dir.join(".env"), "VSSH_DIR_ENV=inside\n").unwrap();
+        shell.interactive = true;
+        shell.set_var("HOME", dir.to_str().unwrap());
+        shell.set_var("VSSH_DIR_ENV", "outside");
+        let visit = |shell: &mut Shell, path: &std::path::Path| {
+            run(shell, &format!("cd {}", path.display()));
+            shell.var("VSSH_DIR_ENV").unwrap_or("").to_string()
+        };
+        assert_eq!(visit(&mut shell, &dir), "outside");
+        assert_eq!(run(&mut shell, "envctl allow"), (0, String::new()));
+        assert_eq!(shell.var("VSSH_DIR_ENV"), Some("inside"));
+        assert_eq!(visit(&mut shell, &dir.join("sub")), "inside");
+        assert_eq!(visit(&mut shell, &cwd), "outside");
+        assert!(std::env::var("VSSH_DIR_ENV").is_err());
+        assert_eq!(visit(&mut shell, &dir), "inside");
+        assert_eq!(run(&mut shell, "envctl deny"), (0, String::new()));
+        assert_eq!(shell.var("VSSH_DIR_ENV"), Some("outside"));
+        std::env::set_current_dir(cwd).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn theme_use_loads_a_theme_by_name() {
+        let (_lock, mut shell) = shell();
+        let home = crate::tests::temp_path("theme-home");
+        std::fs::create_dir_all(home.join(".config/vssh/themes")).unwrap();
+        std::fs::write(home.join(".config/vssh/themes/plain.toml"), "end = \"> \"\n").unwrap();
+        std::fs::write(home.join(".config/vssh/themes/broken.toml"), "end = 1\n").unwrap();
+        shell.set_var("HOME", home.to_str().unwrap());
+        shell.vars.remove("XDG_CONFIG_HOME");
+        assert_eq!(run(&mut shell, "theme use plain"), (0, String::new()));
+        assert_eq!(shell.theme.as_ref().map(|(name, _)| name.as_str()), Some("plain"));
+        assert_eq!(run(&mut shell, "theme use broken"), (1, String::new()));
+        assert_eq!(run(&mut shell, "theme use missing"), (1, String::new()));
+        assert_eq!(run(&mut shell, "theme use ../plain"), (2, String::new()));
+        assert_eq!(shell.theme.as_ref().map(|(name, _)| name.as_str()), Some("plain"));
+        assert_eq!(run(&mut shell, "theme off"), (0, String::new()));
+        assert!(shell.theme.is_none());
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn j_jumps_to_the_best_matching_directory_visited() {
+        let (_lock, mut shell) = shell();
+        let cwd = std::env::current_dir().unwrap();
+        let dir = crate::tests::temp_path("jump");
+        for sub in ["work/project", "play/project", "play/Other"] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        let dir = std::fs::canonicalize(dir).unwrap();
+        shell.interactive = true;
+        shell.set_var("VSSH_JUMP_FILE", dir.join("jumps").to_str().unwrap());
+        for sub in ["work/project", "play/project", "play/project", "play/Other"] {
+            run(&mut shell, &format!("cd {}", dir.join(sub).display()));
+        }
+        assert_eq!(run(&mut shell, "j project"), (0, String::new()));
+        assert_eq!(std::env::current_dir().unwrap(), dir.join("play/project"));
+        assert_eq!(run(&mut shell, "j wo pro"), (0, String::new()));
+        assert_eq!(std::env::current_dir().unwrap(), dir.join("work/project"));
+        assert_eq!(run(&mut shell, "j other"), (0, String::new()));
+        assert_eq!(std::env::current_dir().unwrap(), dir.join("play/Other"));
+        assert_eq!(run(&mut shell, "j nowhere"), (1, String::new()));
+        std::env::set_current_dir(cwd).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn envrestore_puts_back_what_envsave_saved() {
+        let (_lock, mut shell) = shell();
+        let dir = crate::tests::temp_path("envs-round-trip");
+        shell.set_var("VSSH_ENV_DIR", dir.to_str().unwrap());
+        assert_eq!(run(&mut shell, "x=1; alias ll='ls -l'; set -o noclobber; envsave project"), (0, String::new()));
+        assert_eq!(run(&mut shell, "x=2; y=3; alias ll=other; set +o noclobber"), (0, String::new()));
+        assert_eq!(run(&mut shell, "envrestore project"), (0, String::new()));
+        assert_eq!((shell.var("x"), shell.var("y")), (Some("1"), None));
+        assert_eq!(shell.alias("ll"), Some("ls -l"));
+        assert!(shell.option("noclobber"));
+        assert_eq!(run(&mut shell, "envrestore missing"), (1, String::new()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+//! Per-directory environments: variables that an interactive shell sets
+//! while it is in a directory with a `.vssh_env` (or `.env`) file, or below
+//! one, and puts back when it leaves. A file is only loaded once the user
+//! has trusted it with `envctl allow`; trust is recorded with a SHA-256
+//! hash of the file, so a file changed since is not loaded until it is
+//! trusted again.
+
+use std::io::{self, Write};
+use crate::lexer::is_name;
+
+/// The files a directory's environment is read from, the first that
+/// exists.
+const ENV_FILES: &[&str] = &[".vssh_env", ".env"];
+
+/// An environment file that has been loaded, and the values its variables
+/// had before as shell variables and in the environment (`None` if unset),
+/// to put back when it is unloaded.
+pub(crate) struct Loaded {
+    pub(crate) path: String,
+    pub(crate) saved: Vec<(String, Option<String>, Option<std::ffi::OsString>)>,
+}
+
+/// The environment file that applies in directory `dir`: the one in `dir`
+/// or in the nearest of its parents that has one.
+pub(crate) fn find(dir: &str) -> Option<String> {
+    let mut dir = dir.trim_end_matches('/');
+    loop {
+        for name in ENV_FILES {
+            let path = format!("{}/{}", dir, name);
+            if std::path::Path::new(&path).is_file() {
+                return Some(path);
+            }
+        }
+        dir = &dir[..dir.rfind('/')?];
+    }
+}
+
+/// The assignments in an environment file: `NAME=value` lines, each
+/// perhaps starting with `export`, where the value may be quoted with `'`
+/// or `"` and is otherwise taken as it is, without expansion. Blank lines
+/// and `#` comments are skipped. An error names the first bad line.
+pub(crate) fn parse(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut assignments = Vec::new();
+    for (number, line) in (1..).zip(text.lines()) {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").map_or(line, str::trim_start);
+        let Some((name, value)) = line.split_once('=').filter(|(name, _)| is_name(name)) else {
+            return Err(format!("line {}: not an assignment", number));
+        };
+        let value = match value.as_bytes() {
+            [quote @ (b'\'' | b'"'), .., last] if last == quote && value.len() > 1 => &value[1..value.len() - 1],
+            _ => value,
+        };
+        assignments.push((name.to_string(), value.to_string()));
+    }
+    Ok(assignments)
+}
+
+/// Whether the trust file at `trust_file` has the environment file `path`
+/// trusted as it is now.
+pub(crate) fn is_trusted(trust_file: &str, path: &str) -> io::Result<bool> {
+    let hash = hash_file(path)?;
+    Ok(read_trusted(trust_file)?.iter().any(|(trusted_hash, trusted_path)| *trusted_hash == hash && trusted_path == path))
+}
+
+/// Trust the environment file `path` as it is now, or with `trust` false,
+/// stop trusting it.
+pub(crate) fn set_trusted(trust_file: &str, path: &str, trust: bool) -> io::Result<()> {
+    let mut trusted = read_trusted(trust_file)?;
+    trusted.retain(|(_, trusted_path)| trusted_path != path);
+    if trust {
+        trusted.push((hash_file(path)?, path.to_string()));
+    }
+    let mut out = String::new();
+    for (hash, path) in trusted {
+        out.push_str(&format!("{} {}\n", hash, path));
+    }
+    std::fs::File::create(trust_file)?.write_all(out.as_bytes())
+}
+
+/// The `(hash, path)` lines of a trust file; none if it does not exist.
+fn read_trusted(trust_file: &str) -> io::Result<Vec<(String, String)>> {
+    match std::fs::read_to_string(trust_file) {
+        Ok(text) => Ok(text
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(hash, path)| (hash.to_string(), path.to_string()))
+            .collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The SHA-256 hash of the file at `path`, in hex.
+fn hash_file(path: &str) -> io::Result<String> {
+    Ok(sha256(&std::fs::read(path)?).iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// The SHA-256 hash of `data`, as FIPS 180-4 defines it.
+fn sha256(data: &[u8]) -> [u8; 32] {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+        0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+        0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+        0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+        0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+        0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+        0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+    ];
+    let mut state: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(choice).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(majority);
+            (h, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+    let mut hash = [0u8; 32];
+    for (bytes, word) in hash.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_assignments_without_expanding_them() {
+        let text = "# settings\nA=1\n\nexport  B='two words'\nC=\"$HOME\"\nD=\n";
+        let expected = [("A", "1"), ("B", "two words"), ("C", "$HOME"), ("D", "")];
+        assert_eq!(parse(text), Ok(expected.map(|(name, value)| (name.to_string(), value.to_string())).to_vec()));
+        assert_eq!(parse("A=1\necho hi\n"), Err("line 2: not an assignment".to_string()));
+        assert_eq!(parse("1A=x\n"), Err("line 1: not an assignment".to_string()));
+    }
+
+    #[test]
+    fn finds_the_nearest_file_and_trusts_it_as_it_is() {
+        let dir = crate::tests::temp_path("direnv");
+        std::fs::create_dir_all(dir.join("a/b")).unwrap();
+        let root = dir.to_str().unwrap();
+        let path = format!("{}/.env", root);
+        std::fs::write(&path, "A=1\n").unwrap();
+        std::fs::write(format!("{}/a/.vssh_env", root), "A=2\n").unwrap();
+        std::fs::write(format!("{}/a/.env", root), "A=3\n").unwrap();
+        assert_eq!(find(&format!("{}/a/b/", root)), Some(format!("{}/a/.vssh_env", root)));
+        assert_eq!(find(root), Some(path.clone()));
+        let trust_file = format!("{}/trusted", root);
+        assert!(!is_trusted(&trust_file, &path).unwrap());
+        set_trusted(&trust_file, &path, true).unwrap();
+        assert!(is_trusted(&trust_file, &path).unwrap());
+        std::fs::write(&path, "A=changed\n").unwrap();
+        assert!(!is_trusted(&trust_file, &path).unwrap());
+        set_trusted(&trust_file, &path, true).unwrap();
+        assert_eq!(std::fs::read_to_string(&trust_file).unwrap().lines().count(), 1);
+        set_trusted(&trust_file, &path, false).unwrap();
+        assert!(!is_trusted(&trust_file, &path).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hashes_with_sha256() {
+        let hex = |data: &[u8]| sha256(data).iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        assert_eq!(hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(&[b'a'; 64]), "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb");
+    }
+}
@@ -0,0 +1,1055 @@
+//! The line editor an interactive shell reads commands with when its
+//! input is a terminal. It works on grapheme clusters, so that the cursor
+//! never lands inside a multi-byte character or between a letter and its
+//! accents, and it lays text out by display width, so that wide (CJK)
+//! characters and lines longer than the terminal keep the cursor right.
+
+use std::borrow::Cow;
+use std::io::{self, Write};
+use nix::errno::Errno;
+use nix::sys::termios::{self, LocalFlags, SetArg, SpecialCharacterIndices, Termios};
+use crate::jobs::take_interrupt;
+
+/// A key, as decoded from the bytes the terminal sends.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Key {
+    Char(char),
+    /// A control character, named by its letter: `Ctrl('a')` is Ctrl-A.
+    Ctrl(char),
+    /// A character typed with Alt (or after Escape).
+    Alt(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    WordLeft,
+    WordRight,
+    /// The start of a bracketed paste: the keys up to `PasteEnd` were
+    /// pasted rather than typed.
+    PasteStart,
+    PasteEnd,
+    /// A sequence the editor does not know, which is ignored.
+    Unknown,
+}
+
+/// Turns the bytes read from the terminal into keys. Escape sequences and
+/// multi-byte characters arrive a byte at a time and are collected here.
+#[derive(Default)]
+pub(crate) struct KeyReader {
+    pending: Vec<u8>,
+}
+
+impl KeyReader {
+    /// Add one byte, returning the key it completes, if any.
+    pub(crate) fn feed(&mut self, byte: u8) -> Option<Key> {
+        self.pending.push(byte);
+        let key = decode(&self.pending)?;
+        self.pending.clear();
+        Some(key)
+    }
+}
+
+/// The key `bytes` make up, or `None` if more bytes are needed.
+fn decode(bytes: &[u8]) -> Option<Key> {
+    match bytes {
+        [b'\r' | b'\n'] => Some(Key::Enter),
+        [0x7f | 0x08] => Some(Key::Backspace),
+        [0x1b] => None,
+        [byte] if *byte < 0x1b => Some(Key::Ctrl((byte + b'a' - 1) as char)),
+        // Ctrl-\, Ctrl-], Ctrl-^ and Ctrl-_.
+        [byte] if *byte < 0x20 => Some(Key::Ctrl((byte + b'@') as char)),
+        [0x1b, b'[' | b'O', rest @ ..] => {
+            // A CSI or SS3 sequence ends with a byte from `@` to `~`.
+            let (&last, parameters) = rest.split_last()?;
+            if !(0x40..=0x7e).contains(&last) {
+                return None;
+            }
+            Some(match (parameters, last) {
+                (_, b'A') => Key::Up,
+                (_, b'B') => Key::Down,
+                (b"1;5" | b"1;3", b'C') => Key::WordRight,
+                (b"1;5" | b"1;3", b'D') => Key::WordLeft,
+                (_, b'C') => Key::Right,
+                (_, b'D') => Key::Left,
+                (_, b'H') | (b"1" | b"7", b'~') => Key::Home,
+                (_, b'F') | (b"4" | b"8", b'~') => Key::End,
+                (b"3", b'~') => Key::Delete,
+                (b"200", b'~') => Key::PasteStart,
+                (b"201", b'~') => Key::PasteEnd,
+                _ => Key::Unknown,
+            })
+        }
+        [0x1b, rest @ ..] => match decode(rest)? {
+            Key::Char(c) => Some(Key::Alt(c)),
+            Key::Backspace => Some(Key::Alt('\x7f')),
+            _ => Some(Key::Unknown),
+        },
+        _ => match std::str::from_utf8(bytes) {
+            Ok(text) => text.chars().next().map(Key::Char),
+            // An incomplete character waits for the rest, up to four bytes.
+            Err(e) if e.error_len().is_none() && bytes.len() < 4 => None,
+            Err(_) => Some(Key::Unknown),
+        },
+    }
+}
+
+/// What `Editor::handle` made of a key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Edit {
+    /// Keep editing; the line needs drawing again.
+    Continue,
+    /// Part of a paste, which is drawn once it is all in.
+    Pasting,
+    /// Enter was pressed: the line is done.
+    Accept,
+    /// Ctrl-D on an empty line: end of input.
+    Eof,
+    /// Ctrl-X Ctrl-E: the line is to be edited in a text editor, and the
+    /// result put in with `replace`.
+    OpenEditor,
+    /// Ctrl-R or Ctrl-T: something is to be picked with a fuzzy finder.
+    Pick(Pick),
+    /// Tab: the word before the cursor is to be completed, with
+    /// `insert_text`.
+    Complete,
+}
+
+/// What a fuzzy finder is to pick from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Pick {
+    /// A line of history, put in place of the line with `replace`.
+    History,
+    /// Paths under the current directory, put in with `insert_text`.
+    Paths,
+}
+
+/// Text killed in the line editor, oldest first, to be yanked back. It
+/// outlives the line it was killed from.
+#[derive(Default)]
+pub(crate) struct KillRing {
+    entries: Vec<String>,
+    /// Whether the latest entry changed since `take_changed`.
+    changed: bool,
+}
+
+impl KillRing {
+    /// How many kills are kept.
+    const SIZE: usize = 32;
+
+    /// Add `text`, just killed, as a new entry or, when `extend` (the kill
+    /// follows another), to the latest entry: in front of it if the text
+    /// was `before` the cursor.
+    fn kill(&mut self, text: &str, extend: bool, before: bool) {
+        match self.entries.last_mut() {
+            Some(latest) if extend => latest.insert_str(if before { 0 } else { latest.len() }, text),
+            _ => {
+                self.entries.push(text.to_string());
+                if self.entries.len() > Self::SIZE {
+                    self.entries.remove(0);
+                }
+            }
+        }
+        self.changed = true;
+    }
+
+    /// The latest entry, if it changed since the last call, for copying to
+    /// the system clipboard.
+    pub(crate) fn take_changed(&mut self) -> Option<&str> {
+        if !std::mem::take(&mut self.changed) {
+            return None;
+        }
+        self.entries.last().map(String::as_str)
+    }
+}
+
+/// What the key before the current one did, for the keys that carry on
+/// from it.
+#[derive(Default, Clone, Copy, PartialEq)]
+enum Previous {
+    #[default]
+    Other,
+    /// Killed text, which a further kill adds to.
+    Kill,
+    /// Yanked kill ring entry `index` in as `start..end`, which Alt-Y
+    /// replaces with the entry before it.
+    Yank { start: usize, end: usize, index: usize },
+    /// Typed a character; characters typed in a row are undone together.
+    Typing,
+    /// Undid or redid a change, which is not itself a change to undo.
+    Undo,
+    /// Pressed Ctrl-X, which starts a two-key binding.
+    CtrlX,
+}
+
+/// Where Up and Down have got to in the history.
+struct Recall {
+    /// The entry shown, or the number of entries for the line typed.
+    index: usize,
+    /// The text typed before Up was first pressed, which only entries
+    /// starting with it are recalled for.
+    typed: String,
+}
+
+/// The line being edited and where it is on the screen.
+#[derive(Default)]
+pub(crate) struct Editor {
+    text: String,
+    /// A byte offset into `text`, always at a grapheme boundary.
+    cursor: usize,
+    /// The screen row the cursor was left on by the last drawing, counted
+    /// from the row the prompt starts on.
+    cursor_row: usize,
+    /// The width of the terminal at the last drawing.
+    columns: usize,
+    /// Whether a bracketed paste is coming in.
+    pasting: bool,
+    /// The text of the last paste, highlighted until the next key.
+    pasted: Option<(usize, usize)>,
+    previous: Previous,
+    /// The text and cursor before each change, latest last.
+    undo: Vec<(String, usize)>,
+    /// The changes undone, latest last, until another change is made.
+    redo: Vec<(String, usize)>,
+    /// Set while Up and Down move through the history.
+    recall: Option<Recall>,
+}
+
+impl Editor {
+    /// The text typed so far.
+    pub(crate) fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The text before the cursor.
+    pub(crate) fn before_cursor(&self) -> &str {
+        &self.text[..self.cursor]
+    }
+
+    /// Act on a key. Pasted text goes in as it is, newlines and tabs
+    /// included, so that a pasted command does not run until Enter is
+    /// pressed. Text deleted a word or more at a time goes to `kill_ring`,
+    /// for Ctrl-Y to yank back and Alt-Y to cycle through. Ctrl-_ or
+    /// Ctrl-X u undoes the last change, and Alt-_ redoes it. Up and Down
+    /// go through the lines of `history` that start with what was typed,
+    /// or through all of them if nothing was.
+    pub(crate) fn handle(&mut self, key: Key, kill_ring: &mut KillRing, history: &[String]) -> Edit {
+        let previous = std::mem::take(&mut self.previous);
+        if self.pasting {
+            match key {
+                Key::PasteEnd => self.pasting = false,
+                Key::Enter => self.insert('\n'),
+                Key::Ctrl('i') => self.insert('\t'),
+                Key::Char(c) => self.insert(c),
+                _ => {}
+            }
+            if let Some((_, end)) = &mut self.pasted {
+                *end = self.cursor;
+            }
+            return if self.pasting { Edit::Pasting } else { Edit::Continue };
+        }
+        self.pasted = None;
+        let before = (self.text.clone(), self.cursor);
+        let edit = match key {
+            Key::Up | Key::Ctrl('p') => self.recall(history, true),
+            Key::Down | Key::Ctrl('n') => self.recall(history, false),
+            _ => {
+                self.recall = None;
+                self.act(key, kill_ring, previous)
+            }
+        };
+        match self.previous {
+            Previous::Undo => {}
+            Previous::Typing if previous == Previous::Typing => {}
+            _ if self.text != before.0 || self.pasting => {
+                self.undo.push(before);
+                self.redo.clear();
+            }
+            _ => {}
+        }
+        edit
+    }
+
+    /// Worker for `handle`, for keys other than those of a paste.
+    fn act(&mut self, key: Key, kill_ring: &mut KillRing, previous: Previous) -> Edit {
+        match key {
+            Key::PasteStart => {
+                self.pasting = true;
+                self.pasted = Some((self.cursor, self.cursor));
+                return Edit::Pasting;
+            }
+            Key::Char('u') | Key::Ctrl('u') if previous == Previous::CtrlX => self.step(true),
+            Key::Ctrl('e') if previous == Previous::CtrlX => return Edit::OpenEditor,
+            Key::Ctrl('r') => return Edit::Pick(Pick::History),
+            Key::Ctrl('t') => return Edit::Pick(Pick::Paths),
+            Key::Ctrl('i') => return Edit::Complete,
+            Key::Ctrl('x') => self.previous = Previous::CtrlX,
+            Key::Ctrl('_') => self.step(true),
+            Key::Alt('_') => self.step(false),
+            Key::Enter => return Edit::Accept,
+            Key::Ctrl('d') if self.text.is_empty() => return Edit::Eof,
+            Key::Char(c) => {
+                self.insert(c);
+                self.previous = Previous::Typing;
+            }
+            Key::Left | Key::Ctrl('b') => self.cursor = previous_boundary(&self.text, self.cursor),
+            Key::Right | Key::Ctrl('f') => self.cursor = next_boundary(&self.text, self.cursor),
+            Key::Home | Key::Ctrl('a') => self.cursor = 0,
+            Key::End | Key::Ctrl('e') => self.cursor = self.text.len(),
+            Key::WordLeft | Key::Alt('b') => self.cursor = self.word_start(),
+            Key::WordRight | Key::Alt('f') => self.cursor = self.word_end(),
+            Key::Backspace | Key::Ctrl('h') => {
+                let start = previous_boundary(&self.text, self.cursor);
+                self.remove(start, self.cursor);
+            }
+            Key::Delete | Key::Ctrl('d') => self.remove(self.cursor, next_boundary(&self.text, self.cursor)),
+            Key::Ctrl('w') | Key::Alt('\x7f') => self.kill(self.word_start(), self.cursor, kill_ring, previous),
+            Key::Alt('d') => self.kill(self.cursor, self.word_end(), kill_ring, previous),
+            Key::Ctrl('k') => self.kill(self.cursor, self.text.len(), kill_ring, previous),
+            Key::Ctrl('u') => self.kill(0, self.cursor, kill_ring, previous),
+            Key::Ctrl('y') => {
+                if let Some(index) = kill_ring.entries.len().checked_sub(1) {
+                    self.yank(self.cursor, self.cursor, kill_ring, index);
+                }
+            }
+            Key::Alt('y') => {
+                if let Previous::Yank { start, end, index } = previous {
+                    let index = index.checked_sub(1).unwrap_or(kill_ring.entries.len() - 1);
+                    self.yank(start, end, kill_ring, index);
+                }
+            }
+            _ => {}
+        }
+        Edit::Continue
+    }
+
+    /// Show the previous line of `history` (or the next one if not
+    /// `backward`) that starts with the text typed and differs from the
+    /// line shown. Going forward past the last brings back what was typed.
+    fn recall(&mut self, history: &[String], backward: bool) -> Edit {
+        let recall = self.recall.get_or_insert_with(|| Recall { index: history.len(), typed: self.text.clone() });
+        let wanted = |line: &String| line.starts_with(&recall.typed) && *line != self.text;
+        let found = if backward {
+            history[..recall.index.min(history.len())].iter().rposition(wanted)
+        } else {
+            history.iter().skip(recall.index + 1).position(wanted).map(|offset| recall.index + 1 + offset)
+        };
+        match found {
+            Some(index) => {
+                recall.index = index;
+                self.text = history[index].clone();
+            }
+            None if !backward => {
+                recall.index = history.len();
+                self.text = recall.typed.clone();
+            }
+            None => {}
+        }
+        self.cursor = self.text.len();
+        Edit::Continue
+    }
+
+    /// Put `text` in place of the whole line, with the cursor at its end,
+    /// as a change that can be undone.
+    pub(crate) fn replace(&mut self, text: String) {
+        let cursor = text.len();
+        self.undo.push((std::mem::replace(&mut self.text, text), std::mem::replace(&mut self.cursor, cursor)));
+        self.redo.clear();
+    }
+
+    /// Insert `text` at the cursor, as a change that can be undone.
+    pub(crate) fn insert_text(&mut self, text: &str) {
+        self.undo.push((self.text.clone(), self.cursor));
+        self.redo.clear();
+        self.text.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    /// Undo the last change, or redo the last change undone.
+    fn step(&mut self, undo: bool) {
+        let (from, to) = if undo { (&mut self.undo, &mut self.redo) } else { (&mut self.redo, &mut self.undo) };
+        if let Some((text, cursor)) = from.pop() {
+            to.push((std::mem::replace(&mut self.text, text), std::mem::replace(&mut self.cursor, cursor)));
+        }
+        self.previous = Previous::Undo;
+    }
+
+    /// Remove `start..end`, adding it to `kill_ring`.
+    fn kill(&mut self, start: usize, end: usize, kill_ring: &mut KillRing, previous: Previous) {
+        if start == end {
+            self.previous = previous;
+            return;
+        }
+        kill_ring.kill(&self.text[start..end], previous == Previous::Kill, start < self.cursor);
+        self.remove(start, end);
+        self.previous = Previous::Kill;
+    }
+
+    /// Put kill ring entry `index` in place of `start..end`.
+    fn yank(&mut self, start: usize, end: usize, kill_ring: &KillRing, index: usize) {
+        let entry = &kill_ring.entries[index];
+        self.text.replace_range(start..end, entry);
+        self.cursor = start + entry.len();
+        self.previous = Previous::Yank { start, end: self.cursor, index };
+    }
+
+    fn insert(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        // A combining character joins the cluster before it; the cursor
+        // goes after the cluster it ends up in.
+        let mut end = self.cursor + c.len_utf8();
+        while !is_boundary(&self.text, end) {
+            end = next_boundary(&self.text, end);
+        }
+        self.cursor = end;
+    }
+
+    fn remove(&mut self, start: usize, end: usize) {
+        self.text.replace_range(start..end, "");
+        self.cursor = start;
+    }
+
+    /// The start of the word before the cursor, skipping any gap first.
+    fn word_start(&self) -> usize {
+        let mut position = self.cursor;
+        let mut seen_word = false;
+        while position > 0 {
+            let previous = previous_boundary(&self.text, position);
+            let in_word = self.text[previous..position].chars().next().is_some_and(char::is_alphanumeric);
+            if seen_word && !in_word {
+                break;
+            }
+            seen_word |= in_word;
+            position = previous;
+        }
+        position
+    }
+
+    /// The end of the word after the cursor, skipping any gap first.
+    fn word_end(&self) -> usize {
+        let mut position = self.cursor;
+        let mut seen_word = false;
+        while position < self.text.len() {
+            let next = next_boundary(&self.text, position);
+            let in_word = self.text[position..next].chars().next().is_some_and(char::is_alphanumeric);
+            if seen_word && !in_word {
+                break;
+            }
+            seen_word |= in_word;
+            position = next;
+        }
+        position
+    }
+
+    /// Draw `prompt` and the line over the previous drawing, leaving the
+    /// terminal's cursor where the editor's is. A prompt too long for the
+    /// terminal is shortened with `fit_prompt`.
+    pub(crate) fn draw(&mut self, prompt: &str) -> io::Result<()> {
+        let columns = terminal_width();
+        let prompt = fit_prompt(prompt, columns / 2);
+        let mut out = String::new();
+        if self.cursor_row > 0 {
+            out.push_str(&format!("\x1b[{}A", self.cursor_row));
+        }
+        out.push_str("\r\x1b[J");
+        out.push_str(&prompt);
+        match self.pasted {
+            Some((start, end)) => {
+                out.push_str(&self.text[..start]);
+                out.push_str("\x1b[7m");
+                out.push_str(&self.text[start..end]);
+                out.push_str("\x1b[27m");
+                out.push_str(&self.text[end..]);
+            }
+            None => out.push_str(&self.text),
+        }
+        let start = advance((0, 0), &prompt, columns);
+        let (end_row, end_column) = advance(start, &self.text, columns);
+        // A line that exactly fills the last row leaves the terminal's
+        // cursor at its edge; a newline puts it where the layout has it.
+        if end_column == 0 && end_row > 0 {
+            out.push_str("\r\n");
+        }
+        let (row, column) = advance(start, &self.text[..self.cursor], columns);
+        if end_row > row {
+            out.push_str(&format!("\x1b[{}A", end_row - row));
+        }
+        out.push('\r');
+        if column > 0 {
+            out.push_str(&format!("\x1b[{}C", column));
+        }
+        self.cursor_row = row;
+        self.columns = columns;
+        let mut stdout = io::stdout();
+        stdout.write_all(out.as_bytes())?;
+        stdout.flush()
+    }
+
+    /// Work out where the last drawing of `prompt` and the line has gone
+    /// after the terminal changed size, so that the next one replaces it
+    /// rather than leaving stale rows behind. Terminals rewrap what is on
+    /// the screen to the new width, so the cursor's row is recomputed with
+    /// the prompt as it was drawn.
+    pub(crate) fn resize(&mut self, prompt: &str) {
+        if self.columns == 0 {
+            return;
+        }
+        let prompt = fit_prompt(prompt, self.columns / 2);
+        let columns = terminal_width();
+        let start = advance((0, 0), &prompt, columns);
+        self.cursor_row = advance(start, &self.text[..self.cursor], columns).0;
+    }
+
+    /// Move the terminal's cursor to a fresh line below the text, as when
+    /// the line is done or something else is about to be printed. The next
+    /// drawing starts there.
+    pub(crate) fn leave(&mut self, prompt: &str) -> io::Result<()> {
+        let columns = terminal_width();
+        let prompt = fit_prompt(prompt, columns / 2);
+        let start = advance((0, 0), &prompt, columns);
+        let (end_row, end_column) = advance(start, &self.text, columns);
+        let mut out = String::new();
+        if end_row > self.cursor_row {
+            out.push_str(&format!("\x1b[{}B", end_row - self.cursor_row));
+        }
+        // A line that exactly fills its last row already ends on a fresh one.
+        out.push_str(if end_column == 0 && end_row > 0 { "\r" } else { "\r\n" });
+        self.cursor_row = 0;
+        let mut stdout = io::stdout();
+        stdout.write_all(out.as_bytes())?;
+        stdout.flush()
+    }
+}
+
+/// A simple fuzzy finder, for when there is no other. What the user types
+/// narrows `candidates` down to those containing its characters in turn,
+/// which are listed below the line, best match first. Up and Down move
+/// through them and Enter picks one; Ctrl-G or Ctrl-C gives up. The
+/// terminal must be in raw mode.
+pub(crate) fn choose(candidates: &[String]) -> Option<String> {
+    const SHOWN: usize = 10;
+    let mut keys = KeyReader::default();
+    let mut query = String::new();
+    let mut selected = 0;
+    let mut drawn = 0;
+    let chosen = loop {
+        let mut matches: Vec<(usize, usize)> = (0..)
+            .zip(candidates)
+            .filter_map(|(index, candidate)| Some((fuzzy_score(&query, candidate)?, index)))
+            .collect();
+        matches.sort_by_key(|&(score, index)| (std::cmp::Reverse(score), index));
+        matches.truncate(SHOWN);
+        selected = selected.min(matches.len().saturating_sub(1));
+        let columns = terminal_width();
+        let mut out = String::new();
+        if drawn > 0 {
+            out.push_str(&format!("\x1b[{}A", drawn));
+        }
+        out.push_str("\r\x1b[J");
+        for (row, &(_, index)) in matches.iter().enumerate() {
+            let line = clip(&candidates[index].replace('\n', " "), columns.saturating_sub(3)).to_string();
+            if row == selected {
+                out.push_str(&format!("\x1b[7m> {}\x1b[27m\r\n", line));
+            } else {
+                out.push_str(&format!("  {}\r\n", line));
+            }
+        }
+        out.push_str(&format!("{}/{} > {}", matches.len(), candidates.len(), query));
+        drawn = matches.len();
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(out.as_bytes()).and_then(|_| stdout.flush());
+        let key = loop {
+            let mut byte = [0u8];
+            match nix::unistd::read(0, &mut byte) {
+                Ok(1) => {
+                    if let Some(key) = keys.feed(byte[0]) {
+                        break Some(key);
+                    }
+                }
+                Err(Errno::EINTR) if !take_interrupt() => {}
+                _ => break None,
+            }
+        };
+        match key {
+            None | Some(Key::Ctrl('g')) => break None,
+            Some(Key::Enter) => break matches.get(selected).map(|&(_, index)| candidates[index].clone()),
+            Some(Key::Up | Key::Ctrl('p')) => selected = selected.saturating_sub(1),
+            Some(Key::Down | Key::Ctrl('n')) => selected += 1,
+            Some(Key::Backspace | Key::Ctrl('h')) => {
+                query.pop();
+            }
+            Some(Key::Ctrl('u')) => query.clear(),
+            Some(Key::Char(c)) => query.push(c),
+            Some(_) => {}
+        }
+    };
+    let mut out = String::new();
+    if drawn > 0 {
+        out.push_str(&format!("\x1b[{}A", drawn));
+    }
+    out.push_str("\r\x1b[J");
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(out.as_bytes()).and_then(|_| stdout.flush());
+    chosen
+}
+
+/// How well `candidate` matches `query` for `choose`, if it contains the
+/// characters of `query` in turn, ignoring case. Characters that match in
+/// a row, or at the start of a word or path component, count for more.
+fn fuzzy_score(query: &str, candidate: &str) -> Option<usize> {
+    let chars: Vec<char> = candidate.chars().collect();
+    let mut position = 0;
+    let mut score = 0;
+    let mut last_match = None;
+    for wanted in query.chars() {
+        while !chars.get(position)?.to_lowercase().eq(wanted.to_lowercase()) {
+            position += 1;
+        }
+        score += 1;
+        if position > 0 && last_match == Some(position - 1) {
+            score += 5;
+        }
+        if position == 0 || " /-_.".contains(chars[position - 1]) {
+            score += 3;
+        }
+        last_match = Some(position);
+        position += 1;
+    }
+    Some(score)
+}
+
+/// As much of the start of `text` as fits in `columns`.
+fn clip(text: &str, columns: usize) -> &str {
+    let mut width = 0;
+    for (index, c) in text.char_indices() {
+        width += char_width(c);
+        if width > columns {
+            return &text[..index];
+        }
+    }
+    text
+}
+
+/// The terminal settings in force before `RawMode::enter`, put back when
+/// it is dropped, and the ones the editor uses.
+pub(crate) struct RawMode {
+    saved: Termios,
+    raw: Termios,
+}
+
+impl RawMode {
+    /// Stop the terminal from echoing and from collecting whole lines, so
+    /// that the editor sees each key as it is typed, and ask it to bracket
+    /// pasted text. Signals such as Ctrl-C still work. `None` if standard
+    /// input is not a terminal.
+    pub(crate) fn enter() -> Option<RawMode> {
+        let saved = termios::tcgetattr(0).ok()?;
+        let mut raw = saved.clone();
+        raw.local_flags.remove(LocalFlags::ICANON | LocalFlags::ECHO | LocalFlags::IEXTEN);
+        raw.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        raw.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+        termios::tcsetattr(0, SetArg::TCSADRAIN, &raw).ok()?;
+        bracketed_paste(true);
+        Some(RawMode { saved, raw })
+    }
+
+    /// Put the usual settings back for a while, to run commands (such as
+    /// traps) in the middle of editing.
+    pub(crate) fn suspend(&self) {
+        bracketed_paste(false);
+        let _ = termios::tcsetattr(0, SetArg::TCSADRAIN, &self.saved);
+    }
+
+    /// Go back to editing after `suspend`.
+    pub(crate) fn resume(&self) {
+        let _ = termios::tcsetattr(0, SetArg::TCSADRAIN, &self.raw);
+        bracketed_paste(true);
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        self.suspend();
+    }
+}
+
+/// Turn the terminal's bracketed paste mode on or off. While it is on,
+/// the terminal sends pasted text between `PasteStart` and `PasteEnd`.
+fn bracketed_paste(on: bool) {
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(if on { b"\x1b[?2004h" } else { b"\x1b[?2004l" });
+    let _ = stdout.flush();
+}
+
+/// The width of the terminal on standard output, or 80 if unknown.
+pub(crate) fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(1, libc::TIOCGWINSZ, &mut size) } == 0 && size.ws_col > 0 {
+        size.ws_col as usize
+    } else {
+        80
+    }
+}
+
+/// `prompt`, shortened if it is wider than `room` columns by putting `…`
+/// in place of whole directories from the middle of the path in it, so
+/// that `/home/me/src/vssh/src$ ` becomes `/home/…/vssh/src$ `. The first
+/// directory is always kept, and as many of the last as fit. A prompt
+/// with no such path is left alone and wraps instead.
+fn fit_prompt(prompt: &str, room: usize) -> Cow<'_, str> {
+    let width = |text: &str| advance((0, 0), text, usize::MAX).1;
+    if width(prompt) <= room {
+        return Cow::Borrowed(prompt);
+    }
+    let slashes: Vec<usize> = prompt.match_indices('/').map(|(index, _)| index).collect();
+    if slashes.len() < 3 {
+        return Cow::Borrowed(prompt);
+    }
+    let head = &prompt[..=slashes[1]];
+    let shortened = |cut: usize| format!("{}…{}", head, &prompt[cut..]);
+    let fitting = slashes[2..].iter().map(|&cut| shortened(cut)).find(|candidate| width(candidate) <= room);
+    Cow::Owned(fitting.unwrap_or_else(|| shortened(slashes[slashes.len() - 1])))
+}
+
+/// Where on the screen the cursor ends up after writing `text` from
+/// `(row, column)` on a terminal `columns` wide. Each grapheme cluster
+/// takes the width of the character it starts with, and a wide one that
+/// does not fit at the end of a row goes to the next, as terminals do.
+/// Escape sequences, such as colours in a prompt, take no room.
+fn advance((mut row, mut column): (usize, usize), text: &str, columns: usize) -> (usize, usize) {
+    let mut index = 0;
+    while let Some(c) = text[index..].chars().next() {
+        if c == '\x1b' {
+            index += 1;
+            if text[index..].starts_with('[') {
+                let length = text[index + 1..].find(|c| ('\x40'..='\x7e').contains(&c)).map_or(text.len() - index, |end| end + 2);
+                index += length;
+            }
+            continue;
+        }
+        index = next_boundary(text, index);
+        if c == '\n' {
+            (row, column) = (row + 1, 0);
+            continue;
+        }
+        let width = char_width(c);
+        if column + width > columns {
+            (row, column) = (row + 1, 0);
+        }
+        column += width;
+        if column == columns {
+            (row, column) = (row + 1, 0);
+        }
+    }
+    (row, column)
+}
+
+/// The next grapheme boundary after byte `index` of `text`.
+fn next_boundary(text: &str, index: usize) -> usize {
+    let mut next = index;
+    loop {
+        match text[next..].chars().next() {
+            Some(c) => next += c.len_utf8(),
+            None => return next,
+        }
+        if is_boundary(text, next) {
+            return next;
+        }
+    }
+}
+
+/// The grapheme boundary before byte `index` of `text`.
+fn previous_boundary(text: &str, index: usize) -> usize {
+    let mut previous = index;
+    loop {
+        match text[..previous].chars().next_back() {
+            Some(c) => previous -= c.len_utf8(),
+            None => return previous,
+        }
+        if is_boundary(text, previous) {
+            return previous;
+        }
+    }
+}
+
+/// Whether a grapheme cluster may start at byte `index` of `text`: a
+/// simplified form of the Unicode rules that keeps combining marks,
+/// variation selectors and emoji modifiers with what they follow, joins
+/// characters around a zero-width joiner, pairs regional indicators into
+/// flags, and keeps CR LF together.
+fn is_boundary(text: &str, index: usize) -> bool {
+    let (Some(before), Some(after)) = (text[..index].chars().next_back(), text[index..].chars().next()) else {
+        return true;
+    };
+    if before == '\r' && after == '\n' {
+        return false;
+    }
+    if is_extend(after) || after == '\u{200d}' || before == '\u{200d}' {
+        return false;
+    }
+    if is_regional_indicator(before) && is_regional_indicator(after) {
+        // Flags pair up from the start of a run of indicators.
+        let run = text[..index].chars().rev().take_while(|&c| is_regional_indicator(c)).count();
+        return run % 2 == 0;
+    }
+    true
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1f1e6}'..='\u{1f1ff}').contains(&c)
+}
+
+/// Whether `c` extends the grapheme cluster before it.
+fn is_extend(c: char) -> bool {
+    const EXTEND: &[(u32, u32)] = &[
+        (0x0300, 0x036f),
+        (0x0483, 0x0489),
+        (0x0591, 0x05bd),
+        (0x05bf, 0x05c7),
+        (0x0610, 0x061a),
+        (0x064b, 0x065f),
+        (0x0670, 0x0670),
+        (0x06d6, 0x06ed),
+        (0x0900, 0x0903),
+        (0x093a, 0x094f),
+        (0x0951, 0x0957),
+        (0x0962, 0x0963),
+        (0x0e31, 0x0e31),
+        (0x0e34, 0x0e3a),
+        (0x0e47, 0x0e4e),
+        (0x1160, 0x11ff),
+        (0x1ab0, 0x1aff),
+        (0x1dc0, 0x1dff),
+        (0x200c, 0x200c),
+        (0x20d0, 0x20ff),
+        (0x302a, 0x302f),
+        (0x3099, 0x309a),
+        (0xfe00, 0xfe0f),
+        (0xfe20, 0xfe2f),
+        (0x1f3fb, 0x1f3ff),
+        (0xe0020, 0xe007f),
+        (0xe0100, 0xe01ef),
+    ];
+    in_ranges(c, EXTEND)
+}
+
+/// How many columns `c` takes on a terminal: 0 for combining and other
+/// zero-width characters, 2 for wide East Asian characters and emoji, and
+/// otherwise 1.
+fn char_width(c: char) -> usize {
+    const WIDE: &[(u32, u32)] = &[
+        (0x1100, 0x115f),
+        (0x231a, 0x231b),
+        (0x23e9, 0x23ec),
+        (0x25fd, 0x25fe),
+        (0x2614, 0x2615),
+        (0x2648, 0x2653),
+        (0x26a1, 0x26a1),
+        (0x26bd, 0x26be),
+        (0x26c4, 0x26c5),
+        (0x26f2, 0x26f5),
+        (0x26fa, 0x26fd),
+        (0x2705, 0x2705),
+        (0x270a, 0x270b),
+        (0x2728, 0x2728),
+        (0x274c, 0x274c),
+        (0x2753, 0x2755),
+        (0x2795, 0x2797),
+        (0x2b1b, 0x2b1c),
+        (0x2e80, 0x303e),
+        (0x3041, 0x33ff),
+        (0x3400, 0x4dbf),
+        (0x4e00, 0x9fff),
+        (0xa000, 0xa4cf),
+        (0xa960, 0xa97f),
+        (0xac00, 0xd7a3),
+        (0xf900, 0xfaff),
+        (0xfe10, 0xfe19),
+        (0xfe30, 0xfe6f),
+        (0xff00, 0xff60),
+        (0xffe0, 0xffe6),
+        (0x16fe0, 0x18cff),
+        (0x1b000, 0x1b2ff),
+        (0x1f004, 0x1f004),
+        (0x1f0cf, 0x1f0cf),
+        (0x1f18e, 0x1f18e),
+        (0x1f191, 0x1f19a),
+        (0x1f1e6, 0x1f1ff),
+        (0x1f200, 0x1f251),
+        (0x1f300, 0x1f64f),
+        (0x1f680, 0x1f6ff),
+        (0x1f7e0, 0x1f7eb),
+        (0x1f900, 0x1f9ff),
+        (0x1fa70, 0x1faff),
+        (0x20000, 0x2fffd),
+        (0x30000, 0x3fffd),
+    ];
+    if c < ' ' || ('\u{7f}'..'\u{a0}').contains(&c) || c == '\u{200b}' || c == '\u{200d}' || is_extend(c) {
+        0
+    } else if in_ranges(c, WIDE) {
+        2
+    } else {
+        1
+    }
+}
+
+/// Whether `c` falls in one of `ranges`, which are sorted and inclusive.
+fn in_ranges(c: char, ranges: &[(u32, u32)]) -> bool {
+    let c = c as u32;
+    ranges
+        .binary_search_by(|&(start, end)| {
+            if end < c {
+                std::cmp::Ordering::Less
+            } else if start > c {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        })
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An editor after typing `text` and then pressing `keys`.
+    fn edited(text: &str, keys: &[Key]) -> Editor {
+        let mut editor = Editor::default();
+        let mut kill_ring = KillRing::default();
+        for key in text.chars().map(Key::Char).chain(keys.iter().copied()) {
+            editor.handle(key, &mut kill_ring, &[]);
+        }
+        editor
+    }
+
+    #[test]
+    fn moves_and_deletes_whole_grapheme_clusters() {
+        let text = "ae\u{301}漢🇫🇷";
+        assert_eq!(edited(text, &[Key::Backspace]).text(), "ae\u{301}漢");
+        assert_eq!(edited(text, &[Key::Backspace, Key::Backspace]).text(), "ae\u{301}");
+        assert_eq!(edited(text, &[Key::Backspace, Key::Backspace, Key::Backspace]).text(), "a");
+        assert_eq!(edited(text, &[Key::Left, Key::Left]).before_cursor(), "ae\u{301}");
+        assert_eq!(edited(text, &[Key::Home, Key::Right, Key::Delete]).text(), "a漢🇫🇷");
+        assert_eq!(edited("héllo wörld", &[Key::WordLeft, Key::Ctrl('w')]).text(), "wörld");
+        assert_eq!(edited("héllo wörld", &[Key::Home, Key::WordRight]).before_cursor(), "héllo");
+    }
+
+    #[test]
+    fn lays_out_wide_characters_by_display_width() {
+        assert_eq!(char_width('a'), 1);
+        assert_eq!(char_width('漢'), 2);
+        assert_eq!(char_width('\u{301}'), 0);
+        assert_eq!(advance((0, 0), "ae\u{301}", 80), (0, 2));
+        // A wide character that does not fit at the end of a row wraps.
+        assert_eq!(advance((0, 0), "a漢字", 4), (1, 2));
+        assert_eq!(advance((0, 0), "\x1b[1;32m漢\x1b[0m", 80), (0, 2));
+        assert_eq!(clip("漢字x", 3), "漢");
+    }
+
+    #[test]
+    fn shortens_long_prompts_in_the_middle_of_the_path() {
+        let prompt = "/home/me/src/vssh/src$ ";
+        assert_eq!(fit_prompt(prompt, 40), prompt);
+        assert_eq!(fit_prompt(prompt, 20), "/home/…/vssh/src$ ");
+        assert_eq!(fit_prompt(prompt, 15), "/home/…/src$ ");
+        // The last directory stays even when nothing fits.
+        assert_eq!(fit_prompt(prompt, 5), "/home/…/src$ ");
+        assert_eq!(fit_prompt("a very long prompt without a path> ", 10), "a very long prompt without a path> ");
+        assert_eq!(fit_prompt("\x1b[1m/a/b/c/d\x1b[0m$ ", 10), "\x1b[1m/a/b/c/d\x1b[0m$ ");
+    }
+
+    #[test]
+    fn wraps_lines_at_the_width_of_the_terminal() {
+        assert_eq!(advance((0, 0), "0123456789", 10), (1, 0));
+        assert_eq!(advance((0, 0), "0123456789ab", 10), (1, 2));
+        assert_eq!(advance((0, 5), "0123456789ab", 10), (1, 7));
+        assert_eq!(advance((0, 0), "ab\ncd", 10), (1, 2));
+    }
+
+    #[test]
+    fn pastes_text_verbatim_without_running_it() {
+        let mut keys = KeyReader::default();
+        let decoded: Vec<Key> = b"\x1b[200~a\rb\x1b[201~".iter().filter_map(|&byte| keys.feed(byte)).collect();
+        assert_eq!(decoded, [Key::PasteStart, Key::Char('a'), Key::Enter, Key::Char('b'), Key::PasteEnd]);
+        let mut editor = Editor::default();
+        let mut kill_ring = KillRing::default();
+        let edits: Vec<Edit> = decoded.into_iter().map(|key| editor.handle(key, &mut kill_ring, &[])).collect();
+        assert_eq!(edits, [Edit::Pasting, Edit::Pasting, Edit::Pasting, Edit::Pasting, Edit::Continue]);
+        assert_eq!(editor.text(), "a\nb");
+        assert_eq!(editor.pasted, Some((0, 3)));
+        assert_eq!(editor.handle(Key::Enter, &mut kill_ring, &[]), Edit::Accept);
+        assert_eq!(editor.pasted, None);
+    }
+
+    #[test]
+    fn kills_go_to_a_ring_to_yank_back() {
+        let mut editor = Editor::default();
+        let mut kill_ring = KillRing::default();
+        let mut press = |keys: &[Key], kill_ring: &mut KillRing| {
+            for &key in keys {
+                editor.handle(key, kill_ring, &[]);
+            }
+            editor.text().to_string()
+        };
+        press(&"one two three".chars().map(Key::Char).collect::<Vec<_>>(), &mut kill_ring);
+        // Kills in a row add up to one entry.
+        assert_eq!(press(&[Key::Ctrl('w'), Key::Ctrl('w')], &mut kill_ring), "one ");
+        assert_eq!(kill_ring.entries, ["two three"]);
+        assert_eq!(press(&[Key::End, Key::Ctrl('u'), Key::Ctrl('y')], &mut kill_ring), "one ");
+        assert_eq!(press(&[Key::Alt('y')], &mut kill_ring), "two three");
+        assert_eq!(press(&[Key::Alt('y')], &mut kill_ring), "one ");
+        assert_eq!(kill_ring.take_changed(), Some("one "));
+        assert_eq!(kill_ring.take_changed(), None);
+    }
+
+    #[test]
+    fn undoes_and_redoes_changes_to_the_line() {
+        // Characters typed in a row are undone together.
+        assert_eq!(edited("ab cd", &[Key::Ctrl('_')]).text(), "");
+        assert_eq!(edited("ab cd", &[Key::Ctrl('u'), Key::Ctrl('_')]).text(), "ab cd");
+        assert_eq!(edited("ab cd", &[Key::Ctrl('w'), Key::Backspace, Key::Ctrl('x'), Key::Char('u')]).text(), "ab ");
+        assert_eq!(edited("ab cd", &[Key::Ctrl('w'), Key::Backspace, Key::Ctrl('_'), Key::Ctrl('_'), Key::Alt('_')]).text(), "ab ");
+        // A new change drops what could be redone.
+        assert_eq!(edited("ab cd", &[Key::Ctrl('u'), Key::Ctrl('_'), Key::Char('x'), Key::Alt('_')]).text(), "ab cdx");
+        let undone = edited("ab cd", &[Key::Left, Key::Left, Key::Ctrl('k'), Key::Ctrl('_')]);
+        assert_eq!((undone.text(), undone.before_cursor()), ("ab cd", "ab "));
+    }
+
+    #[test]
+    fn up_recalls_lines_starting_with_what_was_typed() {
+        let history: Vec<String> = ["git status", "ls", "git log", "git log", "make"].map(String::from).to_vec();
+        let mut editor = Editor::default();
+        let mut kill_ring = KillRing::default();
+        let mut press = |key: Key| {
+            editor.handle(key, &mut kill_ring, &history);
+            editor.text().to_string()
+        };
+        for c in "git ".chars() {
+            press(Key::Char(c));
+        }
+        assert_eq!(press(Key::Up), "git log");
+        // Repeats of the line shown are skipped.
+        assert_eq!(press(Key::Up), "git status");
+        assert_eq!(press(Key::Up), "git status");
+        assert_eq!(press(Key::Down), "git log");
+        assert_eq!(press(Key::Down), "git ");
+        press(Key::Ctrl('u'));
+        assert_eq!(press(Key::Up), "make");
+        assert_eq!(press(Key::Ctrl('p')), "git log");
+        assert_eq!(press(Key::Up), "ls");
+    }
+
+    #[test]
+    fn scores_fuzzy_matches_by_runs_and_word_starts() {
+        assert_eq!(fuzzy_score("xyz", "src/main.rs"), None);
+        assert_eq!(fuzzy_score("", "anything"), Some(0));
+        assert!(fuzzy_score("main", "src/main.rs") > fuzzy_score("main", "m_a_i_n"));
+        assert!(fuzzy_score("sm", "src/main.rs") > fuzzy_score("sm", "asm"));
+        assert_eq!(fuzzy_score("MAIN", "main"), fuzzy_score("main", "main"));
+    }
+}
@@ -0,0 +1,1914 @@
+//! Running commands: compound commands, simple commands, pipelines
+//! and redirections.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io::{self, Write};
+use nix::errno::Errno;
+use nix::unistd::{fork, ForkResult, execv, dup2, close};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::wait::WaitStatus;
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use anyhow::Result;
+use crate::{Shell, Status};
+use crate::ast::{Command, Launch, Redirect};
+use crate::builtins::{BUILTINS, CondExpr, RESTRICTED_VARIABLES, edit_distance, interpret_escapes};
+use crate::expand::{fnmatch, join_path};
+use crate::jobs::{INTERRUPTED, PENDING_TRAPS, detach_from_terminal, enter_job_process, take_interrupt};
+use crate::lexer::{assignment, quote_for_display};
+use crate::parser::parse;
+
+impl Shell {
+    /// Executes a sequence of commands, stopping early on `exit` or `return`.
+    /// Ctrl-C skips the rest of the sequence.
+    fn execute_list(&mut self, commands: &[Command]) -> Result<Status> {
+        for command in commands {
+            match self.execute(command)? {
+                Status::Continue => {}
+                status => return Ok(status),
+            }
+            match self.run_pending_traps() {
+                Status::Continue => {}
+                status => return Ok(status),
+            }
+            if INTERRUPTED.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+        Ok(Status::Continue)
+    }
+
+    /// Executes one parsed command.
+    pub(crate) fn execute(&mut self, command: &Command) -> Result<Status> {
+        match command {
+            Command::Simple { words, redirects } => self.run_simple_command(words, redirects),
+            Command::Pipeline { stages, launch, text } => {
+                match self.run_debug_trap(text) {
+                    Status::Continue => {}
+                    status => return Ok(status),
+                }
+                let started = self.audit.as_ref().map(|_| AuditStart::now());
+                // A lone simple command in the foreground runs in the shell if
+                // it is a builtin or function; anything else is forked.
+                let status = match &stages[..] {
+                    [Command::Simple { words, redirects }] if *launch == Launch::Foreground => self.run_simple_command(words, redirects),
+                    _ => {
+                        match self.execute_pipeline(stages, *launch, text) {
+                            Ok(status) => self.last_status = status,
+                            Err(e) => {
+                                eprintln!("Pipeline error: {}", e);
+                                self.last_status = 1;
+                            }
+                        }
+                        Ok(Status::Continue)
+                    }
+                };
+                if let (Some(audit), Some(started)) = (&self.audit, started) {
+                    let status = if status.is_ok() { self.last_status } else { 1 };
+                    audit.record(text, status, started);
+                }
+                match status? {
+                    Status::Continue => Ok(self.check_failure()),
+                    status => Ok(status),
+                }
+            }
+            Command::If { branches, otherwise, redirects } => {
+                let Some(saved) = self.redirect_shell(redirects)? else { return Ok(self.check_failure()) };
+                let status = self.execute_if(branches, otherwise.as_deref());
+                restore_fds(saved);
+                status
+            }
+            Command::Loop { until, condition, body, redirects } => {
+                let Some(saved) = self.redirect_shell(redirects)? else { return Ok(self.check_failure()) };
+                let status = self.execute_loop(*until, condition, body);
+                restore_fds(saved);
+                status
+            }
+            Command::Case { word, items, redirects } => {
+                let Some(saved) = self.redirect_shell(redirects)? else { return Ok(self.check_failure()) };
+                let status = self.execute_case(word, items);
+                restore_fds(saved);
+                status
+            }
+            Command::For { variable, words, body, redirects } => {
+                let Some(saved) = self.redirect_shell(redirects)? else { return Ok(self.check_failure()) };
+                let status = self.execute_for(variable, words.as_deref(), body);
+                restore_fds(saved);
+                status
+            }
+            Command::Cond(words) => {
+                match self.run_debug_trap(&format!("[[ {} ]]", words.join(" "))) {
+                    Status::Continue => {}
+                    status => return Ok(status),
+                }
+                let mut cond = CondExpr { shell: self, words, pos: 0 };
+                let result = cond.or().and_then(|value| match words.get(cond.pos) {
+                    Some(word) => Err(format!("unexpected `{}'", word)),
+                    None => Ok(value),
+                });
+                self.last_status = match result {
+                    Ok(true) => 0,
+                    Ok(false) => 1,
+                    Err(e) => {
+                        eprintln!("[[: {}", e);
+                        2
+                    }
+                };
+                Ok(self.check_failure())
+            }
+            Command::Group { body, redirects } => {
+                let Some(saved) = self.redirect_shell(redirects)? else { return Ok(self.check_failure()) };
+                let status = self.execute_list(body);
+                restore_fds(saved);
+                status
+            }
+            Command::Function { name, body } => {
+                self.functions.insert(name.to_string(), Rc::new(body.to_static()));
+                self.last_status = 0;
+                Ok(Status::Continue)
+            }
+            Command::And(first, second) | Command::Or(first, second) => {
+                match self.execute_condition(std::slice::from_ref(first))? {
+                    Status::Continue => {}
+                    status => return Ok(status),
+                }
+                if (self.last_status == 0) == matches!(command, Command::And(..)) {
+                    return self.execute(second);
+                }
+                Ok(Status::Continue)
+            }
+            Command::Not(command) => {
+                let status = self.execute_condition(std::slice::from_ref(command))?;
+                self.last_status = i32::from(self.last_status == 0);
+                Ok(status)
+            }
+            Command::Time { posix, command } => {
+                let started = std::time::Instant::now();
+                let (user, system) = cpu_times();
+                let status = match command {
+                    Some(command) => self.execute(command)?,
+                    None => {
+                        self.last_status = 0;
+                        Status::Continue
+                    }
+                };
+                let (user_after, system_after) = cpu_times();
+                let format = if *posix {
+                    "real %2R\nuser %2U\nsys %2S".to_string()
+                } else {
+                    let default = "\nreal\t%3lR\nuser\t%3lU\nsys\t%3lS";
+                    self.vars.get("TIMEFORMAT").cloned().unwrap_or_else(|| default.to_string())
+                };
+                if !format.is_empty() {
+                    // Backslash escapes work as with `echo -e`, so that
+                    // `$TIMEFORMAT` can span lines.
+                    let (format, _) = interpret_escapes(&format);
+                    eprintln!("{}", format_times(&format, started.elapsed(), user_after - user, system_after - system));
+                }
+                Ok(status)
+            }
+            Command::Coproc { name, command } => self.start_coproc(name, command),
+        }
+    }
+
+    /// Runs a list whose exit status is being tested, such as an `if`
+    /// condition, where a failure must not trigger `set -e`.
+    fn execute_condition(&mut self, commands: &[Command]) -> Result<Status> {
+        self.conditions += 1;
+        let status = self.execute_list(commands);
+        self.conditions -= 1;
+        status
+    }
+
+    /// After a simple command or `[[ ]]` that failed outside a condition,
+    /// run the `ERR` trap, then exit if `set -e` says so.
+    fn check_failure(&mut self) -> Status {
+        if self.last_status == 0 || self.conditions != 0 {
+            return Status::Continue;
+        }
+        if let Some(action) = self.traps.get("ERR").filter(|_| !self.running_trap).cloned() {
+            match self.run_trap(&action) {
+                Status::Continue => {}
+                status => return status,
+            }
+        }
+        if self.option("errexit") { Status::Exit } else { Status::Continue }
+    }
+
+    /// Runs the body of the first branch whose condition succeeds, or else
+    /// `otherwise`.
+    fn execute_if(&mut self, branches: &[(Vec<Command>, Vec<Command>)], otherwise: Option<&[Command]>) -> Result<Status> {
+        for (condition, body) in branches {
+            match self.execute_condition(condition)? {
+                Status::Continue => {}
+                status => return Ok(status),
+            }
+            if self.last_status == 0 {
+                return self.execute_list(body);
+            }
+        }
+        match otherwise {
+            Some(body) => self.execute_list(body),
+            None => {
+                self.last_status = 0;
+                Ok(Status::Continue)
+            }
+        }
+    }
+
+    /// Runs `body` while `condition` succeeds (or, for `until`, fails).
+    /// Ctrl-C stops the loop with status 130.
+    fn execute_loop(&mut self, until: bool, condition: &[Command], body: &[Command]) -> Result<Status> {
+        let mut status = 0;
+        take_interrupt();
+        loop {
+            match self.execute_condition(condition)? {
+                Status::Continue => {}
+                status => return Ok(status),
+            }
+            if take_interrupt() {
+                status = 130;
+                break;
+            }
+            if (self.last_status == 0) == until {
+                break;
+            }
+            match self.execute_list(body)? {
+                Status::Continue => {}
+                status => return Ok(status),
+            }
+            status = self.last_status;
+            if take_interrupt() {
+                status = 130;
+                break;
+            }
+        }
+        self.last_status = status;
+        Ok(Status::Continue)
+    }
+
+    /// Runs the body of the first `case` item with a pattern matching `word`.
+    fn execute_case(&mut self, word: &str, items: &[(Vec<String>, Vec<Command>)]) -> Result<Status> {
+        let value = self.expand_single(word)?;
+        for (patterns, body) in items {
+            for pattern in patterns {
+                if fnmatch(&self.expand_pattern(pattern)?, &value) {
+                    self.last_status = 0;
+                    return self.execute_list(body);
+                }
+            }
+        }
+        self.last_status = 0;
+        Ok(Status::Continue)
+    }
+
+    /// Runs `body` once for each field the expanded `words` produce, with
+    /// `variable` set to that field.
+    fn execute_for(&mut self, variable: &str, words: Option<&[Cow<str>]>, body: &[Command]) -> Result<Status> {
+        let values = match words {
+            Some(words) => self.expand_words(words)?,
+            None => self.positional.clone(),
+        };
+        let mut status = 0;
+        if !self.may_assign(variable) {
+            self.last_status = 1;
+            return Ok(Status::Continue);
+        }
+        take_interrupt();
+        for value in values {
+            self.vars.insert(variable.to_string(), value);
+            match self.execute_list(body)? {
+                Status::Continue => {}
+                status => return Ok(status),
+            }
+            status = self.last_status;
+            if take_interrupt() {
+                status = 130;
+                break;
+            }
+        }
+        self.last_status = status;
+        Ok(Status::Continue)
+    }
+
+    /// Runs a simple command in the foreground: builtins and functions in
+    /// the shell itself, with their redirections undone afterwards, and
+    /// external commands in a child it waits for.
+    fn run_simple_command(&mut self, words: &[Cow<str>], redirects: &[Redirect]) -> Result<Status> {
+        if let Some((source, names)) = self.alias_expansion(words) {
+            return self.run_alias(&source, names, redirects);
+        }
+        //variable assignments
+        if words.iter().all(|word| assignment(word).is_some()) {
+            for word in words {
+                let (name, value) = assignment(word).unwrap();
+                if !self.may_assign(name) {
+                    self.last_status = 1;
+                    return Ok(Status::Continue);
+                }
+                let value = self.expand_single(value)?;
+                self.trace_line(&format!("{}={}", name, quote_for_display(&value)))?;
+                self.vars.insert(name.to_string(), value);
+            }
+            self.last_status = 0;
+            return Ok(Status::Continue);
+        }
+        let argv = self.expand_words(words)?;
+        if argv.is_empty() {
+            self.last_status = 0;
+            return Ok(Status::Continue);
+        }
+        self.trace(&argv)?;
+        //builtin
+        if self.is_builtin(&argv[0]) {
+            let Some(saved) = self.redirect_shell(redirects)? else { return Ok(Status::Continue) };
+            let status = self.run_builtin(&argv);
+            // `exec` without a command keeps its redirections for good.
+            if argv[0] == "exec" {
+                discard_saved_fds(saved);
+            } else {
+                restore_fds(saved);
+            }
+            return Ok(status);
+        }
+        //function call
+        if let Some(body) = self.functions.get(&argv[0]).cloned() {
+            let Some(saved) = self.redirect_shell(redirects)? else { return Ok(Status::Continue) };
+            let status = self.call_function(&body, &argv[1..]);
+            restore_fds(saved);
+            return status;
+        }
+        //single command
+        let program = self.resolve_command(&argv[0]);
+        if self.restricted_command(&argv[0]) {
+            self.last_status = 1;
+            return Ok(Status::Continue);
+        }
+        // With `autocd`, a directory named on its own is gone to, unless a
+        // command of that name is found in `$PATH`.
+        if argv.len() == 1
+            && self.option("autocd")
+            && !self.option("restricted")
+            && std::path::Path::new(program.as_deref().unwrap_or(&argv[0])).is_dir()
+        {
+            eprintln!("cd -- {}", argv[0]);
+            self.last_status = self.changing_dir(|shell| shell.builtin_cd(&argv));
+            return Ok(Status::Continue);
+        }
+        match self.run_command(program.as_deref(), &argv, redirects) {
+            Ok(status) => self.last_status = status,
+            Err(e) => {
+                eprintln!("vssh: {}: {}", argv[0], e);
+                self.last_status = 1;
+            }
+        }
+        Ok(Status::Continue)
+    }
+
+    /// The text of the command `words` if its command word, the first that
+    /// is not an assignment, is an alias not already being expanded: the
+    /// words with the alias replaced by the text it stands for. When that
+    /// text ends with a blank the next word is looked up too, as in bash.
+    /// Returns the aliases replaced along with the text.
+    fn alias_expansion(&self, words: &[Cow<str>]) -> Option<(String, Vec<String>)> {
+        if self.aliases.is_empty() {
+            return None;
+        }
+        let mut index = words.iter().position(|word| assignment(word).is_none())?;
+        let mut parts: Vec<&str> = words[..index].iter().map(|word| word.as_ref()).collect();
+        let mut names: Vec<String> = Vec::new();
+        while let Some(word) = words.get(index) {
+            let expanding = self.expanding_aliases.iter().chain(&names).any(|name| name == word);
+            let Some(value) = self.aliases.get(word.as_ref()).filter(|_| !expanding) else { break };
+            parts.push(value);
+            names.push(word.to_string());
+            index += 1;
+            if !value.ends_with([' ', '\t']) {
+                break;
+            }
+        }
+        if names.is_empty() {
+            return None;
+        }
+        parts.extend(words[index..].iter().map(|word| word.as_ref()));
+        Some((parts.join(" "), names))
+    }
+
+    /// Run `source`, a command whose aliases `names` have been expanded,
+    /// with `redirects`.
+    fn run_alias(&mut self, source: &str, names: Vec<String>, redirects: &[Redirect]) -> Result<Status> {
+        let commands = match parse(source) {
+            Ok(commands) => commands,
+            Err(e) => {
+                eprintln!("vssh: {}: syntax error: {}", names[0], e);
+                self.last_status = 2;
+                return Ok(Status::Continue);
+            }
+        };
+        let Some(saved) = self.redirect_shell(redirects)? else { return Ok(Status::Continue) };
+        let depth = self.expanding_aliases.len();
+        self.expanding_aliases.extend(names);
+        let status = self.execute_list(&commands);
+        self.expanding_aliases.truncate(depth);
+        restore_fds(saved);
+        status
+    }
+
+    /// Why variable `name` may not be changed, if it may not: it is one of
+    /// the `RESTRICTED_VARIABLES` in a restricted shell, or `VSSH_POLICY`
+    /// while a policy is in force.
+    pub(crate) fn protection(&self, name: &str) -> Option<&'static str> {
+        if self.option("restricted") && RESTRICTED_VARIABLES.contains(&name) {
+            Some("restricted")
+        } else if self.policy.is_some() && name == "VSSH_POLICY" {
+            Some("readonly variable")
+        } else {
+            None
+        }
+    }
+
+    /// Whether variable `name` may be changed, saying why not if not.
+    pub(crate) fn may_assign(&self, name: &str) -> bool {
+        match self.protection(name) {
+            Some(reason) => {
+                eprintln!("vssh: {}: {}", name, reason);
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Load the system-wide policy, `/etc/vssh/policy`, if there is one,
+    /// and then the one named with `--policy` or `$VSSH_POLICY`. Their
+    /// rules add up. The policy file's name is put in the environment so
+    /// that shells started from this one load it too.
+    pub(crate) fn load_policies(&mut self, policy_file: Option<String>) -> io::Result<()> {
+        let system = "/etc/vssh/policy";
+        let mut files: Vec<String> = Vec::new();
+        if std::path::Path::new(system).exists() {
+            files.push(system.to_string());
+        }
+        files.extend(policy_file.filter(|path| path != system));
+        for file in &files {
+            let policy = Policy::load(file).map_err(|e| io::Error::new(e.kind(), format!("policy {}: {}", file, e)))?;
+            self.policy.get_or_insert_with(Policy::default).rules.extend(policy.rules);
+        }
+        if let Some(file) = files.last() {
+            unsafe { std::env::set_var("VSSH_POLICY", file) };
+            self.vars.insert("VSSH_POLICY".to_string(), file.clone());
+        }
+        Ok(())
+    }
+
+    /// Whether the policy, if any, lets the external command `name`, found
+    /// at `program`, run. Reports a refusal.
+    pub(crate) fn policy_permits(&self, name: &str, program: Option<&str>) -> bool {
+        let (Some(policy), Some(program)) = (&self.policy, program) else { return true };
+        if policy.permits(name, program) {
+            return true;
+        }
+        eprintln!("vssh: {}: denied by policy", name);
+        false
+    }
+
+    /// In a restricted shell, refuse (with a message) to run a command
+    /// named by a path.
+    fn restricted_command(&self, name: &str) -> bool {
+        if self.option("restricted") && name.contains('/') {
+            eprintln!("vssh: {}: restricted: cannot specify `/' in command names", name);
+            return true;
+        }
+        false
+    }
+
+    /// In a restricted shell, refuse (with a message) to make expanded
+    /// redirections that write to a file.
+    fn restricted_redirect(&self, redirects: &[Redirect]) -> bool {
+        match redirects.iter().find(|redirect| redirect.writes_file()) {
+            Some(redirect) if self.option("restricted") => {
+                eprintln!("vssh: {}: restricted: cannot redirect output", redirect.target());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Expand the targets of redirections, ready for `apply_redirect`. A
+    /// `>` becomes `>|` unless `noclobber` is on.
+    fn expand_redirects(&mut self, redirects: &[Redirect]) -> Result<Vec<Redirect<'static>>> {
+        let mut expanded = Vec::new();
+        for redirect in redirects {
+            let target = self.expand_single(redirect.target())?;
+            expanded.push(match redirect {
+                Redirect::Output(fd, _) if !self.option("noclobber") => Redirect::Clobber(*fd, target.into()),
+                _ => redirect.with_target(target),
+            });
+        }
+        Ok(expanded)
+    }
+
+    /// With `set -x`, print an expanded command to standard error, after
+    /// the expansion of `$PS4`.
+    fn trace(&mut self, argv: &[String]) -> Result<()> {
+        let words: Vec<String> = argv.iter().map(|word| quote_for_display(word)).collect();
+        self.trace_line(&words.join(" "))
+    }
+
+    /// Worker for `trace`, printing an already quoted line.
+    fn trace_line(&mut self, line: &str) -> Result<()> {
+        if !self.option("xtrace") {
+            return Ok(());
+        }
+        let ps4 = self.vars.get("PS4").cloned().unwrap_or_else(|| "+ ".to_string());
+        // Commands run to expand `$PS4` would be traced in turn, forever.
+        self.set_option("xtrace", false);
+        let prefix = self.expand_single(&ps4);
+        self.set_option("xtrace", true);
+        eprintln!("{}{}", prefix?, line);
+        Ok(())
+    }
+
+    /// Runs a function body with the positional parameters set to `args`,
+    /// restoring any variables it declared `local` afterwards.
+    fn call_function(&mut self, body: &Command, args: &[String]) -> Result<Status> {
+        let saved = std::mem::replace(&mut self.positional, args.to_vec());
+        self.local_frames.push(HashMap::new());
+        let status = match self.execute(body) {
+            Ok(Status::Return) => Ok(Status::Continue),
+            status => status,
+        };
+        for (name, value) in self.local_frames.pop().unwrap_or_default() {
+            match value {
+                Some(value) => self.vars.insert(name, value),
+                None => self.vars.remove(&name),
+            };
+        }
+        self.positional = saved;
+        status
+    }
+
+    /// Run an external command in the foreground with I/O redirection and
+    /// return its exit status. Without job control it is started with
+    /// `posix_spawn` when its redirections allow; otherwise it is forked.
+    fn run_command(&mut self, program: Option<&str>, argv: &[String], redirects: &[Redirect]) -> Result<i32> {
+        if !self.policy_permits(&argv[0], program) {
+            return Ok(126);
+        }
+        let redirects = self.expand_redirects(redirects)?;
+        if self.restricted_redirect(&redirects) {
+            return Ok(1);
+        }
+        // Without job control there is no process group or terminal to set
+        // up in the child, so it can be spawned without copying the shell.
+        if let Some(program) = program
+            && !self.job_control
+            && redirects.iter().all(spawnable)
+        {
+            return Ok(match spawn_command(program, argv, &redirects) {
+                Ok(child) => self.wait_foreground(argv.join(" "), vec![child]),
+                Err(status) => status,
+            });
+        }
+
+        match unsafe { fork()? } {
+            ForkResult::Child => {
+                if self.job_control {
+                    enter_job_process(None, true);
+                }
+                if let Err(e) = redirects.iter().try_for_each(apply_redirect) {
+                    eprintln!("vssh: {}", e);
+                    std::process::exit(1);
+                }
+                self.exec_external(program, argv);
+            },
+            ForkResult::Parent { child } => {
+                if self.job_control {
+                    let _ = nix::unistd::setpgid(child, child);
+                }
+                Ok(self.wait_foreground(argv.join(" "), vec![child]))
+            }
+        }
+    }
+
+    /// Exec `argv` in a forked child, running it in-process instead if it
+    /// names a builtin or shell function. Never returns.
+    pub(crate) fn exec_in_child(&mut self, program: Option<&str>, argv: &[String]) -> ! {
+        if argv.first().is_some_and(|name| self.restricted_command(name)) {
+            std::process::exit(1);
+        }
+        if argv.first().is_some_and(|name| self.is_builtin(name)) {
+            self.run_builtin(argv);
+            let _ = io::stdout().flush();
+            std::process::exit(self.last_status);
+        }
+        if let Some(body) = argv.first().and_then(|name| self.functions.get(name)).cloned() {
+            if let Err(e) = self.call_function(&body, &argv[1..]) {
+                eprintln!("Error: {}", e);
+                self.last_status = 1;
+            }
+            let _ = io::stdout().flush();
+            std::process::exit(self.last_status);
+        }
+        if !argv.is_empty() && !self.policy_permits(&argv[0], program) {
+            std::process::exit(126);
+        }
+        self.exec_external(program, argv);
+    }
+
+    /// Exec the external command `argv`, found at `program`, in this child.
+    /// A command that was not found is passed to the function
+    /// `command_not_found_handle`, if there is one, whose status is then
+    /// the command's; otherwise it is reported along with similar names.
+    fn exec_external(&mut self, program: Option<&str>, argv: &[String]) -> ! {
+        if program.is_none() && !argv.is_empty() {
+            if let Some(body) = self.functions.get("command_not_found_handle").cloned()
+                && !self.handling_not_found
+            {
+                self.handling_not_found = true;
+                if let Err(e) = self.call_function(&body, argv) {
+                    eprintln!("Error: {}", e);
+                    self.last_status = 1;
+                }
+                let _ = io::stdout().flush();
+                std::process::exit(self.last_status);
+            }
+            eprintln!("vssh: {}: command not found", argv[0]);
+            let similar = self.similar_commands(&argv[0]);
+            if !similar.is_empty() {
+                eprintln!("vssh: similar commands: {}", similar.join(", "));
+            }
+            std::process::exit(127);
+        }
+        exec_command(program, argv);
+    }
+
+    /// Builtins, functions, aliases and commands in `$PATH` whose names are
+    /// at most two edits from `name`, closest first.
+    fn similar_commands(&mut self, name: &str) -> Vec<String> {
+        let path = self.vars.get("PATH").cloned().unwrap_or_default();
+        let mut names: Vec<String> = BUILTINS.iter().map(|name| name.to_string()).collect();
+        names.extend(self.functions.keys().cloned());
+        names.extend(self.aliases.keys().cloned());
+        names.extend(self.path_index.names(&path).filter(|file| file.len().abs_diff(name.len()) <= 2).map(str::to_string));
+        let mut similar: Vec<(usize, String)> = names
+            .into_iter()
+            .map(|candidate| (edit_distance(name, &candidate), candidate))
+            .filter(|(distance, _)| *distance <= 2 && *distance < name.chars().count())
+            .collect();
+        similar.sort();
+        similar.dedup();
+        similar.into_iter().take(5).map(|(_, name)| name).collect()
+    }
+
+    /// Find the file that would run for an external command `name`,
+    /// consulting and filling the hash table, and the index of `$PATH`
+    /// behind it. Names with a slash are
+    /// used as they are.
+    pub(crate) fn resolve_command(&mut self, name: &str) -> Option<String> {
+        if name.contains('/') {
+            return Some(name.to_string());
+        }
+        let path = self.vars.get("PATH").cloned().unwrap_or_default();
+        if path != self.hashed_path {
+            self.hashed.clear();
+            self.hashed_path = path;
+        }
+        if let Some((file, hits)) = self.hashed.get_mut(name)
+            && is_executable(file)
+        {
+            *hits += 1;
+            return Some(file.clone());
+        }
+        let file = self.path_index.find(&self.hashed_path, name)?;
+        self.hashed.insert(name.to_string(), (file.clone(), 1));
+        Some(file)
+    }
+
+    /// Run each stage of a pipeline in a child process of its own and
+    /// return the exit status of the last one (or, with `pipefail`, of the
+    /// last one to fail), or 0 if it was started in the background. This is
+    /// also how a lone command is started in the background, even a builtin.
+    /// A simple command is expanded by the shell before forking; a compound
+    /// command runs in its child as in a subshell.
+    fn execute_pipeline(&mut self, stages: &[Command], launch: Launch, text: &str) -> Result<i32> {
+        let num_commands = stages.len();
+        let mut child_process_ids = Vec::new();
+        let mut pipe_ends = Vec::new();
+
+        for _ in 0..(num_commands - 1) {
+            pipe_ends.push(cloexec_pipe()?);
+        }
+        for (i, stage) in stages.iter().enumerate() {
+            let simple = match stage {
+                // Assignments alone are left to the child, as in a subshell.
+                // An alias is expanded in the child, like a compound command.
+                Command::Simple { words, redirects }
+                    if !words.iter().all(|word| assignment(word).is_some()) && self.alias_expansion(words).is_none() =>
+                {
+                    let argv = self.expand_words(words)?;
+                    self.trace(&argv)?;
+                    let program = match argv.first() {
+                        Some(name) if !self.is_builtin(name) && !self.functions.contains_key(name) => self.resolve_command(name),
+                        _ => None,
+                    };
+                    Some((program, argv, self.expand_redirects(redirects)?))
+                }
+                _ => None,
+            };
+            match unsafe { fork()? } {
+                ForkResult::Child => {
+                    if self.job_control {
+                        enter_job_process(child_process_ids.first().copied(), launch == Launch::Foreground);
+                    }
+                    if launch == Launch::Detached {
+                        detach_from_terminal();
+                    }
+                    // If not first command, the input is previous pipe’s read end
+                    if i > 0 {
+                        let (ref prev_read, _) = pipe_ends[i - 1];
+                        let _ = dup2(prev_read.as_raw_fd(), 0);
+                    }
+                    // If not the last command, the output is current pipe’s write end.
+                    if i < num_commands - 1 {
+                        let (_, ref next_write) = pipe_ends[i];
+                        let _ = dup2(next_write.as_raw_fd(), 1);
+                    }
+                    // A subshell keeps running here, so its copies of the
+                    // pipes have to go before it does.
+                    drop(std::mem::take(&mut pipe_ends));
+                    let Some((program, argv, redirects)) = simple else {
+                        self.exit_subshell(stage);
+                    };
+                    if self.restricted_redirect(&redirects) {
+                        std::process::exit(1);
+                    }
+                    // The command's own redirections come after the pipes,
+                    // so that `2>&1 |` sends errors down the pipe too.
+                    if let Err(e) = redirects.iter().try_for_each(apply_redirect) {
+                        eprintln!("vssh: {}", e);
+                        std::process::exit(1);
+                    }
+                    self.exec_in_child(program.as_deref(), &argv);
+                },
+                ForkResult::Parent { child } => {
+                    if self.job_control {
+                        let _ = nix::unistd::setpgid(child, *child_process_ids.first().unwrap_or(&child));
+                    }
+                    child_process_ids.push(child);
+                }
+            }
+        }
+        drop(pipe_ends);
+        Ok(self.launch_job(text.to_string(), child_process_ids, launch))
+    }
+
+    /// In a child forked to run `command` on its own, as part of a pipeline
+    /// or a coprocess: run it and exit with its status. Commands it starts
+    /// stay in the child's process group.
+    fn exit_subshell(&mut self, command: &Command) -> ! {
+        self.job_control = false;
+        let status = match self.execute(command) {
+            Ok(_) => self.last_status,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                1
+            }
+        };
+        let _ = io::stdout().flush();
+        std::process::exit(status);
+    }
+
+    /// Apply redirections to the shell's own descriptors, returning the saved
+    /// originals so that `restore_fds` can put them back afterwards. A
+    /// redirection that cannot be made is reported, as a failed command
+    /// would be, and `None` returned with the status set to 1.
+    pub(crate) fn redirect_shell(&mut self, redirects: &[Redirect]) -> Result<Option<Vec<(RawFd, RawFd)>>> {
+        let mut saved = Vec::new();
+        io::stdout().flush()?;
+        for redirect in redirects {
+            let expanded = match self.expand_redirects(std::slice::from_ref(redirect)) {
+                Ok(expanded) => expanded,
+                Err(e) => {
+                    restore_fds(saved);
+                    return Err(e);
+                }
+            };
+            if self.restricted_redirect(&expanded) {
+                restore_fds(saved);
+                self.last_status = 1;
+                return Ok(None);
+            }
+            // The copy goes above the descriptors scripts use, and is not
+            // inherited; a descriptor that was closed is saved as -1.
+            let copy = nix::fcntl::fcntl(redirect.fd(), nix::fcntl::FcntlArg::F_DUPFD_CLOEXEC(10)).unwrap_or(-1);
+            saved.push((redirect.fd(), copy));
+            if let Err(e) = apply_redirect(&expanded[0]) {
+                restore_fds(saved);
+                eprintln!("vssh: {}", e);
+                self.last_status = 1;
+                return Ok(None);
+            }
+        }
+        Ok(Some(saved))
+    }
+}
+
+/// Which external commands may run, from policy files for kiosks and
+/// sandboxes. Each line of a policy file is `allow pattern` or `deny
+/// pattern`, or a `#` comment. A pattern with a slash is matched against
+/// the path a command was found at, any other against its name. If any
+/// `allow` rule exists, only what one allows may run; `deny` rules win
+/// over `allow` ones. Builtins and functions are not affected. A policy
+/// cannot be turned off from inside the shell, so commands such as `env`
+/// or other shells that could get around it should not be allowed.
+#[derive(Default)]
+pub(crate) struct Policy {
+    /// (whether it allows, pattern), in file order.
+    rules: Vec<(bool, String)>,
+}
+
+impl Policy {
+    fn load(path: &str) -> io::Result<Policy> {
+        let mut policy = Policy::default();
+        for (number, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = match line.split_once(char::is_whitespace) {
+                Some(("allow", pattern)) => (true, pattern.trim().to_string()),
+                Some(("deny", pattern)) => (false, pattern.trim().to_string()),
+                _ => {
+                    let message = format!("line {}: expected `allow pattern' or `deny pattern'", number + 1);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+                }
+            };
+            policy.rules.push(rule);
+        }
+        Ok(policy)
+    }
+
+    /// Whether the command `name`, found at `path`, may run.
+    fn permits(&self, name: &str, path: &str) -> bool {
+        let base = name.rsplit('/').next().unwrap_or(name);
+        let matches = |pattern: &str| if pattern.contains('/') { fnmatch(pattern, path) } else { fnmatch(pattern, base) };
+        if self.rules.iter().any(|(allow, pattern)| !allow && matches(pattern)) {
+            return false;
+        }
+        let mut allows = self.rules.iter().filter(|(allow, _)| *allow).peekable();
+        allows.peek().is_none() || allows.any(|(_, pattern)| matches(pattern))
+    }
+}
+
+/// The audit log, for shared servers that must keep a record of what was
+/// run: one line per pipeline, as a JSON object with its start time,
+/// user, process, directory, text, exit status and duration. It is kept
+/// apart from the history, which the user can edit. `/etc/vssh/audit`,
+/// when it exists, names the log for every shell and takes precedence over
+/// `--audit`; either names a file to append to, or is `syslog`.
+pub(crate) struct AuditLog {
+    /// The log file, or `None` to log through syslog.
+    file: Option<std::fs::File>,
+}
+
+impl AuditLog {
+    pub(crate) fn open(requested: Option<String>) -> io::Result<Option<AuditLog>> {
+        let system = match std::fs::read_to_string("/etc/vssh/audit") {
+            Ok(config) => {
+                config.lines().map(str::trim).find(|line| !line.is_empty() && !line.starts_with('#')).map(str::to_string)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(io::Error::new(e.kind(), format!("/etc/vssh/audit: {}", e))),
+        };
+        let Some(target) = system.or(requested) else { return Ok(None) };
+        if target == "syslog" {
+            unsafe { libc::openlog(c"vssh".as_ptr(), libc::LOG_PID, libc::LOG_AUTHPRIV) };
+            return Ok(Some(AuditLog { file: None }));
+        }
+        let file = std::fs::OpenOptions::new().append(true).create(true).open(&target);
+        let file = file.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", target, e)))?;
+        Ok(Some(AuditLog { file: Some(file) }))
+    }
+
+    fn record(&self, command: &str, status: i32, started: AuditStart) {
+        let time = started.time.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        let entry = format!(
+            "{{\"time\":{:.3},\"uid\":{},\"pid\":{},\"cwd\":{},\"command\":{},\"status\":{},\"duration\":{:.3}}}",
+            time,
+            nix::unistd::getuid(),
+            std::process::id(),
+            json_string(&started.cwd),
+            json_string(command),
+            status,
+            started.clock.elapsed().as_secs_f64()
+        );
+        match &self.file {
+            Some(file) => {
+                if let Err(e) = writeln!(&*file, "{}", entry) {
+                    eprintln!("vssh: audit: {}", e);
+                }
+            }
+            None => {
+                if let Ok(entry) = CString::new(entry) {
+                    unsafe { libc::syslog(libc::LOG_INFO, c"%s".as_ptr(), entry.as_ptr()) };
+                }
+            }
+        }
+    }
+}
+
+/// When and where a command that is being audited started.
+struct AuditStart {
+    time: std::time::SystemTime,
+    clock: std::time::Instant,
+    cwd: String,
+}
+
+impl AuditStart {
+    fn now() -> AuditStart {
+        AuditStart {
+            time: std::time::SystemTime::now(),
+            clock: std::time::Instant::now(),
+            cwd: std::env::current_dir().map(|dir| dir.display().to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl Shell {
+    /// Run the traps of the signals that have arrived since the last call,
+    /// stopping if one of them runs `exit` or `return`.
+    pub(crate) fn run_pending_traps(&mut self) -> Status {
+        let pending = PENDING_TRAPS.swap(0, Ordering::SeqCst);
+        for signal in Signal::iterator().filter(|&signal| pending & (1 << signal as i32) != 0) {
+            if let Some(action) = self.traps.get(&signal.as_str()[3..]).cloned() {
+                match self.run_trap(&action) {
+                    Status::Continue => {}
+                    status => return status,
+                }
+            }
+        }
+        Status::Continue
+    }
+
+    /// Run the `EXIT` trap, if any, as the shell exits with `status`.
+    /// Returns the status to exit with, which the trap can change with
+    /// `exit`.
+    pub(crate) fn run_exit_trap(&mut self, status: i32) -> i32 {
+        let Some(action) = self.traps.remove("EXIT") else { return status };
+        self.last_status = status;
+        match self.run_trap(&action) {
+            Status::Exit => self.last_status,
+            _ => status,
+        }
+    }
+
+    /// Run the `DEBUG` trap, if any, before `command`, which it can see as
+    /// `$BASH_COMMAND`.
+    fn run_debug_trap(&mut self, command: &str) -> Status {
+        if self.running_trap {
+            return Status::Continue;
+        }
+        self.current_command = command.to_string();
+        match self.traps.get("DEBUG").cloned() {
+            Some(action) => self.run_trap(&action),
+            None => Status::Continue,
+        }
+    }
+
+    /// Run a trap's action. `$?` is left as it was unless the action exits.
+    fn run_trap(&mut self, action: &str) -> Status {
+        let status = self.last_status;
+        let running = std::mem::replace(&mut self.running_trap, true);
+        let mut lines = action.lines();
+        let result = self.run(|_, _| lines.next().map(str::to_string));
+        self.running_trap = running;
+        if !matches!(result, Status::Exit) {
+            self.last_status = status;
+        }
+        result
+    }
+
+    /// Run the hook function `name` with `args` if it is defined, leaving
+    /// `$?` as it was: `precmd` before each prompt, `preexec` with each
+    /// command line read at the prompt before it runs, and `chpwd` after
+    /// each change of directory. Plugin hooks for `name` run first.
+    pub(crate) fn run_hook(&mut self, name: &'static str, args: &[String]) -> Status {
+        if self.running_hooks.contains(&name) {
+            return Status::Continue;
+        }
+        self.plugins.run_hooks(name, args);
+        let Some(body) = self.functions.get(name).cloned() else { return Status::Continue };
+        let status = self.last_status;
+        self.running_hooks.push(name);
+        let result = self.call_function(&body, args);
+        self.running_hooks.pop();
+        match result {
+            Ok(Status::Exit) => return Status::Exit,
+            Ok(_) => {}
+            Err(e) => match e.downcast_ref::<FatalError>() {
+                Some(fatal) => eprintln!("{}", fatal),
+                None => eprintln!("Error: {}", e),
+            },
+        }
+        self.last_status = status;
+        Status::Continue
+    }
+
+    /// Start `command` as a background job reading from and writing to
+    /// pipes: the shell writes to it on `${name[1]}` and reads from it on
+    /// `${name[0]}`, and its process ID is `$name_PID`. Starting another
+    /// coprocess of the same name closes the old one's descriptors.
+    fn start_coproc(&mut self, name: &str, command: &Command) -> Result<Status> {
+        let (child_input, to_child) = cloexec_pipe()?;
+        let (from_child, child_output) = cloexec_pipe()?;
+        io::stdout().flush()?;
+        match unsafe { fork()? } {
+            ForkResult::Child => {
+                if self.job_control {
+                    enter_job_process(None, false);
+                }
+                let _ = dup2(child_input.as_raw_fd(), 0);
+                let _ = dup2(child_output.as_raw_fd(), 1);
+                drop((child_input, to_child, from_child, child_output));
+                self.exit_subshell(command);
+            }
+            ForkResult::Parent { child } => {
+                if self.job_control {
+                    let _ = nix::unistd::setpgid(child, child);
+                }
+                drop((child_input, child_output));
+                // Kept above the descriptors scripts use themselves, and
+                // out of the commands the shell runs.
+                let mut ends = Vec::new();
+                for fd in [from_child, to_child] {
+                    let end = nix::fcntl::fcntl(fd.as_raw_fd(), nix::fcntl::FcntlArg::F_DUPFD_CLOEXEC(60));
+                    ends.push(end?.to_string());
+                }
+                if let Some(old) = self.arrays.insert(name.to_string(), ends) {
+                    for fd in old.iter().filter_map(|fd| fd.parse().ok()) {
+                        let _ = close(fd);
+                    }
+                }
+                self.vars.insert(format!("{}_PID", name), child.to_string());
+                self.last_status = self.launch_job(format!("coproc {}", name), vec![child], Launch::Background);
+                Ok(Status::Continue)
+            }
+        }
+    }
+}
+
+/// An error that stops a non-interactive shell, such as expanding an unset
+/// variable under `set -u`. It carries the complete message.
+#[derive(Debug)]
+pub(crate) struct FatalError(pub(crate) String);
+
+impl std::fmt::Display for FatalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for FatalError {}
+
+/// Make an expanded redirection (see `Shell::expand_redirects`) of this
+/// process's descriptors.
+pub(crate) fn apply_redirect(redirect: &Redirect) -> Result<()> {
+    let fd = redirect.fd();
+    if let Some(socket) = open_socket(redirect.target()) {
+        let socket = socket.map_err(|e| anyhow::anyhow!("{}: {}", redirect.target(), e))?;
+        return Ok(install_fd(socket, fd)?);
+    }
+    let file = match redirect {
+        Redirect::Duplicate(_, target) if target == "-" => {
+            let _ = close(fd);
+            return Ok(());
+        }
+        Redirect::Duplicate(_, target) => {
+            let source = target.parse::<RawFd>().map_err(|_| anyhow::anyhow!("{}: ambiguous redirect", target))?;
+            nix::fcntl::fcntl(source, nix::fcntl::FcntlArg::F_GETFD).map_err(|_| anyhow::anyhow!("{}: bad file descriptor", source))?;
+            if source != fd {
+                dup2(source, fd)?;
+            }
+            return Ok(());
+        }
+        _ => open_redirect(redirect)?,
+    };
+    Ok(install_fd(file, fd)?)
+}
+
+/// Make `file`, which is close-on-exec like every descriptor the shell
+/// opens, descriptor `fd`, which is not: the one meant for commands.
+pub(crate) fn install_fd(file: OwnedFd, fd: RawFd) -> nix::Result<()> {
+    if file.as_raw_fd() == fd {
+        nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::empty()))?;
+        let _ = file.into_raw_fd();
+        return Ok(());
+    }
+    dup2(file.as_raw_fd(), fd)?;
+    Ok(())
+}
+
+/// A pipe, both of whose ends are closed on exec; `install_fd` or `dup2`
+/// gives a command the end it is meant to have.
+pub(crate) fn cloexec_pipe() -> nix::Result<(OwnedFd, OwnedFd)> {
+    let (read, write) = nix::unistd::pipe2(OFlag::O_CLOEXEC)?;
+    Ok(unsafe { (OwnedFd::from_raw_fd(read), OwnedFd::from_raw_fd(write)) })
+}
+
+/// Open, close-on-exec, the file named by a redirection other than a
+/// duplication.
+pub(crate) fn open_redirect(redirect: &Redirect) -> Result<OwnedFd> {
+    let (path, flags) = match redirect {
+        Redirect::Input(_, path) => (path, OFlag::O_RDONLY),
+        Redirect::ReadWrite(_, path) => (path, OFlag::O_RDWR | OFlag::O_CREAT),
+        // Devices such as /dev/null can still be written to.
+        Redirect::Output(_, path) if !std::fs::metadata(path.as_ref()).is_ok_and(|metadata| !metadata.is_file()) => {
+            (path, OFlag::O_CREAT | OFlag::O_WRONLY | OFlag::O_TRUNC | OFlag::O_EXCL)
+        }
+        Redirect::Output(_, path) | Redirect::Clobber(_, path) => (path, OFlag::O_CREAT | OFlag::O_WRONLY | OFlag::O_TRUNC),
+        Redirect::Duplicate(..) => unreachable!("a duplication opens no file"),
+    };
+    let file = open(path.as_ref(), flags | OFlag::O_CLOEXEC, Mode::from_bits(0o644).unwrap()).map_err(|e| {
+        if e == Errno::EEXIST && flags.contains(OFlag::O_EXCL) {
+            return anyhow::anyhow!("{}: cannot overwrite existing file", path);
+        }
+        let kind = if matches!(redirect, Redirect::Input(..)) { "input" } else { "output" };
+        anyhow::anyhow!("Error opening {} file {}: {}", kind, path, e)
+    })?;
+    Ok(unsafe { OwnedFd::from_raw_fd(file) })
+}
+
+/// For a redirection to `/dev/tcp/host/port` or `/dev/udp/host/port`, as
+/// in bash, a socket connected to that port, unless there is such a file.
+/// `None` for any other path.
+fn open_socket(path: &str) -> Option<io::Result<OwnedFd>> {
+    let (udp, address) = match path.strip_prefix("/dev/tcp/") {
+        Some(address) => (false, address),
+        None => (true, path.strip_prefix("/dev/udp/")?),
+    };
+    if std::path::Path::new(path).exists() {
+        return None;
+    }
+    let (host, port) = address.rsplit_once('/')?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid port");
+    let connect = || -> io::Result<OwnedFd> {
+        let port = port.parse::<u16>().map_err(|_| invalid())?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addresses: Vec<_> = std::net::ToSocketAddrs::to_socket_addrs(&(host, port))?.collect();
+        if !udp {
+            return Ok(std::net::TcpStream::connect(&addresses[..])?.into());
+        }
+        let mut error = io::Error::new(io::ErrorKind::NotFound, "no address");
+        for address in addresses {
+            let local = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+            match std::net::UdpSocket::bind(local).and_then(|socket| socket.connect(address).map(|_| socket)) {
+                Ok(socket) => return Ok(socket.into()),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    };
+    Some(connect())
+}
+
+/// Undo `redirect_shell`, most recent redirection first.
+pub(crate) fn restore_fds(saved: Vec<(RawFd, RawFd)>) {
+    let _ = io::stdout().flush();
+    for (fd, copy) in saved.into_iter().rev() {
+        if copy < 0 {
+            let _ = close(fd);
+            continue;
+        }
+        let _ = dup2(copy, fd);
+        let _ = close(copy);
+    }
+}
+
+/// Keep the redirections made by `redirect_shell`, closing the saved copies.
+fn discard_saved_fds(saved: Vec<(RawFd, RawFd)>) {
+    let _ = io::stdout().flush();
+    for (_, copy) in saved.into_iter().filter(|(_, copy)| *copy >= 0) {
+        let _ = close(copy);
+    }
+}
+
+/// Exec `program`, the file found for `argv[0]`, in the current (child)
+/// process. Never returns: if there is no such file or the exec fails the
+/// child exits with 127 (not found) or 126 (not executable).
+pub(crate) fn exec_command(program: Option<&str>, argv: &[String]) -> ! {
+    let command_execute = externalize(argv).unwrap_or_else(|e| {
+        eprintln!("vssh: {}", e);
+        std::process::exit(1);
+    });
+    if command_execute.is_empty() {
+        std::process::exit(1);
+    }
+    let name = command_execute[0].to_string_lossy();
+    let Some(program) = program else {
+        eprintln!("vssh: {}: command not found", name);
+        std::process::exit(127);
+    };
+    // Rust ignores SIGPIPE; commands expect it to kill them.
+    let default = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+    let _ = unsafe { sigaction(Signal::SIGPIPE, &default) };
+    let Ok(program) = CString::new(program) else {
+        eprintln!("vssh: {}: command not found", name);
+        std::process::exit(127);
+    };
+    let Err(e) = execv(&program, &command_execute);
+    match e {
+        Errno::ENOENT => {
+            eprintln!("vssh: {}: command not found", name);
+            std::process::exit(127);
+        }
+        _ => {
+            eprintln!("vssh: {}: {}", name, e.desc());
+            std::process::exit(126);
+        }
+    }
+}
+
+/// Whether `spawn_command` can make `redirect`, expanded, with a spawn
+/// file action: a file other than a `/dev/tcp` or `/dev/udp` socket, or a
+/// copy or close of one of the standard descriptors.
+fn spawnable(redirect: &Redirect) -> bool {
+    match redirect {
+        Redirect::Duplicate(_, target) => target == "-" || matches!(target.as_ref(), "0" | "1" | "2"),
+        _ => !redirect.target().starts_with("/dev/tcp/") && !redirect.target().starts_with("/dev/udp/"),
+    }
+}
+
+/// Start `program` with `posix_spawn` rather than fork and exec, with
+/// `redirects` (all `spawnable`) made in the child. Their files are opened
+/// here, close-on-exec, so that errors read as they would from a forked
+/// child. On failure the error is reported and the status a forked child
+/// would have exited with is returned.
+fn spawn_command(program: &str, argv: &[String], redirects: &[Redirect]) -> std::result::Result<nix::unistd::Pid, i32> {
+    unsafe extern "C" {
+        static environ: *const *mut libc::c_char;
+    }
+    let (program, args) = match (CString::new(program), externalize(argv)) {
+        (Ok(program), Ok(args)) => (program, args),
+        (Err(_), _) => {
+            eprintln!("vssh: {}: command not found", argv[0].replace('\0', "\\0"));
+            return Err(127);
+        }
+        (_, Err(e)) => {
+            eprintln!("vssh: {}", e);
+            return Err(1);
+        }
+    };
+    let mut opened = Vec::new();
+    let mut actions = std::mem::MaybeUninit::<libc::posix_spawn_file_actions_t>::uninit();
+    let mut attributes = std::mem::MaybeUninit::<libc::posix_spawnattr_t>::uninit();
+    let result = unsafe {
+        libc::posix_spawn_file_actions_init(actions.as_mut_ptr());
+        libc::posix_spawnattr_init(attributes.as_mut_ptr());
+        let mut outcome = Ok(());
+        for redirect in redirects {
+            let fd = redirect.fd();
+            match redirect {
+                Redirect::Duplicate(_, target) if target == "-" => {
+                    libc::posix_spawn_file_actions_addclose(actions.as_mut_ptr(), fd);
+                }
+                Redirect::Duplicate(_, target) => {
+                    libc::posix_spawn_file_actions_adddup2(actions.as_mut_ptr(), target.parse().unwrap(), fd);
+                }
+                _ => match open_redirect(redirect) {
+                    Ok(file) => {
+                        libc::posix_spawn_file_actions_adddup2(actions.as_mut_ptr(), file.as_raw_fd(), fd);
+                        opened.push(file);
+                    }
+                    Err(e) => {
+                        outcome = Err(e);
+                        break;
+                    }
+                },
+            }
+        }
+        // Rust ignores SIGPIPE; commands expect it to kill them.
+        let mut defaults = SigSet::empty();
+        defaults.add(Signal::SIGPIPE);
+        libc::posix_spawnattr_setsigdefault(attributes.as_mut_ptr(), defaults.as_ref());
+        libc::posix_spawnattr_setflags(attributes.as_mut_ptr(), libc::POSIX_SPAWN_SETSIGDEF as libc::c_short);
+        let spawned = outcome.map(|()| {
+            let mut pointers: Vec<*mut libc::c_char> = args.iter().map(|arg| arg.as_ptr() as *mut libc::c_char).collect();
+            pointers.push(std::ptr::null_mut());
+            let mut pid = 0;
+            let errno = libc::posix_spawn(&mut pid, program.as_ptr(), actions.as_ptr(), attributes.as_ptr(), pointers.as_ptr(), environ);
+            if errno == 0 { Ok(nix::unistd::Pid::from_raw(pid)) } else { Err(Errno::from_i32(errno)) }
+        });
+        libc::posix_spawn_file_actions_destroy(actions.as_mut_ptr());
+        libc::posix_spawnattr_destroy(attributes.as_mut_ptr());
+        spawned
+    };
+    drop(opened);
+    match result {
+        Ok(Ok(child)) => Ok(child),
+        Ok(Err(Errno::ENOENT)) => {
+            eprintln!("vssh: {}: command not found", argv[0]);
+            Err(127)
+        }
+        Ok(Err(e)) => {
+            eprintln!("vssh: {}: {}", argv[0], e.desc());
+            Err(126)
+        }
+        Err(e) => {
+            eprintln!("vssh: {}", e);
+            Err(1)
+        }
+    }
+}
+
+/// The commands in the directories of a `$PATH`, scanned once: each name
+/// and the file that runs for it, the first in `$PATH` order. Command
+/// lookup and the listing of command names both use it rather than read
+/// the directories each time.
+#[derive(Default)]
+pub(crate) struct PathIndex {
+    /// The `$PATH` scanned, if any; another one is scanned afresh.
+    path: Option<String>,
+    commands: HashMap<String, String>,
+}
+
+impl PathIndex {
+    /// Scan `path` unless it is the one already indexed.
+    fn scan(&mut self, path: &str) {
+        if self.path.as_deref() == Some(path) {
+            return;
+        }
+        self.commands.clear();
+        for dir in path.split(':').map(|dir| if dir.is_empty() { "." } else { dir }) {
+            let Ok(entries) = std::fs::read_dir(dir) else { continue };
+            for name in entries.flatten().map(|entry| entry.file_name().to_string_lossy().into_owned()) {
+                if let std::collections::hash_map::Entry::Vacant(entry) = self.commands.entry(name) {
+                    let file = join_path(dir, entry.key());
+                    if is_executable(&file) {
+                        entry.insert(file);
+                    }
+                }
+            }
+        }
+        self.path = Some(path.to_string());
+    }
+
+    /// The file that runs for `name` through `path`. One that is no longer
+    /// there, or was not yet when `path` was scanned, is searched for.
+    pub(crate) fn find(&mut self, path: &str, name: &str) -> Option<String> {
+        self.scan(path);
+        if let Some(file) = self.commands.get(name).filter(|file| is_executable(file)) {
+            return Some(file.clone());
+        }
+        let file = search_path(name, path, false).pop();
+        match &file {
+            Some(file) => self.commands.insert(name.to_string(), file.clone()),
+            None => self.commands.remove(name),
+        };
+        file
+    }
+
+    /// The names of all the commands in `path`.
+    pub(crate) fn names(&mut self, path: &str) -> impl Iterator<Item = &str> {
+        self.scan(path);
+        self.commands.keys().map(String::as_str)
+    }
+
+    /// Forget what was scanned, so that the next lookup scans again.
+    pub(crate) fn clear(&mut self) {
+        self.path = None;
+    }
+}
+
+/// Find the executable files `name` resolves to through `path`: a name
+/// containing a slash is used as is, otherwise each directory is tried in
+/// order. Stops after the first match unless `all` is set.
+pub(crate) fn search_path(name: &str, path: &str, all: bool) -> Vec<String> {
+    let candidates: Vec<String> = if name.contains('/') {
+        vec![name.to_string()]
+    } else {
+        path.split(':')
+            .map(|dir| if dir.is_empty() { "." } else { dir })
+            .map(|dir| format!("{}/{}", dir, name))
+            .collect()
+    };
+    let mut found = Vec::new();
+    for candidate in candidates {
+        if is_executable(&candidate) {
+            found.push(candidate);
+            if !all {
+                break;
+            }
+        }
+    }
+    found
+}
+
+/// Whether `path` is a regular file we may execute
+fn is_executable(path: &str) -> bool {
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.is_file())
+        && nix::unistd::access(path, nix::unistd::AccessFlags::X_OK).is_ok()
+}
+
+/// The user and system CPU time used so far by the shell and by the
+/// children it has waited for.
+fn cpu_times() -> (std::time::Duration, std::time::Duration) {
+    use nix::sys::resource::{getrusage, UsageWho};
+    let to_duration = |time: nix::sys::time::TimeVal| {
+        std::time::Duration::new(time.tv_sec() as u64, time.tv_usec() as u32 * 1000)
+    };
+    let mut user = std::time::Duration::ZERO;
+    let mut system = std::time::Duration::ZERO;
+    for who in [UsageWho::RUSAGE_SELF, UsageWho::RUSAGE_CHILDREN] {
+        if let Ok(usage) = getrusage(who) {
+            user += to_duration(usage.user_time());
+            system += to_duration(usage.system_time());
+        }
+    }
+    (user, system)
+}
+
+/// Fill in a `$TIMEFORMAT`: `%R`, `%U` and `%S` are the real, user and
+/// system times in seconds, optionally with a precision of 0 to 3 digits
+/// (`%2R`) and in minutes and seconds (`%lR`); `%P` is the CPU usage as a
+/// percentage of the real time, and `%%` a percent sign.
+fn format_times(format: &str, real: std::time::Duration, user: std::time::Duration, system: std::time::Duration) -> String {
+    let mut output = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            output.push(c);
+            continue;
+        }
+        let precision = chars.next_if(|c| c.is_ascii_digit()).map_or(3, |digit| digit.to_digit(10).unwrap().min(3) as usize);
+        let long = chars.next_if_eq(&'l').is_some();
+        let time = match chars.next() {
+            Some('R') => real,
+            Some('U') => user,
+            Some('S') => system,
+            Some('P') => {
+                let percent = if real.is_zero() { 0.0 } else { (user + system).as_secs_f64() * 100.0 / real.as_secs_f64() };
+                output.push_str(&format!("{:.2}", percent));
+                continue;
+            }
+            Some('%') => {
+                output.push('%');
+                continue;
+            }
+            Some(other) => {
+                output.push('%');
+                output.push(other);
+                continue;
+            }
+            None => {
+                output.push('%');
+                break;
+            }
+        };
+        let seconds = time.as_secs_f64();
+        if long {
+            let minutes = (seconds / 60.0).floor();
+            output.push_str(&format!("{}m{:.*}s", minutes, precision, seconds - minutes * 60.0));
+        } else {
+            output.push_str(&format!("{:.*}", precision, seconds));
+        }
+    }
+    output
+}
+
+/// Convert a wait status into a shell exit code (128 + n for signal n)
+pub(crate) fn exit_code(status: WaitStatus) -> i32 {
+    match status {
+        WaitStatus::Exited(_, code) => code,
+        WaitStatus::Signaled(_, signal, _) => 128 + signal as i32,
+        _ => 0,
+    }
+}
+
+/// Convert expanded words into a vector of C-style strings, failing with
+/// a message for the first that has a NUL byte in it, which no C string
+/// can hold.
+fn externalize(argv: &[String]) -> std::result::Result<Vec<CString>, String> {
+    argv.iter()
+        .map(|s| CString::new(s.as_str()).map_err(|_| format!("{}: argument contains a NUL byte", s.replace('\0', "\\0"))))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{run, shell, temp_path};
+
+    #[test]
+    fn autocd_goes_to_directories_named_by_path() {
+        let (_lock, mut shell) = shell();
+        let cwd = std::env::current_dir().unwrap();
+        let dir = temp_path("autocd");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let dir = std::fs::canonicalize(dir).unwrap();
+        let dir_name = dir.to_str().unwrap();
+        assert_ne!(run(&mut shell, dir_name).0, 0);
+        assert_eq!(run(&mut shell, &format!("shopt -s autocd; {}", dir_name)), (0, String::new()));
+        assert_eq!(shell.var("PWD"), Some(dir_name));
+        assert_eq!(run(&mut shell, "./sub"), (0, String::new()));
+        assert_eq!(std::env::current_dir().unwrap(), dir.join("sub"));
+        assert_eq!(run(&mut shell, ".."), (0, String::new()));
+        assert_eq!(shell.var("PWD"), Some(dir_name));
+        std::env::set_current_dir(cwd).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restricted_shell_refuses_redirections_to_files() {
+        let (_lock, mut shell) = shell();
+        let file = temp_path("restricted");
+        let file_name = file.to_str().unwrap();
+        shell.set_option("restricted", true);
+        for command in ["echo a >", "{ echo a; } >", "true >|", "ls >", "echo a | cat >"] {
+            assert_eq!(run(&mut shell, &format!("{}{}; echo $?", command, file_name)), (0, "1\n".to_string()), "{}", command);
+        }
+        assert_eq!(run(&mut shell, &format!("echo a >{} | cat", file_name)), (0, String::new()));
+        assert!(!file.exists());
+        assert_eq!(run(&mut shell, "echo a 2>&1"), (0, "a\n".to_string()));
+    }
+
+    #[test]
+    fn runs_the_branch_whose_condition_succeeds() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "if false; then echo a; elif true; then echo b; else echo c; fi"), (0, "b\n".to_string()));
+        assert_eq!(run(&mut shell, "if false\nthen echo a\nelse\n  echo c\nfi"), (0, "c\n".to_string()));
+        assert_eq!(run(&mut shell, "if false; then echo a; fi"), (0, String::new()));
+        assert_eq!(run(&mut shell, "if true; then false; fi"), (1, String::new()));
+    }
+
+    #[test]
+    fn loops_while_and_until_the_condition_holds() {
+        let (_lock, mut shell) = shell();
+        let file = temp_path("lines");
+        std::fs::write(&file, "one\ntwo\n").unwrap();
+        let source = format!("while read line; do echo \"<$line>\"; done <{}", file.to_str().unwrap());
+        assert_eq!(run(&mut shell, &source), (0, "<one>\n<two>\n".to_string()));
+        assert_eq!(run(&mut shell, "x=; until [ \"$x\" = aaa ]; do x=a$x; done; echo $x"), (0, "aaa\n".to_string()));
+        assert_eq!(run(&mut shell, "while false; do echo never; done"), (0, String::new()));
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn loops_over_expanded_words() {
+        let (_lock, mut shell) = shell();
+        let dir = temp_path("for");
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["b.txt", "a.txt", "c.log"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let source = "for x in a \"b c\" $(echo d e) $none; do echo \"[$x]\"; done; echo $x";
+        assert_eq!(run(&mut shell, source), (0, "[a]\n[b c]\n[d]\n[e]\ne\n".to_string()));
+        let dir_name = dir.to_str().unwrap();
+        let source = format!("for x in {}/*.txt; do echo \"$x\"; done", dir_name);
+        assert_eq!(run(&mut shell, &source), (0, format!("{0}/a.txt\n{0}/b.txt\n", dir_name)));
+        shell.positional = vec!["p1".to_string(), "p 2".to_string()];
+        assert_eq!(run(&mut shell, "for x; do echo \"$x\"; done"), (0, "p1\np 2\n".to_string()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn runs_the_first_case_item_that_matches() {
+        let (_lock, mut shell) = shell();
+        let source = "for x in start stop restart x.c '*'; do case $x in start|stop) echo \"$x: run\";; *.c) echo \"$x: c\";; '*') echo star;; *) echo \"$x: other\";; esac; done";
+        assert_eq!(run(&mut shell, source), (0, "start: run\nstop: run\nrestart: other\nx.c: c\nstar\n".to_string()));
+        assert_eq!(run(&mut shell, "case x in y) echo y;; esac"), (0, String::new()));
+    }
+
+    #[test]
+    fn calls_functions_with_their_arguments() {
+        let (_lock, mut shell) = shell();
+        shell.positional = vec!["outer".to_string()];
+        let source = "greet() { echo \"$# $1 $2\"; }; function twice { greet \"$@\"; greet x; }; twice a 'b c'; echo \"$1\"";
+        assert_eq!(run(&mut shell, source), (0, "2 a b c\n1 x \nouter\n".to_string()));
+        assert_eq!(run(&mut shell, "f() { false; }; f"), (1, String::new()));
+    }
+
+    #[test]
+    fn errexit_stops_at_a_failure_outside_conditions() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "set -e; if false; then :; fi; false && :; ! true; false || echo a; echo b"), (0, "a\nb\n".to_string()));
+        assert_eq!(run(&mut shell, "while false; do :; done; echo c; false; echo d"), (1, "c\n".to_string()));
+        assert_eq!(run(&mut shell, "f() { false; echo e; }; f || echo f"), (0, "e\n".to_string()));
+        assert_eq!(run(&mut shell, "f; echo h"), (1, String::new()));
+        assert_eq!(run(&mut shell, "set +e; false; echo g"), (0, "g\n".to_string()));
+        assert_eq!(crate::builtins::option_for_flag('e'), Some("errexit"));
+    }
+
+    #[test]
+    fn xtrace_expands_ps4_before_each_command() {
+        let (_lock, mut shell) = shell();
+        let dir = temp_path("xtrace");
+        std::fs::create_dir(&dir).unwrap();
+        shell.set_var("PS4", &format!("$(mktemp -p {}) ", dir.to_str().unwrap()));
+        assert_eq!(run(&mut shell, "echo a; set -x; echo b; x=1; set +x; echo c"), (0, "a\nb\nc\n".to_string()));
+        assert!(!shell.option("xtrace"));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pipefail_takes_the_last_failing_stage() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "sh -c 'exit 3' | sh -c 'exit 4' | true"), (0, String::new()));
+        assert_eq!(shell.arrays["PIPESTATUS"], ["3", "4", "0"]);
+        assert_eq!(run(&mut shell, "set -o pipefail; sh -c 'exit 3' | sh -c 'exit 4' | true"), (4, String::new()));
+        assert_eq!(run(&mut shell, "sh -c 'exit 3' | true"), (3, String::new()));
+        assert_eq!(run(&mut shell, "true | echo a"), (0, "a\n".to_string()));
+        assert_eq!(run(&mut shell, "set +o pipefail; false | true"), (0, String::new()));
+    }
+
+    #[test]
+    fn noclobber_refuses_to_overwrite_files_unless_forced() {
+        let (_lock, mut shell) = shell();
+        let file = temp_path("noclobber");
+        let file_name = file.to_str().unwrap();
+        assert_eq!(run(&mut shell, &format!("set -o noclobber; echo a > {0}; echo b > {0}", file_name)), (1, String::new()));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "a\n");
+        assert_eq!(run(&mut shell, &format!("echo c >| {0}; echo d > /dev/null", file_name)), (0, String::new()));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "c\n");
+        assert_eq!(run(&mut shell, &format!("set +o noclobber; echo e > {0}", file_name)), (0, String::new()));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "e\n");
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn time_reports_a_pipeline_in_timeformat() {
+        use std::time::Duration;
+        let (_lock, mut shell) = shell();
+        let (real, user, system) = (Duration::from_millis(61_500), Duration::from_millis(250), Duration::from_millis(1_250));
+        let format = |format: &str| super::format_times(format, real, user, system);
+        assert_eq!(format("%R %2U %0S"), "61.500 0.25 1");
+        assert_eq!(format("%lR|%1lU"), "1m1.500s|0m0.2s");
+        assert_eq!(format("%P%% %x %"), "2.44% %x %");
+        assert_eq!(run(&mut shell, "TIMEFORMAT=; time sh -c 'exit 3' | echo a"), (0, "a\n".to_string()));
+        assert_eq!(run(&mut shell, "time -p false"), (1, String::new()));
+        assert_eq!(run(&mut shell, "time"), (0, String::new()));
+    }
+
+    #[test]
+    fn restricted_shell_refuses_cd_path_and_commands_with_slashes() {
+        let (_lock, mut shell) = shell();
+        let path = shell.var("PATH").map(str::to_string);
+        shell.set_option("restricted", true);
+        assert_eq!(run(&mut shell, "cd /"), (1, String::new()));
+        assert_eq!(run(&mut shell, "PATH=/tmp"), (1, String::new()));
+        assert_eq!(shell.var("PATH").map(str::to_string), path);
+        assert_eq!(run(&mut shell, "/bin/echo a"), (1, String::new()));
+        assert_eq!(run(&mut shell, "echo a; sh -c 'echo b'"), (0, "a\nb\n".to_string()));
+        assert_eq!(run(&mut shell, "set +r"), (0, String::new()));
+        assert!(shell.option("restricted"));
+    }
+
+    #[test]
+    fn policy_allows_and_denies_external_commands() {
+        let (_lock, mut shell) = shell();
+        let file = temp_path("policy");
+        std::fs::write(&file, "# kiosk\nallow /usr/bin/*\nallow /bin/*\nallow printf\ndeny rm\n").unwrap();
+        shell.load_policies(Some(file.to_str().unwrap().to_string())).unwrap();
+        unsafe { std::env::remove_var("VSSH_POLICY") };
+        assert_eq!(run(&mut shell, "sh -c 'echo a'; echo b"), (0, "a\nb\n".to_string()));
+        assert_eq!(run(&mut shell, "rm -f /nonexistent"), (126, String::new()));
+        let program = temp_path("true");
+        std::fs::copy("/bin/true", &program).unwrap();
+        assert_eq!(run(&mut shell, program.to_str().unwrap()), (126, String::new()));
+        std::fs::remove_file(&program).unwrap();
+        assert_eq!(run(&mut shell, "VSSH_POLICY="), (1, String::new()));
+        assert_eq!(shell.var("VSSH_POLICY"), file.to_str());
+        std::fs::write(&file, "permit ls\n").unwrap();
+        let error = shell.load_policies(Some(file.to_str().unwrap().to_string())).unwrap_err();
+        assert!(error.to_string().ends_with("line 1: expected `allow pattern' or `deny pattern'"), "{}", error);
+        unsafe { std::env::remove_var("VSSH_POLICY") };
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn audit_log_records_each_pipeline() {
+        let (_lock, mut shell) = shell();
+        let log = temp_path("audit");
+        shell.audit = super::AuditLog::open(Some(log.to_str().unwrap().to_string())).unwrap();
+        assert_eq!(run(&mut shell, "echo \"a\tb\" | cat; sh -c 'exit 3'"), (3, "a\tb\n".to_string()));
+        let entries = std::fs::read_to_string(&log).unwrap();
+        let entries: Vec<&str> = entries.lines().collect();
+        assert_eq!(entries.len(), 2, "{:?}", entries);
+        let cwd = super::json_string(&std::env::current_dir().unwrap().display().to_string());
+        assert!(entries[0].contains(&format!("\"cwd\":{},\"command\":\"echo \\\"a\\tb\\\" | cat\",\"status\":0,", cwd)), "{}", entries[0]);
+        assert!(entries[1].contains(&format!("\"uid\":{},\"pid\":{},", nix::unistd::getuid(), std::process::id())));
+        assert!(entries[1].contains("\"command\":\"sh -c 'exit 3'\",\"status\":3,"), "{}", entries[1]);
+        std::fs::remove_file(&log).unwrap();
+    }
+
+    #[test]
+    fn unknown_commands_get_suggestions_or_the_handler() {
+        let (_lock, mut shell) = shell();
+        let dir = temp_path("notfound");
+        std::fs::create_dir(&dir).unwrap();
+        for name in ["make", "cmake", "unrelated"] {
+            std::fs::copy("/bin/true", dir.join(name)).unwrap();
+        }
+        shell.set_var("PATH", dir.to_str().unwrap());
+        assert_eq!(run(&mut shell, "mkae() { :; }; alias mak=make"), (0, String::new()));
+        assert_eq!(shell.similar_commands("make"), ["make", "cmake", "mak", "mkae"]);
+        assert_eq!(shell.similar_commands("ehco"), ["echo"]);
+        assert!(shell.similar_commands("zz").is_empty());
+        assert_eq!(run(&mut shell, "nosuch a; echo $?"), (0, "127\n".to_string()));
+        let handler = "command_not_found_handle() { echo \"missing $1 with $2\"; nosuch_either; return 3; }";
+        assert_eq!(run(&mut shell, &format!("{}; nosuch a", handler)), (3, "missing nosuch with a\n".to_string()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn preexec_and_precmd_hooks_run_around_typed_commands() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "preexec() { seen=\"$1\"; false; }; precmd() { prompts=x$prompts; false; }"), (0, String::new()));
+        shell.prompt_input = true;
+        assert_eq!(run(&mut shell, "echo a;  sh -c 'exit 4'  "), (4, "a\n".to_string()));
+        assert_eq!(shell.var("seen"), Some("echo a;  sh -c 'exit 4'"));
+        assert_eq!(run(&mut shell, "seen=; true"), (0, String::new()));
+        assert_eq!(shell.var("seen"), Some(""));
+        assert!(matches!(shell.run_hook("precmd", &[]), crate::Status::Continue));
+        assert_eq!((shell.var("prompts"), shell.last_status), (Some("x"), 0));
+    }
+
+    #[test]
+    fn redirects_numbered_descriptors_in_order() {
+        let (_lock, mut shell) = shell();
+        let input = temp_path("fd-input");
+        let output = temp_path("fd-output");
+        std::fs::write(&input, "from three\n").unwrap();
+        let (input, output) = (input.to_str().unwrap(), output.to_str().unwrap());
+        assert_eq!(run(&mut shell, &format!("cat 3< {} <&3", input)), (0, "from three\n".to_string()));
+        assert_eq!(run(&mut shell, &format!("cat <&3 3< {}", input)), (1, String::new()));
+        assert_eq!(run(&mut shell, &format!("sh -c 'echo four >&4' 4> {}", output)), (0, String::new()));
+        assert_eq!(std::fs::read_to_string(output).unwrap(), "four\n");
+        assert_eq!(run(&mut shell, "sh -c 'echo err >&2' 2>&1"), (0, "err\n".to_string()));
+        assert_eq!(run(&mut shell, &format!("sh -c 'echo out; echo err >&2' 2>&1 > {}", output)), (0, "err\n".to_string()));
+        assert_eq!(std::fs::read_to_string(output).unwrap(), "out\n");
+        assert_eq!(run(&mut shell, "sh -c 'echo a >&5' 5>&-").0, 2);
+        std::fs::remove_file(input).unwrap();
+        std::fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn redirects_compound_commands_and_restores_afterwards() {
+        let (_lock, mut shell) = shell();
+        let file = temp_path("compound");
+        let name = file.to_str().unwrap();
+        std::fs::write(&file, "a\nb\n").unwrap();
+        assert_eq!(run(&mut shell, &format!("while read l; do echo \"<$l>\"; done < {}; echo after", name)), (0, "<a>\n<b>\nafter\n".to_string()));
+        assert_eq!(run(&mut shell, &format!("if true; then echo yes; fi > {}; echo after", name)), (0, "after\n".to_string()));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "yes\n");
+        assert_eq!(run(&mut shell, &format!("{{ echo out; sh -c 'echo err >&2'; }} 2> {}", name)), (0, "out\n".to_string()));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "err\n");
+        assert_eq!(run(&mut shell, &format!("for i in 1 2; do echo $i; done > {}; cat {}", name, name)), (0, "1\n2\n".to_string()));
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn opens_sockets_for_dev_tcp_and_dev_udp() {
+        use std::io::{BufRead, BufReader, Write};
+        let (_lock, mut shell) = shell();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            writeln!(&stream, "pong to {}", line.trim()).unwrap();
+        });
+        // A high descriptor, clear of the ones the test process has open.
+        let source = format!("exec 50<>/dev/tcp/127.0.0.1/{}; echo ping >&50; read reply <&50; exec 50>&-; echo \"$reply\"", port);
+        assert_eq!(run(&mut shell, &source), (0, "pong to ping\n".to_string()));
+        server.join().unwrap();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        assert_eq!(run(&mut shell, &format!("echo datagram > /dev/udp/127.0.0.1/{}", port)), (0, String::new()));
+        let mut buffer = [0; 64];
+        let length = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"datagram\n");
+        assert_eq!(run(&mut shell, "echo > /dev/tcp/127.0.0.1/no-port").0, 1);
+    }
+
+    #[test]
+    fn coprocesses_talk_to_the_shell_through_pipes() {
+        let (_lock, mut shell) = shell();
+        let source = "coproc upper { read l; echo \"$l\" | tr a-z A-Z; }; echo hello >&${upper[1]}; read reply <&${upper[0]}; wait $upper_PID; echo \"$reply\"";
+        assert_eq!(run(&mut shell, source), (0, "HELLO\n".to_string()));
+        let old: Vec<i32> = shell.arrays["upper"].iter().map(|fd| fd.parse().unwrap()).collect();
+        assert!(old.iter().all(|&fd| fd >= 60));
+        assert_eq!(run(&mut shell, "coproc upper { cat; }; echo again >&${upper[1]}; read reply <&${upper[0]}; echo \"$reply\""), (0, "again\n".to_string()));
+        assert!(old.iter().all(|&fd| nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_GETFD).is_err()));
+        for fd in shell.arrays["upper"].iter().filter_map(|fd| fd.parse().ok()) {
+            let _ = nix::unistd::close(fd);
+        }
+        assert_eq!(run(&mut shell, "wait $upper_PID"), (0, String::new()));
+    }
+
+    #[test]
+    fn applies_redirections_on_every_stage_of_a_pipeline() {
+        let (_lock, mut shell) = shell();
+        let file = temp_path("middle-stage");
+        let name = file.to_str().unwrap();
+        assert_eq!(run(&mut shell, &format!("echo a | cat > {} | echo b", name)), (0, "b\n".to_string()));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "a\n");
+        assert_eq!(run(&mut shell, &format!("cat < {} | sh -c 'cat; echo err >&2' 2>&1 | tr a-z A-Z", name)), (0, "A\nERR\n".to_string()));
+        assert_eq!(run(&mut shell, "if true; then echo x | tr x y; fi | { read v; echo \"[$v]\"; }"), (0, "[y]\n".to_string()));
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn spawns_simple_commands_with_their_redirections() {
+        assert!(spawnable(&Redirect::Output(1, Cow::from("out"))));
+        assert!(spawnable(&Redirect::Duplicate(2, Cow::from("1"))));
+        assert!(spawnable(&Redirect::Duplicate(5, Cow::from("-"))));
+        assert!(!spawnable(&Redirect::Duplicate(1, Cow::from("5"))));
+        assert!(!spawnable(&Redirect::ReadWrite(3, Cow::from("/dev/tcp/localhost/80"))));
+        let input = temp_path("spawn-input");
+        let output = temp_path("spawn-output");
+        std::fs::write(&input, "in\n").unwrap();
+        let redirects = [
+            Redirect::Input(0, Cow::from(input.to_str().unwrap())),
+            Redirect::Output(1, Cow::from(output.to_str().unwrap())),
+            Redirect::Duplicate(2, Cow::from("1")),
+        ];
+        let argv = ["sh", "-c", "cat; echo err >&2; exit 4"].map(String::from);
+        let child = spawn_command("/bin/sh", &argv, &redirects).unwrap();
+        assert_eq!(nix::sys::wait::waitpid(child, None), Ok(WaitStatus::Exited(child, 4)));
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "in\nerr\n");
+        assert_eq!(spawn_command("/no/such/program", &["x".to_string()], &[]), Err(127));
+        std::fs::remove_file(&input).unwrap();
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn indexes_path_once_and_looks_again_for_changed_files() {
+        let (first, second) = (temp_path("index-first"), temp_path("index-second"));
+        for dir in [&first, &second] {
+            std::fs::create_dir(dir).unwrap();
+            std::fs::copy("/bin/true", dir.join("both")).unwrap();
+        }
+        std::fs::copy("/bin/true", second.join("only")).unwrap();
+        std::fs::write(first.join("plain"), "").unwrap();
+        let path = format!("{}:{}", first.display(), second.display());
+        let file = |dir: &std::path::Path, name: &str| Some(dir.join(name).to_str().unwrap().to_string());
+        let mut index = PathIndex::default();
+        assert_eq!(index.find(&path, "both"), file(&first, "both"));
+        assert_eq!(index.find(&path, "only"), file(&second, "only"));
+        let mut names: Vec<&str> = index.names(&path).collect();
+        names.sort();
+        assert_eq!(names, ["both", "only"]);
+        std::fs::remove_file(first.join("both")).unwrap();
+        std::fs::copy("/bin/true", first.join("new")).unwrap();
+        assert_eq!(index.find(&path, "both"), file(&second, "both"));
+        assert_eq!(index.find(&path, "new"), file(&first, "new"));
+        assert_eq!(index.find(second.to_str().unwrap(), "new"), None);
+        std::fs::remove_dir_all(&first).unwrap();
+        std::fs::remove_dir_all(&second).unwrap();
+    }
+
+    #[test]
+    fn commands_inherit_only_the_descriptors_meant_for_them() {
+        let (_lock, mut shell) = shell();
+        let inherited = |fd: &i32| nix::fcntl::fcntl(*fd, nix::fcntl::FcntlArg::F_GETFD).is_ok_and(|flags| flags & libc::FD_CLOEXEC == 0);
+        // Those the test process itself lets children inherit are expected.
+        let open = std::fs::read_dir("/proc/self/fd").unwrap().flatten().filter_map(|entry| entry.file_name().to_str()?.parse().ok());
+        let mut expected: Vec<i32> = open.filter(|fd| *fd > 2 && inherited(fd)).collect();
+        let file = temp_path("inherit");
+        let name = file.to_str().unwrap();
+        std::fs::write(&file, "").unwrap();
+        let (_, listed) = run(&mut shell, &format!("sh -c 'ls /proc/$$/fd' < {} 2> {} | cat | cat", name, name));
+        let mut fds: Vec<i32> = listed.lines().map(|fd| fd.parse().unwrap()).filter(|&fd| fd > 2).collect();
+        fds.sort();
+        expected.sort();
+        assert_eq!(fds, expected);
+        let (read, write) = cloexec_pipe().unwrap();
+        assert!(!inherited(&read.as_raw_fd()) && !inherited(&write.as_raw_fd()));
+        let opened = open_redirect(&Redirect::Input(0, Cow::from(name))).unwrap();
+        assert!(!inherited(&opened.as_raw_fd()));
+        std::fs::remove_file(&file).unwrap();
+    }
+}
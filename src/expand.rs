@@ -0,0 +1,778 @@
+//! Expansion of words into fields: parameters, command substitution,
+//! tildes and globs.
+
+use std::borrow::Cow;
+use std::ffi::CString;
+use std::io::{self, Read, Write};
+use nix::unistd::{fork, ForkResult, dup2};
+use nix::sys::wait::waitpid;
+use std::os::unix::io::AsRawFd;
+use anyhow::Result;
+use crate::Shell;
+use crate::exec::{FatalError, cloexec_pipe, exit_code};
+
+/// A field being assembled during word expansion.
+#[derive(Default)]
+struct Field {
+    /// The field's final text, with quotes removed.
+    value: String,
+    /// The same text as a glob or regular expression pattern, with quoted
+    /// special characters escaped.
+    pattern: String,
+    /// Whether an unquoted glob character appears in the field.
+    has_glob: bool,
+}
+
+impl Field {
+    fn push_unquoted(&mut self, c: char) {
+        self.value.push(c);
+        self.pattern.push(c);
+        self.has_glob |= matches!(c, '*' | '?' | '[');
+    }
+
+    fn push_quoted(&mut self, c: char) {
+        self.value.push(c);
+        if "*?[]\\.+(){}|^$".contains(c) {
+            self.pattern.push('\\');
+        }
+        self.pattern.push(c);
+    }
+}
+
+impl Shell {
+    /// Expand every word of a command into its final list of arguments.
+    pub(crate) fn expand_words(&mut self, words: &[Cow<str>]) -> Result<Vec<String>> {
+        let mut fields = Vec::new();
+        for word in words {
+            // "$@" keeps each positional parameter as its own field.
+            if word == "\"$@\"" {
+                fields.extend(self.positional.iter().cloned());
+                continue;
+            }
+            if is_literal(word) {
+                fields.push(word.to_string());
+                continue;
+            }
+            fields.extend(self.expand_word(word)?);
+        }
+        Ok(fields)
+    }
+
+    /// Expand a word that must stay a single string, such as a redirection
+    /// target or an assignment value: no field splitting or globbing.
+    pub(crate) fn expand_single(&mut self, word: &str) -> Result<String> {
+        if is_literal(word) {
+            return Ok(word.to_string());
+        }
+        let mut field = Field::default();
+        let mut fields = Vec::new();
+        self.expand_into(word, false, &mut field, &mut fields)?;
+        Ok(field.value)
+    }
+
+    /// Expand a word used as a pattern: like `expand_single`, but quoted
+    /// glob characters stay escaped so they match literally.
+    pub(crate) fn expand_pattern(&mut self, word: &str) -> Result<String> {
+        let mut field = Field::default();
+        let mut fields = Vec::new();
+        self.expand_into(word, false, &mut field, &mut fields)?;
+        Ok(field.pattern)
+    }
+
+    /// Expand one word into zero or more fields: parameter and command
+    /// substitution, splitting of unquoted results on `$IFS`, quote removal,
+    /// and pathname expansion.
+    fn expand_word(&mut self, word: &str) -> Result<Vec<String>> {
+        let mut current = Field::default();
+        let mut fields = Vec::new();
+        let started = self.expand_into(word, true, &mut current, &mut fields)?;
+        if started || !current.value.is_empty() {
+            fields.push(current);
+        }
+        let mut expanded = Vec::new();
+        for field in fields {
+            let matches = if field.has_glob { glob(&field.pattern, self.option("globstar"), self.glob_order()) } else { Vec::new() };
+            if matches.is_empty() {
+                expanded.push(field.value);
+            } else {
+                expanded.extend(matches);
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// Worker for the expansion functions. Appends the expansion of `word`
+    /// to `current`, moving completed fields to `fields` when `split` is
+    /// set. Returns whether quoting forced the current field to exist even
+    /// if it is empty (as with `""`).
+    fn expand_into(&mut self, word: &str, split: bool, current: &mut Field, fields: &mut Vec<Field>) -> Result<bool> {
+        let chars: Vec<char> = word.chars().collect();
+        let mut started = false;
+        let mut in_double = false;
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            i += 1;
+            match c {
+                '~' if i == 1 && !in_double => {
+                    let end = chars.iter().position(|&c| c == '/').unwrap_or(chars.len());
+                    let prefix: String = chars[1..end].iter().collect();
+                    match self.tilde_expansion(&prefix) {
+                        Some(dir) => {
+                            dir.chars().for_each(|c| current.push_quoted(c));
+                            i = end;
+                        }
+                        None => current.push_unquoted(c),
+                    }
+                }
+                '\'' if !in_double => {
+                    started = true;
+                    while i < chars.len() && chars[i] != '\'' {
+                        current.push_quoted(chars[i]);
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                '"' => {
+                    started = true;
+                    in_double = !in_double;
+                }
+                '\\' if i < chars.len() => {
+                    let next = chars[i];
+                    i += 1;
+                    if in_double && !matches!(next, '$' | '`' | '"' | '\\' | '\n') {
+                        current.push_quoted('\\');
+                    }
+                    if next != '\n' {
+                        current.push_quoted(next);
+                    }
+                }
+                '$' | '`' => {
+                    let (value, consumed) = self.expand_dollar(&chars[i - 1..])?;
+                    i += consumed - 1;
+                    match value {
+                        None if in_double => current.push_quoted('$'),
+                        None => current.push_unquoted('$'),
+                        Some(value) if in_double || !split => value.chars().for_each(|c| current.push_quoted(c)),
+                        Some(value) => {
+                            let ifs = self.vars.get("IFS").map_or(" \t\n", String::as_str);
+                            for c in value.chars() {
+                                if ifs.contains(c) {
+                                    if started || !current.value.is_empty() {
+                                        fields.push(std::mem::take(current));
+                                        started = false;
+                                    }
+                                } else {
+                                    current.push_unquoted(c);
+                                }
+                            }
+                        }
+                    }
+                }
+                _ if in_double => current.push_quoted(c),
+                _ => current.push_unquoted(c),
+            }
+        }
+        Ok(started)
+    }
+
+    /// Every path under the current directory that is not hidden, as `**`
+    /// would match them, for picking from with Ctrl-T.
+    pub(crate) fn paths_below(&self) -> Vec<String> {
+        let mut paths = Vec::new();
+        descendants("", &mut paths, self.glob_order());
+        paths
+    }
+
+    /// The paths that start with `word`, for Tab to complete it to, with
+    /// `/` after directories. Hidden files are left out unless `word`
+    /// names them with a leading `.`.
+    pub(crate) fn paths_starting(&self, word: &str) -> Vec<String> {
+        let (dir, prefix) = match word.rfind('/') {
+            Some(index) => (&word[..=index], &word[index + 1..]),
+            None => ("", word),
+        };
+        let Ok(entries) = std::fs::read_dir(if dir.is_empty() { "." } else { dir }) else { return Vec::new() };
+        let mut paths: Vec<String> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                    return None;
+                }
+                let slash = if entry.path().is_dir() { "/" } else { "" };
+                Some(format!("{}{}{}", dir, name, slash))
+            })
+            .collect();
+        self.glob_order().sort(&mut paths);
+        paths
+    }
+
+    /// The order glob results are sorted in, going by the `globcollate`
+    /// and `numericglobsort` options. For collation, the C library is set
+    /// to the locale in `$LC_ALL`, `$LC_COLLATE` or `$LANG`, the first that
+    /// is set, as the shell has them.
+    fn glob_order(&self) -> GlobOrder {
+        let collate = self.option("globcollate");
+        if collate {
+            let locale = ["LC_ALL", "LC_COLLATE", "LANG"].iter().find_map(|name| self.vars.get(*name).filter(|value| !value.is_empty()));
+            let locale = CString::new(locale.map_or("C", String::as_str)).unwrap_or_default();
+            unsafe { libc::setlocale(libc::LC_COLLATE, locale.as_ptr()) };
+        }
+        GlobOrder { collate, numeric: self.option("numericglobsort") }
+    }
+
+    /// The directory named by `~prefix` at the start of a word: `$HOME`
+    /// for an empty prefix, `$PWD` and `$OLDPWD` for `+` and `-`, entry
+    /// `N` of the directory stack for `N` or `+N` (`-N` counts from the
+    /// bottom), and otherwise the home directory of the user called `prefix`.
+    fn tilde_expansion(&self, prefix: &str) -> Option<String> {
+        let digits = prefix.trim_start_matches(['+', '-']);
+        if !digits.is_empty() && prefix.len() - digits.len() <= 1 && digits.chars().all(|c| c.is_ascii_digit()) {
+            let n: usize = digits.parse().ok()?;
+            let len = self.dir_stack.len() + 1;
+            let n = if prefix.starts_with('-') { len.checked_sub(n + 1)? } else { n };
+            return match n {
+                0 => self.vars.get("PWD").cloned(),
+                _ => self.dir_stack.get(n - 1).cloned(),
+            };
+        }
+        match prefix {
+            "" => self.vars.get("HOME").cloned(),
+            "+" => self.vars.get("PWD").cloned(),
+            "-" => self.vars.get("OLDPWD").cloned(),
+            _ if prefix.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) => {
+                let name = CString::new(prefix).ok()?;
+                let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+                if entry.is_null() {
+                    return None;
+                }
+                let dir = unsafe { std::ffi::CStr::from_ptr((*entry).pw_dir) };
+                Some(dir.to_string_lossy().into_owned())
+            }
+            _ => None,
+        }
+    }
+
+    /// Expand the `$...` or backquote construct at the start of `chars`.
+    /// Returns its value (`None` for a lone `$`) and the number of
+    /// characters it spans.
+    fn expand_dollar(&mut self, chars: &[char]) -> Result<(Option<String>, usize)> {
+        if chars[0] == '`' {
+            let end = chars[1..].iter().position(|&c| c == '`').map_or(chars.len(), |p| p + 1);
+            let source: String = chars[1..end].iter().collect();
+            return Ok((Some(self.command_substitution(&source)?), (end + 1).min(chars.len())));
+        }
+        match chars.get(1) {
+            Some('(') => {
+                let mut depth = 0;
+                let mut end = chars.len();
+                for (i, &c) in chars.iter().enumerate().skip(1) {
+                    match c {
+                        '(' => depth += 1,
+                        ')' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                end = i;
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                let source: String = chars[2..end].iter().collect();
+                Ok((Some(self.command_substitution(&source)?), (end + 1).min(chars.len())))
+            }
+            Some('{') => {
+                let end = chars.iter().position(|&c| c == '}').unwrap_or(chars.len());
+                let name: String = chars[2..end].iter().collect();
+                Ok((Some(self.lookup_set(&name)?), (end + 1).min(chars.len())))
+            }
+            Some(&c) if matches!(c, '?' | '$' | '#' | '@' | '*' | '!' | '-') || c.is_ascii_digit() => {
+                Ok((Some(self.lookup_set(&c.to_string())?), 2))
+            }
+            Some(&c) if c.is_ascii_alphabetic() || c == '_' => {
+                let len = chars[1..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+                    .count();
+                let name: String = chars[1..=len].iter().collect();
+                Ok((Some(self.lookup_set(&name)?), len + 1))
+            }
+            _ => Ok((None, 1)),
+        }
+    }
+
+    /// `lookup`, except that with `set -u` an unset parameter is an error.
+    fn lookup_set(&self, name: &str) -> Result<String> {
+        if self.option("nounset") && !self.is_set(name) {
+            let error = format!("{}: line {}: {}: unbound variable", self.name, self.line_number, name);
+            return Err(FatalError(error).into());
+        }
+        Ok(self.lookup(name))
+    }
+
+    /// Whether a variable or special parameter has a value, even an empty one.
+    fn is_set(&self, name: &str) -> bool {
+        match name {
+            "?" | "$" | "#" | "@" | "*" | "-" | "0" | "LINENO" | "BASH_COMMAND" => true,
+            "!" => self.last_background.is_some(),
+            _ if name.bytes().all(|b| b.is_ascii_digit()) => {
+                name.parse::<usize>().is_ok_and(|n| n <= self.positional.len())
+            }
+            _ if name.ends_with(']') && name.contains('[') => {
+                let (name, index) = name[..name.len() - 1].split_once('[').unwrap();
+                let elements = self.arrays.get(name).map(Vec::as_slice).unwrap_or_default();
+                matches!(index, "@" | "*") || index.parse::<usize>().is_ok_and(|i| i < elements.len())
+            }
+            _ => self.vars.contains_key(name) || self.arrays.contains_key(name),
+        }
+    }
+
+    /// The value of a variable or special parameter, empty when unset.
+    fn lookup(&self, name: &str) -> String {
+        match name {
+            "LINENO" => self.line_number.to_string(),
+            "BASH_COMMAND" => self.current_command.clone(),
+            "-" => self.option_flags(),
+            "?" => self.last_status.to_string(),
+            "$" => std::process::id().to_string(),
+            "!" => self.last_background.map(|pid| pid.to_string()).unwrap_or_default(),
+            "#" => self.positional.len().to_string(),
+            "@" | "*" => self.positional.join(" "),
+            "0" => self.name.clone(),
+            _ if name.bytes().all(|b| b.is_ascii_digit()) => name
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| self.positional.get(n.checked_sub(1)?))
+                .cloned()
+                .unwrap_or_default(),
+            _ if name.ends_with(']') && name.contains('[') => {
+                let (name, index) = name[..name.len() - 1].split_once('[').unwrap();
+                let elements = self.arrays.get(name).map(Vec::as_slice).unwrap_or_default();
+                match index {
+                    "@" | "*" => elements.join(" "),
+                    _ => index
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|i| elements.get(i))
+                        .cloned()
+                        .unwrap_or_default(),
+                }
+            }
+            _ => match self.vars.get(name) {
+                Some(value) => value.clone(),
+                None => self.arrays.get(name).and_then(|a| a.first()).cloned().unwrap_or_default(),
+            },
+        }
+    }
+
+    /// Run `source` in a subshell and capture its standard output, minus
+    /// trailing newlines.
+    fn command_substitution(&mut self, source: &str) -> Result<String> {
+        let (read, write) = cloexec_pipe()?;
+        io::stdout().flush()?;
+        match unsafe { fork()? } {
+            ForkResult::Child => {
+                let _ = dup2(write.as_raw_fd(), 1);
+                drop((read, write));
+                let status = self.run_source(source);
+                let _ = io::stdout().flush();
+                std::process::exit(status);
+            }
+            ForkResult::Parent { child } => {
+                drop(write);
+                let mut output = Vec::new();
+                let mut reader = std::fs::File::from(read);
+                reader.read_to_end(&mut output)?;
+                self.last_status = exit_code(waitpid(child, None)?);
+                let mut text = String::from_utf8_lossy(&output).into_owned();
+                while text.ends_with('\n') {
+                    text.pop();
+                }
+                Ok(text)
+            }
+        }
+    }
+}
+
+/// Whether `word` expands to itself, as most words in scripts do: it has
+/// no quotes, escapes, expansions, tilde or glob characters. Such a word
+/// is copied as it is, without going through `expand_into`.
+fn is_literal(word: &str) -> bool {
+    !word.is_empty() && !word.starts_with('~') && !word.contains(['\'', '"', '\\', '$', '`', '*', '?', '['])
+}
+
+/// How glob results are sorted: byte by byte, as in the C locale, unless
+/// `collate` asks for the collation order of the current locale; and with
+/// `numeric`, runs of digits compare by their value, as in a version sort,
+/// so that `file9` comes before `file10`.
+#[derive(Clone, Copy)]
+struct GlobOrder {
+    collate: bool,
+    numeric: bool,
+}
+
+impl GlobOrder {
+    fn compare(self, a: &str, b: &str) -> std::cmp::Ordering {
+        if !self.numeric {
+            return self.compare_text(a, b);
+        }
+        let (mut a_chunks, mut b_chunks) = (digit_chunks(a), digit_chunks(b));
+        loop {
+            let ordering = match (a_chunks.next(), b_chunks.next()) {
+                (None, None) => return self.compare_text(a, b),
+                (None, Some(_)) => return std::cmp::Ordering::Less,
+                (Some(_), None) => return std::cmp::Ordering::Greater,
+                (Some(x), Some(y)) if x.starts_with(|c: char| c.is_ascii_digit()) && y.starts_with(|c: char| c.is_ascii_digit()) => {
+                    let (x_value, y_value) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                    x_value.len().cmp(&y_value.len()).then_with(|| x_value.cmp(y_value))
+                }
+                (Some(x), Some(y)) => self.compare_text(x, y),
+            };
+            if ordering.is_ne() {
+                return ordering;
+            }
+        }
+    }
+
+    /// Compare without regard to numbers: by collation or by bytes. Names
+    /// that collate equal still go in byte order, so the order is always
+    /// the same.
+    fn compare_text(self, a: &str, b: &str) -> std::cmp::Ordering {
+        if self.collate
+            && let (Ok(x), Ok(y)) = (CString::new(a), CString::new(b))
+        {
+            let ordering = unsafe { libc::strcoll(x.as_ptr(), y.as_ptr()) }.cmp(&0);
+            if ordering.is_ne() {
+                return ordering;
+            }
+        }
+        a.cmp(b)
+    }
+
+    fn sort(self, names: &mut [String]) {
+        names.sort_by(|a, b| self.compare(a, b));
+    }
+}
+
+/// `text` split into runs of ASCII digits and runs of anything else.
+fn digit_chunks(text: &str) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        let digits = rest.starts_with(|c: char| c.is_ascii_digit());
+        let end = rest.find(|c: char| c.is_ascii_digit() != digits).unwrap_or(rest.len());
+        let (chunk, after) = rest.split_at(end);
+        rest = after;
+        (!chunk.is_empty()).then_some(chunk)
+    })
+}
+
+/// Expand a glob pattern against the filesystem, returning the matches in
+/// `order`, or nothing if no path matches. With `globstar`, a `**`
+/// component matches any number of directories.
+fn glob(pattern: &str, globstar: bool, order: GlobOrder) -> Vec<String> {
+    let mut paths = vec![if pattern.starts_with('/') { "/".to_string() } else { String::new() }];
+    let components: Vec<&str> = pattern.split('/').filter(|component| !component.is_empty()).collect();
+    for (n, component) in components.iter().enumerate() {
+        let mut next = Vec::new();
+        for base in &paths {
+            let join = |name: &str| join_path(base, name);
+            if globstar && *component == "**" {
+                if !base.is_empty() || n + 1 < components.len() {
+                    next.push(base.clone());
+                }
+                descendants(base, &mut next, order);
+                continue;
+            }
+            if !has_glob_chars(component) {
+                let path = join(&unescape(component));
+                if std::fs::symlink_metadata(&path).is_ok() {
+                    next.push(path);
+                }
+                continue;
+            }
+            let dir = if base.is_empty() { "." } else { base.as_str() };
+            let Ok(entries) = std::fs::read_dir(dir) else { continue };
+            let mut names: Vec<String> = entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| !name.starts_with('.') || component.starts_with('.'))
+                .filter(|name| fnmatch(component, name))
+                .collect();
+            order.sort(&mut names);
+            next.extend(names.iter().map(|name| join(name)));
+        }
+        paths = next;
+    }
+    if globstar && components.contains(&"**") {
+        order.sort(&mut paths);
+        paths.dedup();
+    }
+    if pattern.ends_with('/') {
+        paths.retain(|path| std::path::Path::new(path).is_dir());
+        paths.iter_mut().for_each(|path| path.push('/'));
+    }
+    paths
+}
+
+/// `name` inside directory `base`, where an empty `base` is the current
+/// directory.
+pub(crate) fn join_path(base: &str, name: &str) -> String {
+    if base.is_empty() || base.ends_with('/') {
+        format!("{}{}", base, name)
+    } else {
+        format!("{}/{}", base, name)
+    }
+}
+
+/// Append everything under directory `base` that is not hidden, in
+/// `order` with each directory followed by its contents.
+fn descendants(base: &str, paths: &mut Vec<String>, order: GlobOrder) {
+    let dir = if base.is_empty() { "." } else { base };
+    let Ok(entries) = std::fs::read_dir(dir) else { return };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| !name.starts_with('.'))
+        .collect();
+    order.sort(&mut names);
+    for name in names {
+        let path = join_path(base, &name);
+        paths.push(path.clone());
+        if std::fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir()) {
+            descendants(&path, paths, order);
+        }
+    }
+}
+
+/// Whether a pattern contains an unescaped `*`, `?`, or `[`.
+fn has_glob_chars(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Remove the backslash escapes from a pattern.
+fn unescape(pattern: &str) -> String {
+    let mut text = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            text.extend(chars.next());
+        } else {
+            text.push(c);
+        }
+    }
+    text
+}
+
+/// Match `text` against a shell pattern with `*`, `?`, `[...]` classes,
+/// and backslash escapes.
+pub(crate) fn fnmatch(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_from(&pattern, &text)
+}
+
+fn match_from(pattern: &[char], text: &[char]) -> bool {
+    let Some(&first) = pattern.first() else {
+        return text.is_empty();
+    };
+    match first {
+        '*' => (0..=text.len()).any(|skip| match_from(&pattern[1..], &text[skip..])),
+        '?' => !text.is_empty() && match_from(&pattern[1..], &text[1..]),
+        '[' => match (text.first(), match_class(&pattern[1..], text.first().copied())) {
+            (Some(_), Some((true, len))) => match_from(&pattern[len + 1..], &text[1..]),
+            (_, Some((false, _))) | (None, _) => false,
+            // An unterminated `[` matches itself.
+            (Some(&c), None) => c == '[' && match_from(&pattern[1..], &text[1..]),
+        },
+        '\\' if pattern.len() > 1 => text.first() == Some(&pattern[1]) && match_from(&pattern[2..], &text[1..]),
+        c => text.first() == Some(&c) && match_from(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Match one character against the bracket expression that follows a `[`.
+/// Returns whether it matched and how many pattern characters the class
+/// used (including the closing `]`), or `None` if the class is unterminated.
+fn match_class(class: &[char], c: Option<char>) -> Option<(bool, usize)> {
+    let negated = matches!(class.first(), Some('!') | Some('^'));
+    let mut i = usize::from(negated);
+    let mut matched = false;
+    let mut first = true;
+    while i < class.len() {
+        if class[i] == ']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+        let low = class[i];
+        if i + 2 < class.len() && class[i + 1] == '-' && class[i + 2] != ']' {
+            matched |= c.is_some_and(|c| low <= c && c <= class[i + 2]);
+            i += 3;
+        } else {
+            matched |= c == Some(low);
+            i += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell() -> Shell {
+        let mut shell = Shell::new();
+        shell.vars.remove("IFS");
+        shell.set_var("a", "one");
+        shell.set_var("spaced", "x  y\tz");
+        shell.set_var("HOME", "/home/test");
+        shell.positional = vec!["p 1".to_string(), "p2".to_string()];
+        shell
+    }
+
+    fn expand(shell: &mut Shell, source: &str) -> Vec<String> {
+        let words: Vec<Cow<str>> = crate::lexer::tokenize(source).iter().map(|token| Cow::Borrowed(token.text)).collect();
+        shell.expand_words(&words).unwrap()
+    }
+
+    #[test]
+    fn expands_parameters() {
+        let mut shell = shell();
+        assert_eq!(expand(&mut shell, "$a ${a}b $1 $# $unset x"), ["one", "oneb", "p", "1", "2", "x"]);
+        shell.last_status = 3;
+        assert_eq!(expand(&mut shell, "$? $"), ["3", "$"]);
+    }
+
+    #[test]
+    fn removes_quotes() {
+        let mut shell = shell();
+        assert_eq!(expand(&mut shell, "'$a' \"$a b\" a\\ b \"\" \"\\$a\\q\""), ["$a", "one b", "a b", "", "$a\\q"]);
+    }
+
+    #[test]
+    fn splits_unquoted_expansions_on_ifs() {
+        let mut shell = shell();
+        assert_eq!(expand(&mut shell, "$spaced \"$spaced\""), ["x", "y", "z", "x  y\tz"]);
+        shell.set_var("IFS", ":");
+        shell.set_var("path", "/bin:/usr/bin");
+        assert_eq!(expand(&mut shell, "$path"), ["/bin", "/usr/bin"]);
+        // An unquoted expansion to nothing leaves no field at all.
+        assert_eq!(expand(&mut shell, "$unset"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn keeps_positional_parameters_apart_in_quoted_at() {
+        let mut shell = shell();
+        assert_eq!(expand(&mut shell, "\"$@\""), ["p 1", "p2"]);
+        assert_eq!(expand(&mut shell, "$@"), ["p", "1", "p2"]);
+        assert_eq!(expand(&mut shell, "\"$*\""), ["p 1 p2"]);
+    }
+
+    #[test]
+    fn expands_tildes_at_the_start() {
+        let mut shell = shell();
+        assert_eq!(expand(&mut shell, "~ ~/bin a~ \"~\""), ["/home/test", "/home/test/bin", "a~", "~"]);
+    }
+
+    #[test]
+    fn expands_single_words_without_splitting() {
+        let mut shell = shell();
+        assert_eq!(shell.expand_single("$spaced*").unwrap(), "x  y\tz*");
+        assert_eq!(shell.expand_single("plain").unwrap(), "plain");
+        assert_eq!(shell.expand_pattern("\"*\"$a*").unwrap(), "\\*one*");
+    }
+
+    #[test]
+    fn reports_unset_variables_with_nounset() {
+        let mut shell = shell();
+        shell.set_option("nounset", true);
+        let error = shell.expand_single("$missing").unwrap_err();
+        assert!(error.downcast_ref::<FatalError>().is_some());
+        assert!(error.to_string().ends_with("missing: unbound variable"), "{}", error);
+    }
+
+    #[test]
+    fn globs_unquoted_patterns() {
+        let dir = std::env::temp_dir().join(format!("vssh-glob-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in ["b.txt", "a.txt", ".hidden.txt", "c.log", "sub/d.txt"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let dir_name = dir.to_str().unwrap();
+        let mut shell = shell();
+        let matches = |shell: &mut Shell, pattern: &str| {
+            expand(shell, &format!("{}/{}", dir_name, pattern)).iter().map(|path| path[dir_name.len() + 1..].to_string()).collect::<Vec<_>>()
+        };
+        assert_eq!(matches(&mut shell, "*.txt"), ["a.txt", "b.txt"]);
+        assert_eq!(matches(&mut shell, "[ab].t?t"), ["a.txt", "b.txt"]);
+        assert_eq!(matches(&mut shell, "'*'.txt"), ["*.txt"]);
+        assert_eq!(matches(&mut shell, "*.none"), ["*.none"]);
+        shell.set_option("globstar", true);
+        assert_eq!(matches(&mut shell, "**/*.txt"), ["a.txt", "b.txt", "sub/d.txt"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn matches_patterns() {
+        assert!(fnmatch("a*c", "abbc"));
+        assert!(fnmatch("?[0-9]", "x7"));
+        assert!(fnmatch("[!a]b", "cb"));
+        assert!(!fnmatch("[!a]b", "ab"));
+        assert!(fnmatch("\\*", "*"));
+        assert!(!fnmatch("\\*", "x"));
+        assert_eq!(join_path("", "a"), "a");
+        assert_eq!(join_path("/", "a"), "/a");
+        assert_eq!(join_path("dir", "a"), "dir/a");
+    }
+
+    #[test]
+    fn copies_literal_words_as_they_are() {
+        assert!(is_literal("ls") && is_literal("--color=auto"));
+        assert!(!is_literal("$x") && !is_literal("~") && !is_literal("*.rs") && !is_literal("'a'") && !is_literal(""));
+    }
+
+    #[test]
+    fn nounset_allows_set_variables_and_the_positional_list() {
+        let mut shell = shell();
+        shell.set_option("nounset", true);
+        shell.set_var("empty", "");
+        assert_eq!(expand(&mut shell, "$a \"$empty\" \"$@\" $# $?"), ["one", "", "p 1", "p2", "2", "0"]);
+        assert!(shell.expand_single("$3").unwrap_err().to_string().ends_with("3: unbound variable"));
+        assert!(shell.expand_single("x${missing}").is_err());
+    }
+
+    #[test]
+    fn sorts_glob_results_in_byte_or_numeric_order() {
+        let sorted = |numeric: bool, names: &[&str]| {
+            let mut names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+            GlobOrder { collate: false, numeric }.sort(&mut names);
+            names
+        };
+        assert_eq!(sorted(false, &["b", "file9", "B", "a", "_x", "file10"]), ["B", "_x", "a", "b", "file10", "file9"]);
+        assert_eq!(sorted(true, &["file10", "file9", "file09", "v1.10", "v1.9", "x"]), ["file09", "file9", "file10", "v1.9", "v1.10", "x"]);
+        let mut shell = shell();
+        let dir = crate::tests::temp_path("globsort");
+        std::fs::create_dir(&dir).unwrap();
+        for name in ["log10", "log2", "Log1"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let pattern = format!("{}/*", dir.display());
+        let names = |shell: &mut Shell| -> Vec<String> {
+            expand(shell, &pattern).iter().map(|path| path.rsplit('/').next().unwrap().to_string()).collect()
+        };
+        assert_eq!(names(&mut shell), ["Log1", "log10", "log2"]);
+        shell.set_option("numericglobsort", true);
+        assert_eq!(names(&mut shell), ["Log1", "log2", "log10"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+//! The directories an interactive shell has been to, scored by how often
+//! and how lately, for `j` to jump to by part of their name. The file they
+//! are kept in has a line `path|rank|time` for each, as `z` writes.
+
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// When ranks add up to more than this, they are all scaled down, and
+/// directories left with a rank below 1 are forgotten.
+const MAX_TOTAL_RANK: f64 = 9000.0;
+
+/// A directory and how it has been used.
+pub(crate) struct Visit {
+    pub(crate) path: String,
+    /// How many times it has been entered, less the ageing of all ranks.
+    rank: f64,
+    /// When it was last entered, in seconds since the epoch.
+    time: u64,
+}
+
+impl Visit {
+    /// `rank` weighted by how recently the directory was entered.
+    pub(crate) fn score(&self, now: u64) -> f64 {
+        let age = now.saturating_sub(self.time);
+        let weight = match age {
+            0..3600 => 4.0,
+            3600..86400 => 2.0,
+            86400..604800 => 0.5,
+            _ => 0.25,
+        };
+        self.rank * weight
+    }
+}
+
+/// The current time in seconds since the epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// The directories saved in the file at `path`; none if it does not exist.
+/// Lines that cannot be read are skipped.
+pub(crate) fn load(path: &str) -> io::Result<Vec<Visit>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(text
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.rsplitn(3, '|');
+            let time = fields.next()?.parse().ok()?;
+            let rank = fields.next()?.parse().ok()?;
+            Some(Visit { path: fields.next()?.to_string(), rank, time })
+        })
+        .collect())
+}
+
+/// Save `visits` to the file at `path`.
+pub(crate) fn save(path: &str, visits: &[Visit]) -> io::Result<()> {
+    let mut out = String::new();
+    for visit in visits {
+        out.push_str(&format!("{}|{}|{}\n", visit.path, visit.rank, visit.time));
+    }
+    std::fs::File::create(path)?.write_all(out.as_bytes())
+}
+
+/// Count a visit to `dir` now in the file at `path`.
+pub(crate) fn record(path: &str, dir: &str) -> io::Result<()> {
+    let mut visits = load(path)?;
+    let now = now();
+    match visits.iter_mut().find(|visit| visit.path == dir) {
+        Some(visit) => {
+            visit.rank += 1.0;
+            visit.time = now;
+        }
+        None => visits.push(Visit { path: dir.to_string(), rank: 1.0, time: now }),
+    }
+    if visits.iter().map(|visit| visit.rank).sum::<f64>() > MAX_TOTAL_RANK {
+        for visit in &mut visits {
+            visit.rank *= 0.99;
+        }
+        visits.retain(|visit| visit.rank >= 1.0);
+    }
+    save(path, &visits)
+}
+
+/// Whether `path` contains each of `terms` in turn. With `fold`, case is
+/// ignored.
+pub(crate) fn matches(path: &str, terms: &[String], fold: bool) -> bool {
+    let path = if fold { path.to_lowercase() } else { path.to_string() };
+    let mut rest = path.as_str();
+    terms.iter().all(|term| {
+        let term = if fold { term.to_lowercase() } else { term.clone() };
+        match rest.find(&term) {
+            Some(index) => {
+                rest = &rest[index + term.len()..];
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores_visits_by_rank_and_recency() {
+        let visit = |rank, time| Visit { path: String::new(), rank, time };
+        let now = 10_000_000;
+        assert_eq!(visit(3.0, now - 10).score(now), 12.0);
+        assert_eq!(visit(3.0, now - 7200).score(now), 6.0);
+        assert_eq!(visit(3.0, now - 2 * 86400).score(now), 1.5);
+        assert_eq!(visit(3.0, now - 30 * 86400).score(now), 0.75);
+    }
+
+    #[test]
+    fn records_visits_in_the_file() {
+        let path = crate::tests::temp_path("frecency");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "/a|b|c|5|100\nnot a line\n").unwrap();
+        record(path, "/x").unwrap();
+        record(path, "/a|b|c").unwrap();
+        record(path, "/x").unwrap();
+        let visits = load(path).unwrap();
+        let ranks: Vec<(&str, f64)> = visits.iter().map(|visit| (visit.path.as_str(), visit.rank)).collect();
+        assert_eq!(ranks, [("/a|b|c", 6.0), ("/x", 2.0)]);
+        assert!(visits.iter().all(|visit| visit.time + 60 > now()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn matches_terms_in_order() {
+        let terms = |terms: &[&str]| terms.iter().map(|term| term.to_string()).collect::<Vec<_>>();
+        assert!(matches("/home/me/src/vssh", &terms(&["src", "vs"]), false));
+        assert!(!matches("/home/me/src/vssh", &terms(&["vs", "src"]), false));
+        assert!(!matches("/home/me/Src", &terms(&["src"]), false));
+        assert!(matches("/home/me/Src", &terms(&["src"]), true));
+    }
+}
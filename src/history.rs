@@ -0,0 +1,175 @@
+//! The command lines typed at an interactive shell's prompt, kept for the
+//! line editor to recall and saved to a file between sessions.
+
+use std::io::{self, Write};
+use crate::expand::fnmatch;
+
+/// Which lines the history leaves out, and how many it keeps.
+pub(crate) struct HistoryRules {
+    /// Leave out a line that repeats the one before it.
+    pub(crate) ignore_dups: bool,
+    /// Drop older copies of a line when it is added again.
+    pub(crate) ignore_all_dups: bool,
+    /// Leave out lines that start with a space.
+    pub(crate) ignore_space: bool,
+    /// Patterns, from `$HISTIGNORE`, for lines to leave out; `&` stands
+    /// for the line before.
+    pub(crate) patterns: Vec<String>,
+    /// How many lines are kept, the oldest being dropped first.
+    pub(crate) size: usize,
+}
+
+/// The lines, oldest first.
+#[derive(Default)]
+pub(crate) struct History {
+    entries: Vec<String>,
+    /// How many of the last entries were added in this session rather than
+    /// loaded, and so have yet to be saved.
+    added: usize,
+}
+
+impl History {
+    pub(crate) fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// Add `line` unless `rules` leave it out.
+    pub(crate) fn add(&mut self, line: &str, rules: &HistoryRules) {
+        let previous = self.entries.last().map(String::as_str);
+        if line.trim().is_empty()
+            || (rules.ignore_space && line.starts_with(' '))
+            || (rules.ignore_dups && previous == Some(line))
+            || rules.patterns.iter().any(|pattern| match pattern.as_str() {
+                "&" => previous == Some(line),
+                pattern => fnmatch(pattern, line),
+            })
+        {
+            return;
+        }
+        if rules.ignore_all_dups {
+            let mut index = 0;
+            while index < self.entries.len() {
+                if self.entries[index] == line {
+                    self.remove(index);
+                } else {
+                    index += 1;
+                }
+            }
+        }
+        self.entries.push(line.to_string());
+        self.added += 1;
+        while self.entries.len() > rules.size {
+            self.remove(0);
+        }
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.entries.remove(index);
+        if index + self.added > self.entries.len() {
+            self.added -= 1;
+        }
+    }
+
+    /// Forget every line.
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.added = 0;
+    }
+
+    /// Add the lines saved in the file at `path`, as already saved.
+    pub(crate) fn load(&mut self, path: &str) -> io::Result<()> {
+        self.entries.extend(read_file(path)?);
+        Ok(())
+    }
+
+    /// Save the lines added in this session to the file at `path`, after
+    /// those saved there since it was loaded, by other shells too. `rules`
+    /// apply to the whole file, so that, for instance, a line saved before
+    /// that is now ignored is dropped from it.
+    pub(crate) fn save(&mut self, path: &str, rules: &HistoryRules) -> io::Result<()> {
+        let saved = match read_file(path) {
+            Ok(lines) => lines,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let mut merged = History::default();
+        for line in saved.iter().chain(&self.entries[self.entries.len() - self.added..]) {
+            merged.add(line, rules);
+        }
+        let mut out = String::new();
+        for line in &merged.entries {
+            out.push_str(&line.replace('\n', "\\\n"));
+            out.push('\n');
+        }
+        std::fs::File::create(path)?.write_all(out.as_bytes())?;
+        self.added = 0;
+        Ok(())
+    }
+}
+
+/// The lines in a history file, where a line ending with a backslash
+/// goes on to the next, as lines of a command typed over several do.
+fn read_file(path: &str) -> io::Result<Vec<String>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut lines: Vec<String> = Vec::new();
+    let mut continued = false;
+    for line in text.lines() {
+        let (line, continues) = match line.strip_suffix('\\') {
+            Some(line) => (line, true),
+            None => (line, false),
+        };
+        match lines.last_mut() {
+            Some(last) if continued => {
+                last.push('\n');
+                last.push_str(line);
+            }
+            _ => lines.push(line.to_string()),
+        }
+        continued = continues;
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> HistoryRules {
+        HistoryRules { ignore_dups: false, ignore_all_dups: false, ignore_space: false, patterns: Vec::new(), size: 100 }
+    }
+
+    fn added(lines: &[&str], rules: &HistoryRules) -> Vec<String> {
+        let mut history = History::default();
+        lines.iter().for_each(|line| history.add(line, rules));
+        history.entries
+    }
+
+    #[test]
+    fn leaves_out_what_the_rules_say() {
+        let lines = ["ls", "ls", " secret", "cd /", "ls", "exit", ""];
+        assert_eq!(added(&lines, &rules()), ["ls", "ls", " secret", "cd /", "ls", "exit"]);
+        assert_eq!(added(&lines, &HistoryRules { ignore_dups: true, ..rules() }), ["ls", " secret", "cd /", "ls", "exit"]);
+        assert_eq!(added(&lines, &HistoryRules { ignore_all_dups: true, ..rules() }), [" secret", "cd /", "ls", "exit"]);
+        assert_eq!(added(&lines, &HistoryRules { ignore_space: true, ..rules() }), ["ls", "ls", "cd /", "ls", "exit"]);
+        let patterns = vec!["&".to_string(), "exit".to_string(), "cd *".to_string()];
+        assert_eq!(added(&lines, &HistoryRules { patterns, ..rules() }), ["ls", " secret", "ls"]);
+        assert_eq!(added(&lines, &HistoryRules { size: 2, ..rules() }), ["ls", "exit"]);
+    }
+
+    #[test]
+    fn saves_after_lines_other_shells_saved() {
+        let path = crate::tests::temp_path("history");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "old\nfor x in a\\\nb; do :; done\n").unwrap();
+        let mut history = History::default();
+        history.load(path).unwrap();
+        assert_eq!(history.entries(), ["old", "for x in a\nb; do :; done"]);
+        history.add("mine", &rules());
+        std::fs::write(path, "old\nfor x in a\\\nb; do :; done\nother\nmine\n").unwrap();
+        history.save(path, &HistoryRules { ignore_all_dups: true, ..rules() }).unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "old\nfor x in a\\\nb; do :; done\nother\nmine\n");
+        history.save(path, &HistoryRules { patterns: vec!["old".to_string()], ..rules() }).unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "for x in a\\\nb; do :; done\nother\nmine\n");
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,1206 @@
+//! Job control and signal handling.
+
+use std::io::{self, Write};
+use nix::errno::Errno;
+use nix::unistd::{Pid, dup2};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use anyhow::Result;
+use crate::Shell;
+use crate::ast::{Launch, Redirect};
+use crate::exec::{apply_redirect, exit_code, install_fd};
+
+/// Set by the SIGINT handler; checked by loops so Ctrl-C stops them.
+pub(crate) static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) extern "C" fn on_sigint(_: i32) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Set by the SIGCHLD handler; tells the shell there are children to reap.
+pub(crate) static CHILD_EXITED: AtomicBool = AtomicBool::new(false);
+
+/// Besides setting `CHILD_EXITED`, the handler interrupts the read of a
+/// command line so the `notify` option can report finished jobs at once.
+pub(crate) extern "C" fn on_sigchld(_: i32) {
+    CHILD_EXITED.store(true, Ordering::SeqCst);
+}
+
+/// Install the SIGCHLD handler. An interactive shell wants it to interrupt
+/// reads; otherwise interrupted system calls are restarted.
+pub(crate) fn install_sigchld_handler(interrupt: bool) {
+    let flags = if interrupt { SaFlags::empty() } else { SaFlags::SA_RESTART };
+    let action = SigAction::new(SigHandler::Handler(on_sigchld), flags, SigSet::empty());
+    if let Err(e) = unsafe { sigaction(Signal::SIGCHLD, &action) } {
+        eprintln!("vssh: cannot install SIGCHLD handler: {}", e);
+    }
+}
+
+/// Signals that have arrived with a trap set but whose trap has not run
+/// yet, one bit per signal number. Traps run between commands, never in
+/// the handler itself.
+pub(crate) static PENDING_TRAPS: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) extern "C" fn on_trapped_signal(signal: i32) {
+    PENDING_TRAPS.fetch_or(1 << signal, Ordering::SeqCst);
+    if signal == Signal::SIGCHLD as i32 {
+        CHILD_EXITED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// What `timeout` kills when its timer expires: a process id, or minus a
+/// process group id. Zero when no timer is armed.
+pub(crate) static TIMEOUT_TARGET: AtomicI32 = AtomicI32::new(0);
+
+/// The signal `timeout` sends first.
+pub(crate) static TIMEOUT_SIGNAL: AtomicI32 = AtomicI32::new(libc::SIGTERM);
+
+/// Seconds `timeout` waits after that signal before sending SIGKILL.
+pub(crate) static TIMEOUT_KILL_AFTER: AtomicI32 = AtomicI32::new(0);
+
+/// How many signals the expired timer has sent.
+pub(crate) static TIMEOUT_SENT: AtomicI32 = AtomicI32::new(0);
+
+/// Kill the target of `timeout` when its timer expires, first with its
+/// signal, then, if it is still running after the grace period, with
+/// SIGKILL. Only async-signal-safe calls are made.
+pub(crate) extern "C" fn on_timeout(_: i32) {
+    let target = TIMEOUT_TARGET.load(Ordering::SeqCst);
+    if target == 0 {
+        return;
+    }
+    if TIMEOUT_SENT.fetch_add(1, Ordering::SeqCst) == 0 {
+        unsafe { libc::kill(target, TIMEOUT_SIGNAL.load(Ordering::SeqCst)) };
+        let kill_after = TIMEOUT_KILL_AFTER.load(Ordering::SeqCst);
+        if kill_after > 0 {
+            unsafe { libc::alarm(kill_after as u32) };
+        }
+    } else {
+        unsafe { libc::kill(target, libc::SIGKILL) };
+    }
+}
+
+/// Set by the SIGWINCH handler: an interactive shell then redraws the line
+/// being edited, and a recording session passes the terminal's new size on
+/// to the pseudo-terminal.
+pub(crate) static WINDOW_RESIZED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) extern "C" fn on_sigwinch(_: i32) {
+    WINDOW_RESIZED.store(true, Ordering::SeqCst);
+}
+
+/// Returns whether Ctrl-C was pressed since the last call.
+pub(crate) fn take_interrupt() -> bool {
+    INTERRUPTED.swap(false, Ordering::SeqCst)
+}
+
+/// A pipeline the shell started, while it runs in the background or is
+/// stopped (or, briefly, while the shell waits for it in the foreground).
+pub(crate) struct Job {
+    /// The `n` in `%n` job specifications.
+    id: usize,
+    /// The job's process group, led by its first process, when job
+    /// control is on.
+    pgid: Option<Pid>,
+    /// Each process in the pipeline, with its exit status once it ends.
+    processes: Vec<(Pid, Option<i32>)>,
+    /// The command line, as shown by `jobs`.
+    command: String,
+    state: JobState,
+    /// The terminal settings the job was using when it was stopped.
+    tty_modes: Option<nix::sys::termios::Termios>,
+    /// The signal that killed the last process, and whether it dumped core.
+    killed_by: Option<(Signal, bool)>,
+    /// Set by `disown -h`: the job is not sent SIGHUP when the shell exits.
+    keep_on_exit: bool,
+    /// A pidfd for each process, opened when the shell first waits for
+    /// input with the job in the background; it becomes readable when the
+    /// process ends.
+    pidfds: Vec<(Pid, OwnedFd)>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum JobState {
+    Running,
+    Stopped,
+    /// Every process has ended; holds the last one's exit status.
+    Done(i32),
+}
+
+impl Job {
+    /// Note a wait status reported for one of the job's processes.
+    fn record(&mut self, pid: Pid, wait_status: WaitStatus) {
+        match wait_status {
+            WaitStatus::StillAlive => return,
+            WaitStatus::Stopped(..) => self.state = JobState::Stopped,
+            WaitStatus::Continued(_) => self.state = JobState::Running,
+            _ => {
+                if let Some((_, status)) = self.processes.iter_mut().find(|(process, _)| *process == pid) {
+                    *status = Some(exit_code(wait_status));
+                }
+                if let WaitStatus::Signaled(_, signal, core_dumped) = wait_status
+                    && self.processes.last().is_some_and(|(last, _)| *last == pid)
+                {
+                    self.killed_by = Some((signal, core_dumped));
+                }
+            }
+        }
+        if let Some(statuses) = self.processes.iter().map(|(_, status)| *status).collect::<Option<Vec<_>>>() {
+            self.state = JobState::Done(statuses.last().copied().unwrap_or(0));
+        }
+    }
+
+    /// The job's exit status: that of its last process, or with `pipefail`
+    /// that of the last process to fail.
+    fn exit_status(&self, pipefail: bool) -> i32 {
+        let mut statuses = self.processes.iter().rev().map(|(_, status)| status.unwrap_or(0));
+        if pipefail {
+            statuses.find(|&status| status != 0).unwrap_or(0)
+        } else {
+            statuses.next().unwrap_or(0)
+        }
+    }
+
+    /// Processes that have not ended yet.
+    fn pending(&self) -> Vec<Pid> {
+        self.processes.iter().filter(|(_, status)| status.is_none()).map(|(pid, _)| *pid).collect()
+    }
+
+    /// The command as `jobs` shows it, marked with `&` while running.
+    fn display_command(&self) -> String {
+        match self.state {
+            JobState::Running => format!("{} &", self.command),
+            _ => self.command.clone(),
+        }
+    }
+
+    /// The status column of `jobs`.
+    fn describe(&self) -> String {
+        match self.state {
+            JobState::Running => "Running".to_string(),
+            JobState::Stopped => "Stopped".to_string(),
+            JobState::Done(_) if self.killed_by.is_some() => {
+                let (signal, core_dumped) = self.killed_by.unwrap();
+                let core = if core_dumped { " (core dumped)" } else { "" };
+                format!("{}{}", signal_description(signal as i32), core)
+            }
+            JobState::Done(0) => "Done".to_string(),
+            JobState::Done(status) => format!("Exit {}", status),
+        }
+    }
+}
+
+/// What `Shell::wait_for_input` returned for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Wakeup {
+    /// Standard input has something to read, or is at its end.
+    Input,
+    /// A process of a background job has ended.
+    JobEnded,
+    /// A signal interrupted the wait: Ctrl-C, a trapped signal, a child
+    /// stopping, or a resized window.
+    Signal,
+    /// The deadline passed first.
+    Timeout,
+}
+
+impl Shell {
+    /// Wait for whatever an interactive shell reading a command has to
+    /// react to first: input, the end of a background process, a signal,
+    /// or `deadline`. Processes are watched through pidfds in the same
+    /// `poll` as the input, so one that ends just before the wait still
+    /// wakes it, which a flag set by the SIGCHLD handler cannot promise.
+    pub(crate) fn wait_for_input(&mut self, deadline: Option<std::time::Instant>) -> Wakeup {
+        use nix::poll::{poll, PollFd, PollFlags};
+        let mut watched = Vec::new();
+        for job in self.jobs.iter_mut().filter(|job| job.state == JobState::Running) {
+            for pid in job.pending() {
+                if !job.pidfds.iter().any(|(process, _)| *process == pid)
+                    && let Some(pidfd) = pidfd_open(pid)
+                {
+                    job.pidfds.push((pid, pidfd));
+                }
+            }
+            let pending = job.pending();
+            watched.extend(job.pidfds.iter().filter(|(pid, _)| pending.contains(pid)).map(|(_, pidfd)| pidfd.as_raw_fd()));
+        }
+        let mut fds = vec![PollFd::new(0, PollFlags::POLLIN)];
+        fds.extend(watched.into_iter().map(|fd| PollFd::new(fd, PollFlags::POLLIN)));
+        let timeout = deadline.map_or(-1, |deadline| {
+            let left = deadline.saturating_duration_since(std::time::Instant::now());
+            left.as_millis().min(i32::MAX as u128) as i32
+        });
+        let ready = |fd: &PollFd| fd.revents().is_some_and(|events| !events.is_empty());
+        match poll(&mut fds, timeout) {
+            Ok(0) => Wakeup::Timeout,
+            Ok(_) if fds[1..].iter().any(ready) => Wakeup::JobEnded,
+            Ok(_) => Wakeup::Input,
+            Err(Errno::EINTR) => Wakeup::Signal,
+            // Let the read that follows report the trouble.
+            Err(_) => Wakeup::Input,
+        }
+    }
+
+    /// Before an interactive shell exits, warn about stopped and running
+    /// jobs it would leave behind and list them, unless the previous
+    /// attempt to exit already did. Returns whether it warned, in which
+    /// case the shell stays.
+    pub(crate) fn warn_about_jobs(&mut self) -> bool {
+        if self.exit_warned {
+            return false;
+        }
+        self.update_jobs();
+        let left = |state: JobState| self.jobs.iter().any(|job| job.state == state && !job.keep_on_exit);
+        let kind = if left(JobState::Stopped) {
+            "stopped"
+        } else if left(JobState::Running) {
+            "running"
+        } else {
+            return false;
+        };
+        eprintln!("There are {} jobs.", kind);
+        self.builtin_jobs(&[]);
+        self.jobs_warning = true;
+        true
+    }
+
+    /// Put the shell in its own process group in control of the terminal,
+    /// and ignore the job control signals the terminal will now send to
+    /// the foreground job instead.
+    pub(crate) fn enable_job_control(&mut self) {
+        for signal in [Signal::SIGTSTP, Signal::SIGTTIN, Signal::SIGTTOU] {
+            let ignore = SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty());
+            let _ = unsafe { sigaction(signal, &ignore) };
+        }
+        let shell = nix::unistd::getpid();
+        if nix::unistd::getpgrp() != shell && nix::unistd::setpgid(shell, shell).is_err() {
+            return;
+        }
+        self.job_control = nix::unistd::tcsetpgrp(0, shell).is_ok();
+        self.tty_modes = nix::sys::termios::tcgetattr(0).ok();
+    }
+
+    /// Record a newly started pipeline in the job table, returning its index.
+    fn push_job(&mut self, command: String, processes: Vec<Pid>) -> usize {
+        let id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        let pgid = self.job_control.then(|| processes[0]);
+        let processes = processes.into_iter().map(|pid| (pid, None)).collect();
+        self.jobs.push(Job { id, pgid, processes, command, state: JobState::Running, tty_modes: None, killed_by: None, keep_on_exit: false, pidfds: Vec::new() });
+        self.jobs.len() - 1
+    }
+
+    /// Record a newly started background pipeline and announce it.
+    fn add_job(&mut self, command: String, processes: Vec<Pid>) {
+        let last = processes.last().copied();
+        let index = self.push_job(command, processes);
+        if let Some(last) = last {
+            println!("[{}] {}", self.jobs[index].id, last);
+        }
+        self.last_background = last;
+    }
+
+    /// Deal with a pipeline just started as `launch` says: wait for it in
+    /// the foreground, returning its exit status, or leave it running (in
+    /// the job table unless detached), returning 0.
+    pub(crate) fn launch_job(&mut self, command: String, processes: Vec<Pid>, launch: Launch) -> i32 {
+        match launch {
+            Launch::Foreground => self.wait_foreground(command, processes),
+            Launch::Background => {
+                self.add_job(command, processes);
+                0
+            }
+            Launch::Detached => {
+                self.last_background = processes.last().copied();
+                0
+            }
+        }
+    }
+
+    /// Wait for a pipeline just started in the foreground, returning its
+    /// exit status. If it is stopped it stays in the job table.
+    pub(crate) fn wait_foreground(&mut self, command: String, processes: Vec<Pid>) -> i32 {
+        let index = self.push_job(command, processes);
+        self.resume_foreground(index)
+    }
+
+    /// Give the terminal to a job and wait for it, then take the terminal
+    /// back. A stopped job keeps the terminal settings it had made, to be
+    /// restored when it is resumed; the shell's own are put back either
+    /// way. A job killed by SIGINT counts as Ctrl-C in the shell too, so
+    /// that loops stop, unless INT is trapped. Other fatal signals are
+    /// reported, and so are failures with the `printexitvalue` option.
+    /// Once the job ends, `$PIPESTATUS` holds the status of each process.
+    fn resume_foreground(&mut self, index: usize) -> i32 {
+        use nix::sys::termios::{tcgetattr, tcsetattr, SetArg};
+        let id = self.jobs[index].id;
+        if let Some(pgid) = self.jobs[index].pgid {
+            if let Some(modes) = &self.jobs[index].tty_modes {
+                let _ = tcsetattr(0, SetArg::TCSADRAIN, modes);
+            }
+            let _ = nix::unistd::tcsetpgrp(0, pgid);
+        }
+        let finished = self.collect_job(index);
+        if let Some(job) = &finished {
+            let statuses = job.processes.iter().map(|(_, status)| status.unwrap_or(0).to_string()).collect();
+            self.arrays.insert("PIPESTATUS".to_string(), statuses);
+        }
+        let pipefail = self.option("pipefail");
+        let status = finished.as_ref().map_or(128 + Signal::SIGTSTP as i32, |job| job.exit_status(pipefail));
+        // A signal sent by `timeout` is its business, not an interrupt.
+        let timed_out = TIMEOUT_TARGET.load(Ordering::SeqCst) != 0 && TIMEOUT_SENT.load(Ordering::SeqCst) > 0;
+        match finished.and_then(|job| job.killed_by) {
+            _ if timed_out => {}
+            Some((Signal::SIGINT | Signal::SIGPIPE, _)) => {}
+            Some((signal, core_dumped)) => {
+                eprintln!("vssh: terminated by {}{}", signal.as_str(), if core_dumped { " (core dumped)" } else { "" });
+            }
+            None if status != 0 && self.option("printexitvalue") => eprintln!("vssh: exit {}", status),
+            None => {}
+        }
+        if self.job_control {
+            let _ = nix::unistd::tcsetpgrp(0, nix::unistd::getpgrp());
+            if let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) {
+                job.tty_modes = tcgetattr(0).ok();
+            }
+            if let Some(modes) = &self.tty_modes {
+                let _ = tcsetattr(0, SetArg::TCSADRAIN, modes);
+            }
+        }
+        if status == 128 + Signal::SIGINT as i32 && !timed_out {
+            if !self.traps.contains_key("INT") {
+                INTERRUPTED.store(true, Ordering::SeqCst);
+            }
+            if self.job_control {
+                println!();
+            }
+        }
+        status
+    }
+
+    /// Reap every child that has stopped, continued or ended, without
+    /// blocking, and record the news in the job table. Children that are
+    /// in no job, such as disowned ones, are simply reaped.
+    pub(crate) fn update_jobs(&mut self) {
+        let flags = WaitPidFlag::WNOHANG | WaitPidFlag::WUNTRACED | WaitPidFlag::WCONTINUED;
+        while let Ok(wait_status) = waitpid(Pid::from_raw(-1), Some(flags)) {
+            let Some(pid) = wait_status.pid() else { break };
+            if let Some(job) = self.jobs.iter_mut().find(|job| job.processes.iter().any(|(process, _)| *process == pid)) {
+                job.record(pid, wait_status);
+            }
+        }
+    }
+
+    /// Forget the jobs that have ended since they were last looked at,
+    /// returning a `jobs`-style report line for each.
+    pub(crate) fn finished_jobs(&mut self) -> Vec<String> {
+        self.update_jobs();
+        let mut reports = Vec::new();
+        for (index, job) in self.jobs.iter().enumerate() {
+            if matches!(job.state, JobState::Done(_)) {
+                reports.push(format!("[{}]{}  {:<24}{}", job.id, self.job_marker(index), job.describe(), job.display_command()));
+            }
+        }
+        self.jobs.retain(|job| !matches!(job.state, JobState::Done(_)));
+        reports
+    }
+
+    /// Find the job named by a `%` job specification: `%n`, `%+` or `%%`
+    /// (the current job), `%-` (the previous one), `%string` (command
+    /// starts with string) or `%?string` (command contains it). Returns
+    /// its index in the job table.
+    fn find_job(&self, spec: &str) -> Result<usize, String> {
+        let Some(name) = spec.strip_prefix('%') else {
+            return Err(format!("{}: no such job", spec));
+        };
+        let found = match name {
+            "" | "+" | "%" => self.jobs.len().checked_sub(1),
+            "-" => self.jobs.len().checked_sub(2).or(self.jobs.len().checked_sub(1)),
+            _ if name.chars().all(|c| c.is_ascii_digit()) => {
+                self.jobs.iter().position(|job| name.parse() == Ok(job.id))
+            }
+            _ => {
+                let matches: Vec<usize> = match name.strip_prefix('?') {
+                    Some(text) => (0..self.jobs.len()).filter(|&i| self.jobs[i].command.contains(text)).collect(),
+                    None => (0..self.jobs.len()).filter(|&i| self.jobs[i].command.starts_with(name)).collect(),
+                };
+                if matches.len() > 1 {
+                    return Err(format!("{}: ambiguous job spec", spec));
+                }
+                matches.first().copied()
+            }
+        };
+        found.ok_or_else(|| format!("{}: no such job", spec))
+    }
+
+    /// Wait until every process of a job has ended or one of them stops.
+    /// An ended job leaves the table and its exit status is returned; a
+    /// stopped one is reported and stays, giving 128 + SIGTSTP.
+    fn wait_for_job(&mut self, index: usize) -> i32 {
+        match self.collect_job(index) {
+            Some(job) => job.exit_status(self.option("pipefail")),
+            None => 128 + Signal::SIGTSTP as i32,
+        }
+    }
+
+    /// Worker for `wait_for_job`: returns the job once it has ended and
+    /// left the table, or `None` if it stopped.
+    fn collect_job(&mut self, index: usize) -> Option<Job> {
+        let job_control = self.job_control;
+        let job = &mut self.jobs[index];
+        for pid in job.pending() {
+            match wait_for_process(pid, job_control) {
+                Ok(wait_status) => job.record(pid, wait_status),
+                Err(_) => job.record(pid, WaitStatus::Exited(pid, 0)),
+            }
+            if job.state == JobState::Stopped {
+                println!();
+                println!("[{}]+  {:<24}{}", job.id, job.describe(), job.display_command());
+                return None;
+            }
+        }
+        Some(self.jobs.remove(index))
+    }
+
+    /// `wait [-n] [pid|%job...]`: wait for the given processes or jobs and
+    /// return the last one's status, or for every job (returning 0). `-n`
+    /// waits for whichever job finishes next and returns its status.
+    pub(crate) fn builtin_wait(&mut self, args: &[String]) -> i32 {
+        self.update_jobs();
+        if args.first().is_some_and(|arg| arg == "-n") {
+            return self.wait_next();
+        }
+        if args.is_empty() {
+            while let Some(index) = self.jobs.iter().position(|job| job.state != JobState::Stopped) {
+                self.wait_for_job(index);
+            }
+            return 0;
+        }
+        let mut status = 0;
+        for arg in args {
+            if arg.starts_with('%') {
+                status = match self.find_job(arg) {
+                    Ok(index) => self.wait_for_job(index),
+                    Err(e) => {
+                        eprintln!("wait: {}", e);
+                        127
+                    }
+                };
+                continue;
+            }
+            let Ok(pid) = arg.parse::<i32>().map(Pid::from_raw) else {
+                eprintln!("wait: `{}': not a pid or valid job spec", arg);
+                status = 2;
+                continue;
+            };
+            let Some(index) = self.jobs.iter().position(|job| job.processes.iter().any(|(process, _)| *process == pid)) else {
+                eprintln!("wait: pid {} is not a child of this shell", pid);
+                status = 127;
+                continue;
+            };
+            let job_control = self.job_control;
+            let job = &mut self.jobs[index];
+            if job.pending().contains(&pid) {
+                match wait_for_process(pid, job_control) {
+                    Ok(wait_status) => job.record(pid, wait_status),
+                    Err(_) => job.record(pid, WaitStatus::Exited(pid, 0)),
+                }
+            }
+            status = job.processes.iter().find(|(process, _)| *process == pid).and_then(|(_, status)| *status).unwrap_or(0);
+            if matches!(job.state, JobState::Done(_)) {
+                self.jobs.remove(index);
+            }
+        }
+        status
+    }
+
+    /// `wait -n`: wait until some job has ended, then forget it and
+    /// return its status; 127 if there is no job to wait for.
+    fn wait_next(&mut self) -> i32 {
+        loop {
+            if let Some(index) = self.jobs.iter().position(|job| matches!(job.state, JobState::Done(_))) {
+                return self.jobs.remove(index).exit_status(self.option("pipefail"));
+            }
+            if !self.jobs.iter().any(|job| job.state == JobState::Running) {
+                return 127;
+            }
+            match wait_for_process(Pid::from_raw(-1), self.job_control) {
+                Ok(wait_status) => {
+                    let Some(pid) = wait_status.pid() else { continue };
+                    if let Some(job) = self.jobs.iter_mut().find(|job| job.pending().contains(&pid)) {
+                        job.record(pid, wait_status);
+                    }
+                }
+                Err(_) => return 127,
+            }
+        }
+    }
+
+    /// Send `signal` to a job's process group, or without job control to
+    /// every process of the job that is still running.
+    fn signal_job(&self, index: usize, signal: Signal) -> nix::Result<()> {
+        if let Some(pgid) = self.jobs[index].pgid {
+            return nix::sys::signal::killpg(pgid, signal);
+        }
+        for (pid, _) in self.jobs[index].processes.iter().filter(|(_, status)| status.is_none()) {
+            nix::sys::signal::kill(*pid, signal)?;
+        }
+        Ok(())
+    }
+
+    /// The job a builtin such as `fg` acts on: the one named by its first
+    /// argument, or the current job.
+    fn job_argument(&mut self, builtin: &str, args: &[String]) -> Option<usize> {
+        self.update_jobs();
+        let found = match args.first() {
+            Some(spec) => self.find_job(spec),
+            None if self.jobs.is_empty() => Err("no current job".to_string()),
+            None => Ok(self.jobs.len() - 1),
+        };
+        found.map_err(|e| eprintln!("{}: {}", builtin, e)).ok()
+    }
+
+    /// `fg [%job]`: continue a job in the foreground and wait for it.
+    pub(crate) fn builtin_fg(&mut self, args: &[String]) -> i32 {
+        let Some(index) = self.job_argument("fg", args) else { return 1 };
+        if let JobState::Done(status) = self.jobs[index].state {
+            eprintln!("fg: job has terminated");
+            self.jobs.remove(index);
+            return status;
+        }
+        println!("{}", self.jobs[index].command);
+        let _ = io::stdout().flush();
+        if let Err(e) = self.signal_job(index, Signal::SIGCONT) {
+            eprintln!("fg: {}", e);
+            return 1;
+        }
+        self.jobs[index].state = JobState::Running;
+        // The resumed job becomes the current one.
+        let job = self.jobs.remove(index);
+        self.jobs.push(job);
+        self.resume_foreground(self.jobs.len() - 1)
+    }
+
+    /// `bg [%job...]`: continue stopped jobs in the background.
+    pub(crate) fn builtin_bg(&mut self, args: &[String]) -> i32 {
+        let specs: Vec<Option<&String>> = if args.is_empty() { vec![None] } else { args.iter().map(Some).collect() };
+        let mut status = 0;
+        for spec in specs {
+            let Some(index) = self.job_argument("bg", spec.map(std::slice::from_ref).unwrap_or_default()) else {
+                status = 1;
+                continue;
+            };
+            match self.jobs[index].state {
+                JobState::Running => {
+                    eprintln!("bg: job {} already in background", self.jobs[index].id);
+                    continue;
+                }
+                JobState::Done(_) => {
+                    eprintln!("bg: job has terminated");
+                    status = 1;
+                    continue;
+                }
+                JobState::Stopped => {}
+            }
+            if let Err(e) = self.signal_job(index, Signal::SIGCONT) {
+                eprintln!("bg: {}", e);
+                status = 1;
+                continue;
+            }
+            self.jobs[index].state = JobState::Running;
+            let job = &self.jobs[index];
+            println!("[{}]{} {}", job.id, self.job_marker(index), job.display_command());
+        }
+        status
+    }
+
+    /// `kill [-s sig | -sig] pid|%job...` sends a signal (SIGTERM by
+    /// default) to processes or jobs; `kill -l [status...]` lists signal
+    /// names.
+    pub(crate) fn builtin_kill(&mut self, args: &[String]) -> i32 {
+        let mut signal = Some(Signal::SIGTERM);
+        let mut targets = args;
+        match targets.first().map(String::as_str) {
+            Some("-l" | "-L") => return list_signals(&targets[1..]),
+            Some("-s" | "-n") => {
+                let Some(name) = targets.get(1) else {
+                    eprintln!("kill: {}: option requires an argument", targets[0]);
+                    return 2;
+                };
+                match parse_signal(name) {
+                    Some(parsed) => signal = parsed,
+                    None => {
+                        eprintln!("kill: {}: invalid signal specification", name);
+                        return 1;
+                    }
+                }
+                targets = &targets[2..];
+            }
+            Some("--") => targets = &targets[1..],
+            Some(flag) if flag.len() > 1 && flag.starts_with('-') => {
+                match parse_signal(&flag[1..]) {
+                    Some(parsed) => signal = parsed,
+                    None => {
+                        eprintln!("kill: {}: invalid signal specification", &flag[1..]);
+                        return 1;
+                    }
+                }
+                targets = &targets[1..];
+            }
+            _ => {}
+        }
+        if targets.is_empty() {
+            eprintln!("kill: usage: kill [-s sigspec | -sigspec] pid | jobspec ... or kill -l [sigspec]");
+            return 2;
+        }
+        self.update_jobs();
+        let mut status = 0;
+        for target in targets {
+            let result = if target.starts_with('%') {
+                match self.find_job(target) {
+                    Ok(index) => self.signal_stopped_job(index, signal),
+                    Err(e) => {
+                        eprintln!("kill: {}", e);
+                        status = 1;
+                        continue;
+                    }
+                }
+            } else {
+                match target.parse::<i32>() {
+                    Ok(pid) => nix::sys::signal::kill(Pid::from_raw(pid), signal),
+                    Err(_) => {
+                        eprintln!("kill: {}: arguments must be process or job IDs", target);
+                        status = 1;
+                        continue;
+                    }
+                }
+            };
+            if let Err(e) = result {
+                eprintln!("kill: ({}) - {}", target, e.desc());
+                status = 1;
+            }
+        }
+        status
+    }
+
+    /// `disown [-h] [-a|-r] [%job...]`: forget jobs, so the shell no longer
+    /// reports or waits for them. `-a` means every job and `-r` every
+    /// running one; the default is the current job. With `-h` the jobs
+    /// stay, but are spared the SIGHUP of `huponexit`.
+    pub(crate) fn builtin_disown(&mut self, args: &[String]) -> i32 {
+        self.update_jobs();
+        let keep = args.first().is_some_and(|arg| arg == "-h");
+        let args = if keep { &args[1..] } else { args };
+        if let Some(flag @ ("-a" | "-r")) = args.first().map(String::as_str) {
+            let chosen = |job: &Job| flag == "-a" || job.state == JobState::Running;
+            if keep {
+                self.jobs.iter_mut().filter(|job| chosen(job)).for_each(|job| job.keep_on_exit = true);
+            } else {
+                self.jobs.retain(|job| !chosen(job));
+            }
+            return 0;
+        }
+        let mut ids = Vec::new();
+        let mut status = 0;
+        if args.is_empty() {
+            match self.jobs.last() {
+                Some(job) => ids.push(job.id),
+                None => {
+                    eprintln!("disown: current: no such job");
+                    status = 1;
+                }
+            }
+        }
+        for spec in args {
+            match self.find_job(spec) {
+                Ok(index) => ids.push(self.jobs[index].id),
+                Err(e) => {
+                    eprintln!("disown: {}", e);
+                    status = 1;
+                }
+            }
+        }
+        if keep {
+            self.jobs.iter_mut().filter(|job| ids.contains(&job.id)).for_each(|job| job.keep_on_exit = true);
+        } else {
+            self.jobs.retain(|job| !ids.contains(&job.id));
+        }
+        status
+    }
+
+    /// With `huponexit`, as an interactive shell exits: send SIGHUP to
+    /// every job not marked with `disown -h`, and SIGCONT to stopped ones
+    /// so that they see it.
+    pub(crate) fn hang_up_jobs(&mut self) {
+        self.update_jobs();
+        for index in 0..self.jobs.len() {
+            if !self.jobs[index].keep_on_exit && !matches!(self.jobs[index].state, JobState::Done(_)) {
+                let _ = self.signal_stopped_job(index, Some(Signal::SIGHUP));
+            }
+        }
+    }
+
+    /// Signal a job, also continuing it if it is stopped so that it sees
+    /// the signal now. `None` only checks that the job can be signalled.
+    fn signal_stopped_job(&self, index: usize, signal: Option<Signal>) -> nix::Result<()> {
+        let Some(signal) = signal else {
+            let pid = self.jobs[index].pgid.map_or(self.jobs[index].processes[0].0, |pgid| Pid::from_raw(-pgid.as_raw()));
+            return nix::sys::signal::kill(pid, None);
+        };
+        self.signal_job(index, signal)?;
+        if self.jobs[index].state == JobState::Stopped
+            && !matches!(signal, Signal::SIGCONT | Signal::SIGSTOP | Signal::SIGTSTP | Signal::SIGTTIN | Signal::SIGTTOU)
+        {
+            self.signal_job(index, Signal::SIGCONT)?;
+        }
+        Ok(())
+    }
+
+    /// The `+` or `-` shown after the current and previous job numbers.
+    fn job_marker(&self, index: usize) -> char {
+        match self.jobs.len() - index {
+            1 => '+',
+            2 => '-',
+            _ => ' ',
+        }
+    }
+
+    /// `jobs [-l|-p] [%job...]`: list background jobs and their state,
+    /// with process ids (`-l`) or only the process group leaders (`-p`).
+    /// Finished jobs are forgotten once they have been listed.
+    pub(crate) fn builtin_jobs(&mut self, args: &[String]) -> i32 {
+        let mut long = false;
+        let mut pids_only = false;
+        let mut specs = args;
+        while let Some(flag) = specs.first().filter(|arg| arg.starts_with('-') && arg.len() > 1) {
+            for c in flag[1..].chars() {
+                match c {
+                    'l' => long = true,
+                    'p' => pids_only = true,
+                    _ => {
+                        eprintln!("jobs: -{}: invalid option", c);
+                        return 2;
+                    }
+                }
+            }
+            specs = &specs[1..];
+        }
+        self.update_jobs();
+        let mut selected = Vec::new();
+        let mut status = 0;
+        for spec in specs {
+            match self.find_job(spec) {
+                Ok(index) => selected.push(index),
+                Err(e) => {
+                    eprintln!("jobs: {}", e);
+                    status = 1;
+                }
+            }
+        }
+        if specs.is_empty() {
+            selected = (0..self.jobs.len()).collect();
+        }
+        for &index in &selected {
+            let job = &self.jobs[index];
+            let leader = job.processes[0].0;
+            if pids_only {
+                println!("{}", leader);
+            } else if long {
+                println!("[{}]{} {:>6} {:<22}{}", job.id, self.job_marker(index), leader, job.describe(), job.display_command());
+            } else {
+                println!("[{}]{}  {:<24}{}", job.id, self.job_marker(index), job.describe(), job.display_command());
+            }
+        }
+        let mut index = 0;
+        self.jobs.retain(|job| {
+            index += 1;
+            !(selected.contains(&(index - 1)) && matches!(job.state, JobState::Done(_)))
+        });
+        status
+    }
+}
+
+/// A pidfd for the process `pid`, which polls as readable once it ends;
+/// `None` where the kernel has no pidfds. It is closed on exec.
+fn pidfd_open(pid: Pid) -> Option<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    (fd >= 0).then(|| unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Block until `pid` (any child, for -1) ends, or also stops if `stops` is
+/// set, retrying when a signal interrupts the wait. Without job control,
+/// as in a pipeline's subshell, a stopped process is left to be continued
+/// along with the rest of its job.
+fn wait_for_process(pid: Pid, stops: bool) -> nix::Result<WaitStatus> {
+    let flags = if stops { WaitPidFlag::WUNTRACED } else { WaitPidFlag::empty() };
+    loop {
+        match waitpid(pid, Some(flags)) {
+            Err(Errno::EINTR) => continue,
+            result => return result,
+        }
+    }
+}
+
+/// In a child forked for a job under job control: join process group
+/// `pgid` (or lead a new one), take the terminal if the job runs in the
+/// foreground, and restore the signals the shell ignores to their defaults.
+pub(crate) fn enter_job_process(pgid: Option<Pid>, foreground: bool) {
+    let pid = nix::unistd::getpid();
+    let pgid = pgid.unwrap_or(pid);
+    let _ = nix::unistd::setpgid(pid, pgid);
+    if foreground {
+        let _ = nix::unistd::tcsetpgrp(0, pgid);
+    }
+    for signal in [Signal::SIGINT, Signal::SIGTSTP, Signal::SIGTTIN, Signal::SIGTTOU] {
+        let default = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+        let _ = unsafe { sigaction(signal, &default) };
+    }
+}
+
+/// In a child started with `&!`, as `nohup` would: ignore SIGHUP, read
+/// from /dev/null instead of the terminal, and append output that would
+/// go to the terminal to `nohup.out` (in `$HOME` if the current directory
+/// is not writable).
+pub(crate) fn detach_from_terminal() {
+    let ignore = SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty());
+    let _ = unsafe { sigaction(Signal::SIGHUP, &ignore) };
+    if nix::unistd::isatty(0).unwrap_or(false) {
+        let _ = apply_redirect(&Redirect::Input(0, "/dev/null".into()));
+    }
+    if nix::unistd::isatty(1).unwrap_or(false) {
+        let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_APPEND;
+        let home = std::env::var("HOME").map(|home| format!("{}/nohup.out", home));
+        let flags = flags | OFlag::O_CLOEXEC;
+        let output = open("nohup.out", flags, Mode::from_bits(0o600).unwrap())
+            .or_else(|e| home.map_err(|_| e).and_then(|path| open(path.as_str(), flags, Mode::from_bits(0o600).unwrap())));
+        if let Ok(output) = output {
+            let _ = install_fd(unsafe { OwnedFd::from_raw_fd(output) }, 1);
+        }
+    }
+    if nix::unistd::isatty(2).unwrap_or(false) {
+        let _ = dup2(1, 2);
+    }
+}
+
+/// Parse a signal given by number or by name, with or without the `SIG`
+/// prefix and in either case. Signal 0 is `Some(None)`.
+pub(crate) fn parse_signal(text: &str) -> Option<Option<Signal>> {
+    if let Ok(number) = text.parse::<i32>() {
+        return if number == 0 { Some(None) } else { Signal::try_from(number).ok().map(Some) };
+    }
+    let name = text.to_ascii_uppercase();
+    let name = if name.starts_with("SIG") { name } else { format!("SIG{}", name) };
+    name.parse::<Signal>().ok().map(Some)
+}
+
+/// `kill -l`: list every signal, or the name of each signal number or
+/// exit status given.
+pub(crate) fn list_signals(args: &[String]) -> i32 {
+    if args.is_empty() {
+        let names: Vec<String> = Signal::iterator().map(|signal| format!("{:2}) {:<10}", signal as i32, signal.as_str())).collect();
+        for row in names.chunks(5) {
+            println!("{}", row.concat().trim_end());
+        }
+        return 0;
+    }
+    let mut status = 0;
+    for arg in args {
+        let signal = match arg.parse::<i32>() {
+            Ok(number) => Signal::try_from(if number > 128 { number - 128 } else { number }).ok(),
+            Err(_) => parse_signal(arg).flatten(),
+        };
+        match signal {
+            Some(signal) if arg.parse::<i32>().is_ok() => println!("{}", &signal.as_str()[3..]),
+            Some(signal) => println!("{}", signal as i32),
+            None => {
+                eprintln!("kill: {}: invalid signal specification", arg);
+                status = 1;
+            }
+        }
+    }
+    status
+}
+
+/// The C library's description of signal number `signal`, such as
+/// "Terminated".
+fn signal_description(signal: i32) -> String {
+    let description = unsafe { libc::strsignal(signal) };
+    if description.is_null() {
+        return format!("Signal {}", signal);
+    }
+    unsafe { std::ffi::CStr::from_ptr(description) }.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{run, shell};
+
+    /// Wait until the first process of the job at `index` has stopped,
+    /// and note that in the job table.
+    fn wait_until_stopped(shell: &mut Shell, index: usize) {
+        let pid = shell.jobs[index].processes[0].0;
+        let wait_status = waitpid(pid, Some(WaitPidFlag::WUNTRACED)).unwrap();
+        assert!(matches!(wait_status, WaitStatus::Stopped(..)));
+        shell.jobs[index].record(pid, wait_status);
+    }
+
+    #[test]
+    fn background_jobs_are_numbered_and_found_by_spec() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "sleep 5 & sleep 6 & sh -c 'exit 3' &"), (0, String::new()));
+        assert_eq!(shell.jobs.iter().map(|job| job.id).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(shell.find_job("%2"), Ok(1));
+        assert_eq!(shell.find_job("%+"), Ok(2));
+        assert_eq!(shell.find_job("%-"), Ok(1));
+        assert_eq!(shell.find_job("%sh"), Ok(2));
+        assert_eq!(shell.find_job("%?6"), Ok(1));
+        assert_eq!(shell.find_job("%sleep"), Err("%sleep: ambiguous job spec".to_string()));
+        assert_eq!(shell.find_job("%4"), Err("%4: no such job".to_string()));
+        assert_eq!(run(&mut shell, "wait %3"), (3, String::new()));
+        assert_eq!(shell.jobs.len(), 2);
+        assert_eq!(run(&mut shell, "sh -c 'exit 4' &"), (0, String::new()));
+        assert_eq!(shell.jobs[2].id, 3);
+        assert_eq!(run(&mut shell, "kill %1 %2; wait %1"), (128 + 15, String::new()));
+        assert_eq!(run(&mut shell, "wait %sleep"), (128 + 15, String::new()));
+        assert_eq!(run(&mut shell, "wait %3"), (4, String::new()));
+        assert!(shell.jobs.is_empty());
+    }
+
+    #[test]
+    fn fg_continues_a_job_and_waits_for_it() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "fg"), (1, String::new()));
+        assert_eq!(run(&mut shell, "sh -c 'sleep 0.1; exit 5' & kill -STOP %1; fg %1"), (5, String::new()));
+        assert!(shell.jobs.is_empty());
+        assert_eq!(run(&mut shell, "sh -c 'exit 6' & sleep 5 & fg %sh"), (6, String::new()));
+        assert_eq!(run(&mut shell, "fg %9"), (1, String::new()));
+        assert_eq!(run(&mut shell, "kill %sleep; fg"), (128 + 15, String::new()));
+    }
+
+    #[test]
+    fn bg_continues_stopped_jobs() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "bg"), (1, String::new()));
+        assert_eq!(run(&mut shell, "sh -c 'sleep 0.1; exit 7' & sh -c 'exit 8' & kill -STOP %1"), (0, String::new()));
+        wait_until_stopped(&mut shell, 0);
+        assert!(shell.jobs[0].describe() == "Stopped" && shell.jobs[0].display_command() == "sh -c 'sleep 0.1; exit 7'");
+        assert_eq!(run(&mut shell, "wait %2; bg %1"), (0, String::new()));
+        assert_eq!(shell.jobs[0].describe(), "Running");
+        assert_eq!(run(&mut shell, "bg %1"), (0, String::new()));
+        assert_eq!(run(&mut shell, "wait %1"), (7, String::new()));
+    }
+
+    #[test]
+    fn a_job_that_stops_stays_in_the_table() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "sh -c 'kill -STOP $$; exit 9' &"), (0, String::new()));
+        shell.job_control = true;
+        assert_eq!(shell.wait_for_job(0), 128 + Signal::SIGTSTP as i32);
+        shell.job_control = false;
+        assert!(shell.jobs[0].state == JobState::Stopped);
+        assert_eq!(run(&mut shell, "fg"), (9, String::new()));
+        assert!(shell.jobs.is_empty());
+    }
+
+    #[test]
+    fn kill_sends_named_signals_to_jobs_and_processes() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(parse_signal("9"), Some(Some(Signal::SIGKILL)));
+        assert_eq!(parse_signal("0"), Some(None));
+        assert_eq!(parse_signal("hup"), Some(Some(Signal::SIGHUP)));
+        assert_eq!(parse_signal("SIGUSR1"), Some(Some(Signal::SIGUSR1)));
+        assert_eq!(parse_signal("NOPE"), None);
+        assert_eq!(run(&mut shell, "sleep 5 & sleep 5 & kill -s usr1 %1; kill -9 $!; wait %1"), (128 + Signal::SIGUSR1 as i32, String::new()));
+        assert_eq!(run(&mut shell, "wait %2"), (128 + Signal::SIGKILL as i32, String::new()));
+        assert_eq!(run(&mut shell, "sleep 5 & kill -0 %1 && kill -- %1; wait"), (0, String::new()));
+        assert!(shell.jobs.is_empty());
+        assert_eq!(run(&mut shell, "kill -BOGUS 1"), (1, String::new()));
+        assert_eq!(run(&mut shell, "kill %1"), (1, String::new()));
+        assert_eq!(run(&mut shell, "kill"), (2, String::new()));
+    }
+
+    #[test]
+    fn disown_forgets_jobs_or_spares_them_the_hangup() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "disown"), (1, String::new()));
+        assert_eq!(run(&mut shell, "sleep 5 & a=$!; sleep 5 & b=$!; sleep 5 & c=$!; disown -h %2"), (0, String::new()));
+        assert_eq!(shell.jobs.iter().map(|job| job.keep_on_exit).collect::<Vec<_>>(), [false, true, false]);
+        assert_eq!(run(&mut shell, "disown; disown %9"), (1, String::new()));
+        assert_eq!(shell.jobs.iter().map(|job| job.id).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(run(&mut shell, "kill -STOP %1"), (0, String::new()));
+        wait_until_stopped(&mut shell, 0);
+        assert_eq!(run(&mut shell, "disown -r"), (0, String::new()));
+        assert_eq!(shell.jobs.iter().map(|job| job.id).collect::<Vec<_>>(), [1]);
+        assert_eq!(run(&mut shell, "disown -a; kill -9 $a $b $c; wait $a"), (127, String::new()));
+        assert!(shell.jobs.is_empty());
+    }
+
+    #[test]
+    fn wait_collects_jobs_and_processes() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "sh -c 'sleep 0.2; exit 3' & sh -c 'exit 4' & wait -n"), (4, String::new()));
+        assert_eq!(run(&mut shell, "wait -n"), (3, String::new()));
+        assert_eq!(run(&mut shell, "wait -n"), (127, String::new()));
+        assert_eq!(run(&mut shell, "sh -c 'exit 5' & wait $!"), (5, String::new()));
+        assert_eq!(run(&mut shell, "wait $$"), (127, String::new()));
+        assert_eq!(run(&mut shell, "wait x"), (2, String::new()));
+        assert_eq!(run(&mut shell, "sleep 0.1 & sh -c 'exit 6' & wait"), (0, String::new()));
+        assert!(shell.jobs.is_empty());
+    }
+
+    #[test]
+    fn finished_jobs_are_reported_once() {
+        let (_lock, mut shell) = shell();
+        let report = |shell: &mut Shell| loop {
+            let reports = shell.finished_jobs();
+            if !reports.is_empty() {
+                return reports;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        };
+        assert_eq!(run(&mut shell, "sleep 5 & sh -c 'exit 1' &"), (0, String::new()));
+        assert_eq!(report(&mut shell), [format!("[2]+  {:<24}sh -c 'exit 1'", "Exit 1")]);
+        assert_eq!(run(&mut shell, "true &"), (0, String::new()));
+        assert_eq!(report(&mut shell), [format!("[2]+  {:<24}true", "Done")]);
+        assert!(shell.finished_jobs().is_empty());
+        assert_eq!(shell.jobs.len(), 1);
+        assert_eq!(run(&mut shell, "kill %1; wait"), (0, String::new()));
+    }
+
+    #[test]
+    fn an_interrupted_foreground_job_stops_the_loop_around_it() {
+        let (_lock, mut shell) = shell();
+        let interrupt = "for i in 1 2; do echo $i; sh -c 'kill -INT $$'; done";
+        assert_eq!(run(&mut shell, interrupt), (130, "1\n".to_string()));
+        assert!(!take_interrupt());
+        assert_eq!(run(&mut shell, &format!("trap : INT; {}", interrupt)), (130, "1\n2\n".to_string()));
+        assert_eq!(run(&mut shell, "trap - INT"), (0, String::new()));
+        on_sigint(Signal::SIGINT as i32);
+        assert!(take_interrupt() && !take_interrupt());
+    }
+
+    #[test]
+    fn a_foreground_job_has_the_terminal_while_it_runs() {
+        let (_lock, mut shell) = shell();
+        let pty = nix::pty::openpty(None, None).unwrap();
+        nix::unistd::write(pty.master, b"typed\n").unwrap();
+        // The shell needs a terminal of its own, so it runs in a child
+        // that makes the pseudo-terminal its controlling terminal.
+        let child = match unsafe { nix::unistd::fork() }.unwrap() {
+            nix::unistd::ForkResult::Child => {
+                let _ = nix::unistd::setsid();
+                unsafe { libc::ioctl(pty.slave, libc::TIOCSCTTY, 0) };
+                let _ = dup2(pty.slave, 0);
+                shell.enable_job_control();
+                // Reading the terminal from a background process group
+                // would stop the job instead.
+                let status = shell.eval("sh -c 'read line; test \"$line\" = typed'").map_or(1, |status| status.code());
+                let back = nix::unistd::tcgetpgrp(0) == Ok(nix::unistd::getpgrp());
+                unsafe { libc::_exit(if shell.job_control && back { status } else { 2 }) };
+            }
+            nix::unistd::ForkResult::Parent { child } => child,
+        };
+        assert_eq!(waitpid(child, None), Ok(WaitStatus::Exited(child, 0)));
+    }
+
+    #[test]
+    fn ended_background_children_are_reaped_between_commands() {
+        let (_lock, mut shell) = shell();
+        install_sigchld_handler(false);
+        assert_eq!(run(&mut shell, "sh -c 'exit 3' & sleep 0.1"), (0, String::new()));
+        let default = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+        let _ = unsafe { sigaction(Signal::SIGCHLD, &default) };
+        assert!(shell.jobs[0].state == JobState::Done(3));
+        assert_eq!(waitpid(shell.jobs[0].processes[0].0, Some(WaitPidFlag::WNOHANG)), Err(Errno::ECHILD));
+        assert_eq!(run(&mut shell, "wait %1"), (3, String::new()));
+    }
+
+    #[test]
+    fn exit_statuses_and_fatal_signals_are_kept() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "sh -c 'exit 4'; echo $?"), (0, "4\n".to_string()));
+        assert_eq!(run(&mut shell, "sh -c 'kill -SEGV $$'; echo $?"), (0, "139\n".to_string()));
+        assert_eq!(run(&mut shell, "setopt printexitvalue; false"), (1, String::new()));
+        assert!(shell.option("printexitvalue"));
+        let (first, last) = (Pid::from_raw(1), Pid::from_raw(2));
+        let index = shell.push_job("a | b".to_string(), vec![first, last]);
+        let job = &mut shell.jobs[index];
+        job.record(first, WaitStatus::Exited(first, 2));
+        assert!(job.state == JobState::Running);
+        job.record(last, WaitStatus::Signaled(last, Signal::SIGSEGV, true));
+        assert_eq!(job.describe(), "Segmentation fault (core dumped)");
+        assert_eq!((job.exit_status(false), job.exit_status(true)), (139, 139));
+        job.killed_by = None;
+        assert_eq!(job.describe(), "Exit 139");
+        shell.jobs.clear();
+    }
+
+    #[test]
+    fn hangups_spare_detached_and_disowned_jobs() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "sleep 5 &! sleep 0.1; kill -HUP $!"), (0, String::new()));
+        assert!(shell.jobs.is_empty());
+        let detached = shell.last_background.unwrap();
+        assert_eq!(run(&mut shell, "sleep 5 & sleep 5 & disown -h %2"), (0, String::new()));
+        shell.hang_up_jobs();
+        assert_eq!(run(&mut shell, "wait %1"), (128 + Signal::SIGHUP as i32, String::new()));
+        let spared = shell.jobs[0].processes[0].0;
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        for pid in [detached, spared] {
+            assert_eq!(waitpid(pid, Some(WaitPidFlag::WNOHANG)), Ok(WaitStatus::StillAlive));
+            nix::sys::signal::kill(pid, Signal::SIGKILL).unwrap();
+            assert_eq!(waitpid(pid, None), Ok(WaitStatus::Signaled(pid, Signal::SIGKILL, false)));
+        }
+        shell.jobs.clear();
+    }
+
+    #[test]
+    fn exit_warns_once_about_jobs_left_behind() {
+        let (_lock, mut shell) = shell();
+        shell.interactive = true;
+        assert_eq!(run(&mut shell, "sleep 5 &"), (0, String::new()));
+        assert_eq!(run(&mut shell, "exit 7"), (1, String::new()));
+        assert!(shell.jobs_warning);
+        // The warning holds until the next prompt, and a second `exit` leaves.
+        shell.exit_warned = std::mem::take(&mut shell.jobs_warning);
+        assert_eq!(run(&mut shell, "exit 7"), (7, String::new()));
+        shell.exit_warned = false;
+        assert_eq!(run(&mut shell, "kill %1; wait; exit 7"), (7, String::new()));
+    }
+
+    #[test]
+    fn pipestatus_holds_the_status_of_each_stage() {
+        let (_lock, mut shell) = shell();
+        let source = "sh -c 'exit 1' | sh -c 'kill -TERM $$' | sh -c 'exit 2'; echo \"${PIPESTATUS[@]}\"";
+        assert_eq!(run(&mut shell, source), (0, "1 143 2\n".to_string()));
+        assert_eq!(run(&mut shell, "sh -c 'exit 3'; echo \"${PIPESTATUS[0]}\""), (0, "3\n".to_string()));
+        assert_eq!(shell.arrays["PIPESTATUS"], ["3"]);
+    }
+
+    #[test]
+    fn waiting_for_input_wakes_for_ended_jobs_input_or_the_deadline() {
+        use std::time::{Duration, Instant};
+        let (_lock, mut shell) = shell();
+        // Stand a pipe in for the terminal on standard input.
+        let (reader, writer) = nix::unistd::pipe().unwrap();
+        let stdin = unsafe { libc::dup(0) };
+        unsafe { libc::dup2(reader, 0) };
+        assert_eq!(shell.wait_for_input(Some(Instant::now() + Duration::from_millis(20))), Wakeup::Timeout);
+        assert_eq!(run(&mut shell, "sleep 0.1 &"), (0, String::new()));
+        let started = Instant::now();
+        assert_eq!(shell.wait_for_input(Some(started + Duration::from_secs(10))), Wakeup::JobEnded);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        shell.update_jobs();
+        assert!(shell.jobs.iter().all(|job| job.state != JobState::Running));
+        nix::unistd::write(writer, b"x").unwrap();
+        assert_eq!(shell.wait_for_input(None), Wakeup::Input);
+        unsafe { libc::dup2(stdin, 0) };
+        for fd in [stdin, reader, writer] {
+            let _ = nix::unistd::close(fd);
+        }
+    }
+}
@@ -0,0 +1,385 @@
+//! Splitting source text into words and operators.
+
+use crate::ast::Redirect;
+
+/// Whether `token` is a redirection operator, such as `>`, `2>&` or `<`.
+pub(crate) fn is_redirect_operator(token: &str) -> bool {
+    Redirect::parse(token, "".into()).is_some()
+}
+
+/// Words that start or continue compound commands when they appear unquoted
+/// at the start of a command.
+pub(crate) const RESERVED_WORDS: &[&str] = &["if", "then", "elif", "else", "fi", "while", "until", "do", "done", "for", "case", "esac", "{", "}", "function", "[[", "!", "time", "coproc"];
+
+/// One lexical token of the input: its text, borrowed from the source,
+/// and its byte range there.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Token<'a> {
+    pub(crate) text: &'a str,
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+}
+
+impl Token<'_> {
+    /// Whether the token separates commands (`;` or a newline).
+    pub(crate) fn is_separator(&self) -> bool {
+        self.text == ";" || self.text == "\n"
+    }
+
+    /// Whether the token ends a simple command: a separator or the `;;`
+    /// that closes a `case` item.
+    pub(crate) fn ends_command(&self) -> bool {
+        self.is_separator() || self.text == ";;"
+    }
+
+    /// Whether the token is `&&` or `||`.
+    pub(crate) fn is_and_or(&self) -> bool {
+        self.text == "&&" || self.text == "||"
+    }
+
+    /// Whether the token ends a pipeline: anything that ends a command,
+    /// `&&` or `||`, or `|`, `&` or `&!`.
+    pub(crate) fn ends_pipeline(&self) -> bool {
+        self.ends_command() || self.is_and_or() || matches!(self.text, "|" | "&" | "&!")
+    }
+}
+
+/// Why the input so far cannot be complete, whatever the parser would
+/// make of it: the shell has to read another line first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Pending {
+    /// Inside quotes opened with this character.
+    Quote(char),
+    /// Inside `$( ... )`.
+    Substitution,
+    /// The last line ended with a backslash, joining it to the next.
+    Continuation,
+    /// After `|`, `&&` or `||`, which need a command after them.
+    Operator,
+}
+
+/// A tokenizer fed a line of input at a time. Tokens already complete are
+/// kept as byte ranges, so each line only costs the scanning of what it
+/// adds, and what is still unfinished at its end is reported as `Pending`.
+#[derive(Default)]
+pub(crate) struct Lexer {
+    source: String,
+    /// The ranges of the complete tokens.
+    spans: Vec<(usize, usize)>,
+    /// Where scanning resumes: the end of the last complete token, or the
+    /// start of an unfinished one.
+    scanned: usize,
+    pending: Option<Pending>,
+}
+
+impl Lexer {
+    /// Add `line`, which has no newline of its own. If it ends with a
+    /// backslash outside single quotes, the backslash is dropped and the
+    /// next line continues this one.
+    pub(crate) fn feed_line(&mut self, line: &str) {
+        self.source.push_str(line);
+        self.source.push('\n');
+        let (scanned, pending) = scan(&self.source, self.scanned, &mut self.spans);
+        self.scanned = scanned;
+        self.pending = pending;
+        if pending == Some(Pending::Continuation) {
+            self.source.truncate(self.source.len() - 2);
+        }
+    }
+
+    /// The input so far, without the backslashes that join lines.
+    pub(crate) fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// What the input still needs, if it cannot be complete yet.
+    pub(crate) fn pending(&self) -> Option<Pending> {
+        self.pending
+    }
+
+    /// The tokens so far, ending with an unfinished one if there is one.
+    pub(crate) fn tokens(&self) -> Vec<Token<'_>> {
+        tokens_of(&self.source, &self.spans, self.scanned)
+    }
+
+    /// Forget the input, to start on the next command line.
+    pub(crate) fn clear(&mut self) {
+        *self = Lexer::default();
+    }
+}
+
+/// Split input into words, operators and command separators, all at once.
+/// Quotes and backslashes are kept in the word text; `#` at the start of
+/// a word begins a comment.
+pub(crate) fn tokenize(source: &str) -> Vec<Token<'_>> {
+    let mut spans = Vec::new();
+    let (scanned, _) = scan(source, 0, &mut spans);
+    tokens_of(source, &spans, scanned)
+}
+
+/// The tokens with the given ranges, and the rest of `source` after
+/// `scanned` as a last one, if it is not empty.
+fn tokens_of<'a>(source: &'a str, spans: &[(usize, usize)], scanned: usize) -> Vec<Token<'a>> {
+    let mut tokens: Vec<Token> = spans.iter().map(|&(start, end)| Token { text: &source[start..end], start, end }).collect();
+    if scanned < source.len() {
+        tokens.push(Token { text: &source[scanned..], start: scanned, end: source.len() });
+    }
+    tokens
+}
+
+/// Add the ranges of the complete tokens in `source` from byte `from` on
+/// to `spans`, returning where scanning stopped and what is pending there:
+/// a token that the end of the input leaves unfinished is not added, and
+/// is where the scan stops.
+fn scan(source: &str, from: usize, spans: &mut Vec<(usize, usize)>) -> (usize, Option<Pending>) {
+    let mut chars = source[from..].char_indices().map(|(i, c)| (from + i, c)).peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        match c {
+            ' ' | '\t' => {
+                chars.next();
+            }
+            '\n' | ';' => {
+                chars.next();
+                let double = c == ';' && chars.next_if(|&(_, c)| c == ';').is_some();
+                spans.push((start, start + 1 + double as usize));
+            }
+            '#' => {
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+            }
+            // The `()` of a function definition, even written against the
+            // name before it or the body after it.
+            '(' if source[start..].starts_with("()") => {
+                chars.next();
+                chars.next();
+                spans.push((start, start + 2));
+            }
+            '&' | '|' if is_and_or_at(source, start) => {
+                chars.next();
+                chars.next();
+                spans.push((start, start + 2));
+            }
+            '|' | '&' => {
+                chars.next();
+                let detached = c == '&' && chars.next_if(|&(_, c)| c == '!').is_some();
+                spans.push((start, start + 1 + detached as usize));
+            }
+            _ => {
+                let mut quote = None;
+                let mut depth = 0;
+                while let Some(&(i, c)) = chars.peek() {
+                    match quote {
+                        Some(q) if c == q => quote = None,
+                        Some('\'') => {}
+                        Some(_) if c == '\\' => {
+                            chars.next();
+                        }
+                        Some(_) => {}
+                        None if matches!(c, '\'' | '"' | '`') => quote = Some(c),
+                        None if c == '\\' => {
+                            chars.next();
+                        }
+                        None if depth == 0 && matches!(c, ' ' | '\t' | '\n' | ';' | '|' | '&') => break,
+                        None if depth == 0 && source[i..].starts_with("()") && is_name(&source[start..i]) => break,
+                        // A redirection operator is a token of its own,
+                        // taking the digits of a descriptor before it.
+                        None if depth == 0 && matches!(c, '<' | '>') => {
+                            if !source[start..i].bytes().all(|b| b.is_ascii_digit()) {
+                                break;
+                            }
+                            chars.next();
+                            let follows = if c == '>' { &['|', '&'][..] } else { &['>', '&'][..] };
+                            chars.next_if(|&(_, next)| follows.contains(&next));
+                            break;
+                        }
+                        None => {}
+                    }
+                    if c == '\\' && quote != Some('\'') && &source[i + 1..] == "\n" {
+                        return (start, Some(Pending::Continuation));
+                    }
+                    chars.next();
+                    // Track `$( ... )` nesting so its contents stay in one word.
+                    if quote != Some('\'') {
+                        let opens = c == '$' && chars.next_if(|&(_, c)| c == '(').is_some();
+                        if opens || (depth > 0 && c == '(') {
+                            depth += 1;
+                        } else if depth > 0 && c == ')' {
+                            depth -= 1;
+                        }
+                    }
+                }
+                match (quote, depth) {
+                    (Some(q), _) if chars.peek().is_none() => return (start, Some(Pending::Quote(q))),
+                    (None, 1..) if chars.peek().is_none() => return (start, Some(Pending::Substitution)),
+                    _ => {}
+                }
+                let end = chars.peek().map_or(source.len(), |&(i, _)| i);
+                spans.push((start, end));
+            }
+        }
+    }
+    let last = spans.iter().rev().map(|&(start, end)| &source[start..end]).find(|text| *text != "\n");
+    let pending = matches!(last, Some("|" | "&&" | "||")).then_some(Pending::Operator);
+    (source.len(), pending)
+}
+
+/// Whether `&&` or `||` starts at byte `index` of `source`.
+fn is_and_or_at(source: &str, index: usize) -> bool {
+    source[index..].starts_with("&&") || source[index..].starts_with("||")
+}
+
+/// Split a `case` pattern list on the `|` characters outside quotes
+pub(crate) fn split_patterns(text: &str) -> Vec<String> {
+    let mut patterns = vec![String::new()];
+    let mut quote = None;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, '|') => {
+                patterns.push(String::new());
+                continue;
+            }
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            (_, '\\') if quote != Some('\'') => {
+                patterns.last_mut().unwrap().push(c);
+                patterns.last_mut().unwrap().extend(chars.next());
+                continue;
+            }
+            _ => {}
+        }
+        patterns.last_mut().unwrap().push(c);
+    }
+    patterns
+}
+
+/// `word` as it would have to be typed to mean itself: single-quoted
+/// unless it is made only of characters that are never special.
+pub(crate) fn quote_for_display(word: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%^".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        return word.to_string();
+    }
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+/// Split a `NAME=value` word into its name and (unexpanded) value
+pub(crate) fn assignment(word: &str) -> Option<(&str, &str)> {
+    let (name, value) = word.split_once('=')?;
+    is_name(name).then_some((name, value))
+}
+
+/// Whether `name` is a valid variable name
+pub(crate) fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(source: &str) -> Vec<&str> {
+        tokenize(source).iter().map(|token| token.text).collect()
+    }
+
+    #[test]
+    fn splits_words_and_operators() {
+        assert_eq!(texts("echo \"a b\" | wc -l && x; y &"), ["echo", "\"a b\"", "|", "wc", "-l", "&&", "x", ";", "y", "&"]);
+        assert_eq!(texts("a||b&!"), ["a", "||", "b", "&!"]);
+        assert_eq!(texts("case x in a) y;; esac"), ["case", "x", "in", "a)", "y", ";;", "esac"]);
+        assert_eq!(texts("f(){ x; } a=() $(g())"), ["f", "()", "{", "x", ";", "}", "a=()", "$(g())"]);
+    }
+
+    #[test]
+    fn keeps_quotes_and_substitutions_in_one_word() {
+        assert_eq!(texts("echo 'a;b' a\\ b $(ls | wc -l) \"x $(y; z)\""), ["echo", "'a;b'", "a\\ b", "$(ls | wc -l)", "\"x $(y; z)\""]);
+    }
+
+    #[test]
+    fn drops_comments() {
+        assert_eq!(texts("a # b; c\nd"), ["a", "\n", "d"]);
+        assert_eq!(texts("a#b"), ["a#b"]);
+    }
+
+    #[test]
+    fn splits_off_redirections() {
+        assert_eq!(texts("cmd 2>&1 >out <in 3<>f >|g"), ["cmd", "2>&", "1", ">", "out", "<", "in", "3<>", "f", ">|", "g"]);
+        // Only digits right before the operator name a descriptor.
+        assert_eq!(texts("a2>f"), ["a2", ">", "f"]);
+    }
+
+    #[test]
+    fn records_byte_ranges() {
+        let source = "echo  héllo |x";
+        for token in tokenize(source) {
+            assert_eq!(&source[token.start..token.end], token.text);
+        }
+        assert_eq!(tokenize(source)[2].start, 13);
+    }
+
+    #[test]
+    fn reports_what_is_pending() {
+        let pending = |lines: &[&str]| {
+            let mut lexer = Lexer::default();
+            lines.iter().for_each(|line| lexer.feed_line(line));
+            lexer.pending()
+        };
+        assert_eq!(pending(&["echo 'a"]), Some(Pending::Quote('\'')));
+        assert_eq!(pending(&["echo \"a", "b"]), Some(Pending::Quote('"')));
+        assert_eq!(pending(&["echo $(ls"]), Some(Pending::Substitution));
+        assert_eq!(pending(&["echo a \\"]), Some(Pending::Continuation));
+        assert_eq!(pending(&["a |"]), Some(Pending::Operator));
+        assert_eq!(pending(&["a &&", "b"]), None);
+        assert_eq!(pending(&["echo 'a", "b'"]), None);
+    }
+
+    #[test]
+    fn joins_continued_lines() {
+        let mut lexer = Lexer::default();
+        lexer.feed_line("echo a\\");
+        lexer.feed_line("b c");
+        assert_eq!(lexer.source(), "echo ab c\n");
+        let texts: Vec<&str> = lexer.tokens().iter().map(|token| token.text).collect();
+        assert_eq!(texts, ["echo", "ab", "c", "\n"]);
+    }
+
+    #[test]
+    fn splits_case_patterns() {
+        assert_eq!(split_patterns("a|'b|c'|d\\|e"), ["a", "'b|c'", "d\\|e"]);
+    }
+
+    #[test]
+    fn quotes_for_display() {
+        assert_eq!(quote_for_display("plain-word.txt"), "plain-word.txt");
+        assert_eq!(quote_for_display("a b"), "'a b'");
+        assert_eq!(quote_for_display("it's"), "'it'\\''s'");
+        assert_eq!(quote_for_display(""), "''");
+    }
+
+    #[test]
+    fn recognizes_assignments() {
+        assert_eq!(assignment("x=1"), Some(("x", "1")));
+        assert_eq!(assignment("_a1=b=c"), Some(("_a1", "b=c")));
+        assert_eq!(assignment("1x=y"), None);
+        assert_eq!(assignment("echo"), None);
+        assert!(is_name("PATH") && !is_name("") && !is_name("a-b"));
+    }
+
+    #[test]
+    fn resumes_where_the_last_line_left_off() {
+        let mut lexer = Lexer::default();
+        lexer.feed_line("echo 'a");
+        assert_eq!(lexer.pending(), Some(Pending::Quote('\'')));
+        lexer.feed_line("b' |");
+        assert_eq!(lexer.pending(), Some(Pending::Operator));
+        lexer.feed_line("wc");
+        assert_eq!(lexer.pending(), None);
+        let source = lexer.source();
+        let tokens = lexer.tokens();
+        let texts: Vec<&str> = tokens.iter().map(|token| token.text).collect();
+        assert_eq!(texts, ["echo", "'a\nb'", "|", "\n", "wc", "\n"]);
+        // The tokens are spans of the input, not copies.
+        assert!(tokens.iter().all(|token| std::ptr::eq(token.text, &source[token.start..token.end])));
+    }
+}
@@ -0,0 +1,1610 @@
+//! vssh, a small Unix shell. The binary only calls `main`.
+
+mod ast;
+mod lexer;
+mod parser;
+mod expand;
+mod exec;
+mod builtins;
+mod jobs;
+mod editor;
+mod history;
+mod frecency;
+mod direnv;
+mod snapshot;
+mod plugin;
+mod wasm;
+mod theme;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, Write};
+use nix::errno::Errno;
+use nix::unistd::{fork, ForkResult, Pid, dup2, close};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use crate::ast::{Command, Redirect};
+use crate::builtins::{names_current_dir, option_for_flag};
+use crate::editor::{Edit, Editor, KeyReader, KillRing, Pick, RawMode, choose};
+use crate::exec::{AuditLog, FatalError, PathIndex, Policy, cloexec_pipe, install_fd, restore_fds};
+use crate::history::{History, HistoryRules};
+use crate::jobs::{CHILD_EXITED, Job, PENDING_TRAPS, WINDOW_RESIZED, Wakeup, install_sigchld_handler, on_sigint, on_sigwinch, take_interrupt};
+use crate::lexer::{Lexer, quote_for_display};
+use crate::parser::{ParseError, parse, parse_lexed};
+use crate::plugin::Plugins;
+use crate::theme::{PromptState, Theme};
+
+/// `bytes` in base64, with padding.
+fn base64(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| group | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            out.push(if i <= chunk.len() { DIGITS[(group >> (18 - 6 * i) & 63) as usize] as char } else { '=' });
+        }
+    }
+    out
+}
+
+/// Represents the status of processing a line.
+#[derive(Debug)]
+enum Status {
+    Continue,
+    Exit,
+    /// `return` was run; unwinds to the enclosing function or sourced file.
+    Return,
+}
+
+/// Which startup files to run, as chosen on the command line. A login
+/// shell (started with `-l` or `--login`, or with a name beginning with
+/// `-`) first runs `~/.vssh_profile`, unless given `--noprofile`. An
+/// interactive shell then runs `~/.vsshrc`, or the file named with
+/// `--rcfile`, unless given `--norc`. `vssh --noprofile --norc` thus starts
+/// with no startup files at all.
+struct Startup {
+    login: bool,
+    profile: bool,
+    rc: bool,
+    rcfile: Option<String>,
+}
+
+impl Startup {
+    /// The rc file an interactive shell runs, if any, and whether it must
+    /// exist.
+    fn rc_file(&self) -> Option<(&str, bool)> {
+        match &self.rcfile {
+            _ if !self.rc => None,
+            Some(path) => Some((path, true)),
+            None => Some(("~/.vsshrc", false)),
+        }
+    }
+}
+
+/// With `--profile-startup`, how long each phase of starting up took, to
+/// be reported on standard error once the shell is ready to run commands.
+struct StartupProfile {
+    started: std::time::Instant,
+    /// When the last phase ended.
+    lap: std::time::Instant,
+    phases: Vec<(&'static str, std::time::Duration)>,
+}
+
+impl StartupProfile {
+    fn new(started: std::time::Instant) -> StartupProfile {
+        StartupProfile { started, lap: started, phases: Vec::new() }
+    }
+
+    /// Record that `phase` has just ended.
+    fn lap(&mut self, phase: &'static str) {
+        let now = std::time::Instant::now();
+        self.phases.push((phase, now - self.lap));
+        self.lap = now;
+    }
+
+    /// The lines of the report: the total time and then each phase, in
+    /// order, with its own.
+    fn lines(&self) -> Vec<String> {
+        let total = self.lap - self.started;
+        let mut lines = vec![format!("vssh: startup took {:.3}ms", total.as_secs_f64() * 1000.0)];
+        for (phase, time) in &self.phases {
+            lines.push(format!("{:>10.3}ms  {}", time.as_secs_f64() * 1000.0, phase));
+        }
+        lines
+    }
+
+    /// Print the report on standard error.
+    fn report(self) {
+        for line in self.lines() {
+            eprintln!("{}", line);
+        }
+    }
+}
+
+/// State carried by the shell from one command to the next.
+///
+/// This is also the way to embed vssh in another program instead of
+/// running `/bin/sh -c`:
+///
+/// ```
+/// use std::io::Read;
+///
+/// let (mut output, stdout) = std::io::pipe().unwrap();
+/// let mut shell = vssh::Shell::new();
+/// shell.set_stdout(stdout);
+/// shell.set_var("NAME", "world");
+/// shell.set_alias("greet", "echo hello,");
+/// let status = shell.eval("greeting=\"hello, $NAME\"; greet \"$NAME\"").unwrap();
+/// assert!(status.success());
+/// assert_eq!(shell.var("greeting"), Some("hello, world"));
+/// assert_eq!(shell.eval("false").unwrap().code(), 1);
+///
+/// // The shell holds the pipe's other end until it is dropped.
+/// drop(shell);
+/// let mut text = String::new();
+/// output.read_to_string(&mut text).unwrap();
+/// assert_eq!(text, "hello, world\n");
+/// ```
+pub struct Shell {
+    /// Exit status of the most recently executed pipeline.
+    last_status: i32,
+    /// Shell variables, seeded from the environment at startup.
+    vars: HashMap<String, String>,
+    /// Indexed array variables such as `BASH_REMATCH`.
+    arrays: HashMap<String, Vec<String>>,
+    /// Defined shell functions, by name.
+    functions: HashMap<String, Rc<Command<'static>>>,
+    /// Aliases defined with `alias`: each name and the text it stands for.
+    aliases: HashMap<String, String>,
+    /// The aliases whose text is running, which are not expanded again
+    /// inside it, so that `alias ls='ls -F'` runs the command `ls`.
+    expanding_aliases: Vec<String>,
+    /// `$0`: the shell or script name.
+    name: String,
+    /// `$1`, `$2`, ...: script or function arguments.
+    positional: Vec<String>,
+    /// One frame per active function call, holding the values that its
+    /// `local` declarations shadowed (`None` if the variable was unset).
+    local_frames: Vec<HashMap<String, Option<String>>>,
+    /// How many `source` builtins are currently running.
+    source_depth: usize,
+    /// `getopts` progress as (`OPTIND` it last set, offset of the next
+    /// option character within that argument), for clustered flags.
+    getopts_position: (usize, usize),
+    /// Remembered `$PATH` lookups: command name to (path, times used).
+    hashed: HashMap<String, (String, usize)>,
+    /// The `$PATH` that `hashed` was filled from; a change clears it.
+    hashed_path: String,
+    /// Every command in `$PATH`, for finding them without a search.
+    path_index: PathIndex,
+    /// Directories saved by `pushd`, most recent first (`~1`, `~2`, ...).
+    dir_stack: Vec<String>,
+    /// The options that are on, as named in `SHELL_OPTIONS`.
+    options: HashSet<&'static str>,
+    /// Background jobs, oldest first; the last one is the current job.
+    jobs: Vec<Job>,
+    /// Whether each pipeline runs in its own process group, so that the
+    /// terminal can stop or interrupt it without affecting the shell.
+    job_control: bool,
+    /// `$!`: the last process of the most recent background job.
+    last_background: Option<Pid>,
+    /// Set when Ctrl-C discards the command being typed, so that `run`
+    /// drops what it has buffered of it.
+    input_abandoned: bool,
+    /// The terminal settings to put back whenever the shell takes the
+    /// terminal back from a job.
+    tty_modes: Option<nix::sys::termios::Termios>,
+    /// How many conditions (of `if`, `while`, `&&`, `||` or `!`) are being
+    /// run; `set -e` ignores failures inside them.
+    conditions: usize,
+    /// Whether commands are being read from the terminal.
+    interactive: bool,
+    /// `$LINENO`: the input line on which the running command starts.
+    line_number: usize,
+    /// The actions set with `trap`, by condition: `EXIT`, `DEBUG`, `ERR`,
+    /// or a signal name without `SIG`.
+    traps: HashMap<String, String>,
+    /// Whether a trap's action is running; `DEBUG` and `ERR` do not fire
+    /// inside one.
+    running_trap: bool,
+    /// `$BASH_COMMAND`: the simple command being run, as written.
+    current_command: String,
+    /// The rules deciding which external commands may run, if a policy is
+    /// in force.
+    policy: Option<Policy>,
+    /// Set while `command_not_found_handle` runs, so that a command it
+    /// cannot find does not run it again.
+    handling_not_found: bool,
+    /// Set when a line is read at the prompt, so that the command it
+    /// completes runs the `preexec` hook.
+    prompt_input: bool,
+    /// The hook functions running, which are not run again from within.
+    running_hooks: Vec<&'static str>,
+    /// The shell's own process id, which children forked for commands
+    /// do not share.
+    pid: Pid,
+    /// Set when `exit` or Ctrl-D is refused because of jobs; becomes
+    /// `exit_warned` at the next prompt.
+    jobs_warning: bool,
+    /// Whether the last attempt to exit, at the previous prompt, warned
+    /// about jobs, so that exiting now goes ahead.
+    exit_warned: bool,
+    /// Where every command run is recorded, if anywhere.
+    audit: Option<AuditLog>,
+    /// What `eval` gives commands as their standard input, output and
+    /// error instead of the process's own, where set.
+    stdio: [Option<OwnedFd>; 3],
+    /// The phases of startup timed so far, with `--profile-startup`.
+    startup_profile: Option<StartupProfile>,
+    /// What the line editor has killed, kept from one line to the next.
+    kill_ring: KillRing,
+    /// The command lines typed at the prompt.
+    history: History,
+    /// The environment file of the current directory, if one is loaded.
+    dir_env: Option<direnv::Loaded>,
+    /// The plugins loaded with `plugin load`.
+    plugins: Plugins,
+    /// The prompt theme chosen with `theme use`, if any, and its name.
+    theme: Option<(String, Theme)>,
+}
+
+/// The exit status of the commands that `Shell::eval` ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStatus(i32);
+
+impl ExitStatus {
+    /// The status as `$?` shows it: 0 to 255, or 128 plus the number of
+    /// the signal that killed the last command.
+    pub fn code(self) -> i32 {
+        self.0
+    }
+
+    /// Whether the status is 0.
+    pub fn success(self) -> bool {
+        self.0 == 0
+    }
+}
+
+/// Run the `vssh` command with the process's arguments; never returns.
+pub fn main() {
+    let started = std::time::Instant::now();
+    let args: Vec<String> = std::env::args().collect();
+    let mut shell = Shell::new();
+    install_sigchld_handler(false);
+    let mut startup = Startup {
+        login: args.first().is_some_and(|name| name.starts_with('-')),
+        profile: true,
+        rc: true,
+        rcfile: None,
+    };
+    let mut policy_file = std::env::var("VSSH_POLICY").ok().filter(|path| !path.is_empty());
+    let mut audit_target = None;
+    let mut record = None;
+    // Option flags such as `-e` come first, as with `set`.
+    let mut first = 1;
+    while let Some(arg) = args.get(first).filter(|arg| arg.len() > 1 && arg.starts_with(['-', '+']) && *arg != "-c") {
+        first += 1;
+        match arg.as_str() {
+            "--" => break,
+            "-l" | "--login" => startup.login = true,
+            "--noprofile" | "--no-profile" => startup.profile = false,
+            "--norc" => startup.rc = false,
+            "--profile-startup" => shell.startup_profile = Some(StartupProfile::new(started)),
+            "--audit" => match args.get(first) {
+                Some(target) => {
+                    audit_target = Some(target.clone());
+                    first += 1;
+                }
+                None => {
+                    eprintln!("vssh: --audit: option requires an argument");
+                    std::process::exit(2);
+                }
+            },
+            "--policy" => match args.get(first) {
+                Some(path) => {
+                    policy_file = Some(path.clone());
+                    first += 1;
+                }
+                None => {
+                    eprintln!("vssh: --policy: option requires an argument");
+                    std::process::exit(2);
+                }
+            },
+            "--record" => match args.get(first) {
+                Some(path) => {
+                    record = Some(path.clone());
+                    first += 1;
+                }
+                None => {
+                    eprintln!("vssh: --record: option requires an argument");
+                    std::process::exit(2);
+                }
+            },
+            "--rcfile" => match args.get(first) {
+                Some(path) => {
+                    startup.rcfile = Some(path.clone());
+                    first += 1;
+                }
+                None => {
+                    eprintln!("vssh: --rcfile: option requires an argument");
+                    std::process::exit(2);
+                }
+            },
+            _ if arg.starts_with("--") => {
+                eprintln!("vssh: {}: invalid option", arg);
+                std::process::exit(2);
+            }
+            _ => {
+                for flag in arg[1..].chars() {
+                    match option_for_flag(flag) {
+                        Some(name) => shell.set_option(name, arg.starts_with('-')),
+                        None => {
+                            eprintln!("vssh: {}{}: invalid option", &arg[..1], flag);
+                            std::process::exit(2);
+                        }
+                    };
+                }
+            }
+        }
+    }
+    // A restricted shell (`-r`, or one run as `rvssh`) only becomes so
+    // after its startup files, which can then set it up.
+    let restricted = shell.options.remove("restricted") || args.first().is_some_and(|name| name.rsplit('/').next() == Some("rvssh"));
+    let args = &args[first - 1..];
+    shell.profile_phase("shell state and options");
+    if let Some(path) = record {
+        record_session(&path);
+    }
+    if let Err(e) = shell.load_policies(policy_file) {
+        eprintln!("vssh: {}", e);
+        std::process::exit(126);
+    }
+    shell.profile_phase("policy");
+    match AuditLog::open(audit_target) {
+        Ok(audit) => shell.audit = audit,
+        Err(e) => {
+            eprintln!("vssh: audit: {}", e);
+            std::process::exit(126);
+        }
+    }
+    shell.profile_phase("audit log");
+    if startup.login && startup.profile {
+        shell.run_startup_file("~/.vssh_profile", false);
+        shell.profile_phase("~/.vssh_profile");
+    }
+    // A shell that reads no commands from the terminal is ready now.
+    if args.len() > 1
+        && let Some(profile) = shell.startup_profile.take()
+    {
+        profile.report();
+    }
+    let status = match args.get(1).map(String::as_str) {
+        Some("-c") => match args.get(2) {
+            Some(command) => {
+                if let Some(name) = args.get(3) {
+                    shell.name = name.clone();
+                    shell.positional = args[4..].to_vec();
+                }
+                shell.set_option("restricted", restricted);
+                shell.run_source(command)
+            }
+            None => {
+                eprintln!("vssh: -c: option requires an argument");
+                2
+            }
+        },
+        Some(path) => {
+            shell.name = path.to_string();
+            shell.positional = args[2..].to_vec();
+            shell.set_option("restricted", restricted);
+            shell.run_script(path)
+        }
+        None => {
+            if let Some((path, required)) = startup.rc_file() {
+                shell.run_startup_file(path, required);
+            }
+            shell.profile_phase("rc file");
+            shell.set_option("restricted", restricted);
+            shell.run_interactive()
+        }
+    };
+    std::process::exit(shell.run_exit_trap(status));
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Shell::new()
+    }
+}
+
+impl Shell {
+    /// A non-interactive shell with the process's environment as its
+    /// variables, as `vssh -c` starts, but with no startup files run.
+    pub fn new() -> Self {
+        let mut shell = Shell {
+            last_status: 0,
+            vars: std::env::vars().collect(),
+            arrays: HashMap::new(),
+            functions: HashMap::new(),
+            aliases: HashMap::new(),
+            expanding_aliases: Vec::new(),
+            name: "vssh".to_string(),
+            positional: Vec::new(),
+            local_frames: Vec::new(),
+            source_depth: 0,
+            getopts_position: (1, 1),
+            hashed: HashMap::new(),
+            hashed_path: String::new(),
+            path_index: PathIndex::default(),
+            dir_stack: Vec::new(),
+            options: HashSet::new(),
+            jobs: Vec::new(),
+            job_control: false,
+            last_background: None,
+            input_abandoned: false,
+            tty_modes: None,
+            conditions: 0,
+            interactive: false,
+            line_number: 0,
+            traps: HashMap::new(),
+            running_trap: false,
+            current_command: String::new(),
+            policy: None,
+            handling_not_found: false,
+            prompt_input: false,
+            running_hooks: Vec::new(),
+            pid: nix::unistd::getpid(),
+            jobs_warning: false,
+            exit_warned: false,
+            audit: None,
+            stdio: [None, None, None],
+            startup_profile: None,
+            kill_ring: KillRing::default(),
+            history: History::default(),
+            dir_env: None,
+            plugins: Plugins::default(),
+            theme: None,
+        };
+        // Keep an inherited `$PWD` only if it still names the current
+        // directory, so that a logical path through symlinks survives.
+        if !shell.vars.get("PWD").is_some_and(|pwd| names_current_dir(pwd))
+            && let Ok(physical) = std::env::current_dir()
+        {
+            shell.vars.insert("PWD".to_string(), physical.display().to_string());
+        }
+        shell
+    }
+
+    /// With `--profile-startup`, record that the startup phase `phase` has
+    /// just ended.
+    fn profile_phase(&mut self, phase: &'static str) {
+        if let Some(profile) = &mut self.startup_profile {
+            profile.lap(phase);
+        }
+    }
+
+    /// Runs a startup file, where a leading `~/` stands for `$HOME`. A
+    /// missing file is skipped quietly unless it is `required`.
+    fn run_startup_file(&mut self, path: &str, required: bool) {
+        let path = match path.strip_prefix("~/") {
+            Some(rest) => match self.vars.get("HOME") {
+                Some(home) => format!("{}/{}", home, rest),
+                None => return,
+            },
+            None => path.to_string(),
+        };
+        if !required && !std::path::Path::new(&path).exists() {
+            return;
+        }
+        match self.source_file(&path) {
+            Ok(Status::Exit) => std::process::exit(self.run_exit_trap(self.last_status)),
+            Ok(_) => {}
+            Err(e) => eprintln!("vssh: {}: {}", path, e),
+        }
+    }
+
+    /// Reads commands from the terminal until `exit` or end of input.
+    fn run_interactive(&mut self) -> i32 {
+        // The handler is reset to the default in children by exec, so
+        // Ctrl-C still interrupts the foreground command. There is no
+        // SA_RESTART, so that Ctrl-C at the prompt abandons the line.
+        let action = SigAction::new(SigHandler::Handler(on_sigint), SaFlags::empty(), SigSet::empty());
+        if let Err(e) = unsafe { sigaction(Signal::SIGINT, &action) } {
+            eprintln!("vssh: cannot install SIGINT handler: {}", e);
+        }
+        install_sigchld_handler(true);
+        // The line editor redraws the line when the terminal changes size.
+        let action = SigAction::new(SigHandler::Handler(on_sigwinch), SaFlags::SA_RESTART, SigSet::empty());
+        let _ = unsafe { sigaction(Signal::SIGWINCH, &action) };
+        self.interactive = true;
+        if nix::unistd::isatty(0).unwrap_or(false) {
+            self.enable_job_control();
+        }
+        self.profile_phase("signals and job control");
+        if let Some(path) = self.history_file()
+            && let Err(e) = self.history.load(&path)
+            && e.kind() != io::ErrorKind::NotFound
+        {
+            eprintln!("vssh: {}: {}", path, e);
+        }
+        self.update_dir_env();
+        self.run(|shell, continuation| {
+            loop {
+                let prompt = if continuation {
+                    "> ".to_string()
+                } else {
+                    shell.exit_warned = std::mem::take(&mut shell.jobs_warning);
+                    for report in shell.finished_jobs() {
+                        println!("{}", report);
+                    }
+                    if let Status::Exit = shell.run_hook("precmd", &[]) {
+                        return None;
+                    }
+                    let prompt = shell.prompt();
+                    shell.profile_phase("first prompt");
+                    if let Some(profile) = shell.startup_profile.take() {
+                        profile.report();
+                    }
+                    prompt
+                };
+                let line = shell.read_command_line(&prompt);
+                if line.is_none() && !continuation {
+                    // End the line the prompt is on, as the terminal
+                    // does not echo Ctrl-D.
+                    if nix::unistd::isatty(0).unwrap_or(false) {
+                        println!();
+                    }
+                    if shell.warn_about_jobs() {
+                        continue;
+                    }
+                }
+                shell.prompt_input = line.is_some();
+                return line;
+            }
+        });
+        if let Some(path) = self.history_file()
+            && let Err(e) = self.history.save(&path, &self.history_rules())
+        {
+            eprintln!("vssh: {}: {}", path, e);
+        }
+        if self.option("huponexit") {
+            self.hang_up_jobs();
+        }
+        self.last_status
+    }
+
+    /// The primary prompt: as the theme in use lays it out, or else the
+    /// current directory and `$ `.
+    fn prompt(&self) -> String {
+        let cwd = self.vars.get("PWD").map_or("unknown", String::as_str);
+        match &self.theme {
+            Some((_, theme)) => theme.render(&PromptState { cwd, home: self.vars.get("HOME").map(String::as_str), status: self.last_status }),
+            None => format!("{}$ ", cwd),
+        }
+    }
+
+    /// The file the history is kept in between sessions: `$HISTFILE`, or
+    /// `~/.vssh_history` if that is unset. Set but empty, there is none.
+    fn history_file(&self) -> Option<String> {
+        match self.vars.get("HISTFILE") {
+            Some(path) => Some(path.clone()).filter(|path| !path.is_empty()),
+            None => self.vars.get("HOME").map(|home| format!("{}/.vssh_history", home)),
+        }
+    }
+
+    /// What the history leaves out, as the `histignoredups`,
+    /// `histignorealldups` and `histignorespace` options and the
+    /// colon-separated patterns of `$HISTIGNORE` say, and how many lines
+    /// it keeps: `$HISTSIZE`, or 1000.
+    fn history_rules(&self) -> HistoryRules {
+        HistoryRules {
+            ignore_dups: self.option("histignoredups"),
+            ignore_all_dups: self.option("histignorealldups"),
+            ignore_space: self.option("histignorespace"),
+            patterns: self
+                .vars
+                .get("HISTIGNORE")
+                .map(|patterns| patterns.split(':').filter(|pattern| !pattern.is_empty()).map(str::to_string).collect())
+                .unwrap_or_default(),
+            size: self.vars.get("HISTSIZE").and_then(|size| size.parse().ok()).unwrap_or(1000),
+        }
+    }
+
+    /// Show `prompt` and read one line of input from the terminal, with the
+    /// line editor unless `$TERM` is `dumb`; the editor redraws the line
+    /// when the terminal changes size. With the `notify` option, jobs that
+    /// finish meanwhile are reported right away. Ctrl-C abandons the line,
+    /// and any unfinished command before it. If `$TMOUT` is a positive
+    /// number of seconds and no line has been entered by then, the shell
+    /// logs out.
+    fn read_command_line(&mut self, prompt: &str) -> Option<String> {
+        let raw_mode = match self.vars.get("TERM") {
+            Some(term) if term == "dumb" => None,
+            _ => RawMode::enter(),
+        };
+        let mut editor = raw_mode.as_ref().map(|_| Editor::default());
+        let mut keys = KeyReader::default();
+        let show_prompt = |editor: &mut Option<Editor>| match editor {
+            Some(editor) => {
+                let _ = editor.draw(prompt);
+            }
+            None => {
+                print!("{}", prompt);
+                io::stdout().flush().unwrap();
+            }
+        };
+        // Ends the line being edited before other output.
+        let end_line = |editor: &mut Option<Editor>| match editor {
+            Some(editor) => {
+                let _ = editor.leave(prompt);
+            }
+            None => println!(),
+        };
+        show_prompt(&mut editor);
+        let deadline = self
+            .vars
+            .get("TMOUT")
+            .and_then(|seconds| seconds.parse::<u64>().ok())
+            .filter(|&seconds| seconds > 0)
+            .map(|seconds| std::time::Instant::now() + std::time::Duration::from_secs(seconds));
+        let mut bytes = Vec::new();
+        loop {
+            let wakeup = match self.wait_for_input(deadline) {
+                Wakeup::Input => {
+                    let mut byte = [0u8];
+                    match nix::unistd::read(0, &mut byte) {
+                        Ok(0) if bytes.is_empty() && editor.as_ref().is_none_or(|editor| editor.text().is_empty()) => return None,
+                        Ok(0) => break,
+                        Ok(_) => {
+                            let Some(editor) = &mut editor else {
+                                bytes.push(byte[0]);
+                                if byte[0] == b'\n' {
+                                    break;
+                                }
+                                continue;
+                            };
+                            let edit = keys.feed(byte[0]).map(|key| editor.handle(key, &mut self.kill_ring, self.history.entries()));
+                            if let Some(text) = self.kill_ring.take_changed().map(str::to_string) {
+                                self.copy_to_clipboard(&text, raw_mode.as_ref());
+                            }
+                            match edit {
+                                None | Some(Edit::Pasting) => {}
+                                Some(Edit::Continue) => {
+                                    let _ = editor.draw(prompt);
+                                }
+                                Some(Edit::Pick(pick)) => {
+                                    let _ = editor.leave(prompt);
+                                    let candidates = match pick {
+                                        Pick::History => {
+                                            let mut lines: Vec<String> = Vec::new();
+                                            for line in self.history.entries().iter().rev() {
+                                                if !lines.contains(line) {
+                                                    lines.push(line.clone());
+                                                }
+                                            }
+                                            lines
+                                        }
+                                        Pick::Paths => self.paths_below(),
+                                    };
+                                    let picked = self.pick(&candidates, raw_mode.as_ref());
+                                    match pick {
+                                        Pick::History => {
+                                            if let Some(line) = picked.into_iter().next() {
+                                                editor.replace(line);
+                                            }
+                                        }
+                                        Pick::Paths if !picked.is_empty() => {
+                                            let quoted: Vec<String> = picked.iter().map(|path| quote_for_display(path)).collect();
+                                            editor.insert_text(&quoted.join(" "));
+                                        }
+                                        Pick::Paths => {}
+                                    }
+                                    let _ = editor.draw(prompt);
+                                }
+                                Some(Edit::Complete) => {
+                                    let before = editor.before_cursor();
+                                    let word = before.rsplit([' ', '\t']).next().unwrap_or_default().to_string();
+                                    let candidates = self.completions(before);
+                                    let common = candidates.iter().skip(1).fold(candidates.first().map_or("", String::as_str), |common, candidate| {
+                                        let length = common.char_indices().zip(candidate.chars()).take_while(|((_, a), b)| a == b).count();
+                                        &common[..common.char_indices().nth(length).map_or(common.len(), |(index, _)| index)]
+                                    });
+                                    if common.len() > word.len() {
+                                        let mut text = common[word.len()..].to_string();
+                                        if candidates.len() == 1 && !common.ends_with('/') {
+                                            text.push(' ');
+                                        }
+                                        editor.insert_text(&text);
+                                    } else if candidates.len() > 1 {
+                                        let _ = editor.leave(prompt);
+                                        print!("{}\r\n", candidates.join("  "));
+                                    }
+                                    let _ = editor.draw(prompt);
+                                }
+                                Some(Edit::Accept) => {
+                                    let _ = editor.leave(prompt);
+                                    break;
+                                }
+                                Some(Edit::Eof) => return None,
+                                Some(Edit::OpenEditor) => {
+                                    let _ = editor.leave(prompt);
+                                    if let Some(raw_mode) = &raw_mode {
+                                        raw_mode.suspend();
+                                    }
+                                    if let Some(text) = self.edit_externally(editor.text()) {
+                                        editor.replace(text);
+                                    }
+                                    if let Some(raw_mode) = &raw_mode {
+                                        raw_mode.resume();
+                                    }
+                                    let _ = editor.draw(prompt);
+                                }
+                            }
+                            continue;
+                        }
+                        Err(Errno::EINTR) => Wakeup::Signal,
+                        Err(_) => {
+                            eprintln!("Error reading the input");
+                            return None;
+                        }
+                    }
+                }
+                wakeup => wakeup,
+            };
+            if wakeup == Wakeup::Timeout {
+                end_line(&mut editor);
+                eprintln!("vssh: timed out waiting for input: auto-logout");
+                self.exit_warned = true;
+                return None;
+            }
+            if WINDOW_RESIZED.swap(false, Ordering::SeqCst)
+                && let Some(editor) = &mut editor
+            {
+                editor.resize(prompt);
+                let _ = editor.draw(prompt);
+            }
+            if take_interrupt() {
+                end_line(&mut editor);
+                self.last_status = 130;
+                self.input_abandoned = true;
+                return Some(String::new());
+            }
+            if PENDING_TRAPS.load(Ordering::SeqCst) != 0 {
+                end_line(&mut editor);
+                if let Some(raw_mode) = &raw_mode {
+                    raw_mode.suspend();
+                }
+                if let Status::Exit = self.run_pending_traps() {
+                    return None;
+                }
+                if let Some(raw_mode) = &raw_mode {
+                    raw_mode.resume();
+                }
+                show_prompt(&mut editor);
+            }
+            if !self.option("notify") {
+                // Ended jobs are reported at the next prompt; reap them now
+                // so that their pidfds stop waking the shell.
+                self.update_jobs();
+                continue;
+            }
+            let reports = self.finished_jobs();
+            if !reports.is_empty() {
+                end_line(&mut editor);
+                for report in reports {
+                    println!("{}", report);
+                }
+                show_prompt(&mut editor);
+            }
+        }
+        match editor {
+            Some(editor) => Some(format!("{}\n", editor.text())),
+            None => Some(String::from_utf8_lossy(&bytes).into_owned()),
+        }
+    }
+
+    /// Run `source` as `vssh -c` would, returning the exit status of the
+    /// last command. Nothing is run if `source` has a syntax error, which
+    /// is returned as an error instead. Errors met while running are
+    /// reported on standard error, as the shell always does, and count as
+    /// a failure; `exit` stops `source` but not the shell, which stays
+    /// usable.
+    pub fn eval(&mut self, source: &str) -> anyhow::Result<ExitStatus> {
+        if let Err(e) = parse(source) {
+            anyhow::bail!("syntax error: {}", e);
+        }
+        let redirects: Vec<Redirect> = (0..)
+            .zip(&self.stdio)
+            .filter_map(|(fd, file)| Some(Redirect::Duplicate(fd, file.as_ref()?.as_raw_fd().to_string().into())))
+            .collect();
+        let Some(saved) = self.redirect_shell(&redirects)? else { return Ok(ExitStatus(self.last_status)) };
+        let status = self.run_source(source);
+        restore_fds(saved);
+        Ok(ExitStatus(status))
+    }
+
+    /// The value of shell variable `name`, if it is set.
+    pub fn var(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(String::as_str)
+    }
+
+    /// Set shell variable `name` to `value`.
+    pub fn set_var(&mut self, name: &str, value: &str) {
+        self.vars.insert(name.to_string(), value.to_string());
+    }
+
+    /// Unset shell variable `name`.
+    pub fn unset_var(&mut self, name: &str) {
+        self.vars.remove(name);
+    }
+
+    /// Every shell variable that is set, with its value, in no particular
+    /// order.
+    pub fn vars(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// The text alias `name` stands for, if it is defined.
+    pub fn alias(&self, name: &str) -> Option<&str> {
+        self.aliases.get(name).map(String::as_str)
+    }
+
+    /// Define alias `name` to stand for `value`, as `alias name=value` does.
+    pub fn set_alias(&mut self, name: &str, value: &str) {
+        self.aliases.insert(name.to_string(), value.to_string());
+    }
+
+    /// Remove alias `name`.
+    pub fn unalias(&mut self, name: &str) {
+        self.aliases.remove(name);
+    }
+
+    /// Every alias, with the text it stands for, in no particular order.
+    pub fn aliases(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.aliases.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Give the commands that `eval` runs `file` as their standard input.
+    /// The shell reads from and writes to descriptors 0, 1 and 2 itself,
+    /// so while `eval` runs they are `file` for the whole process.
+    pub fn set_stdin(&mut self, file: impl Into<OwnedFd>) {
+        self.stdio[0] = Some(file.into());
+    }
+
+    /// Give the commands that `eval` runs `file` as their standard output,
+    /// as `set_stdin` does for input.
+    pub fn set_stdout(&mut self, file: impl Into<OwnedFd>) {
+        self.stdio[1] = Some(file.into());
+    }
+
+    /// Give the commands that `eval` runs `file` as their standard error,
+    /// as `set_stdin` does for input.
+    pub fn set_stderr(&mut self, file: impl Into<OwnedFd>) {
+        self.stdio[2] = Some(file.into());
+    }
+
+    /// Runs every line of a script file in order.
+    fn run_script(&mut self, path: &str) -> i32 {
+        if let Err(e) = self.source_file(path) {
+            eprintln!("vssh: {}: {}", path, e);
+            return 127;
+        }
+        self.last_status
+    }
+
+    /// Reads and runs the commands in a file, returning how execution stopped.
+    fn source_file(&mut self, path: &str) -> io::Result<Status> {
+        let file = std::fs::File::open(path)?;
+        let mut lines = io::BufReader::new(file).lines();
+        Ok(self.run(|_, _| match lines.next()? {
+            Ok(line) => Some(line),
+            Err(e) => {
+                eprintln!("vssh: {}: {}", path, e);
+                None
+            }
+        }))
+    }
+
+    /// Runs the lines of a `-c` argument.
+    fn run_source(&mut self, source: &str) -> i32 {
+        let mut lines = source.lines();
+        self.run(|_, _| lines.next().map(str::to_string));
+        self.last_status
+    }
+
+    /// Reads lines from `next_line` until a complete command has been
+    /// collected, then executes it. `next_line` gets the shell and is told
+    /// whether it is continuing an unfinished command so it can show the
+    /// right prompt.
+    /// Returns `Exit` or `Return` if one of those stopped execution.
+    fn run<F: FnMut(&mut Shell, bool) -> Option<String>>(&mut self, mut next_line: F) -> Status {
+        let saved_line_number = self.line_number;
+        let status = self.run_lines(&mut next_line);
+        self.line_number = saved_line_number;
+        status
+    }
+
+    /// Worker for `run`. A fatal error, such as an unset variable with
+    /// `set -u`, stops a non-interactive shell as if by `exit`. With `set
+    /// -n` a non-interactive shell only parses what it reads, to check it.
+    /// With `set -v` each line is echoed to stderr as it is read. Lines
+    /// are read until the lexer has nothing pending (such as an open
+    /// quote) and the parser has a complete command.
+    fn run_lines<F: FnMut(&mut Shell, bool) -> Option<String>>(&mut self, next_line: &mut F) -> Status {
+        let mut lexer = Lexer::default();
+        let mut lines_read = 0;
+        let mut first_line = 1;
+        while let Some(line) = next_line(self, !lexer.source().is_empty()) {
+            lines_read += 1;
+            if lexer.source().is_empty() {
+                first_line = lines_read;
+            }
+            if std::mem::take(&mut self.input_abandoned) {
+                lexer.clear();
+                continue;
+            }
+            if self.option("verbose") {
+                eprintln!("{}", line.trim_end_matches('\n'));
+            }
+            lexer.feed_line(line.trim_end_matches('\n'));
+            // The commands borrow their words from the lexer, which starts
+            // afresh on the next line unless these are incomplete.
+            let lexed = std::mem::take(&mut lexer);
+            let parsed = parse_lexed(&lexed);
+            let commands = match parsed {
+                Ok(commands) => commands,
+                Err(ParseError::Incomplete) => {
+                    lexer = lexed;
+                    continue;
+                }
+                Err(e) => {
+                    self.syntax_error(lexed.source(), first_line, &e);
+                    self.prompt_input = false;
+                    continue;
+                }
+            };
+            // A command line typed at the prompt is timed for `notify_if_long`.
+            let mut typed = None;
+            if std::mem::take(&mut self.prompt_input) && !commands.is_empty() {
+                let command = lexed.source().trim_end().to_string();
+                self.history.add(&command, &self.history_rules());
+                match self.run_hook("preexec", std::slice::from_ref(&command)) {
+                    Status::Continue => {}
+                    status => return status,
+                }
+                typed = Some((command, std::time::Instant::now()));
+            }
+            if self.option("noexec") && !self.interactive {
+                continue;
+            }
+            self.line_number = first_line;
+            for command in &commands {
+                match self.execute(command) {
+                    Ok(Status::Continue) => {}
+                    Ok(status) => return status,
+                    Err(e) => match e.downcast_ref::<FatalError>() {
+                        Some(fatal) => {
+                            eprintln!("{}", fatal);
+                            self.last_status = 1;
+                            if !self.interactive {
+                                return Status::Exit;
+                            }
+                            break;
+                        }
+                        None => {
+                            eprintln!("Error: {}", e);
+                            self.last_status = 1;
+                        }
+                    },
+                }
+                if CHILD_EXITED.swap(false, Ordering::SeqCst) {
+                    self.update_jobs();
+                }
+                match self.run_pending_traps() {
+                    Status::Continue => {}
+                    status => return status,
+                }
+                if take_interrupt() {
+                    break;
+                }
+            }
+            if let Some((command, started)) = typed {
+                self.notify_if_long(&command, started.elapsed());
+            }
+        }
+        if !lexer.source().is_empty() {
+            self.syntax_error(lexer.source(), lines_read, &ParseError::Incomplete);
+        }
+        Status::Continue
+    }
+
+    /// Tell the user that the command line `command`, typed at the prompt,
+    /// has finished if it took at least `$VSSH_NOTIFY_AFTER` seconds, so
+    /// that they can work elsewhere meanwhile. `$VSSH_NOTIFY` says how:
+    /// `osc` (the default) sends the terminal an OSC 9 notification,
+    /// which terminals that support it show when their window is not
+    /// focused; `bell` rings the bell, which they flag likewise; and
+    /// `notify-send` pops up a desktop notification.
+    fn notify_if_long(&mut self, command: &str, elapsed: std::time::Duration) {
+        let threshold = self.vars.get("VSSH_NOTIFY_AFTER").and_then(|seconds| seconds.parse::<f64>().ok());
+        if threshold.is_none_or(|threshold| elapsed.as_secs_f64() < threshold) {
+            return;
+        }
+        let status = self.last_status;
+        let summary = format!("{} finished with status {} after {}s", command, status, elapsed.as_secs());
+        match self.vars.get("VSSH_NOTIFY").map_or("osc", String::as_str) {
+            "bell" => eprint!("\x07"),
+            "notify-send" => {
+                let argv = ["notify-send".to_string(), "vssh".to_string(), summary];
+                match self.spawn_foreground(&argv, || {}) {
+                    Ok(child) => {
+                        self.wait_foreground(argv.join(" "), vec![child]);
+                    }
+                    Err(e) => eprintln!("vssh: notify-send: {}", e.desc()),
+                }
+                self.last_status = status;
+            }
+            _ => eprint!("\x1b]9;{}\x07", summary.replace(|c: char| c.is_control(), " ")),
+        }
+    }
+
+    /// What Tab may complete the last word of `before`, the line up to the
+    /// cursor, to: the candidates of plugin completers that start with
+    /// it, or if there are none, the paths that do.
+    fn completions(&mut self, before: &str) -> Vec<String> {
+        let mut words: Vec<String> = before.split_whitespace().map(str::to_string).collect();
+        if before.is_empty() || before.ends_with([' ', '\t']) {
+            words.push(String::new());
+        }
+        let word = words.last().cloned().unwrap_or_default();
+        let mut candidates: Vec<String> = self.plugins.complete(&words).into_iter().filter(|candidate| candidate.starts_with(&word)).collect();
+        if candidates.is_empty() {
+            return self.paths_starting(&word);
+        }
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+
+    /// Let the user pick from `candidates` with the fuzzy finder in
+    /// `$VSSH_FZF_CMD`, or `fzf` if that is unset and installed, which is
+    /// given them one to a line and prints those picked; or failing both,
+    /// with the editor's own `choose`. Returns what was picked, if
+    /// anything.
+    fn pick(&mut self, candidates: &[String], raw_mode: Option<&RawMode>) -> Vec<String> {
+        let command = self.vars.get("VSSH_FZF_CMD").filter(|command| !command.trim().is_empty()).cloned();
+        let argv: Vec<String> = match command {
+            Some(command) => command.split_whitespace().map(str::to_string).collect(),
+            None if self.resolve_command("fzf").is_some() => vec!["fzf".to_string()],
+            None => return choose(candidates).into_iter().collect(),
+        };
+        // Lines of a multi-line candidate are shown as one.
+        let shown: Vec<String> = candidates.iter().map(|candidate| candidate.replace('\n', " ")).collect();
+        let (Ok((input, to_finder)), Ok((from_finder, output))) = (cloexec_pipe(), cloexec_pipe()) else {
+            return Vec::new();
+        };
+        if let Some(raw_mode) = raw_mode {
+            raw_mode.suspend();
+        }
+        let status = self.last_status;
+        let mut picked = String::new();
+        let finished = match self.spawn_foreground(&argv, move || {
+            let _ = install_fd(input, 0);
+            let _ = install_fd(output, 1);
+        }) {
+            Ok(child) => {
+                let mut list = shown.join("\n");
+                list.push('\n');
+                let _ = std::fs::File::from(to_finder).write_all(list.as_bytes());
+                let _ = io::Read::read_to_string(&mut std::fs::File::from(from_finder), &mut picked);
+                self.wait_foreground(argv.join(" "), vec![child]) == 0
+            }
+            Err(e) => {
+                eprintln!("vssh: {}: {}", argv[0], e.desc());
+                false
+            }
+        };
+        self.last_status = status;
+        if let Some(raw_mode) = raw_mode {
+            raw_mode.resume();
+        }
+        if !finished {
+            return Vec::new();
+        }
+        picked
+            .lines()
+            .filter_map(|line| shown.iter().position(|candidate| candidate == line).map(|index| candidates[index].clone()))
+            .collect()
+    }
+
+    /// Let the user edit `text`, the line being edited, in `$VISUAL` or
+    /// `$EDITOR` (`vi` if neither is set) through a temporary file. Returns
+    /// what they saved, without the final newline, or `None` if the editor
+    /// failed.
+    fn edit_externally(&mut self, text: &str) -> Option<String> {
+        let command = ["VISUAL", "EDITOR"]
+            .iter()
+            .find_map(|name| self.vars.get(*name).filter(|command| !command.trim().is_empty()))
+            .map_or("vi", String::as_str);
+        let mut argv: Vec<String> = command.split_whitespace().map(str::to_string).collect();
+        let directory = self.vars.get("TMPDIR").filter(|directory| !directory.is_empty()).map_or("/tmp", String::as_str);
+        let (fd, path) = match nix::unistd::mkstemp(format!("{}/vssh-edit-XXXXXX", directory).as_str()) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("vssh: cannot create a temporary file in {}: {}", directory, e.desc());
+                return None;
+            }
+        };
+        let written = unsafe { std::fs::File::from_raw_fd(fd) }.write_all(format!("{}\n", text).as_bytes());
+        argv.push(path.to_string_lossy().into_owned());
+        let status = self.last_status;
+        let saved = written.is_ok()
+            && match self.spawn_foreground(&argv, || {}) {
+                Ok(child) => self.wait_foreground(argv.join(" "), vec![child]) == 0,
+                Err(e) => {
+                    eprintln!("vssh: {}: {}", argv[0], e.desc());
+                    false
+                }
+            };
+        self.last_status = status;
+        let edited = if saved { std::fs::read_to_string(&path).ok() } else { None };
+        let _ = std::fs::remove_file(&path);
+        edited.map(|text| text.trim_end_matches('\n').to_string())
+    }
+
+    /// Copy `text`, just killed in the line editor, to the system clipboard
+    /// as `$VSSH_CLIPBOARD` says: `osc52` has the terminal do it with an OSC
+    /// 52 sequence, which works over ssh too, and anything else is a command
+    /// given the text on its standard input, such as `wl-copy` or `xclip
+    /// -selection clipboard`. Unset or empty, kills stay in the editor.
+    fn copy_to_clipboard(&mut self, text: &str, raw_mode: Option<&RawMode>) {
+        let argv: Vec<String> = match self.vars.get("VSSH_CLIPBOARD").map(String::as_str) {
+            None | Some("") => return,
+            Some("osc52") => {
+                print!("\x1b]52;c;{}\x07", base64(text.as_bytes()));
+                let _ = io::stdout().flush();
+                return;
+            }
+            Some(command) => command.split_whitespace().map(str::to_string).collect(),
+        };
+        let Ok((read, write)) = cloexec_pipe() else {
+            return;
+        };
+        if let Some(raw_mode) = raw_mode {
+            raw_mode.suspend();
+        }
+        let status = self.last_status;
+        match self.spawn_foreground(&argv, move || {
+            let _ = install_fd(read, 0);
+        }) {
+            Ok(child) => {
+                // The pipe's read end, moved into the closure, was dropped
+                // with it, so only the command holds it now.
+                let _ = std::fs::File::from(write).write_all(text.as_bytes());
+                self.wait_foreground(argv.join(" "), vec![child]);
+            }
+            Err(e) => eprintln!("vssh: {}: {}", argv[0], e.desc()),
+        }
+        self.last_status = status;
+        if let Some(raw_mode) = raw_mode {
+            raw_mode.resume();
+        }
+    }
+
+    /// Report a syntax error in `source`, which starts on line `first_line`
+    /// of the input, showing the line with a caret under the bad token. A
+    /// script's errors are reported with its name and the line number.
+    fn syntax_error(&mut self, source: &str, first_line: usize, error: &ParseError) {
+        let location = error.location(source);
+        let line = first_line + location.map_or(0, |(line, _, _)| line);
+        if self.interactive {
+            eprintln!("vssh: syntax error: {}", error);
+        } else {
+            eprintln!("{}: line {}: syntax error: {}", self.name, line, error);
+        }
+        if let Some((_, text, column)) = location {
+            eprintln!("  {}", text);
+            let padding: String = text.chars().take(column).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+            eprintln!("  {}^", padding);
+        }
+        self.last_status = 2;
+    }
+}
+
+/// `--record path`: run the session under a new pseudo-terminal, as
+/// `script(1)` does, and save what it shows to `path`, with timing data in
+/// `path.timing` for `scriptreplay -t path.timing path` and the keys typed
+/// in `path.input`. Returns in the child, which goes on to be the shell;
+/// the parent passes data between the terminal and the pseudo-terminal
+/// until the shell exits, then exits with its status.
+fn record_session(path: &str) {
+    let create = |path: &str| std::fs::File::create(path).map_err(|e| format!("{}: {}", path, e));
+    let files = create(path).and_then(|log| Ok((log, create(&format!("{}.timing", path))?, create(&format!("{}.input", path))?)));
+    let (log, timing, input) = match files {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("vssh: --record: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let sized = unsafe { libc::ioctl(0, libc::TIOCGWINSZ, &mut size) } == 0;
+    let pty = match nix::pty::openpty(sized.then_some(&size), None) {
+        Ok(pty) => pty,
+        Err(e) => {
+            eprintln!("vssh: --record: cannot open a pseudo-terminal: {}", e);
+            std::process::exit(1);
+        }
+    };
+    match unsafe { fork() } {
+        Ok(ForkResult::Child) => {
+            let _ = close(pty.master);
+            let _ = nix::unistd::setsid();
+            unsafe { libc::ioctl(pty.slave, libc::TIOCSCTTY, 0) };
+            for fd in 0..3 {
+                let _ = dup2(pty.slave, fd);
+            }
+            let _ = close(pty.slave);
+        }
+        Ok(ForkResult::Parent { child }) => {
+            let _ = close(pty.slave);
+            eprintln!("vssh: recording to {}", path);
+            let status = relay_session(child, pty.master, (log, timing, input));
+            eprintln!("vssh: recording saved to {}", path);
+            std::process::exit(status);
+        }
+        Err(e) => {
+            eprintln!("vssh: fork failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The parent's half of `record_session`: copy the terminal's input to
+/// the pseudo-terminal `master` and its output to the terminal and the
+/// log until `child` exits. Returns its exit status.
+fn relay_session(child: Pid, master: RawFd, files: (std::fs::File, std::fs::File, std::fs::File)) -> i32 {
+    use nix::poll::{poll, PollFd, PollFlags};
+    let (mut log, mut timing, mut input) = files;
+    let saved = nix::sys::termios::tcgetattr(0).ok();
+    if let Some(saved) = &saved {
+        let mut raw = saved.clone();
+        nix::sys::termios::cfmakeraw(&mut raw);
+        let _ = nix::sys::termios::tcsetattr(0, nix::sys::termios::SetArg::TCSANOW, &raw);
+    }
+    let action = SigAction::new(SigHandler::Handler(on_sigwinch), SaFlags::SA_RESTART, SigSet::empty());
+    let _ = unsafe { sigaction(Signal::SIGWINCH, &action) };
+    let _ = writeln!(log, "Script started on {} [COMMAND=\"vssh\"]", local_time());
+    let mut last_output = std::time::Instant::now();
+    let mut buffer = [0u8; 4096];
+    let mut status = None;
+    let mut input_open = true;
+    loop {
+        if WINDOW_RESIZED.swap(false, Ordering::SeqCst) {
+            let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+            if unsafe { libc::ioctl(0, libc::TIOCGWINSZ, &mut size) } == 0 {
+                unsafe { libc::ioctl(master, libc::TIOCSWINSZ, &size) };
+            }
+        }
+        if status.is_none() {
+            status = match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::Exited(_, code)) => Some(code),
+                Ok(WaitStatus::Signaled(_, signal, _)) => Some(128 + signal as i32),
+                Err(_) => Some(1),
+                Ok(_) => None,
+            };
+        }
+        // Once the shell has exited, whatever it left unread is drained
+        // without waiting for jobs that still hold the terminal open.
+        let mut fds = vec![PollFd::new(master, PollFlags::POLLIN)];
+        if input_open && status.is_none() {
+            fds.push(PollFd::new(0, PollFlags::POLLIN));
+        }
+        match poll(&mut fds, if status.is_some() { 0 } else { 100 }) {
+            Ok(0) if status.is_some() => break,
+            Ok(_) => {}
+            Err(Errno::EINTR) => continue,
+            Err(_) => break,
+        }
+        let ready = |fd: &PollFd| fd.revents().is_some_and(|events| !events.is_empty());
+        if fds.get(1).is_some_and(ready) {
+            match nix::unistd::read(0, &mut buffer) {
+                // The end of input is passed on as Ctrl-D.
+                Ok(0) | Err(_) => {
+                    input_open = false;
+                    let _ = nix::unistd::write(master, b"\x04");
+                }
+                Ok(count) => {
+                    let _ = input.write_all(&buffer[..count]);
+                    let _ = nix::unistd::write(master, &buffer[..count]);
+                }
+            }
+        }
+        if ready(&fds[0]) {
+            match nix::unistd::read(master, &mut buffer) {
+                Ok(0) | Err(_) if status.is_some() => break,
+                Ok(0) | Err(_) => {}
+                Ok(count) => {
+                    let _ = io::stdout().write_all(&buffer[..count]);
+                    let _ = io::stdout().flush();
+                    let _ = log.write_all(&buffer[..count]);
+                    let _ = writeln!(timing, "{:.6} {}", last_output.elapsed().as_secs_f64(), count);
+                    last_output = std::time::Instant::now();
+                }
+            }
+        }
+    }
+    if let Some(saved) = &saved {
+        let _ = nix::sys::termios::tcsetattr(0, nix::sys::termios::SetArg::TCSANOW, saved);
+    }
+    let status = status.unwrap_or(1);
+    let _ = writeln!(log, "\nScript done on {} [COMMAND_EXIT_CODE=\"{}\"]", local_time(), status);
+    status
+}
+
+/// The local date and time, as `script(1)` writes it in its log.
+fn local_time() -> String {
+    let mut buffer = [0u8; 64];
+    let length = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut time: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut time);
+        libc::strftime(buffer.as_mut_ptr().cast(), buffer.len(), c"%Y-%m-%d %H:%M:%S%z".as_ptr(), &time)
+    };
+    String::from_utf8_lossy(&buffer[..length]).into_owned()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    /// Held while a test runs commands, since the working directory, the
+    /// environment and the children waited for are the whole process's,
+    /// which the tests share.
+    static PROCESS: Mutex<()> = Mutex::new(());
+
+    /// A shell to run commands in, and the lock to hold while running them.
+    pub(crate) fn shell() -> (MutexGuard<'static, ()>, Shell) {
+        (PROCESS.lock().unwrap_or_else(PoisonError::into_inner), Shell::new())
+    }
+
+    /// Run `source` in `shell`, returning its exit status and what it
+    /// wrote to standard output. Only output written to the descriptor is
+    /// seen, not that of `println!`, which the test harness takes.
+    pub(crate) fn run(shell: &mut Shell, source: &str) -> (i32, String) {
+        let path = temp_path("output");
+        shell.set_stdout(std::fs::File::create(&path).unwrap());
+        let status = shell.eval(source).unwrap().code();
+        shell.stdio[1] = None;
+        let output = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        (status, output)
+    }
+
+    /// A path in the temporary directory that no other test uses.
+    pub(crate) fn temp_path(name: &str) -> std::path::PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("vssh-{}-{}-{}", name, std::process::id(), count))
+    }
+
+    #[test]
+    fn runs_commands_spread_over_lines() {
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "if true\nthen\n  echo 'a\nb'\nfi\necho c"), (0, "a\nb\nc\n".to_string()));
+        assert_eq!(run(&mut shell, "x=1\nfalse"), (1, String::new()));
+        assert_eq!(shell.var("x"), Some("1"));
+    }
+
+    #[test]
+    fn refuses_to_run_source_with_a_syntax_error() {
+        let (_lock, mut shell) = shell();
+        assert!(shell.eval("x=1; if true; then").is_err());
+        assert!(shell.eval("x=2; fi").is_err());
+        assert_eq!(shell.var("x"), None);
+    }
+
+    #[test]
+    fn reports_the_status_of_the_last_command() {
+        let (_lock, mut shell) = shell();
+        let script = temp_path("script");
+        std::fs::write(&script, "exit 0").unwrap();
+        let script_name = script.to_str().unwrap();
+        assert_eq!(run(&mut shell, "true; sh -c 'exit 7'"), (7, String::new()));
+        assert_eq!(run(&mut shell, "vssh-no-such-command"), (127, String::new()));
+        assert_eq!(run(&mut shell, script_name), (126, String::new()));
+        assert_eq!(run(&mut shell, "exit 3; true"), (3, String::new()));
+        std::fs::remove_file(&script).unwrap();
+    }
+
+    #[test]
+    fn picks_the_startup_files_asked_for() {
+        let startup = |rc, rcfile: Option<&str>| Startup { login: false, profile: true, rc, rcfile: rcfile.map(str::to_string) };
+        assert_eq!(startup(true, None).rc_file(), Some(("~/.vsshrc", false)));
+        assert_eq!(startup(true, Some("/etc/rc")).rc_file(), Some(("/etc/rc", true)));
+        assert_eq!(startup(false, Some("/etc/rc")).rc_file(), None);
+        let (_lock, mut shell) = shell();
+        let home = temp_path("home");
+        std::fs::create_dir(&home).unwrap();
+        std::fs::write(home.join(".vsshrc"), "from_rc=1\n").unwrap();
+        shell.set_var("HOME", home.to_str().unwrap());
+        shell.run_startup_file("~/.vsshrc", false);
+        assert_eq!(shell.var("from_rc"), Some("1"));
+        shell.run_startup_file("~/.vssh_profile", false);
+        shell.vars.remove("HOME");
+        shell.run_startup_file("~/.vsshrc", true);
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn records_what_a_session_shows() {
+        use std::os::unix::process::CommandExt;
+        let _lock = shell().0;
+        let log = temp_path("typescript");
+        let files = [log.clone(), log.with_extension("timing"), log.with_extension("input")];
+        let shown = temp_path("shown");
+        // The relay reads standard input and writes standard output, so it
+        // runs in a child that has them pointed away from the terminal.
+        let relay = match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let null = std::fs::File::open("/dev/null").unwrap();
+                let shown = std::fs::File::create(&shown).unwrap();
+                let _ = dup2(null.as_raw_fd(), 0);
+                let _ = dup2(shown.as_raw_fd(), 1);
+                let pty = nix::pty::openpty(None, None).unwrap();
+                let session = match unsafe { fork() }.unwrap() {
+                    ForkResult::Child => {
+                        let _ = nix::unistd::setsid();
+                        for fd in 0..3 {
+                            let _ = dup2(pty.slave, fd);
+                        }
+                        let _ = std::process::Command::new("sh").args(["-c", "printf hello; read line; exit 5"]).exec();
+                        unsafe { libc::_exit(127) };
+                    }
+                    ForkResult::Parent { child } => child,
+                };
+                let _ = close(pty.slave);
+                let [log, timing, input] = files.each_ref().map(|path| std::fs::File::create(path).unwrap());
+                unsafe { libc::_exit(relay_session(session, pty.master, (log, timing, input))) };
+            }
+            ForkResult::Parent { child } => child,
+        };
+        assert_eq!(waitpid(relay, None), Ok(WaitStatus::Exited(relay, 5)));
+        let [log, timing, input] = files.each_ref().map(|path| std::fs::read_to_string(path).unwrap());
+        assert!(log.starts_with("Script started on ") && log.contains("hello"), "{}", log);
+        assert!(log.ends_with("[COMMAND_EXIT_CODE=\"5\"]\n"), "{}", log);
+        assert!(timing.lines().all(|line| line.split(' ').count() == 2) && !timing.is_empty(), "{}", timing);
+        assert_eq!(input, "");
+        assert!(std::fs::read_to_string(&shown).unwrap().contains("hello"));
+        std::fs::remove_file(shown).unwrap();
+        for path in files {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn noexec_checks_scripts_without_running_them() {
+        let (_lock, mut shell) = shell();
+        let script = temp_path("noexec");
+        std::fs::write(&script, "x=ran\nif true; then\n  echo a\nfi\n").unwrap();
+        shell.set_option("noexec", true);
+        assert_eq!(shell.run_script(script.to_str().unwrap()), 0);
+        assert_eq!(shell.var("x"), None);
+        std::fs::write(&script, "x=ran\nif true; then\n  echo a\n").unwrap();
+        assert_ne!(shell.run_script(script.to_str().unwrap()), 0);
+        std::fs::write(&script, "echo a |\n| echo b\nx=ran\n").unwrap();
+        assert_ne!(shell.run_script(script.to_str().unwrap()), 0);
+        assert_eq!(shell.var("x"), None);
+        assert_eq!(crate::builtins::option_for_flag('n'), Some("noexec"));
+        std::fs::remove_file(&script).unwrap();
+    }
+
+    #[test]
+    fn verbose_echoes_input_without_changing_what_runs() {
+        // The echo itself goes to standard error through `eprintln!`, which
+        // the test harness takes, so only the option's effect on running
+        // commands can be seen here.
+        let (_lock, mut shell) = shell();
+        assert_eq!(run(&mut shell, "set -v\necho 'a\nb'\nx=1; set +v"), (0, "a\nb\n".to_string()));
+        assert_eq!(shell.var("x"), Some("1"));
+        assert!(!shell.option("verbose"));
+        assert_eq!(crate::builtins::option_for_flag('v'), Some("verbose"));
+    }
+
+    #[test]
+    fn notifies_when_a_long_command_finishes() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::Duration;
+        let (_lock, mut shell) = shell();
+        let dir = temp_path("notify");
+        std::fs::create_dir(&dir).unwrap();
+        let sent = dir.join("sent");
+        let script = dir.join("notify-send");
+        std::fs::write(&script, format!("#!/bin/sh\nprintf '%s|' \"$@\" > {}\n", sent.display())).unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        shell.set_var("PATH", dir.to_str().unwrap());
+        shell.set_var("VSSH_NOTIFY", "notify-send");
+        shell.last_status = 2;
+        shell.notify_if_long("make", Duration::from_secs(12));
+        assert!(!sent.exists());
+        shell.set_var("VSSH_NOTIFY_AFTER", "10");
+        shell.notify_if_long("make", Duration::from_secs(9));
+        assert!(!sent.exists());
+        shell.notify_if_long("make", Duration::from_secs(12));
+        assert_eq!(std::fs::read_to_string(&sent).unwrap(), "vssh|make finished with status 2 after 12s|");
+        assert_eq!(shell.last_status, 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn profiles_each_phase_of_starting_up() {
+        use std::time::{Duration, Instant};
+        let started = Instant::now() - Duration::from_millis(5);
+        let mut profile = StartupProfile::new(started);
+        profile.lap("arguments");
+        std::thread::sleep(Duration::from_millis(20));
+        profile.lap("~/.vsshrc");
+        let lines = profile.lines();
+        assert_eq!(lines.len(), 3);
+        let millis = |line: &str| line.split("ms").next().unwrap().rsplit(' ').next().unwrap().parse::<f64>().unwrap();
+        assert!(lines[0].starts_with("vssh: startup took "));
+        assert!(lines[1].ends_with("ms  arguments") && millis(&lines[1]) >= 5.0);
+        assert!(lines[2].ends_with("ms  ~/.vsshrc") && millis(&lines[2]) >= 20.0);
+        assert!((millis(&lines[0]) - millis(&lines[1]) - millis(&lines[2])).abs() < 0.01);
+    }
+
+    #[test]
+    fn copies_kills_to_the_clipboard_command() {
+        use std::os::unix::fs::PermissionsExt;
+        let (_lock, mut shell) = shell();
+        let dir = temp_path("clipboard");
+        std::fs::create_dir(&dir).unwrap();
+        let copy = dir.join("copy");
+        std::fs::write(&copy, format!("#!/bin/sh\ncat > {}/copied\n", dir.display())).unwrap();
+        std::fs::set_permissions(&copy, std::fs::Permissions::from_mode(0o755)).unwrap();
+        shell.copy_to_clipboard("ignored", None);
+        assert!(!dir.join("copied").exists());
+        shell.set_var("VSSH_CLIPBOARD", copy.to_str().unwrap());
+        shell.last_status = 3;
+        shell.copy_to_clipboard("killed text", None);
+        assert_eq!(std::fs::read_to_string(dir.join("copied")).unwrap(), "killed text");
+        assert_eq!(shell.last_status, 3);
+        assert_eq!(base64(b"killed text"), "a2lsbGVkIHRleHQ=");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn edits_the_line_in_the_users_editor() {
+        let (_lock, mut shell) = shell();
+        let dir = temp_path("edit");
+        std::fs::create_dir(&dir).unwrap();
+        shell.set_var("TMPDIR", dir.to_str().unwrap());
+        shell.set_var("EDITOR", "false");
+        shell.set_var("VISUAL", "sed -i s/one/two/");
+        assert_eq!(shell.edit_externally("echo one"), Some("echo two".to_string()));
+        shell.set_var("VISUAL", " ");
+        assert_eq!(shell.edit_externally("echo one"), None);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        let mut editor = editor::Editor::default();
+        let mut kill_ring = editor::KillRing::default();
+        editor.handle(editor::Key::Ctrl('x'), &mut kill_ring, &[]);
+        assert_eq!(editor.handle(editor::Key::Ctrl('e'), &mut kill_ring, &[]), editor::Edit::OpenEditor);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn picks_through_the_fuzzy_finder_command() {
+        let (_lock, mut shell) = shell();
+        let candidates: Vec<String> = ["git log", "ls", "for x in a\ndo echo log; done"].map(String::from).to_vec();
+        shell.set_var("VSSH_FZF_CMD", "grep log");
+        shell.last_status = 3;
+        assert_eq!(shell.pick(&candidates, None), [candidates[0].clone(), candidates[2].clone()]);
+        shell.set_var("VSSH_FZF_CMD", "grep nothing");
+        assert!(shell.pick(&candidates, None).is_empty());
+        assert_eq!(shell.last_status, 3);
+    }
+}
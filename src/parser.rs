@@ -0,0 +1,588 @@
+//! Parsing source text into commands.
+
+use std::borrow::Cow;
+use std::rc::Rc;
+use anyhow::Result;
+use crate::ast::{Command, Launch, Redirect};
+use crate::lexer::{Lexer, RESERVED_WORDS, Token, is_name, is_redirect_operator, split_patterns};
+
+/// Why a piece of input could not be parsed.
+#[derive(Debug)]
+pub(crate) enum ParseError {
+    /// The input ends inside an unfinished compound command.
+    Incomplete,
+    /// The input contains a token that is not allowed where it appears:
+    /// its text, the token before it, if any, and its byte offset.
+    Unexpected { token: String, after: Option<String>, offset: usize },
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Incomplete => write!(f, "unexpected end of file"),
+            ParseError::Unexpected { token, after: None, .. } => write!(f, "unexpected `{}`", token),
+            ParseError::Unexpected { token, after: Some(after), .. } => write!(f, "unexpected `{}` after `{}`", token, after),
+        }
+    }
+}
+
+impl ParseError {
+    /// Where in `source` the error is, as the number of the line (from 0),
+    /// the line itself and the column in it, for showing a caret.
+    pub(crate) fn location<'a>(&self, source: &'a str) -> Option<(usize, &'a str, usize)> {
+        let ParseError::Unexpected { offset, .. } = self else { return None };
+        let offset = (*offset).min(source.len());
+        let start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let end = source[offset..].find('\n').map_or(source.len(), |i| offset + i);
+        let line = &source[start..end];
+        Some((source[..start].matches('\n').count(), line, source[start..offset].chars().count()))
+    }
+}
+
+/// Parse a complete piece of input into a list of commands, which own
+/// their words: lines joined by backslashes are no longer in `source` as
+/// they are read.
+pub(crate) fn parse(source: &str) -> Result<Vec<Command<'static>>, ParseError> {
+    let mut lexer = Lexer::default();
+    for line in source.lines() {
+        lexer.feed_line(line);
+    }
+    Ok(parse_lexed(&lexer)?.iter().map(Command::to_static).collect())
+}
+
+/// Parse the input fed to `lexer`, which is incomplete while the lexer
+/// has something pending. The commands borrow their words from it.
+pub(crate) fn parse_lexed(lexer: &Lexer) -> Result<Vec<Command<'_>>, ParseError> {
+    if lexer.pending().is_some() {
+        return Err(ParseError::Incomplete);
+    }
+    let mut parser = Parser { source: lexer.source(), tokens: lexer.tokens(), pos: 0 };
+    let commands = parser.parse_list(&[])?;
+    match parser.peek() {
+        Some(_) => Err(parser.unexpected()),
+        None => Ok(commands),
+    }
+}
+
+/// Recursive-descent parser over the token stream.
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.pos)
+    }
+
+    /// The error for the current token being out of place.
+    fn unexpected(&self) -> ParseError {
+        self.unexpected_at(self.pos)
+    }
+
+    /// The error for the token at `index`, or for the end of the line if
+    /// there are no more tokens, being out of place.
+    fn unexpected_at(&self, index: usize) -> ParseError {
+        let name = |token: &Token| if token.text == "\n" { "newline".to_string() } else { token.text.to_string() };
+        let after = index.checked_sub(1).and_then(|i| self.tokens.get(i)).filter(|token| token.text != "\n").map(name);
+        match self.tokens.get(index) {
+            Some(token) => ParseError::Unexpected { token: name(token), after, offset: token.start },
+            None => ParseError::Unexpected { token: "newline".to_string(), after, offset: self.source.trim_end().len() },
+        }
+    }
+
+    /// The current token if it is an unquoted reserved word.
+    fn peek_reserved(&self) -> Option<&str> {
+        self.peek()
+            .map(|token| token.text)
+            .filter(|text| RESERVED_WORDS.contains(text))
+    }
+
+    fn skip_separators(&mut self) {
+        while self.peek().is_some_and(Token::is_separator) {
+            self.pos += 1;
+        }
+    }
+
+    /// Consume the reserved word `word`, failing if something else is next.
+    fn expect(&mut self, word: &str) -> Result<(), ParseError> {
+        match self.peek() {
+            None => Err(ParseError::Incomplete),
+            Some(token) if token.text == word => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(_) => Err(self.unexpected()),
+        }
+    }
+
+    /// Parse commands until one of the reserved words in `terminators`
+    /// (which is left unconsumed) or the end of input.
+    fn parse_list(&mut self, terminators: &[&str]) -> Result<Vec<Command<'a>>, ParseError> {
+        let mut commands = Vec::new();
+        loop {
+            self.skip_separators();
+            match self.peek_reserved() {
+                _ if self.peek().is_none() => {
+                    if terminators.is_empty() {
+                        return Ok(commands);
+                    }
+                    return Err(ParseError::Incomplete);
+                }
+                _ if self.peek().is_some_and(|token| token.text == ";;") => return Ok(commands),
+                Some(word) if terminators.contains(&word) => {
+                    if commands.is_empty() {
+                        return Err(self.unexpected());
+                    }
+                    return Ok(commands);
+                }
+                _ => commands.push(self.parse_and_or()?),
+            }
+        }
+    }
+
+    /// `command [&& command | || command]...`, grouping to the left.
+    fn parse_and_or(&mut self) -> Result<Command<'a>, ParseError> {
+        let mut command = self.parse_negation()?;
+        while let Some(operator) = self.peek().filter(|token| token.is_and_or()).map(|token| token.text) {
+            self.pos += 1;
+            while self.peek().is_some_and(|token| token.text == "\n") {
+                self.pos += 1;
+            }
+            let next = Box::new(self.parse_negation()?);
+            command = match operator {
+                "&&" => Command::And(Box::new(command), next),
+                _ => Command::Or(Box::new(command), next),
+            };
+        }
+        Ok(command)
+    }
+
+    /// `[!] pipeline` or `time [-p] [pipeline]`
+    fn parse_negation(&mut self) -> Result<Command<'a>, ParseError> {
+        match self.peek() {
+            None => Err(ParseError::Incomplete),
+            Some(token) if token.ends_pipeline() => Err(self.unexpected()),
+            Some(token) if token.text == "!" => {
+                self.pos += 1;
+                Ok(Command::Not(Box::new(self.parse_negation()?)))
+            }
+            Some(token) if token.text == "time" => {
+                self.pos += 1;
+                let posix = self.peek().is_some_and(|token| token.text == "-p");
+                if posix {
+                    self.pos += 1;
+                }
+                let command = match self.peek() {
+                    Some(token) if token.ends_pipeline() => None,
+                    _ => Some(Box::new(self.parse_negation()?)),
+                };
+                Ok(Command::Time { posix, command })
+            }
+            Some(_) => self.parse_pipeline(),
+        }
+    }
+
+    /// `command [| command]... [& | &!]`. A compound command that is
+    /// neither piped nor in the background is returned as it is.
+    fn parse_pipeline(&mut self) -> Result<Command<'a>, ParseError> {
+        let start = self.pos;
+        let mut stages = vec![self.parse_command()?];
+        while self.peek().is_some_and(|token| token.text == "|") {
+            self.pos += 1;
+            while self.peek().is_some_and(|token| token.text == "\n") {
+                self.pos += 1;
+            }
+            match self.peek() {
+                None => return Err(ParseError::Incomplete),
+                Some(token) if token.ends_pipeline() => return Err(self.unexpected()),
+                Some(_) => stages.push(self.parse_command()?),
+            }
+        }
+        let text = Cow::Borrowed(&self.source[self.tokens[start].start..self.tokens[self.pos - 1].end]);
+        let launch = match self.peek().map(|token| token.text) {
+            Some("&") => Launch::Background,
+            Some("&!") => Launch::Detached,
+            _ => Launch::Foreground,
+        };
+        if launch != Launch::Foreground {
+            self.pos += 1;
+        } else if stages.len() == 1 && !matches!(stages[0], Command::Simple { .. }) {
+            return Ok(stages.remove(0));
+        }
+        Ok(Command::Pipeline { stages, launch, text })
+    }
+
+    fn parse_command(&mut self) -> Result<Command<'a>, ParseError> {
+        match self.peek_reserved() {
+            Some("if") => self.parse_if(),
+            Some("while") | Some("until") => self.parse_loop(),
+            Some("for") => self.parse_for(),
+            Some("case") => self.parse_case(),
+            Some("{") => self.parse_group(),
+            Some("coproc") => self.parse_coproc(),
+            Some("[[") => {
+                self.pos += 1;
+                let mut words = Vec::new();
+                loop {
+                    match self.peek() {
+                        None => return Err(ParseError::Incomplete),
+                        Some(token) if token.text == "]]" => break,
+                        Some(token) if token.text == "\n" => {}
+                        Some(token) if token.ends_command() => return Err(self.unexpected()),
+                        Some(token) => words.push(Cow::Borrowed(token.text)),
+                    }
+                    self.pos += 1;
+                }
+                self.pos += 1;
+                Ok(Command::Cond(words))
+            }
+            Some("function") => {
+                self.pos += 1;
+                let name = match self.peek() {
+                    None => return Err(ParseError::Incomplete),
+                    Some(token) => token.text,
+                };
+                if !is_name(name) {
+                    return Err(self.unexpected());
+                }
+                self.pos += 1;
+                if self.peek().is_some_and(|token| token.text == "()") {
+                    self.pos += 1;
+                }
+                self.parse_function_body(Cow::Borrowed(name))
+            }
+            Some(_) => Err(self.unexpected()),
+            None => {
+                let text = self.tokens[self.pos].text;
+                if is_name(text) && self.tokens.get(self.pos + 1).is_some_and(|token| token.text == "()") {
+                    self.pos += 2;
+                    return self.parse_function_body(Cow::Borrowed(text));
+                }
+                let mut words = Vec::new();
+                let mut redirects = Vec::new();
+                while let Some(token) = self.peek().filter(|token| !token.ends_pipeline()) {
+                    if is_redirect_operator(token.text) {
+                        redirects.push(self.parse_redirect()?);
+                    } else {
+                        words.push(Cow::Borrowed(token.text));
+                        self.pos += 1;
+                    }
+                }
+                Ok(Command::Simple { words, redirects })
+            }
+        }
+    }
+
+    /// `if list; then list; [elif list; then list;]... [else list;] fi [redirections]`
+    fn parse_if(&mut self) -> Result<Command<'a>, ParseError> {
+        let mut branches = Vec::new();
+        let mut otherwise = None;
+        self.expect("if")?;
+        loop {
+            let condition = self.parse_list(&["then"])?;
+            self.expect("then")?;
+            let body = self.parse_list(&["elif", "else", "fi"])?;
+            branches.push((condition, body));
+            match self.peek_reserved() {
+                Some("elif") => self.pos += 1,
+                Some("else") => {
+                    self.pos += 1;
+                    otherwise = Some(self.parse_list(&["fi"])?);
+                    break;
+                }
+                _ => break,
+            }
+        }
+        self.expect("fi")?;
+        let redirects = self.parse_redirects()?;
+        Ok(Command::If { branches, otherwise, redirects })
+    }
+
+    /// `while list; do list; done [redirections]`, likewise for `until`
+    fn parse_loop(&mut self) -> Result<Command<'a>, ParseError> {
+        let until = self.peek_reserved() == Some("until");
+        self.pos += 1;
+        let condition = self.parse_list(&["do"])?;
+        self.expect("do")?;
+        let body = self.parse_list(&["done"])?;
+        self.expect("done")?;
+        let redirects = self.parse_redirects()?;
+        Ok(Command::Loop { until, condition, body, redirects })
+    }
+
+    /// `for name in words; do list; done [redirections]`
+    fn parse_for(&mut self) -> Result<Command<'a>, ParseError> {
+        self.expect("for")?;
+        let variable = match self.peek() {
+            None => return Err(ParseError::Incomplete),
+            Some(token) if is_name(token.text) => Cow::Borrowed(token.text),
+            Some(_) => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        self.skip_separators();
+        let mut words = None;
+        if self.peek().is_some_and(|token| token.text == "in") {
+            self.pos += 1;
+            let mut list = Vec::new();
+            while let Some(token) = self.peek().filter(|token| !token.ends_command()) {
+                list.push(Cow::Borrowed(token.text));
+                self.pos += 1;
+            }
+            words = Some(list);
+            self.skip_separators();
+        }
+        self.expect("do")?;
+        let body = self.parse_list(&["done"])?;
+        self.expect("done")?;
+        let redirects = self.parse_redirects()?;
+        Ok(Command::For { variable, words, body, redirects })
+    }
+
+    /// `coproc [name] compound-command` or `coproc simple-command`: only a
+    /// compound command can be given a name, which is `COPROC` otherwise.
+    fn parse_coproc(&mut self) -> Result<Command<'a>, ParseError> {
+        self.expect("coproc")?;
+        let compound = |token: Option<&Token>| {
+            token.is_some_and(|token| matches!(token.text, "{" | "if" | "while" | "until" | "for" | "case" | "[["))
+        };
+        let mut name = Cow::Borrowed("COPROC");
+        if let Some(token) = self.peek().filter(|token| is_name(token.text) && compound(self.tokens.get(self.pos + 1))) {
+            name = Cow::Borrowed(token.text);
+            self.pos += 1;
+        }
+        match self.peek() {
+            None => Err(ParseError::Incomplete),
+            Some(token) if token.ends_pipeline() => Err(self.unexpected()),
+            Some(_) => Ok(Command::Coproc { name, command: Box::new(self.parse_command()?) }),
+        }
+    }
+
+    /// `{ list; } [redirections]`
+    fn parse_group(&mut self) -> Result<Command<'a>, ParseError> {
+        self.expect("{")?;
+        let body = self.parse_list(&["}"])?;
+        self.expect("}")?;
+        let redirects = self.parse_redirects()?;
+        Ok(Command::Group { body, redirects })
+    }
+
+    /// The compound command that follows `name()` in a function definition.
+    fn parse_function_body(&mut self, name: Cow<'a, str>) -> Result<Command<'a>, ParseError> {
+        self.skip_separators();
+        if self.peek().is_none() {
+            return Err(ParseError::Incomplete);
+        }
+        let start = self.pos;
+        match self.parse_command()? {
+            Command::Simple { .. } => Err(self.unexpected_at(start)),
+            body => Ok(Command::Function { name, body: Rc::new(body) }),
+        }
+    }
+
+    /// `case word in [(]pattern[|pattern]...) list ;; ... esac`
+    fn parse_case(&mut self) -> Result<Command<'a>, ParseError> {
+        self.expect("case")?;
+        let word = match self.peek() {
+            None => return Err(ParseError::Incomplete),
+            Some(token) if !token.ends_command() => Cow::Borrowed(token.text),
+            Some(_) => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        self.expect("in")?;
+        let mut items = Vec::new();
+        loop {
+            self.skip_separators();
+            if self.peek_reserved() == Some("esac") {
+                self.pos += 1;
+                break;
+            }
+            // The pattern list runs up to the first word ending in `)`.
+            let mut pattern_text = String::new();
+            loop {
+                let token = self.peek().ok_or(ParseError::Incomplete)?;
+                if token.ends_command() {
+                    return Err(self.unexpected());
+                }
+                pattern_text.push_str(token.text);
+                self.pos += 1;
+                if pattern_text.ends_with(')') {
+                    break;
+                }
+            }
+            pattern_text.pop();
+            let pattern_text = pattern_text.strip_prefix('(').unwrap_or(&pattern_text);
+            let patterns = split_patterns(pattern_text);
+            let body = self.parse_list(&["esac"])?;
+            items.push((patterns, body));
+            match self.peek() {
+                Some(token) if token.text == ";;" => self.pos += 1,
+                _ => {}
+            }
+        }
+        let redirects = self.parse_redirects()?;
+        Ok(Command::Case { word, items, redirects })
+    }
+
+    /// Redirections following the end of a compound command.
+    fn parse_redirects(&mut self) -> Result<Vec<Redirect<'a>>, ParseError> {
+        let mut redirects = Vec::new();
+        while let Some(token) = self.peek().filter(|token| !token.ends_pipeline()) {
+            if !is_redirect_operator(token.text) {
+                return Err(self.unexpected());
+            }
+            redirects.push(self.parse_redirect()?);
+        }
+        Ok(redirects)
+    }
+
+    /// A redirection operator, which is the current token, and the word
+    /// after it.
+    fn parse_redirect(&mut self) -> Result<Redirect<'a>, ParseError> {
+        let operator = self.tokens[self.pos].text;
+        self.pos += 1;
+        match self.peek() {
+            Some(token) if !token.ends_pipeline() && !is_redirect_operator(token.text) => {
+                let target = Cow::Borrowed(token.text);
+                self.pos += 1;
+                Ok(Redirect::parse(operator, target).expect("a redirection operator"))
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The words of a simple command, which may be inside a pipeline of
+    /// one stage.
+    fn simple_words<'a>(command: &'a Command) -> Vec<&'a str> {
+        match command {
+            Command::Simple { words, .. } => words.iter().map(|word| word.as_ref()).collect(),
+            Command::Pipeline { stages, launch: Launch::Foreground, .. } if stages.len() == 1 => simple_words(&stages[0]),
+            other => panic!("not a simple command: {:?}", other),
+        }
+    }
+
+    fn parse_one(source: &str) -> Command<'static> {
+        let mut commands = parse(source).unwrap();
+        assert_eq!(commands.len(), 1, "{:?}", commands);
+        commands.remove(0)
+    }
+
+    #[test]
+    fn parses_simple_commands_with_redirections() {
+        let command = parse_one("x=1 echo a 'b c' 2>&1 >out");
+        let Command::Pipeline { stages, launch: Launch::Foreground, text } = &command else { panic!("{:?}", command) };
+        assert_eq!(text, "x=1 echo a 'b c' 2>&1 >out");
+        let Command::Simple { words, redirects } = &stages[0] else { panic!("{:?}", stages[0]) };
+        assert_eq!(words, &["x=1", "echo", "a", "'b c'"]);
+        assert!(matches!(&redirects[..], [Redirect::Duplicate(2, one), Redirect::Output(1, out)] if one == "1" && out == "out"));
+    }
+
+    #[test]
+    fn parses_pipelines_and_lists() {
+        let commands = parse("a | b &\nc; d &!").unwrap();
+        assert_eq!(commands.len(), 3);
+        assert!(matches!(&commands[0], Command::Pipeline { stages, launch: Launch::Background, text } if stages.len() == 2 && text == "a | b"));
+        assert_eq!(simple_words(&commands[1]), ["c"]);
+        assert!(matches!(&commands[2], Command::Pipeline { launch: Launch::Detached, .. }));
+    }
+
+    #[test]
+    fn groups_and_or_to_the_left() {
+        let Command::Or(first, second) = parse_one("a && ! b || c") else { panic!() };
+        let Command::And(a, not) = *first else { panic!() };
+        assert_eq!(simple_words(&a), ["a"]);
+        assert!(matches!(*not, Command::Not(_)));
+        assert_eq!(simple_words(&second), ["c"]);
+    }
+
+    #[test]
+    fn parses_compound_commands() {
+        let Command::If { branches, otherwise, redirects } = parse_one("if a; then b; elif c\nthen d; else e; fi >f") else { panic!() };
+        assert_eq!(branches.len(), 2);
+        assert_eq!(simple_words(&branches[1].0[0]), ["c"]);
+        assert_eq!(simple_words(&otherwise.unwrap()[0]), ["e"]);
+        assert_eq!(redirects.len(), 1);
+
+        let Command::Loop { until: true, condition, body, .. } = parse_one("until a; do b; c; done") else { panic!() };
+        assert_eq!((condition.len(), body.len()), (1, 2));
+
+        let Command::For { variable, words, .. } = parse_one("for i in 1 \"2 3\"; do echo $i; done") else { panic!() };
+        assert_eq!(variable, "i");
+        assert_eq!(words.unwrap(), ["1", "\"2 3\""]);
+        assert!(matches!(parse_one("for i; do :; done"), Command::For { words: None, .. }));
+
+        let Command::Case { word, items, .. } = parse_one("case $x in (a|b) one;; *) two;; esac") else { panic!() };
+        assert_eq!(word, "$x");
+        assert_eq!(items.iter().map(|(patterns, _)| patterns.clone()).collect::<Vec<_>>(), [vec!["a", "b"], vec!["*"]]);
+
+        let Command::Cond(words) = parse_one("[[ -n $a && $b == c* ]]") else { panic!() };
+        assert_eq!(words, ["-n", "$a", "&&", "$b", "==", "c*"]);
+    }
+
+    #[test]
+    fn parses_function_definitions() {
+        for source in ["f() { a; }", "f(){ a; }", "function f { a; }", "function f() { a; }", "function f(){ a; }", "f ()\n{ a; }"] {
+            let Command::Function { name, body } = parse_one(source) else { panic!("{}", source) };
+            assert_eq!(name, "f");
+            assert!(matches!(*body, Command::Group { .. }));
+        }
+        assert!(parse("f() echo").is_err());
+    }
+
+    #[test]
+    fn parses_coproc_and_time() {
+        assert!(matches!(parse_one("coproc cat"), Command::Coproc { name, .. } if name == "COPROC"));
+        assert!(matches!(parse_one("coproc worker { cat; }"), Command::Coproc { name, .. } if name == "worker"));
+        assert!(matches!(parse_one("time -p sleep 1"), Command::Time { posix: true, command: Some(_) }));
+    }
+
+    #[test]
+    fn borrows_words_from_the_lexer() {
+        let mut lexer = Lexer::default();
+        lexer.feed_line("echo a >b");
+        let commands = parse_lexed(&lexer).unwrap();
+        let Command::Pipeline { stages, text, .. } = &commands[0] else { panic!() };
+        let Command::Simple { words, redirects } = &stages[0] else { panic!() };
+        assert!(matches!(text, Cow::Borrowed(_)));
+        assert!(words.iter().all(|word| matches!(word, Cow::Borrowed(_))));
+        assert!(matches!(&redirects[0], Redirect::Output(1, Cow::Borrowed("b"))));
+    }
+
+    #[test]
+    fn reports_incomplete_input() {
+        for source in ["if a; then b", "while a; do", "{ a;", "a &&", "case x in"] {
+            assert!(matches!(parse(source), Err(ParseError::Incomplete)), "{}", source);
+        }
+    }
+
+    #[test]
+    fn reports_unexpected_tokens() {
+        let source = "echo a\nb ;; c";
+        let error = parse("a | ;").unwrap_err();
+        assert_eq!(error.to_string(), "unexpected `;` after `|`");
+        let error = parse(source).unwrap_err();
+        assert!(matches!(&error, ParseError::Unexpected { token, .. } if token == ";;"));
+        assert_eq!(error.location(source), Some((1, "b ;; c", 2)));
+        assert_eq!(parse("fi").unwrap_err().to_string(), "unexpected `fi`");
+    }
+
+    #[test]
+    fn names_the_unexpected_token_and_the_one_before() {
+        let source = "echo a |\n\t| wc";
+        let error = parse(source).unwrap_err();
+        assert_eq!(error.to_string(), "unexpected `|`");
+        assert_eq!(error.location(source), Some((1, "\t| wc", 1)));
+        let error = parse("then b").unwrap_err();
+        assert_eq!(error.to_string(), "unexpected `then`");
+        assert_eq!(error.location("then b"), Some((0, "then b", 0)));
+        let error = parse("echo >").unwrap_err();
+        assert_eq!(error.to_string(), "unexpected `newline` after `>`");
+        assert!(parse("if a; then").unwrap_err().location("if a; then").is_none());
+    }
+}
@@ -0,0 +1,606 @@
+//! Native plugins: shared libraries that `plugin load` opens and that
+//! extend the shell with builtins, completers and hooks. A plugin exports
+//! a C function
+//!
+//! ```c
+//! int vssh_plugin_init(const struct vssh_host *host);
+//! ```
+//!
+//! which registers what it provides through `host` and returns 0, or
+//! anything else to refuse to load, in which case nothing it registered
+//! is kept. In C, the host is
+//!
+//! ```c
+//! typedef int (*vssh_builtin)(void *data, int argc, const char *const *argv);
+//! typedef void (*vssh_add_candidate)(void *completion, const char *candidate);
+//! typedef void (*vssh_completer)(void *data, int argc, const char *const *argv,
+//!                                vssh_add_candidate add, void *completion);
+//! typedef void (*vssh_hook)(void *data, const char *hook, int argc, const char *const *argv);
+//!
+//! struct vssh_host {
+//!     uint32_t abi_version;  /* VSSH_ABI_VERSION, 1 */
+//!     void *registry;
+//!     void (*register_builtin)(void *registry, const char *name, vssh_builtin run, void *data);
+//!     void (*register_completer)(void *registry, const char *command, vssh_completer complete, void *data);
+//!     void (*register_hook)(void *registry, const char *hook, vssh_hook run, void *data);
+//! };
+//! ```
+//!
+//! A builtin gets its arguments, its name first, and returns its exit
+//! status. A completer gets the words of the line up to the cursor, the
+//! last being the one to complete, and calls `add` for each candidate;
+//! with a null `command` it is asked about every command. A hook runs with
+//! the shell hook function of the same name: `precmd`, `preexec` or
+//! `chpwd`. `data` is passed back to each as it was registered. Strings
+//! are only valid during the call they are passed to.
+//!
+//! A native plugin can do anything the shell can. For code that is not
+//! trusted that far, a plugin can instead be a WebAssembly module, which
+//! runs sandboxed: it can reach nothing but the functions it imports from
+//! module `vssh`, where strings are a pointer into its memory and a
+//! length.
+//!
+//! ```c
+//! void register_builtin(const char *name, int name_len, int function);
+//! void register_completer(const char *command, int command_len, int function);
+//! int arg_len(int index);
+//! int arg(int index, char *buffer, int buffer_len);
+//! void add_candidate(const char *candidate, int candidate_len);
+//! int write(int fd, const char *buffer, int len);
+//! int getenv(const char *name, int name_len, char *buffer, int buffer_len);
+//! int read_file(const char *path, int path_len, char *buffer, int buffer_len);
+//! ```
+//!
+//! The module exports `int vssh_plugin_init(void)`, which registers its
+//! builtins and completers, by their index in its function table (a C
+//! function pointer). A builtin is `int builtin(int argc)` and a completer
+//! `void complete(int argc)`; they get their arguments with `arg_len`,
+//! which is -1 past the last, and `arg`, which copies as much of one as
+//! fits and returns its length. A completer gives candidates with
+//! `add_candidate`. `write` writes to standard output (1) or error (2).
+//! The rest are only allowed if granted when the plugin is loaded, and
+//! return -1 otherwise, or when the variable is unset or the file cannot
+//! be read: `getenv` reads the environment, as `arg` reads an argument,
+//! and `read_file` reads a file in the directories granted. A module has
+//! at most 16 MiB of memory, and a call that runs too long is stopped.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, c_char, c_int, c_void};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use crate::wasm::{self, FuncType, Instance, Memory, ValType};
+
+/// The version of the interface described above, which a plugin checks.
+pub(crate) const ABI_VERSION: u32 = 1;
+
+/// The most memory a WebAssembly plugin may have, in 64 KiB pages.
+const SANDBOX_PAGES: u32 = 256;
+
+/// The functions a WebAssembly plugin may import from module `vssh`, with
+/// their parameters and results.
+const SANDBOX_FUNCTIONS: &[(&str, usize, bool)] = &[
+    ("register_builtin", 3, false),
+    ("register_completer", 3, false),
+    ("arg_len", 1, true),
+    ("arg", 3, true),
+    ("add_candidate", 2, false),
+    ("write", 3, true),
+    ("getenv", 4, true),
+    ("read_file", 4, true),
+];
+
+type BuiltinFn = extern "C" fn(data: *mut c_void, argc: c_int, argv: *const *const c_char) -> c_int;
+type AddCandidateFn = extern "C" fn(completion: *mut c_void, candidate: *const c_char);
+type CompleterFn = extern "C" fn(data: *mut c_void, argc: c_int, argv: *const *const c_char, add: AddCandidateFn, completion: *mut c_void);
+type HookFn = extern "C" fn(data: *mut c_void, hook: *const c_char, argc: c_int, argv: *const *const c_char);
+
+#[repr(C)]
+struct Host {
+    abi_version: u32,
+    registry: *mut c_void,
+    register_builtin: extern "C" fn(registry: *mut c_void, name: *const c_char, run: BuiltinFn, data: *mut c_void),
+    register_completer: extern "C" fn(registry: *mut c_void, command: *const c_char, complete: CompleterFn, data: *mut c_void),
+    register_hook: extern "C" fn(registry: *mut c_void, hook: *const c_char, run: HookFn, data: *mut c_void),
+}
+
+/// What one plugin registered.
+#[derive(Default)]
+struct Registry {
+    builtins: Vec<(String, BuiltinFn, *mut c_void)>,
+    completers: Vec<(Option<String>, CompleterFn, *mut c_void)>,
+    hooks: Vec<(String, HookFn, *mut c_void)>,
+}
+
+/// A loaded plugin. Its library stays open for as long as the shell
+/// runs, since what it registered points into it.
+struct Plugin {
+    path: String,
+    registry: Registry,
+}
+
+/// What a WebAssembly plugin may do besides what every one may, as
+/// granted when it is loaded.
+#[derive(Default, Clone)]
+pub(crate) struct Grants {
+    /// Read the environment.
+    pub(crate) env: bool,
+    /// Read files in these directories.
+    pub(crate) read: Vec<PathBuf>,
+}
+
+/// The state of a WebAssembly plugin outside its own memory, which its
+/// imports work on.
+struct Sandbox {
+    grants: Grants,
+    /// Set while the plugin is being initialized, the only time it may
+    /// register builtins and completers.
+    initializing: bool,
+    /// Builtins by name and table index.
+    builtins: Vec<(String, u32)>,
+    completers: Vec<(Option<String>, u32)>,
+    /// The arguments of the builtin or completer running.
+    args: Vec<String>,
+    candidates: Vec<String>,
+}
+
+/// A loaded WebAssembly plugin.
+struct SandboxedPlugin {
+    path: String,
+    instance: Instance,
+    sandbox: Sandbox,
+}
+
+/// A builtin from a plugin.
+#[derive(Clone, Copy)]
+enum Builtin {
+    Native(BuiltinFn, *mut c_void),
+    /// A function in the table of `sandboxed[plugin]`.
+    Sandboxed { plugin: usize, function: u32 },
+}
+
+/// The plugins loaded, and the builtins they provide by name.
+#[derive(Default)]
+pub(crate) struct Plugins {
+    loaded: Vec<Plugin>,
+    sandboxed: Vec<SandboxedPlugin>,
+    builtins: HashMap<String, Builtin>,
+}
+
+impl Plugins {
+    /// Open the shared library at `path` and have it register what it
+    /// provides. A library already loaded is not loaded again.
+    pub(crate) fn load(&mut self, path: &str) -> Result<(), String> {
+        if self.is_loaded(path) {
+            return Ok(());
+        }
+        let file = CString::new(path).map_err(|_| "invalid path".to_string())?;
+        let handle = unsafe { libc::dlopen(file.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(dl_error());
+        }
+        let init = unsafe { libc::dlsym(handle, c"vssh_plugin_init".as_ptr()) };
+        if init.is_null() {
+            unsafe { libc::dlclose(handle) };
+            return Err("not a vssh plugin: no vssh_plugin_init".to_string());
+        }
+        let init: extern "C" fn(host: *const Host) -> c_int = unsafe { std::mem::transmute(init) };
+        let mut registry = Registry::default();
+        let host = Host {
+            abi_version: ABI_VERSION,
+            registry: &mut registry as *mut Registry as *mut c_void,
+            register_builtin,
+            register_completer,
+            register_hook,
+        };
+        let status = init(&host);
+        if status != 0 {
+            unsafe { libc::dlclose(handle) };
+            return Err(format!("initialization failed with status {}", status));
+        }
+        for (name, run, data) in &registry.builtins {
+            self.builtins.insert(name.clone(), Builtin::Native(*run, *data));
+        }
+        self.loaded.push(Plugin { path: path.to_string(), registry });
+        Ok(())
+    }
+
+    /// Run the WebAssembly module in the file at `path` as a plugin, in a
+    /// sandbox, allowing it what `grants` do, and have it register what it
+    /// provides. A module already loaded is not loaded again.
+    pub(crate) fn load_sandboxed(&mut self, path: &str, grants: Grants) -> Result<(), String> {
+        if self.is_loaded(path) {
+            return Ok(());
+        }
+        let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+        let mut sandbox = Sandbox { grants, initializing: true, builtins: Vec::new(), completers: Vec::new(), args: Vec::new(), candidates: Vec::new() };
+        let mut instance = Instance::new(&bytes, &mut sandbox, SANDBOX_PAGES)?;
+        let init = FuncType { params: Vec::new(), results: vec![ValType::I32] };
+        let status = instance.call_export(&mut sandbox, "vssh_plugin_init", &init, &[])?;
+        sandbox.initializing = false;
+        match status[..] {
+            [0] => {}
+            [status] => return Err(format!("initialization failed with status {}", status as i32)),
+            _ => return Err("initialization failed".to_string()),
+        }
+        for (name, function) in &sandbox.builtins {
+            self.builtins.insert(name.clone(), Builtin::Sandboxed { plugin: self.sandboxed.len(), function: *function });
+        }
+        self.sandboxed.push(SandboxedPlugin { path: path.to_string(), instance, sandbox });
+        Ok(())
+    }
+
+    fn is_loaded(&self, path: &str) -> bool {
+        self.loaded.iter().any(|plugin| plugin.path == path) || self.sandboxed.iter().any(|plugin| plugin.path == path)
+    }
+
+    /// Whether a plugin provides builtin `name`.
+    pub(crate) fn has_builtin(&self, name: &str) -> bool {
+        self.builtins.contains_key(name)
+    }
+
+    /// Run plugin builtin `argv[0]`, returning its exit status.
+    pub(crate) fn run_builtin(&mut self, argv: &[String]) -> i32 {
+        match self.builtins.get(&argv[0]).copied() {
+            Some(Builtin::Native(run, data)) => {
+                let (_strings, pointers) = c_strings(argv);
+                let _ = io::stdout().flush();
+                let status = run(data, argv.len() as c_int, pointers.as_ptr());
+                // The plugin may have written with C's stdio.
+                unsafe { libc::fflush(std::ptr::null_mut()) };
+                status
+            }
+            Some(Builtin::Sandboxed { plugin, function }) => {
+                let plugin = &mut self.sandboxed[plugin];
+                plugin.sandbox.args = argv.to_vec();
+                let builtin = FuncType { params: vec![ValType::I32], results: vec![ValType::I32] };
+                match plugin.instance.call_indirect(&mut plugin.sandbox, function, &builtin, &[argv.len() as u64]) {
+                    Ok(status) => status.first().map_or(0, |status| *status as i32),
+                    Err(e) => {
+                        eprintln!("{}: {}: {}", argv[0], plugin.path, e);
+                        1
+                    }
+                }
+            }
+            None => 127,
+        }
+    }
+
+    /// Ask the completers for `words[0]`, and those for every command,
+    /// for candidates for the last of `words`.
+    pub(crate) fn complete(&mut self, words: &[String]) -> Vec<String> {
+        let mut candidates: Vec<String> = Vec::new();
+        let (_strings, pointers) = c_strings(words);
+        let wanted = |command: &Option<String>| command.as_ref().is_none_or(|command| words.first() == Some(command));
+        for plugin in &self.loaded {
+            for (command, complete, data) in &plugin.registry.completers {
+                if wanted(command) {
+                    complete(*data, words.len() as c_int, pointers.as_ptr(), add_candidate, &mut candidates as *mut Vec<String> as *mut c_void);
+                }
+            }
+        }
+        let completer = FuncType { params: vec![ValType::I32], results: Vec::new() };
+        for plugin in &mut self.sandboxed {
+            let functions: Vec<u32> = plugin.sandbox.completers.iter().filter(|(command, _)| wanted(command)).map(|(_, function)| *function).collect();
+            for function in functions {
+                plugin.sandbox.args = words.to_vec();
+                // A completer that fails just has nothing to offer.
+                let _ = plugin.instance.call_indirect(&mut plugin.sandbox, function, &completer, &[words.len() as u64]);
+                candidates.append(&mut plugin.sandbox.candidates);
+            }
+        }
+        candidates
+    }
+
+    /// Run the plugin hooks for `hook` with `args`.
+    pub(crate) fn run_hooks(&self, hook: &str, args: &[String]) {
+        let name = CString::new(hook).unwrap_or_default();
+        let (_strings, pointers) = c_strings(args);
+        for plugin in &self.loaded {
+            for (_, run, data) in plugin.registry.hooks.iter().filter(|(name, _, _)| name == hook) {
+                run(*data, name.as_ptr(), args.len() as c_int, pointers.as_ptr());
+            }
+        }
+        unsafe { libc::fflush(std::ptr::null_mut()) };
+    }
+
+    /// A line for each plugin loaded, saying what it registered, and for
+    /// WebAssembly plugins, what they were granted.
+    pub(crate) fn describe(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for plugin in &self.loaded {
+            let registry = &plugin.registry;
+            let builtins: Vec<&str> = registry.builtins.iter().map(|(name, _, _)| name.as_str()).collect();
+            let commands: Vec<&str> = registry.completers.iter().map(|(command, _, _)| command.as_deref().unwrap_or("*")).collect();
+            let hooks: Vec<&str> = registry.hooks.iter().map(|(hook, _, _)| hook.as_str()).collect();
+            lines.push(plugin_line(&plugin.path, &[("builtins", builtins), ("completes", commands), ("hooks", hooks)]));
+        }
+        for plugin in &self.sandboxed {
+            let sandbox = &plugin.sandbox;
+            let builtins: Vec<&str> = sandbox.builtins.iter().map(|(name, _)| name.as_str()).collect();
+            let commands: Vec<&str> = sandbox.completers.iter().map(|(command, _)| command.as_deref().unwrap_or("*")).collect();
+            let mut grants = vec!["sandboxed".to_string()];
+            if sandbox.grants.env {
+                grants.push("env".to_string());
+            }
+            grants.extend(sandbox.grants.read.iter().map(|dir| format!("read={}", dir.display())));
+            let grants: Vec<&str> = grants.iter().map(String::as_str).collect();
+            lines.push(plugin_line(&plugin.path, &[("builtins", builtins), ("completes", commands), ("grants", grants)]));
+        }
+        lines
+    }
+}
+
+/// A line for `plugin list` about the plugin at `path`, with the parts
+/// given that are not empty.
+fn plugin_line(path: &str, parts: &[(&str, Vec<&str>)]) -> String {
+    let parts: Vec<String> = parts.iter().filter(|(_, items)| !items.is_empty()).map(|(label, items)| format!("{}: {}", label, items.join(" "))).collect();
+    format!("{}\t{}", path, parts.join("; "))
+}
+
+/// `strings` as C strings, and a null-terminated array of pointers to
+/// them, which is only valid while the strings are kept.
+fn c_strings(strings: &[String]) -> (Vec<CString>, Vec<*const c_char>) {
+    let strings: Vec<CString> = strings.iter().map(|string| CString::new(string.replace('\0', "")).unwrap_or_default()).collect();
+    let mut pointers: Vec<*const c_char> = strings.iter().map(|string| string.as_ptr()).collect();
+    pointers.push(std::ptr::null());
+    (strings, pointers)
+}
+
+/// A string from a plugin, or `None` for a null pointer.
+fn from_c(string: *const c_char) -> Option<String> {
+    (!string.is_null()).then(|| unsafe { CStr::from_ptr(string) }.to_string_lossy().into_owned())
+}
+
+/// The message for the last error of `dlopen` and friends.
+fn dl_error() -> String {
+    from_c(unsafe { libc::dlerror() }).unwrap_or_else(|| "cannot load".to_string())
+}
+
+extern "C" fn register_builtin(registry: *mut c_void, name: *const c_char, run: BuiltinFn, data: *mut c_void) {
+    let registry = unsafe { &mut *(registry as *mut Registry) };
+    if let Some(name) = from_c(name).filter(|name| !name.is_empty()) {
+        registry.builtins.push((name, run, data));
+    }
+}
+
+extern "C" fn register_completer(registry: *mut c_void, command: *const c_char, complete: CompleterFn, data: *mut c_void) {
+    let registry = unsafe { &mut *(registry as *mut Registry) };
+    registry.completers.push((from_c(command), complete, data));
+}
+
+extern "C" fn register_hook(registry: *mut c_void, hook: *const c_char, run: HookFn, data: *mut c_void) {
+    let registry = unsafe { &mut *(registry as *mut Registry) };
+    if let Some(hook) = from_c(hook) {
+        registry.hooks.push((hook, run, data));
+    }
+}
+
+extern "C" fn add_candidate(completion: *mut c_void, candidate: *const c_char) {
+    let candidates = unsafe { &mut *(completion as *mut Vec<String>) };
+    if let Some(candidate) = from_c(candidate) {
+        candidates.push(candidate);
+    }
+}
+
+impl Sandbox {
+    /// The string at `pointer` in `memory`, of `len` bytes.
+    fn string(memory: &Memory, pointer: u64, len: u64) -> Result<String, String> {
+        Ok(String::from_utf8_lossy(memory.read(pointer as u32 as u64, len as u32 as u64)?).into_owned())
+    }
+
+    /// Copy as much of `data` as fits to the buffer of `len` bytes at
+    /// `pointer` in `memory`, returning the length of all of it.
+    fn copy_out(memory: &mut Memory, data: &[u8], pointer: u64, len: u64) -> Result<Option<u64>, String> {
+        let fits = data.len().min(len as u32 as usize);
+        memory.write(pointer as u32 as u64, &data[..fits])?;
+        Ok(Some(data.len() as u32 as u64))
+    }
+}
+
+impl wasm::Host for Sandbox {
+    fn resolve(&self, module: &str, name: &str, ty: &FuncType) -> Option<usize> {
+        if module != "vssh" {
+            return None;
+        }
+        let function = SANDBOX_FUNCTIONS.iter().position(|(function, _, _)| *function == name)?;
+        let (_, params, result) = SANDBOX_FUNCTIONS[function];
+        let wanted = FuncType { params: vec![ValType::I32; params], results: if result { vec![ValType::I32] } else { Vec::new() } };
+        (*ty == wanted).then_some(function)
+    }
+
+    fn call(&mut self, function: usize, args: &[u64], memory: &mut Memory) -> Result<Option<u64>, String> {
+        const DENIED: u64 = -1i32 as u32 as u64;
+        let name = SANDBOX_FUNCTIONS[function].0;
+        match (name, args) {
+            ("register_builtin" | "register_completer", [pointer, len, index]) => {
+                if !self.initializing {
+                    return Err(format!("{}: only allowed in vssh_plugin_init", name));
+                }
+                let command = Sandbox::string(memory, *pointer, *len)?;
+                if name == "register_completer" {
+                    self.completers.push((Some(command).filter(|command| !command.is_empty()), *index as u32));
+                } else if !command.is_empty() {
+                    self.builtins.push((command, *index as u32));
+                }
+                Ok(None)
+            }
+            ("arg_len", [index]) => Ok(Some(self.args.get(*index as u32 as usize).map_or(DENIED, |arg| arg.len() as u32 as u64))),
+            ("arg", [index, pointer, len]) => match self.args.get(*index as u32 as usize) {
+                Some(arg) => Sandbox::copy_out(memory, arg.as_bytes(), *pointer, *len),
+                None => Ok(Some(DENIED)),
+            },
+            ("add_candidate", [pointer, len]) => {
+                self.candidates.push(Sandbox::string(memory, *pointer, *len)?);
+                Ok(None)
+            }
+            ("write", [fd, pointer, len]) => {
+                let data = memory.read(*pointer as u32 as u64, *len as u32 as u64)?;
+                let written = match *fd as u32 {
+                    1 => io::stdout().write_all(data),
+                    2 => io::stderr().write_all(data),
+                    _ => return Ok(Some(DENIED)),
+                };
+                Ok(Some(if written.is_ok() { data.len() as u64 } else { DENIED }))
+            }
+            ("getenv", [name, name_len, pointer, len]) => {
+                let name = Sandbox::string(memory, *name, *name_len)?;
+                match std::env::var_os(name).filter(|_| self.grants.env) {
+                    Some(value) => Sandbox::copy_out(memory, value.as_encoded_bytes(), *pointer, *len),
+                    None => Ok(Some(DENIED)),
+                }
+            }
+            ("read_file", [path, path_len, pointer, len]) => {
+                let path = Sandbox::string(memory, *path, *path_len)?;
+                // Symbolic links and `..` are resolved before the check.
+                let contents = std::fs::canonicalize(path)
+                    .ok()
+                    .filter(|path| self.grants.read.iter().any(|dir| path.starts_with(dir)))
+                    .and_then(|path| std::fs::read(path).ok());
+                match contents {
+                    Some(contents) => Sandbox::copy_out(memory, &contents, *pointer, *len),
+                    None => Ok(Some(DENIED)),
+                }
+            }
+            _ => Err(format!("{}: bad arguments", name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasm::tests::TestModule;
+
+    /// Load a module with builtins `cat` and `env`, which return what
+    /// `read_file` and `getenv` do given their first argument.
+    fn load(dir: &std::path::Path, grants: Grants) -> Plugins {
+        let int = |params| FuncType { params: vec![ValType::I32; params], results: vec![ValType::I32] };
+        // i32.const 512; arg(1, 512, 256); i32.const 1024; i32.const 4096
+        let call_with_arg = |import| [&[0x41, 0x80, 0x04, 0x41, 1, 0x41, 0x80, 0x04, 0x41, 0x80, 0x02, 0x10, 1][..], &[0x41, 0x80, 0x08, 0x41, 0x80, 0x20, 0x10, import]].concat();
+        let module = TestModule {
+            types: vec![FuncType { params: vec![ValType::I32; 3], results: Vec::new() }, int(3), int(4), int(0), int(1)],
+            imports: vec![("register_builtin", 0), ("arg", 1), ("read_file", 2), ("getenv", 2)],
+            functions: vec![
+                // register_builtin("cat", 0); register_builtin("env", 1); 0
+                ("vssh_plugin_init", 3, vec![0x41, 0, 0x41, 3, 0x41, 0, 0x10, 0, 0x41, 8, 0x41, 3, 0x41, 1, 0x10, 0, 0x41, 0]),
+                ("", 4, call_with_arg(2)),
+                ("", 4, call_with_arg(3)),
+            ],
+            table: vec![5, 6],
+            pages: Some(1),
+            data: b"cat\0\0\0\0\0env".to_vec(),
+        };
+        let path = dir.join("test.wasm");
+        std::fs::write(&path, module.encode()).unwrap();
+        let mut plugins = Plugins::default();
+        plugins.load_sandboxed(path.to_str().unwrap(), grants).unwrap();
+        plugins
+    }
+
+    fn run(plugins: &mut Plugins, argv: &[&str]) -> i32 {
+        plugins.run_builtin(&argv.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn reads_files_only_in_the_directories_granted() {
+        let dir = std::fs::canonicalize(std::env::temp_dir()).unwrap().join(format!("vssh-sandbox-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("granted")).unwrap();
+        std::fs::write(dir.join("granted/inside"), "hello").unwrap();
+        std::fs::write(dir.join("outside"), "secret").unwrap();
+        std::os::unix::fs::symlink(dir.join("outside"), dir.join("granted/link")).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let mut plugins = load(&dir, Grants { env: false, read: vec![dir.join("granted")] });
+        assert_eq!(run(&mut plugins, &["cat", &path("granted/inside")]), 5);
+        assert_eq!(run(&mut plugins, &["cat", &path("outside")]), -1);
+        assert_eq!(run(&mut plugins, &["cat", &path("granted/../outside")]), -1);
+        assert_eq!(run(&mut plugins, &["cat", &path("granted/link")]), -1);
+        assert_eq!(run(&mut plugins, &["cat", &path("granted/missing")]), -1);
+        let mut plugins = load(&dir, Grants::default());
+        assert_eq!(run(&mut plugins, &["cat", &path("granted/inside")]), -1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_the_environment_only_when_granted() {
+        let dir = std::env::temp_dir().join(format!("vssh-sandbox-env-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = std::env::var("PATH").unwrap();
+        let mut plugins = load(&dir, Grants::default());
+        assert_eq!(run(&mut plugins, &["env", "PATH"]), -1);
+        let mut plugins = load(&dir, Grants { env: true, read: Vec::new() });
+        assert_eq!(run(&mut plugins, &["env", "PATH"]), path.len() as i32);
+        assert_eq!(run(&mut plugins, &["env", "VSSH_SURELY_UNSET"]), -1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Compile the C source `body`, which has the host's declarations
+    /// before it, into a shared library in `dir`.
+    fn compile(dir: &std::path::Path, name: &str, body: &str) -> String {
+        let header = "typedef int (*builtin)(void *, int, const char *const *);\n\
+            typedef void (*add_candidate)(void *, const char *);\n\
+            typedef void (*completer)(void *, int, const char *const *, add_candidate, void *);\n\
+            typedef void (*hook)(void *, const char *, int, const char *const *);\n\
+            struct vssh_host {\n\
+                unsigned abi_version;\n\
+                void *registry;\n\
+                void (*register_builtin)(void *, const char *, builtin, void *);\n\
+                void (*register_completer)(void *, const char *, completer, void *);\n\
+                void (*register_hook)(void *, const char *, hook, void *);\n\
+            };\n";
+        let source = dir.join(format!("{}.c", name));
+        let library = dir.join(format!("{}.so", name));
+        std::fs::write(&source, format!("{}{}", header, body)).unwrap();
+        let status = std::process::Command::new("cc").arg("-shared").arg("-fPIC").arg("-o").arg(&library).arg(&source).status().unwrap();
+        assert!(status.success());
+        library.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn native_plugins_register_builtins_completers_and_hooks() {
+        let dir = std::env::temp_dir().join(format!("vssh-native-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let plugin = compile(&dir, "plugin", r#"
+            static int hooks_run;
+            static int count(void *data, int argc, const char *const *argv) { return argc + *(int *)data; }
+            static int hooks(void *data, int argc, const char *const *argv) { return hooks_run; }
+            static void git(void *data, int argc, const char *const *argv, add_candidate add, void *completion) {
+                add(completion, "status");
+                add(completion, argv[argc - 1]);
+            }
+            static void on_chpwd(void *data, const char *name, int argc, const char *const *argv) { hooks_run += argc; }
+            static int offset = 10;
+            int vssh_plugin_init(const struct vssh_host *host) {
+                if (host->abi_version != 1) return 1;
+                host->register_builtin(host->registry, "count", count, &offset);
+                host->register_builtin(host->registry, "hooks", hooks, 0);
+                host->register_completer(host->registry, "git", git, 0);
+                host->register_hook(host->registry, "chpwd", on_chpwd, 0);
+                return 0;
+            }
+        "#);
+        let refusing = compile(&dir, "refusing", r#"
+            static int refused(void *data, int argc, const char *const *argv) { return 0; }
+            int vssh_plugin_init(const struct vssh_host *host) {
+                host->register_builtin(host->registry, "refused", refused, 0);
+                return 3;
+            }
+        "#);
+        let other = compile(&dir, "other", "int unrelated(void) { return 0; }\n");
+        let mut plugins = Plugins::default();
+        assert_eq!(plugins.load(&refusing), Err("initialization failed with status 3".to_string()));
+        assert_eq!(plugins.load(&other), Err("not a vssh plugin: no vssh_plugin_init".to_string()));
+        assert!(plugins.load(&dir.join("missing.so").to_string_lossy()).is_err());
+        plugins.load(&plugin).unwrap();
+        plugins.load(&plugin).unwrap();
+        assert!(plugins.has_builtin("count") && !plugins.has_builtin("refused"));
+        assert_eq!(run(&mut plugins, &["count", "a", "b"]), 13);
+        let words = |words: &[&str]| words.iter().map(|word| word.to_string()).collect::<Vec<_>>();
+        assert_eq!(plugins.complete(&words(&["git", "lo"])), ["status", "lo"]);
+        assert!(plugins.complete(&words(&["ls", "lo"])).is_empty());
+        plugins.run_hooks("chpwd", &words(&["a", "b"]));
+        plugins.run_hooks("precmd", &words(&["a"]));
+        assert_eq!(run(&mut plugins, &["hooks"]), 2);
+        assert_eq!(plugins.describe(), [format!("{}\tbuiltins: count hooks; completes: git; hooks: chpwd", plugin)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+//! Snapshots of the shell's state for `envsave` and `envrestore`: its
+//! variables, arrays, environment, aliases and options. A snapshot file
+//! has a line for each, `kind<TAB>name<TAB>value`, with backslash escapes
+//! for tabs, newlines and backslashes in the value.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+#[derive(Default)]
+pub(crate) struct Snapshot {
+    pub(crate) vars: HashMap<String, String>,
+    pub(crate) arrays: HashMap<String, Vec<String>>,
+    /// The process environment, which commands inherit.
+    pub(crate) exported: HashMap<String, String>,
+    pub(crate) aliases: HashMap<String, String>,
+    pub(crate) options: Vec<String>,
+}
+
+impl Snapshot {
+    pub(crate) fn save(&self, path: &str) -> io::Result<()> {
+        let mut lines = Vec::new();
+        for (name, value) in &self.vars {
+            lines.push(format!("var\t{}\t{}", name, escape(value)));
+        }
+        for (name, values) in &self.arrays {
+            let mut line = format!("array\t{}", name);
+            for value in values {
+                line.push('\t');
+                line.push_str(&escape(value));
+            }
+            lines.push(line);
+        }
+        for (name, value) in &self.exported {
+            lines.push(format!("export\t{}\t{}", name, escape(value)));
+        }
+        for (name, value) in &self.aliases {
+            lines.push(format!("alias\t{}\t{}", name, escape(value)));
+        }
+        for name in &self.options {
+            lines.push(format!("option\t{}", name));
+        }
+        // Sorted, so that snapshots of the same state compare equal.
+        lines.sort();
+        let mut out = lines.join("\n");
+        out.push('\n');
+        // The environment often holds secrets, such as tokens.
+        std::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?.write_all(out.as_bytes())
+    }
+
+    pub(crate) fn load(path: &str) -> io::Result<Snapshot> {
+        let mut snapshot = Snapshot::default();
+        for (number, line) in (1..).zip(std::fs::read_to_string(path)?.lines()) {
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields[..] {
+                ["var", name, value] => {
+                    snapshot.vars.insert(name.to_string(), unescape(value));
+                }
+                ["export", name, value] => {
+                    snapshot.exported.insert(name.to_string(), unescape(value));
+                }
+                ["alias", name, value] => {
+                    snapshot.aliases.insert(name.to_string(), unescape(value));
+                }
+                ["array", name, ref values @ ..] => {
+                    snapshot.arrays.insert(name.to_string(), values.iter().map(|value| unescape(value)).collect());
+                }
+                ["option", name] => snapshot.options.push(name.to_string()),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("line {}: not a snapshot line", number))),
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn saves_and_loads_every_kind_of_state() {
+        let path = crate::tests::temp_path("snapshot");
+        let path = path.to_str().unwrap();
+        let mut snapshot = Snapshot::default();
+        snapshot.vars.insert("x".to_string(), "tab\there\nand \\ newline".to_string());
+        snapshot.arrays.insert("list".to_string(), vec!["a b".to_string(), String::new(), "c".to_string()]);
+        snapshot.exported.insert("TOKEN".to_string(), "secret".to_string());
+        snapshot.aliases.insert("ll".to_string(), "ls -l".to_string());
+        snapshot.options = vec!["errexit".to_string()];
+        snapshot.save(path).unwrap();
+        assert_eq!(std::fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+        let loaded = Snapshot::load(path).unwrap();
+        assert_eq!(loaded.vars, snapshot.vars);
+        assert_eq!(loaded.arrays, snapshot.arrays);
+        assert_eq!(loaded.exported, snapshot.exported);
+        assert_eq!(loaded.aliases, snapshot.aliases);
+        assert_eq!(loaded.options, snapshot.options);
+        std::fs::write(path, "var\tx\t1\nbogus\n").unwrap();
+        assert_eq!(Snapshot::load(path).err().map(|e| e.to_string()), Some("line 2: not a snapshot line".to_string()));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,361 @@
+//! Prompt themes: files that lay the prompt out as a row of segments,
+//! each with its own colours, so that no one has to write escape codes by
+//! hand. A theme is a TOML file such as
+//!
+//! ```toml
+//! separator = " "
+//! end = " $ "
+//!
+//! [[segment]]
+//! type = "cwd"
+//! fg = "blue"
+//! bold = true
+//!
+//! [[segment]]
+//! type = "git"
+//! fg = "magenta"
+//! format = "({})"
+//! ```
+//!
+//! A segment's `type` is what it shows: `cwd`, the current directory with
+//! `~` for home; `git`, the branch checked out, if in a repository;
+//! `status`, the exit status of the last command, if it failed; `time`,
+//! the time, as `time_format` (by default `%H:%M:%S`) gives it to
+//! `strftime`; or `text`, its `text`. `format` puts that in place of `{}`.
+//! `fg` and `bg` are colours: `black`, `red`, `green`, `yellow`, `blue`,
+//! `magenta`, `cyan` or `white`, perhaps `bright_`, a number from 0 to 255,
+//! or `#rrggbb`. `bold` makes the text bold. Segments with nothing to show
+//! are left out. `separator` goes between segments, coloured to lead from
+//! the background of one to that of the next, and `end` after them all.
+
+use std::ffi::CString;
+
+/// A parsed theme.
+pub(crate) struct Theme {
+    segments: Vec<Segment>,
+    separator: String,
+    end: String,
+}
+
+struct Segment {
+    kind: Kind,
+    format: String,
+    text: String,
+    time_format: String,
+    fg: Option<Color>,
+    bg: Option<Color>,
+    bold: bool,
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Text,
+    Cwd,
+    Git,
+    Status,
+    Time,
+}
+
+#[derive(Clone, Copy)]
+enum Color {
+    /// One of the 16 basic colours, the bright ones from 8.
+    Basic(u8),
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    /// The SGR parameters setting this as the foreground colour, or with
+    /// `background`, the background colour.
+    fn sgr(self, background: bool) -> String {
+        let base = if background { 40 } else { 30 };
+        match self {
+            Color::Basic(n) if n < 8 => (base + n as u32).to_string(),
+            Color::Basic(n) => (base + 60 + (n - 8) as u32).to_string(),
+            Color::Indexed(n) => format!("{};5;{}", base + 8, n),
+            Color::Rgb(r, g, b) => format!("{};2;{};{};{}", base + 8, r, g, b),
+        }
+    }
+}
+
+/// What the prompt shows, from the shell's state.
+pub(crate) struct PromptState<'a> {
+    pub(crate) cwd: &'a str,
+    pub(crate) home: Option<&'a str>,
+    pub(crate) status: i32,
+}
+
+/// A value in a theme file.
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+}
+
+impl Theme {
+    /// Parse a theme file: top-level keys and `[[segment]]` tables of them,
+    /// with basic and literal strings, integers, booleans and `#` comments.
+    /// An error names the first bad line.
+    pub(crate) fn parse(text: &str) -> Result<Theme, String> {
+        let mut theme = Theme { segments: Vec::new(), separator: " ".to_string(), end: "$ ".to_string() };
+        for (number, line) in (1..).zip(text.lines()) {
+            let at = |e: String| format!("line {}: {}", number, e);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') {
+                match line.split('#').next().map(str::trim) {
+                    Some("[[segment]]") => theme.segments.push(Segment::default()),
+                    _ => return Err(at(format!("unknown table {}", line))),
+                }
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else { return Err(at("not a key = value line".to_string())) };
+            let key = key.trim();
+            let value = parse_value(value.trim()).map_err(at)?;
+            match theme.segments.last_mut() {
+                Some(segment) => segment.set(key, value).map_err(at)?,
+                None => match (key, value) {
+                    ("separator", Value::String(value)) => theme.separator = value,
+                    ("end", Value::String(value)) => theme.end = value,
+                    ("separator" | "end", _) => return Err(at(format!("{} must be a string", key))),
+                    _ => return Err(at(format!("unknown key {}", key))),
+                },
+            }
+        }
+        Ok(theme)
+    }
+
+    /// The prompt for `state`.
+    pub(crate) fn render(&self, state: &PromptState) -> String {
+        let mut prompt = String::new();
+        let mut previous: Option<&Segment> = None;
+        for segment in &self.segments {
+            let Some(text) = segment.text(state) else { continue };
+            if let Some(previous) = previous {
+                match previous.bg {
+                    Some(bg) => {
+                        prompt.push_str(&sgr(&[Some(bg.sgr(false)), segment.bg.map(|bg| bg.sgr(true))]));
+                        prompt.push_str(&self.separator);
+                        prompt.push_str("\x1b[0m");
+                    }
+                    None => prompt.push_str(&self.separator),
+                }
+            }
+            prompt.push_str(&sgr(&[segment.fg.map(|fg| fg.sgr(false)), segment.bg.map(|bg| bg.sgr(true)), segment.bold.then(|| "1".to_string())]));
+            prompt.push_str(&segment.format.replace("{}", &text));
+            prompt.push_str("\x1b[0m");
+            previous = Some(segment);
+        }
+        prompt.push_str(&self.end);
+        prompt
+    }
+}
+
+impl Segment {
+    fn set(&mut self, key: &str, value: Value) -> Result<(), String> {
+        match (key, value) {
+            ("type", Value::String(kind)) => {
+                self.kind = match kind.as_str() {
+                    "cwd" => Kind::Cwd,
+                    "git" => Kind::Git,
+                    "status" => Kind::Status,
+                    "time" => Kind::Time,
+                    "text" => Kind::Text,
+                    _ => return Err(format!("unknown segment type {}", kind)),
+                }
+            }
+            ("format", Value::String(format)) => self.format = format,
+            ("text", Value::String(text)) => self.text = text,
+            ("time_format", Value::String(format)) => self.time_format = format,
+            ("fg" | "bg", value) => {
+                let color = parse_color(&value).ok_or_else(|| format!("{}: not a colour", key))?;
+                if key == "fg" {
+                    self.fg = Some(color);
+                } else {
+                    self.bg = Some(color);
+                }
+            }
+            ("bold", Value::Boolean(bold)) => self.bold = bold,
+            ("type" | "format" | "text" | "time_format" | "bold", _) => return Err(format!("{}: wrong type of value", key)),
+            _ => return Err(format!("unknown key {}", key)),
+        }
+        Ok(())
+    }
+
+    /// What the segment shows, before `format`, or `None` if nothing.
+    fn text(&self, state: &PromptState) -> Option<String> {
+        match self.kind {
+            Kind::Text => Some(self.text.clone()),
+            Kind::Cwd => Some(match state.home.filter(|home| !home.is_empty() && *home != "/") {
+                Some(home) if state.cwd == home => "~".to_string(),
+                Some(home) if state.cwd.strip_prefix(home).is_some_and(|rest| rest.starts_with('/')) => format!("~{}", &state.cwd[home.len()..]),
+                _ => state.cwd.to_string(),
+            }),
+            Kind::Git => git_branch(state.cwd),
+            Kind::Status => (state.status != 0).then(|| state.status.to_string()),
+            Kind::Time => local_time(if self.time_format.is_empty() { "%H:%M:%S" } else { &self.time_format }),
+        }
+    }
+}
+
+impl Default for Segment {
+    fn default() -> Segment {
+        Segment { kind: Kind::Text, format: "{}".to_string(), text: String::new(), time_format: String::new(), fg: None, bg: None, bold: false }
+    }
+}
+
+/// An SGR escape sequence setting the attributes given, or nothing if
+/// none are.
+fn sgr(parameters: &[Option<String>]) -> String {
+    let parameters: Vec<&str> = parameters.iter().flatten().map(String::as_str).collect();
+    if parameters.is_empty() { String::new() } else { format!("\x1b[{}m", parameters.join(";")) }
+}
+
+/// A value, which is all that is left of its line but a comment.
+fn parse_value(text: &str) -> Result<Value, String> {
+    let (value, rest) = match text.chars().next() {
+        Some('"') => {
+            let mut value = String::new();
+            let mut chars = text[1..].char_indices();
+            loop {
+                match chars.next() {
+                    None => return Err("unterminated string".to_string()),
+                    Some((index, '"')) => break (Value::String(value), &text[index + 2..]),
+                    Some((_, '\\')) => {
+                        let escaped = match chars.next().map(|(_, c)| c) {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some('e') => '\x1b',
+                            Some(c @ ('"' | '\\')) => c,
+                            Some(c @ ('u' | 'U')) => {
+                                let digits: String = chars.by_ref().take(if c == 'u' { 4 } else { 8 }).map(|(_, c)| c).collect();
+                                u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32).ok_or("invalid unicode escape")?
+                            }
+                            _ => return Err("invalid escape".to_string()),
+                        };
+                        value.push(escaped);
+                    }
+                    Some((_, c)) => value.push(c),
+                }
+            }
+        }
+        Some('\'') => {
+            let end = text[1..].find('\'').ok_or("unterminated string")? + 1;
+            (Value::String(text[1..end].to_string()), &text[end + 1..])
+        }
+        _ => {
+            let word = text.split('#').next().unwrap_or_default().trim();
+            return match word {
+                "true" => Ok(Value::Boolean(true)),
+                "false" => Ok(Value::Boolean(false)),
+                _ => word.replace('_', "").parse().map(Value::Integer).map_err(|_| format!("invalid value {}", word)),
+            };
+        }
+    };
+    match rest.trim_start() {
+        rest if rest.is_empty() || rest.starts_with('#') => Ok(value),
+        _ => Err("unexpected text after value".to_string()),
+    }
+}
+
+fn parse_color(value: &Value) -> Option<Color> {
+    const NAMES: [&str; 8] = ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+    match value {
+        Value::Integer(n) => u8::try_from(*n).ok().map(Color::Indexed),
+        Value::String(name) => {
+            if let Some(hex) = name.strip_prefix('#').filter(|hex| hex.len() == 6) {
+                let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+                return Some(Color::Rgb(channel(0)?, channel(2)?, channel(4)?));
+            }
+            let (bright, name) = match name.strip_prefix("bright_") {
+                Some(name) => (8, name),
+                None => (0, name.as_str()),
+            };
+            NAMES.iter().position(|known| *known == name).map(|n| Color::Basic(n as u8 + bright))
+        }
+        Value::Boolean(_) => None,
+    }
+}
+
+/// The branch checked out in the git repository `dir` is in, or the
+/// start of the commit's hash if none is; `None` outside a repository.
+fn git_branch(dir: &str) -> Option<String> {
+    let mut dir = std::path::Path::new(dir);
+    let git_dir = loop {
+        let candidate = dir.join(".git");
+        if candidate.is_dir() {
+            break candidate;
+        }
+        // A worktree or submodule has a file pointing to its repository.
+        if let Ok(text) = std::fs::read_to_string(&candidate)
+            && let Some(path) = text.trim().strip_prefix("gitdir: ")
+        {
+            break dir.join(path);
+        }
+        dir = dir.parent()?;
+    };
+    let head = std::fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    let head = head.trim();
+    match head.strip_prefix("ref: ") {
+        Some(reference) => Some(reference.strip_prefix("refs/heads/").unwrap_or(reference).to_string()),
+        None => Some(head.chars().take(7).collect()),
+    }
+}
+
+/// The local time now, as `strftime` formats it with `format`.
+fn local_time(format: &str) -> Option<String> {
+    let format = CString::new(format).ok()?;
+    let mut buffer = [0u8; 256];
+    let length = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return None;
+        }
+        libc::strftime(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len(), format.as_ptr(), &tm)
+    };
+    Some(String::from_utf8_lossy(&buffer[..length]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_segments_with_their_colours() {
+        let text = "separator = \">\"\nend = ' $ ' # after all\n\n[[segment]]\ntype = \"cwd\"\nfg = \"blue\"\nbold = true\n\n\
+            [[segment]]\ntype = \"status\"\nformat = \"[{}]\"\nbg = 196\n\n[[segment]]\ntype = \"text\"\ntext = \"\\u263A\"\nbg = \"#00ff80\"\n";
+        let theme = Theme::parse(text).unwrap();
+        let state = PromptState { cwd: "/home/me/src", home: Some("/home/me"), status: 0 };
+        assert_eq!(theme.render(&state), "\x1b[34;1m~/src\x1b[0m>\x1b[48;2;0;255;128m☺\x1b[0m $ ");
+        let state = PromptState { cwd: "/home/meet", home: Some("/home/me"), status: 2 };
+        let failed = "\x1b[34;1m/home/meet\x1b[0m>\x1b[48;5;196m[2]\x1b[0m\x1b[38;5;196;48;2;0;255;128m>\x1b[0m\x1b[48;2;0;255;128m☺\x1b[0m $ ";
+        assert_eq!(theme.render(&state), failed);
+    }
+
+    #[test]
+    fn reports_the_first_bad_line() {
+        let error = |text: &str| Theme::parse(text).err();
+        assert_eq!(error("[[segment]]\ntype = \"weather\""), Some("line 2: unknown segment type weather".to_string()));
+        assert_eq!(error("# theme\n[colors]"), Some("line 2: unknown table [colors]".to_string()));
+        assert_eq!(error("end = \"$ "), Some("line 1: unterminated string".to_string()));
+        assert_eq!(error("[[segment]]\nfg = \"mauve\""), Some("line 2: fg: not a colour".to_string()));
+        assert_eq!(error("[[segment]]\nbold = 1"), Some("line 2: bold: wrong type of value".to_string()));
+        assert_eq!(error("separator = 1"), Some("line 1: separator must be a string".to_string()));
+        assert_eq!(error("end = '$' x"), Some("line 1: unexpected text after value".to_string()));
+    }
+
+    #[test]
+    fn finds_the_git_branch_from_any_subdirectory() {
+        let dir = crate::tests::temp_path("theme-git");
+        std::fs::create_dir_all(dir.join(".git")).unwrap();
+        std::fs::create_dir_all(dir.join("src/deep")).unwrap();
+        std::fs::write(dir.join(".git/HEAD"), "ref: refs/heads/main\n").unwrap();
+        assert_eq!(git_branch(dir.join("src/deep").to_str().unwrap()), Some("main".to_string()));
+        std::fs::write(dir.join(".git/HEAD"), "0123456789abcdef\n").unwrap();
+        assert_eq!(git_branch(dir.to_str().unwrap()), Some("0123456".to_string()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
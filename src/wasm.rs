@@ -0,0 +1,1188 @@
+//! A WebAssembly interpreter, for running plugins in a sandbox: a module
+//! can reach nothing outside its own memory but the host functions it
+//! imports. It runs the MVP instruction set, with the sign-extension,
+//! non-trapping float-to-int and `memory.copy`/`memory.fill` additions
+//! that compilers emit by default. Every call from the host is given a
+//! budget of instructions, so that a module stuck in a loop is stopped.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// The size of a page of memory.
+const PAGE_SIZE: usize = 65536;
+/// How many instructions a call from the host may run.
+const FUEL: u64 = 100_000_000;
+/// How deep calls within a module may nest.
+const MAX_DEPTH: usize = 1000;
+/// The most entries a table may have.
+const MAX_TABLE: u32 = 65536;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ValType {
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct FuncType {
+    pub(crate) params: Vec<ValType>,
+    pub(crate) results: Vec<ValType>,
+}
+
+/// What a module imports its functions from.
+pub(crate) trait Host {
+    /// Which of the host's functions the module's import of `name` from
+    /// `module`, of type `ty`, is; `None` if there is no such function.
+    fn resolve(&self, module: &str, name: &str, ty: &FuncType) -> Option<usize>;
+    /// Call host function `function` with `args`, returning its result if
+    /// it has one. An error traps.
+    fn call(&mut self, function: usize, args: &[u64], memory: &mut Memory) -> Result<Option<u64>, String>;
+}
+
+/// A module's linear memory.
+pub(crate) struct Memory {
+    bytes: Vec<u8>,
+    max_pages: usize,
+}
+
+impl Memory {
+    /// The `len` bytes at `address`.
+    pub(crate) fn read(&self, address: u64, len: u64) -> Result<&[u8], String> {
+        let range = self.range(address, len)?;
+        Ok(&self.bytes[range])
+    }
+
+    /// Write `data` at `address`.
+    pub(crate) fn write(&mut self, address: u64, data: &[u8]) -> Result<(), String> {
+        let range = self.range(address, data.len() as u64)?;
+        self.bytes[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn range(&self, address: u64, len: u64) -> Result<std::ops::Range<usize>, String> {
+        match address.checked_add(len) {
+            Some(end) if end <= self.bytes.len() as u64 => Ok(address as usize..end as usize),
+            _ => Err("out of bounds memory access".to_string()),
+        }
+    }
+
+    /// Add `pages` pages, returning how many there were, or `None` if
+    /// that would pass the limit.
+    fn grow(&mut self, pages: u32) -> Option<u32> {
+        let old = self.bytes.len() / PAGE_SIZE;
+        let new = old.checked_add(pages as usize).filter(|new| *new <= self.max_pages)?;
+        self.bytes.resize(new * PAGE_SIZE, 0);
+        Some(old as u32)
+    }
+}
+
+/// An instruction, decoded, with where its block ends resolved.
+#[derive(Debug)]
+enum Op {
+    Unreachable,
+    Nop,
+    Block { params: usize, results: usize, end: usize },
+    Loop { params: usize },
+    If { params: usize, results: usize, else_: usize, end: usize },
+    Else { end: usize },
+    End,
+    Br(u32),
+    BrIf(u32),
+    BrTable(Box<[u32]>, u32),
+    Return,
+    Call(u32),
+    CallIndirect(u32),
+    Drop,
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    /// A load, by opcode, with its offset.
+    Load(u8, u32),
+    Store(u8, u32),
+    MemorySize,
+    MemoryGrow,
+    MemoryCopy,
+    MemoryFill,
+    Const(u64),
+    /// A numeric instruction from `i32.eqz` to `i64.extend32_s`, by opcode.
+    Numeric(u8),
+    /// A non-trapping float-to-int conversion, by its `0xfc` subopcode.
+    Saturating(u8),
+}
+
+struct Function {
+    type_index: u32,
+    /// How many locals it has besides its parameters.
+    locals: usize,
+    code: Vec<Op>,
+}
+
+/// A module, decoded.
+#[derive(Default)]
+struct Module {
+    types: Vec<FuncType>,
+    /// The functions imported, as `(module, name, type)`; they come first
+    /// in function indices.
+    imports: Vec<(String, String, u32)>,
+    functions: Vec<Function>,
+    table: Option<(u32, Option<u32>)>,
+    memory: Option<(u32, Option<u32>)>,
+    /// Globals, whether each is mutable and its initial value.
+    globals: Vec<(bool, u64)>,
+    /// The functions exported, by name.
+    exports: HashMap<String, u32>,
+    start: Option<u32>,
+    /// Active element segments: table offset and function indices.
+    elements: Vec<(u32, Vec<u32>)>,
+    /// Active data segments: memory offset and bytes.
+    data: Vec<(u32, Vec<u8>)>,
+}
+
+impl Module {
+    fn function_type(&self, function: u32) -> Result<&FuncType, String> {
+        let function = function as usize;
+        let type_index = match self.imports.get(function) {
+            Some((_, _, type_index)) => *type_index,
+            None => self.functions.get(function - self.imports.len()).ok_or("no such function")?.type_index,
+        };
+        self.types.get(type_index as usize).ok_or_else(|| "no such type".to_string())
+    }
+}
+
+/// The bytes of a module being decoded.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn done(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self.bytes.get(self.position).ok_or("unexpected end")?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.position.checked_add(len).filter(|end| *end <= self.bytes.len()).ok_or("unexpected end")?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    /// An unsigned LEB128 number of at most 32 bits.
+    fn u32(&mut self) -> Result<u32, String> {
+        let mut result: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            result |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return u32::try_from(result).map_err(|_| "integer too large".to_string());
+            }
+            if shift >= 35 {
+                return Err("integer too long".to_string());
+            }
+        }
+    }
+
+    /// A signed LEB128 number of at most `bits` bits.
+    fn signed(&mut self, bits: u32) -> Result<i64, String> {
+        let mut result: i64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            result |= ((byte & 0x7f) as i64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    result |= -1 << shift;
+                }
+                return Ok(result);
+            }
+            if shift >= bits {
+                return Err("integer too long".to_string());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| "invalid name".to_string())
+    }
+
+    fn val_type(&mut self) -> Result<ValType, String> {
+        match self.byte()? {
+            0x7f => Ok(ValType::I32),
+            0x7e => Ok(ValType::I64),
+            0x7d => Ok(ValType::F32),
+            0x7c => Ok(ValType::F64),
+            byte => Err(format!("unsupported value type 0x{:02x}", byte)),
+        }
+    }
+
+    fn limits(&mut self) -> Result<(u32, Option<u32>), String> {
+        match self.byte()? {
+            0 => Ok((self.u32()?, None)),
+            1 => Ok((self.u32()?, Some(self.u32()?))),
+            _ => Err("unsupported limits".to_string()),
+        }
+    }
+
+    /// A constant expression, given the globals defined so far.
+    fn constant(&mut self, globals: &[(bool, u64)]) -> Result<u64, String> {
+        let value = match self.byte()? {
+            0x41 => self.signed(32)? as i32 as u32 as u64,
+            0x42 => self.signed(64)? as u64,
+            0x43 => u32::from_le_bytes(self.bytes(4)?.try_into().unwrap_or_default()) as u64,
+            0x44 => u64::from_le_bytes(self.bytes(8)?.try_into().unwrap_or_default()),
+            0x23 => globals.get(self.u32()? as usize).ok_or("no such global")?.1,
+            _ => return Err("unsupported constant expression".to_string()),
+        };
+        match self.byte()? {
+            0x0b => Ok(value),
+            _ => Err("unsupported constant expression".to_string()),
+        }
+    }
+
+    /// A block type, as how many values the block takes and leaves.
+    fn block_type(&mut self, types: &[FuncType]) -> Result<(usize, usize), String> {
+        match self.bytes.get(self.position) {
+            Some(0x40) => {
+                self.position += 1;
+                Ok((0, 0))
+            }
+            Some(0x7c..=0x7f) => {
+                self.val_type()?;
+                Ok((0, 1))
+            }
+            _ => {
+                let ty = types.get(self.signed(33)? as usize).ok_or("no such type")?;
+                Ok((ty.params.len(), ty.results.len()))
+            }
+        }
+    }
+}
+
+/// Decode a module, without checking more of it than running it needs.
+fn decode(bytes: &[u8]) -> Result<Module, String> {
+    let mut reader = Reader { bytes, position: 0 };
+    if reader.bytes(4).ok() != Some(b"\0asm") || reader.bytes(4).ok() != Some(&[1, 0, 0, 0]) {
+        return Err("not a WebAssembly module".to_string());
+    }
+    let mut module = Module::default();
+    let mut function_types = Vec::new();
+    while !reader.done() {
+        let id = reader.byte()?;
+        let size = reader.u32()? as usize;
+        let mut section = Reader { bytes: reader.bytes(size)?, position: 0 };
+        let count = if id == 0 || id == 8 { 0 } else { section.u32()? };
+        match id {
+            0 | 12 => {}
+            1 => {
+                for _ in 0..count {
+                    if section.byte()? != 0x60 {
+                        return Err("invalid function type".to_string());
+                    }
+                    let params = (0..section.u32()?).map(|_| section.val_type()).collect::<Result<_, _>>()?;
+                    let results = (0..section.u32()?).map(|_| section.val_type()).collect::<Result<_, _>>()?;
+                    module.types.push(FuncType { params, results });
+                }
+            }
+            2 => {
+                for _ in 0..count {
+                    let (from, name) = (section.name()?, section.name()?);
+                    if section.byte()? != 0 {
+                        return Err(format!("{}.{}: only functions can be imported", from, name));
+                    }
+                    module.imports.push((from, name, section.u32()?));
+                }
+            }
+            3 => {
+                for _ in 0..count {
+                    function_types.push(section.u32()?);
+                }
+            }
+            4 => {
+                for _ in 0..count {
+                    if section.byte()? != 0x70 {
+                        return Err("unsupported table type".to_string());
+                    }
+                    module.table = Some(section.limits()?);
+                }
+            }
+            5 => {
+                for _ in 0..count {
+                    module.memory = Some(section.limits()?);
+                }
+            }
+            6 => {
+                for _ in 0..count {
+                    section.val_type()?;
+                    let mutable = section.byte()? == 1;
+                    let value = section.constant(&module.globals)?;
+                    module.globals.push((mutable, value));
+                }
+            }
+            7 => {
+                for _ in 0..count {
+                    let name = section.name()?;
+                    let kind = section.byte()?;
+                    let index = section.u32()?;
+                    if kind == 0 {
+                        module.exports.insert(name, index);
+                    }
+                }
+            }
+            8 => module.start = Some(section.u32()?),
+            9 => {
+                for _ in 0..count {
+                    if section.u32()? != 0 {
+                        return Err("unsupported element segment".to_string());
+                    }
+                    let offset = section.constant(&module.globals)? as u32;
+                    let functions = (0..section.u32()?).map(|_| section.u32()).collect::<Result<_, _>>()?;
+                    module.elements.push((offset, functions));
+                }
+            }
+            10 => {
+                if count as usize != function_types.len() {
+                    return Err("function and code counts differ".to_string());
+                }
+                for type_index in function_types.iter().copied() {
+                    let size = section.u32()? as usize;
+                    let mut body = Reader { bytes: section.bytes(size)?, position: 0 };
+                    let mut locals: usize = 0;
+                    for _ in 0..body.u32()? {
+                        locals = locals.saturating_add(body.u32()? as usize);
+                        body.val_type()?;
+                    }
+                    if locals > 50000 {
+                        return Err("too many locals".to_string());
+                    }
+                    if module.types.get(type_index as usize).is_none() {
+                        return Err("no such type".to_string());
+                    }
+                    let code = decode_code(&mut body, &module.types)?;
+                    module.functions.push(Function { type_index, locals, code });
+                }
+            }
+            11 => {
+                for _ in 0..count {
+                    match section.u32()? {
+                        0 => {}
+                        2 if section.u32()? == 0 => {}
+                        _ => return Err("unsupported data segment".to_string()),
+                    }
+                    let offset = section.constant(&module.globals)? as u32;
+                    let len = section.u32()? as usize;
+                    module.data.push((offset, section.bytes(len)?.to_vec()));
+                }
+            }
+            _ => return Err(format!("unknown section {}", id)),
+        }
+    }
+    Ok(module)
+}
+
+/// Decode the instructions of a function body.
+fn decode_code(reader: &mut Reader, types: &[FuncType]) -> Result<Vec<Op>, String> {
+    let mut code = Vec::new();
+    // The blocks, loops and ifs not yet ended.
+    let mut open: Vec<usize> = Vec::new();
+    loop {
+        let opcode = reader.byte()?;
+        let op = match opcode {
+            0x00 => Op::Unreachable,
+            0x01 => Op::Nop,
+            0x02..=0x04 => {
+                let (params, results) = reader.block_type(types)?;
+                open.push(code.len());
+                match opcode {
+                    0x02 => Op::Block { params, results, end: 0 },
+                    0x03 => Op::Loop { params },
+                    _ => Op::If { params, results, else_: 0, end: 0 },
+                }
+            }
+            0x05 => {
+                let here = code.len();
+                match open.last().map(|start| &mut code[*start]) {
+                    Some(Op::If { else_, .. }) if *else_ == 0 => *else_ = here,
+                    _ => return Err("else outside if".to_string()),
+                }
+                Op::Else { end: 0 }
+            }
+            0x0b => {
+                let Some(start) = open.pop() else {
+                    code.push(Op::End);
+                    return Ok(code);
+                };
+                let end = code.len();
+                let mut else_at = None;
+                match &mut code[start] {
+                    Op::Block { end: block_end, .. } => *block_end = end,
+                    Op::If { else_, end: if_end, .. } => {
+                        *if_end = end;
+                        if *else_ == 0 {
+                            *else_ = end;
+                        } else {
+                            else_at = Some(*else_);
+                        }
+                    }
+                    _ => {}
+                }
+                if let Some(else_at) = else_at {
+                    code[else_at] = Op::Else { end };
+                }
+                Op::End
+            }
+            0x0c => Op::Br(reader.u32()?),
+            0x0d => Op::BrIf(reader.u32()?),
+            0x0e => {
+                let labels = (0..reader.u32()?).map(|_| reader.u32()).collect::<Result<_, _>>()?;
+                Op::BrTable(labels, reader.u32()?)
+            }
+            0x0f => Op::Return,
+            0x10 => Op::Call(reader.u32()?),
+            0x11 => {
+                let type_index = reader.u32()?;
+                if reader.byte()? != 0 {
+                    return Err("unsupported table".to_string());
+                }
+                Op::CallIndirect(type_index)
+            }
+            0x1a => Op::Drop,
+            0x1b => Op::Select,
+            0x1c => {
+                for _ in 0..reader.u32()? {
+                    reader.val_type()?;
+                }
+                Op::Select
+            }
+            0x20 => Op::LocalGet(reader.u32()?),
+            0x21 => Op::LocalSet(reader.u32()?),
+            0x22 => Op::LocalTee(reader.u32()?),
+            0x23 => Op::GlobalGet(reader.u32()?),
+            0x24 => Op::GlobalSet(reader.u32()?),
+            0x28..=0x3e => {
+                reader.u32()?;
+                let offset = reader.u32()?;
+                if opcode <= 0x35 { Op::Load(opcode, offset) } else { Op::Store(opcode, offset) }
+            }
+            0x3f | 0x40 => {
+                reader.byte()?;
+                if opcode == 0x3f { Op::MemorySize } else { Op::MemoryGrow }
+            }
+            0x41 => Op::Const(reader.signed(32)? as i32 as u32 as u64),
+            0x42 => Op::Const(reader.signed(64)? as u64),
+            0x43 => Op::Const(u32::from_le_bytes(reader.bytes(4)?.try_into().unwrap_or_default()) as u64),
+            0x44 => Op::Const(u64::from_le_bytes(reader.bytes(8)?.try_into().unwrap_or_default())),
+            0x45..=0xc4 => Op::Numeric(opcode),
+            0xfc => match reader.u32()? {
+                sub @ 0..=7 => Op::Saturating(sub as u8),
+                10 => {
+                    reader.bytes(2)?;
+                    Op::MemoryCopy
+                }
+                11 => {
+                    reader.byte()?;
+                    Op::MemoryFill
+                }
+                sub => return Err(format!("unsupported instruction 0xfc {}", sub)),
+            },
+            _ => return Err(format!("unsupported instruction 0x{:02x}", opcode)),
+        };
+        code.push(op);
+    }
+}
+
+/// Where a branch goes, and what it keeps of the stack.
+#[derive(Clone, Copy)]
+struct Label {
+    /// The height of the stack under the values the block took.
+    height: usize,
+    /// How many values a branch to it carries.
+    arity: usize,
+    /// The instruction a branch to it goes on at.
+    target: usize,
+    /// A loop's label stays when it is branched to.
+    is_loop: bool,
+}
+
+/// A module ready to run, with its memory, globals and table.
+pub(crate) struct Instance {
+    module: Rc<Module>,
+    /// The host function each import is.
+    imports: Vec<usize>,
+    memory: Memory,
+    globals: Vec<u64>,
+    table: Vec<Option<u32>>,
+    stack: Vec<u64>,
+    fuel: u64,
+    depth: usize,
+}
+
+impl Instance {
+    /// Decode the module in `bytes` and set it up, with its imports from
+    /// `host` and at most `max_pages` pages of memory, then run its start
+    /// function, if it has one.
+    pub(crate) fn new(bytes: &[u8], host: &mut dyn Host, max_pages: u32) -> Result<Instance, String> {
+        let module = decode(bytes)?;
+        let mut imports = Vec::new();
+        for (from, name, type_index) in &module.imports {
+            let ty = module.types.get(*type_index as usize).ok_or("no such type")?;
+            match host.resolve(from, name, ty) {
+                Some(function) => imports.push(function),
+                None => return Err(format!("unknown import {}.{}", from, name)),
+            }
+        }
+        let (pages, max) = module.memory.unwrap_or((0, Some(0)));
+        let max_pages = max.map_or(max_pages, |max| max.min(max_pages)) as usize;
+        if pages as usize > max_pages {
+            return Err("memory too large".to_string());
+        }
+        let mut memory = Memory { bytes: vec![0; pages as usize * PAGE_SIZE], max_pages };
+        let size = module.table.map_or(0, |(size, _)| size);
+        if size > MAX_TABLE {
+            return Err("table too large".to_string());
+        }
+        let mut table = vec![None; size as usize];
+        let functions = module.imports.len() + module.functions.len();
+        for (offset, indices) in &module.elements {
+            for (slot, function) in (*offset as usize..).zip(indices) {
+                if *function as usize >= functions {
+                    return Err("no such function".to_string());
+                }
+                *table.get_mut(slot).ok_or("element segment out of bounds")? = Some(*function);
+            }
+        }
+        for (offset, bytes) in &module.data {
+            memory.write(*offset as u64, bytes).map_err(|_| "data segment out of bounds".to_string())?;
+        }
+        let globals = module.globals.iter().map(|(_, value)| *value).collect();
+        let start = module.start;
+        let mut instance = Instance { module: Rc::new(module), imports, memory, globals, table, stack: Vec::new(), fuel: 0, depth: 0 };
+        if let Some(start) = start {
+            instance.call(host, start, &FuncType { params: Vec::new(), results: Vec::new() }, &[])?;
+        }
+        Ok(instance)
+    }
+
+    /// Call the function exported as `name`, which must be of type `ty`.
+    pub(crate) fn call_export(&mut self, host: &mut dyn Host, name: &str, ty: &FuncType, args: &[u64]) -> Result<Vec<u64>, String> {
+        let function = *self.module.exports.get(name).ok_or_else(|| format!("no function {} exported", name))?;
+        self.call(host, function, ty, args)
+    }
+
+    /// Call the function at `index` in the table, which must be of type
+    /// `ty`.
+    pub(crate) fn call_indirect(&mut self, host: &mut dyn Host, index: u32, ty: &FuncType, args: &[u64]) -> Result<Vec<u64>, String> {
+        let function = self.table.get(index as usize).copied().flatten().ok_or("no such function in table")?;
+        self.call(host, function, ty, args)
+    }
+
+    fn call(&mut self, host: &mut dyn Host, function: u32, ty: &FuncType, args: &[u64]) -> Result<Vec<u64>, String> {
+        if self.module.function_type(function)? != ty {
+            return Err("function has the wrong type".to_string());
+        }
+        self.stack.clear();
+        self.stack.extend_from_slice(args);
+        self.fuel = FUEL;
+        self.depth = 0;
+        self.invoke(host, function)?;
+        Ok(std::mem::take(&mut self.stack))
+    }
+
+    /// Run `function` on the arguments at the top of the stack, leaving
+    /// its results there instead.
+    fn invoke(&mut self, host: &mut dyn Host, function: u32) -> Result<(), String> {
+        let module = Rc::clone(&self.module);
+        let ty = module.function_type(function)?;
+        let args_start = self.stack.len().checked_sub(ty.params.len()).ok_or("stack underflow")?;
+        if let Some(import) = self.imports.get(function as usize) {
+            let args = self.stack.split_off(args_start);
+            if let Some(result) = host.call(*import, &args, &mut self.memory)? {
+                self.stack.push(result);
+            }
+            return Ok(());
+        }
+        let body = &module.functions[function as usize - self.imports.len()];
+        if self.depth >= MAX_DEPTH {
+            return Err("call stack exhausted".to_string());
+        }
+        self.depth += 1;
+        let mut locals = self.stack.split_off(args_start);
+        locals.resize(locals.len() + body.locals, 0);
+        let code = &body.code;
+        let mut labels = vec![Label { height: self.stack.len(), arity: ty.results.len(), target: code.len(), is_loop: false }];
+        let mut pc = 0;
+        while pc < code.len() {
+            if self.fuel == 0 {
+                return Err("ran too long".to_string());
+            }
+            self.fuel -= 1;
+            let stack = &mut self.stack;
+            match &code[pc] {
+                Op::Unreachable => return Err("unreachable".to_string()),
+                Op::Nop => {}
+                Op::Block { params, results, end } => {
+                    let height = stack.len().checked_sub(*params).ok_or("stack underflow")?;
+                    labels.push(Label { height, arity: *results, target: end + 1, is_loop: false });
+                }
+                Op::Loop { params } => {
+                    let height = stack.len().checked_sub(*params).ok_or("stack underflow")?;
+                    labels.push(Label { height, arity: *params, target: pc + 1, is_loop: true });
+                }
+                Op::If { params, results, else_, end } => {
+                    let condition = pop(stack)? as u32;
+                    let height = stack.len().checked_sub(*params).ok_or("stack underflow")?;
+                    if condition != 0 {
+                        labels.push(Label { height, arity: *results, target: end + 1, is_loop: false });
+                    } else if else_ != end {
+                        labels.push(Label { height, arity: *results, target: end + 1, is_loop: false });
+                        pc = *else_;
+                    } else {
+                        pc = *end;
+                    }
+                }
+                // Reached at the end of the branch taken.
+                Op::Else { end } => {
+                    labels.pop();
+                    pc = *end;
+                }
+                Op::End => {
+                    labels.pop();
+                }
+                Op::Br(depth) => {
+                    pc = branch(stack, &mut labels, *depth)?;
+                    continue;
+                }
+                Op::BrIf(depth) => {
+                    if pop(stack)? as u32 != 0 {
+                        pc = branch(stack, &mut labels, *depth)?;
+                        continue;
+                    }
+                }
+                Op::BrTable(depths, default) => {
+                    let index = pop(stack)? as u32 as usize;
+                    pc = branch(stack, &mut labels, *depths.get(index).unwrap_or(default))?;
+                    continue;
+                }
+                Op::Return => {
+                    let depth = labels.len() as u32 - 1;
+                    pc = branch(stack, &mut labels, depth)?;
+                    continue;
+                }
+                Op::Call(function) => self.invoke(host, *function)?,
+                Op::CallIndirect(type_index) => {
+                    let index = pop(stack)? as u32 as usize;
+                    let function = self.table.get(index).copied().flatten().ok_or("undefined element")?;
+                    if module.types.get(*type_index as usize) != Some(module.function_type(function)?) {
+                        return Err("indirect call type mismatch".to_string());
+                    }
+                    self.invoke(host, function)?;
+                }
+                Op::Drop => {
+                    pop(stack)?;
+                }
+                Op::Select => {
+                    let condition = pop(stack)? as u32;
+                    let second = pop(stack)?;
+                    let first = pop(stack)?;
+                    stack.push(if condition != 0 { first } else { second });
+                }
+                Op::LocalGet(index) => stack.push(*locals.get(*index as usize).ok_or("no such local")?),
+                Op::LocalSet(index) => *locals.get_mut(*index as usize).ok_or("no such local")? = pop(stack)?,
+                Op::LocalTee(index) => *locals.get_mut(*index as usize).ok_or("no such local")? = *stack.last().ok_or("stack underflow")?,
+                Op::GlobalGet(index) => stack.push(*self.globals.get(*index as usize).ok_or("no such global")?),
+                Op::GlobalSet(index) => {
+                    if !module.globals.get(*index as usize).is_some_and(|(mutable, _)| *mutable) {
+                        return Err("global is immutable".to_string());
+                    }
+                    self.globals[*index as usize] = pop(stack)?;
+                }
+                Op::Load(opcode, offset) => {
+                    let address = pop(stack)? as u32 as u64 + *offset as u64;
+                    let size = match opcode {
+                        0x29 | 0x2b => 8,
+                        0x2c | 0x2d | 0x30 | 0x31 => 1,
+                        0x2e | 0x2f | 0x32 | 0x33 => 2,
+                        _ => 4,
+                    };
+                    let mut bytes = [0; 8];
+                    bytes[..size].copy_from_slice(self.memory.read(address, size as u64)?);
+                    let raw = u64::from_le_bytes(bytes);
+                    stack.push(match opcode {
+                        0x2c => raw as i8 as i32 as u32 as u64,
+                        0x2e => raw as i16 as i32 as u32 as u64,
+                        0x30 => raw as i8 as i64 as u64,
+                        0x32 => raw as i16 as i64 as u64,
+                        0x34 => raw as i32 as i64 as u64,
+                        _ => raw,
+                    });
+                }
+                Op::Store(opcode, offset) => {
+                    let value = pop(stack)?;
+                    let address = pop(stack)? as u32 as u64 + *offset as u64;
+                    let size = match opcode {
+                        0x37 | 0x39 => 8,
+                        0x3a | 0x3c => 1,
+                        0x3b | 0x3d => 2,
+                        _ => 4,
+                    };
+                    self.memory.write(address, &value.to_le_bytes()[..size])?;
+                }
+                Op::MemorySize => stack.push((self.memory.bytes.len() / PAGE_SIZE) as u64),
+                Op::MemoryGrow => {
+                    let pages = pop(stack)? as u32;
+                    stack.push(self.memory.grow(pages).unwrap_or(u32::MAX) as u64);
+                }
+                Op::MemoryCopy => {
+                    let len = pop(stack)? as u32 as u64;
+                    let source = pop(stack)? as u32 as u64;
+                    let destination = pop(stack)? as u32 as u64;
+                    let from = self.memory.range(source, len)?;
+                    self.memory.range(destination, len)?;
+                    self.memory.bytes.copy_within(from, destination as usize);
+                }
+                Op::MemoryFill => {
+                    let len = pop(stack)? as u32 as u64;
+                    let value = pop(stack)? as u8;
+                    let destination = pop(stack)? as u32 as u64;
+                    let range = self.memory.range(destination, len)?;
+                    self.memory.bytes[range].fill(value);
+                }
+                Op::Const(value) => stack.push(*value),
+                Op::Numeric(opcode) => numeric(*opcode, stack)?,
+                Op::Saturating(sub) => {
+                    let value = pop(stack)?;
+                    let (single, double) = (f32::from_bits(value as u32), f64::from_bits(value));
+                    stack.push(match sub {
+                        0 => single as i32 as u32 as u64,
+                        1 => single as u32 as u64,
+                        2 => double as i32 as u32 as u64,
+                        3 => double as u32 as u64,
+                        4 => single as i64 as u64,
+                        5 => single as u64,
+                        6 => double as i64 as u64,
+                        _ => double as u64,
+                    });
+                }
+            }
+            pc += 1;
+        }
+        self.depth -= 1;
+        Ok(())
+    }
+}
+
+fn pop(stack: &mut Vec<u64>) -> Result<u64, String> {
+    stack.pop().ok_or_else(|| "stack underflow".to_string())
+}
+
+/// Branch out to the label `depth` blocks out, returning the instruction
+/// to go on at.
+fn branch(stack: &mut Vec<u64>, labels: &mut Vec<Label>, depth: u32) -> Result<usize, String> {
+    let index = labels.len().checked_sub(depth as usize + 1).ok_or("branch out of range")?;
+    let label = labels[index];
+    let kept = stack.len().checked_sub(label.arity).filter(|kept| *kept >= label.height).ok_or("stack underflow")?;
+    stack.drain(label.height..kept);
+    labels.truncate(if label.is_loop { index + 1 } else { index });
+    Ok(label.target)
+}
+
+/// Run numeric instruction `opcode` on the stack.
+fn numeric(opcode: u8, stack: &mut Vec<u64>) -> Result<(), String> {
+    const F32_SIGN: u64 = 1 << 31;
+    const F64_SIGN: u64 = 1 << 63;
+    let b = pop(stack)?;
+    // Most instructions take two operands; `b` is the second.
+    let binary = !matches!(opcode, 0x45 | 0x50 | 0x67..=0x69 | 0x79..=0x7b | 0x8b..=0x91 | 0x99..=0x9f | 0xa7..=0xc4);
+    let a = if binary { pop(stack)? } else { 0 };
+    let (a32, b32) = (a as u32, b as u32);
+    let (single_a, single_b) = (f32::from_bits(a32), f32::from_bits(b32));
+    let (double_a, double_b) = (f64::from_bits(a), f64::from_bits(b));
+    let value = match opcode {
+        0x45 => (b32 == 0) as u64,
+        0x46..=0x4f => (match opcode {
+            0x46 => a32 == b32,
+            0x47 => a32 != b32,
+            0x48 => (a32 as i32) < b32 as i32,
+            0x49 => a32 < b32,
+            0x4a => a32 as i32 > b32 as i32,
+            0x4b => a32 > b32,
+            0x4c => a32 as i32 <= b32 as i32,
+            0x4d => a32 <= b32,
+            0x4e => a32 as i32 >= b32 as i32,
+            _ => a32 >= b32,
+        }) as u64,
+        0x50 => (b == 0) as u64,
+        0x51..=0x5a => (match opcode {
+            0x51 => a == b,
+            0x52 => a != b,
+            0x53 => (a as i64) < b as i64,
+            0x54 => a < b,
+            0x55 => a as i64 > b as i64,
+            0x56 => a > b,
+            0x57 => a as i64 <= b as i64,
+            0x58 => a <= b,
+            0x59 => a as i64 >= b as i64,
+            _ => a >= b,
+        }) as u64,
+        0x5b..=0x60 => compare(opcode - 0x5b, single_a as f64, single_b as f64) as u64,
+        0x61..=0x66 => compare(opcode - 0x61, double_a, double_b) as u64,
+        0x67 => b32.leading_zeros() as u64,
+        0x68 => b32.trailing_zeros() as u64,
+        0x69 => b32.count_ones() as u64,
+        0x6a..=0x78 => (match opcode {
+            0x6a => a32.wrapping_add(b32),
+            0x6b => a32.wrapping_sub(b32),
+            0x6c => a32.wrapping_mul(b32),
+            0x6d if b32 == 0 => return Err("integer divide by zero".to_string()),
+            0x6d => (a32 as i32).checked_div(b32 as i32).ok_or("integer overflow")? as u32,
+            0x6e => a32.checked_div(b32).ok_or("integer divide by zero")?,
+            0x6f if b32 == 0 => return Err("integer divide by zero".to_string()),
+            0x6f => (a32 as i32).wrapping_rem(b32 as i32) as u32,
+            0x70 => a32.checked_rem(b32).ok_or("integer divide by zero")?,
+            0x71 => a32 & b32,
+            0x72 => a32 | b32,
+            0x73 => a32 ^ b32,
+            0x74 => a32.wrapping_shl(b32),
+            0x75 => (a32 as i32).wrapping_shr(b32) as u32,
+            0x76 => a32.wrapping_shr(b32),
+            0x77 => a32.rotate_left(b32 % 32),
+            _ => a32.rotate_right(b32 % 32),
+        }) as u64,
+        0x79 => b.leading_zeros() as u64,
+        0x7a => b.trailing_zeros() as u64,
+        0x7b => b.count_ones() as u64,
+        0x7c => a.wrapping_add(b),
+        0x7d => a.wrapping_sub(b),
+        0x7e => a.wrapping_mul(b),
+        0x7f if b == 0 => return Err("integer divide by zero".to_string()),
+        0x7f => (a as i64).checked_div(b as i64).ok_or("integer overflow")? as u64,
+        0x80 => a.checked_div(b).ok_or("integer divide by zero")?,
+        0x81 if b == 0 => return Err("integer divide by zero".to_string()),
+        0x81 => (a as i64).wrapping_rem(b as i64) as u64,
+        0x82 => a.checked_rem(b).ok_or("integer divide by zero")?,
+        0x83 => a & b,
+        0x84 => a | b,
+        0x85 => a ^ b,
+        0x86 => a.wrapping_shl(b as u32),
+        0x87 => (a as i64).wrapping_shr(b as u32) as u64,
+        0x88 => a.wrapping_shr(b as u32),
+        0x89 => a.rotate_left((b % 64) as u32),
+        0x8a => a.rotate_right((b % 64) as u32),
+        0x8b => b & !F32_SIGN,
+        0x8c => b ^ F32_SIGN,
+        0x8d..=0x91 => (round(opcode - 0x8d, single_b as f64) as f32).to_bits() as u64,
+        0x92..=0x98 => match opcode {
+            0x92 => (single_a + single_b).to_bits() as u64,
+            0x93 => (single_a - single_b).to_bits() as u64,
+            0x94 => (single_a * single_b).to_bits() as u64,
+            0x95 => (single_a / single_b).to_bits() as u64,
+            0x96 => (min(single_a as f64, single_b as f64) as f32).to_bits() as u64,
+            0x97 => (max(single_a as f64, single_b as f64) as f32).to_bits() as u64,
+            _ => (a & !F32_SIGN) | (b & F32_SIGN),
+        },
+        0x99 => b & !F64_SIGN,
+        0x9a => b ^ F64_SIGN,
+        0x9b..=0x9f => round(opcode - 0x9b, double_b).to_bits(),
+        0xa0 => (double_a + double_b).to_bits(),
+        0xa1 => (double_a - double_b).to_bits(),
+        0xa2 => (double_a * double_b).to_bits(),
+        0xa3 => (double_a / double_b).to_bits(),
+        0xa4 => min(double_a, double_b).to_bits(),
+        0xa5 => max(double_a, double_b).to_bits(),
+        0xa6 => (a & !F64_SIGN) | (b & F64_SIGN),
+        0xa7 => b32 as u64,
+        0xa8 => truncate(single_b as f64, -2147483648.0, 2147483648.0)? as i32 as u32 as u64,
+        0xa9 => truncate(single_b as f64, 0.0, 4294967296.0)? as u32 as u64,
+        0xaa => truncate(double_b, -2147483648.0, 2147483648.0)? as i32 as u32 as u64,
+        0xab => truncate(double_b, 0.0, 4294967296.0)? as u32 as u64,
+        0xac => b32 as i32 as i64 as u64,
+        0xad => b32 as u64,
+        0xae => truncate(single_b as f64, -9223372036854775808.0, 9223372036854775808.0)? as i64 as u64,
+        0xaf => truncate(single_b as f64, 0.0, 18446744073709551616.0)? as u64,
+        0xb0 => truncate(double_b, -9223372036854775808.0, 9223372036854775808.0)? as i64 as u64,
+        0xb1 => truncate(double_b, 0.0, 18446744073709551616.0)? as u64,
+        0xb2 => (b32 as i32 as f32).to_bits() as u64,
+        0xb3 => (b32 as f32).to_bits() as u64,
+        0xb4 => (b as i64 as f32).to_bits() as u64,
+        0xb5 => (b as f32).to_bits() as u64,
+        0xb6 => (double_b as f32).to_bits() as u64,
+        0xb7 => (b32 as i32 as f64).to_bits(),
+        0xb8 => (b32 as f64).to_bits(),
+        0xb9 => (b as i64 as f64).to_bits(),
+        0xba => (b as f64).to_bits(),
+        0xbb => (single_b as f64).to_bits(),
+        // Reinterpretations: the bits stay as they are.
+        0xbc | 0xbe => b32 as u64,
+        0xbd | 0xbf => b,
+        0xc0 => b as i8 as i32 as u32 as u64,
+        0xc1 => b as i16 as i32 as u32 as u64,
+        0xc2 => b as i8 as i64 as u64,
+        0xc3 => b as i16 as i64 as u64,
+        _ => b as i32 as i64 as u64,
+    };
+    stack.push(value);
+    Ok(())
+}
+
+/// Comparison `which` of `eq`, `ne`, `lt`, `gt`, `le` and `ge`.
+fn compare(which: u8, a: f64, b: f64) -> bool {
+    match which {
+        0 => a == b,
+        1 => a != b,
+        2 => a < b,
+        3 => a > b,
+        4 => a <= b,
+        _ => a >= b,
+    }
+}
+
+/// Rounding `which` of `ceil`, `floor`, `trunc`, `nearest` and `sqrt`.
+fn round(which: u8, x: f64) -> f64 {
+    match which {
+        0 => x.ceil(),
+        1 => x.floor(),
+        2 => x.trunc(),
+        3 => x.round_ties_even(),
+        _ => x.sqrt(),
+    }
+}
+
+/// The lesser of `a` and `b`, where NaN wins and -0 is less than 0.
+fn min(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b && a.is_sign_negative() {
+        a
+    } else {
+        a.min(b)
+    }
+}
+
+/// The greater of `a` and `b`, where NaN wins and 0 is greater than -0.
+fn max(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b && a.is_sign_positive() {
+        a
+    } else {
+        a.max(b)
+    }
+}
+
+/// `x` truncated to an integer, which must be at least `low` and less
+/// than `high`.
+fn truncate(x: f64, low: f64, high: f64) -> Result<f64, String> {
+    if x.is_nan() {
+        return Err("invalid conversion to integer".to_string());
+    }
+    let x = x.trunc();
+    if x < low || x >= high {
+        return Err("integer overflow".to_string());
+    }
+    Ok(x)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A module to encode: its functions are a type index and a body
+    /// (without locals or the final `end`), exported under their name
+    /// unless it is empty, and numbered after its imports from `vssh`.
+    #[derive(Default)]
+    pub(crate) struct TestModule {
+        pub(crate) types: Vec<FuncType>,
+        pub(crate) imports: Vec<(&'static str, u32)>,
+        pub(crate) functions: Vec<(&'static str, u32, Vec<u8>)>,
+        pub(crate) table: Vec<u32>,
+        pub(crate) pages: Option<u32>,
+        /// Written at address 0.
+        pub(crate) data: Vec<u8>,
+    }
+
+    impl TestModule {
+        pub(crate) fn encode(&self) -> Vec<u8> {
+            let mut bytes = b"\0asm\x01\0\0\0".to_vec();
+            let mut section = |id: u8, count: usize, items: Vec<u8>| {
+                if count > 0 {
+                    let mut body = leb(count as u64);
+                    body.extend(items);
+                    bytes.push(id);
+                    bytes.extend(leb(body.len() as u64));
+                    bytes.extend(body);
+                }
+            };
+            let val_types = |types: &[ValType]| {
+                let mut bytes = leb(types.len() as u64);
+                bytes.extend(types.iter().map(|ty| match ty {
+                    ValType::I32 => 0x7f,
+                    ValType::I64 => 0x7e,
+                    ValType::F32 => 0x7d,
+                    ValType::F64 => 0x7c,
+                }));
+                bytes
+            };
+            let name = |name: &str| [leb(name.len() as u64), name.as_bytes().to_vec()].concat();
+            section(1, self.types.len(), self.types.iter().flat_map(|ty| [vec![0x60], val_types(&ty.params), val_types(&ty.results)].concat()).collect());
+            section(2, self.imports.len(), self.imports.iter().flat_map(|(function, ty)| [name("vssh"), name(function), vec![0], leb(*ty as u64)].concat()).collect());
+            section(3, self.functions.len(), self.functions.iter().flat_map(|(_, ty, _)| leb(*ty as u64)).collect());
+            section(4, usize::from(!self.table.is_empty()), [vec![0x70, 0], leb(self.table.len() as u64)].concat());
+            section(5, self.pages.iter().len(), self.pages.iter().flat_map(|pages| [vec![1], leb(*pages as u64), leb(*pages as u64)].concat()).collect());
+            let exports: Vec<_> = (self.imports.len()..).zip(&self.functions).filter(|(_, (name, _, _))| !name.is_empty()).collect();
+            section(7, exports.len(), exports.iter().flat_map(|(index, (function, _, _))| [name(function), vec![0], leb(*index as u64)].concat()).collect());
+            let table = self.table.iter().flat_map(|function| leb(*function as u64));
+            section(9, usize::from(!self.table.is_empty()), [vec![0, 0x41, 0, 0x0b], leb(self.table.len() as u64), table.collect()].concat());
+            let code = self.functions.iter().flat_map(|(_, _, body)| {
+                // Two i32 locals, for scratch.
+                let body = [vec![1, 2, 0x7f], body.clone(), vec![0x0b]].concat();
+                [leb(body.len() as u64), body].concat()
+            });
+            section(10, self.functions.len(), code.collect());
+            section(11, usize::from(!self.data.is_empty()), [vec![0, 0x41, 0, 0x0b], leb(self.data.len() as u64), self.data.clone()].concat());
+            bytes
+        }
+    }
+
+    /// `n` in unsigned LEB128.
+    fn leb(mut n: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                bytes.push(byte);
+                return bytes;
+            }
+            bytes.push(byte | 0x80);
+        }
+    }
+
+    /// A host with no functions.
+    struct NoHost;
+
+    impl Host for NoHost {
+        fn resolve(&self, _: &str, _: &str, _: &FuncType) -> Option<usize> {
+            None
+        }
+
+        fn call(&mut self, _: usize, _: &[u64], _: &mut Memory) -> Result<Option<u64>, String> {
+            unreachable!()
+        }
+    }
+
+    fn i32_type(params: usize, results: usize) -> FuncType {
+        FuncType { params: vec![ValType::I32; params], results: vec![ValType::I32; results] }
+    }
+
+    fn instance(module: TestModule) -> Instance {
+        Instance::new(&module.encode(), &mut NoHost, 1).unwrap()
+    }
+
+    fn call(instance: &mut Instance, name: &str, ty: &FuncType, args: &[u64]) -> Result<Vec<u64>, String> {
+        instance.call_export(&mut NoHost, name, ty, args)
+    }
+
+    #[test]
+    fn branches_to_the_label_at_each_depth() {
+        let switch = [
+            // block block block (local.get 0; br_table 0 1 2) end
+            &[0x02, 0x40, 0x02, 0x40, 0x02, 0x40, 0x20, 0, 0x0e, 2, 0, 1, 2, 0x0b][..],
+            // i32.const 10; return; end; i32.const 20; return; end; i32.const 30
+            &[0x41, 10, 0x0f, 0x0b, 0x41, 20, 0x0f, 0x0b, 0x41, 30],
+        ]
+        .concat();
+        let sum = [
+            // block loop (br_if 1 on local 0 being 0)
+            &[0x02, 0x40, 0x03, 0x40, 0x20, 0, 0x45, 0x0d, 1][..],
+            // local 1 += local 0; local 0 -= 1; br 0; end end; local.get 1
+            &[0x20, 1, 0x20, 0, 0x6a, 0x21, 1, 0x20, 0, 0x41, 1, 0x6b, 0x21, 0, 0x0c, 0, 0x0b, 0x0b, 0x20, 1],
+        ]
+        .concat();
+        let mut instance = instance(TestModule { types: vec![i32_type(1, 1)], functions: vec![("switch", 0, switch), ("sum", 0, sum)], ..TestModule::default() });
+        let ty = i32_type(1, 1);
+        for (index, result) in [(0, 10), (1, 20), (2, 30), (7, 30)] {
+            assert_eq!(call(&mut instance, "switch", &ty, &[index]).unwrap(), [result]);
+        }
+        assert_eq!(call(&mut instance, "sum", &ty, &[10]).unwrap(), [55]);
+    }
+
+    #[test]
+    fn traps_on_loads_and_stores_out_of_bounds() {
+        let functions = vec![
+            // i32.load offset=0, i32.store offset=0, i32.load offset=4
+            ("load", 0, vec![0x20, 0, 0x28, 2, 0]),
+            ("store", 1, vec![0x20, 0, 0x20, 1, 0x36, 2, 0]),
+            ("load_after", 0, vec![0x20, 0, 0x28, 2, 4]),
+        ];
+        let mut instance = instance(TestModule { types: vec![i32_type(1, 1), i32_type(2, 0)], functions, pages: Some(1), ..TestModule::default() });
+        let (load, store) = (i32_type(1, 1), i32_type(2, 0));
+        let last = PAGE_SIZE as u64 - 4;
+        call(&mut instance, "store", &store, &[last, 42]).unwrap();
+        assert_eq!(call(&mut instance, "load", &load, &[last]).unwrap(), [42]);
+        assert_eq!(call(&mut instance, "load_after", &load, &[last - 4]).unwrap(), [42]);
+        for address in [last + 1, PAGE_SIZE as u64, u32::MAX as u64] {
+            assert_eq!(call(&mut instance, "load", &load, &[address]).unwrap_err(), "out of bounds memory access");
+            assert_eq!(call(&mut instance, "store", &store, &[address, 1]).unwrap_err(), "out of bounds memory access");
+        }
+        assert_eq!(call(&mut instance, "load_after", &load, &[last]).unwrap_err(), "out of bounds memory access");
+        // The offset is added without wrapping around.
+        assert_eq!(call(&mut instance, "load_after", &load, &[u32::MAX as u64 - 2]).unwrap_err(), "out of bounds memory access");
+    }
+
+    #[test]
+    fn stops_a_call_that_runs_out_of_fuel() {
+        // loop br 0 end
+        let spin = vec![0x03, 0x40, 0x0c, 0, 0x0b];
+        let mut instance = instance(TestModule { types: vec![i32_type(0, 0), i32_type(0, 1)], functions: vec![("spin", 0, spin), ("seven", 1, vec![0x41, 7])], ..TestModule::default() });
+        assert_eq!(call(&mut instance, "spin", &i32_type(0, 0), &[]).unwrap_err(), "ran too long");
+        // The next call gets a budget of its own.
+        assert_eq!(call(&mut instance, "seven", &i32_type(0, 1), &[]).unwrap(), [7]);
+    }
+
+    #[test]
+    fn limits_how_deep_calls_nest() {
+        // if local 0 then depth(local 0 - 1) + 1 else 0 end
+        let depth = vec![0x20, 0, 0x04, 0x7f, 0x20, 0, 0x41, 1, 0x6b, 0x10, 0, 0x41, 1, 0x6a, 0x05, 0x41, 0, 0x0b];
+        // As deep as the shell's main thread goes, which is deeper than a
+        // test thread's stack allows.
+        let test = move || {
+            let mut instance = instance(TestModule { types: vec![i32_type(1, 1)], functions: vec![("depth", 0, depth)], ..TestModule::default() });
+            let ty = i32_type(1, 1);
+            let deepest = MAX_DEPTH as u64 - 1;
+            assert_eq!(call(&mut instance, "depth", &ty, &[deepest]).unwrap(), [deepest]);
+            assert_eq!(call(&mut instance, "depth", &ty, &[deepest + 1]).unwrap_err(), "call stack exhausted");
+            assert_eq!(call(&mut instance, "depth", &ty, &[3]).unwrap(), [3]);
+        };
+        std::thread::Builder::new().stack_size(8 << 20).spawn(test).unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn checks_the_type_of_indirect_calls() {
+        let functions = vec![
+            ("", 1, vec![0x41, 7]),
+            // call_indirect (type 1) on local 0
+            ("dispatch", 0, vec![0x20, 0, 0x11, 1, 0]),
+        ];
+        let mut instance = instance(TestModule { types: vec![i32_type(1, 1), i32_type(0, 1)], functions, table: vec![0, 1], ..TestModule::default() });
+        let ty = i32_type(1, 1);
+        assert_eq!(call(&mut instance, "dispatch", &ty, &[0]).unwrap(), [7]);
+        assert_eq!(call(&mut instance, "dispatch", &ty, &[1]).unwrap_err(), "indirect call type mismatch");
+        assert_eq!(call(&mut instance, "dispatch", &ty, &[2]).unwrap_err(), "undefined element");
+        assert_eq!(instance.call_indirect(&mut NoHost, 0, &i32_type(0, 1), &[]).unwrap(), [7]);
+        assert_eq!(instance.call_indirect(&mut NoHost, 1, &i32_type(0, 1), &[]).unwrap_err(), "function has the wrong type");
+    }
+}